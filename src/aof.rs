@@ -0,0 +1,888 @@
+use std::{fs::{self, File, OpenOptions}, io::{Read, Write}, ops::Bound, path::{Path, PathBuf}, thread::JoinHandle};
+
+use anyhow::{anyhow, Error};
+
+use crate::{commands::{execute, lookup}, config::AppendFsync, database::{dbstate, key_value, Reply}, rdb::{self, Snapshot}, resp::{bytes_to_string, string_to_bytes}, stream::{now_ms, Stream}};
+
+/// Lists are rewritten as RPUSH commands of at most this many elements.
+const REWRITE_ITEMS_PER_CMD: usize = 64;
+/// Seconds to wait after a failed rewrite before starting another one.
+const REWRITE_RETRY_DELAY: u64 = 5;
+
+/// The append-only file, kept as a directory of parts listed in a manifest:
+/// a base file holding the dataset as of the last rewrite, as RDB or as
+/// commands, then incr files logging every write command since.
+#[derive(Default)]
+pub struct Aof {
+    /// The incr file being appended to, open while appendonly is on.
+    file: Option<File>,
+    manifest: Manifest,
+    rewrite: Option<Rewrite>,
+    /// Turned on at runtime and waiting for its first base file; until then
+    /// the manifest on disk doesn't describe the dataset and isn't updated.
+    awaiting_base: bool,
+    /// Start a rewrite as soon as the running one is done.
+    rewrite_scheduled: bool,
+    /// Database the file's last SELECT chose; `None` for a file that has
+    /// none yet.
+    selected_db: Option<usize>,
+    /// Unix milliseconds of the last fsync.
+    last_fsync: u64,
+    /// Written to since the last fsync.
+    unsynced: bool,
+    pub last_write_failed: bool,
+    last_rewrite_failed: bool,
+    /// Unix seconds of the last rewrite started.
+    last_rewrite_try: u64,
+    last_rewrite_time: Option<u64>,
+    /// Bytes in the base and incr files.
+    current_size: u64,
+    /// `current_size` after the last rewrite; automatic rewrites measure
+    /// growth against it.
+    base_size: u64,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum FileKind {
+    Base,
+    Incr,
+    /// Replaced by a rewrite and due for deletion.
+    History,
+}
+
+#[derive(Clone)]
+struct AofFile {
+    name: String,
+    seq: u64,
+    kind: FileKind,
+}
+
+/// The files making up the AOF, in the order they are replayed.
+#[derive(Clone, Default)]
+struct Manifest {
+    base: Option<AofFile>,
+    incrs: Vec<AofFile>,
+    history: Vec<AofFile>,
+    /// Highest sequence numbers handed out so far.
+    base_seq: u64,
+    incr_seq: u64,
+}
+
+/// A background rewrite writing a new base file.
+struct Rewrite {
+    handle: JoinHandle<Result<u64, Error>>,
+    base: AofFile,
+    /// Sequence number of the incr file opened for the writes made after
+    /// the snapshot; `None` if the AOF was off.
+    first_incr: Option<u64>,
+    /// Unix milliseconds.
+    start: u64,
+}
+
+impl Manifest {
+    /// Parses lines of `file <name> seq <n> type <b|i|h>`.
+    fn parse(text: &str) -> Result<Manifest, Error> {
+        let invalid = || anyhow!("Invalid AOF manifest file format");
+        let mut manifest = Manifest::default();
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let words: Vec<&str> = line.split_whitespace().collect();
+            if !words.len().is_multiple_of(2) {
+                return Err(invalid())
+            }
+            let (mut name, mut seq, mut kind) = (None, None, None);
+            for pair in words.chunks(2) {
+                match pair[0] {
+                    "file" => name = Some(pair[1].to_string()),
+                    "seq" => seq = Some(pair[1].parse::<u64>().map_err(|_| invalid())?),
+                    "type" => kind = Some(match pair[1] {
+                        "b" => FileKind::Base,
+                        "i" => FileKind::Incr,
+                        "h" => FileKind::History,
+                        _ => return Err(invalid())
+                    }),
+                    // Left for fields added by later versions.
+                    _ => {}
+                }
+            }
+            let (Some(name), Some(seq), Some(kind)) = (name, seq, kind) else { return Err(invalid()) };
+            if name.contains('/') {
+                return Err(invalid())
+            }
+            let file = AofFile { name, seq, kind };
+            match kind {
+                FileKind::Base => {
+                    if manifest.base.is_some() {
+                        return Err(anyhow!("Found duplicate base file information"))
+                    }
+                    manifest.base_seq = seq;
+                    manifest.base = Some(file);
+                }
+                FileKind::Incr => {
+                    if seq <= manifest.incr_seq {
+                        return Err(anyhow!("Found a non-monotonic sequence number"))
+                    }
+                    manifest.incr_seq = seq;
+                    manifest.incrs.push(file);
+                }
+                FileKind::History => manifest.history.push(file)
+            }
+        }
+        if manifest.base.is_none() && manifest.incrs.is_empty() {
+            return Err(anyhow!("Found an empty AOF manifest"))
+        }
+        Ok(manifest)
+    }
+
+    fn encode(&self) -> String {
+        self.base.iter().chain(&self.history).chain(&self.incrs).map(|f| {
+            let kind = match f.kind {
+                FileKind::Base => 'b',
+                FileKind::Incr => 'i',
+                FileKind::History => 'h'
+            };
+            format!("file {} seq {} type {}\n", f.name, f.seq, kind)
+        }).collect()
+    }
+
+    fn new_base(&mut self, filename: &str, rdb: bool) -> AofFile {
+        self.base_seq += 1;
+        let name = format!("{}.{}.base.{}", filename, self.base_seq, if rdb { "rdb" } else { "aof" });
+        AofFile { name, seq: self.base_seq, kind: FileKind::Base }
+    }
+
+    fn new_incr(&mut self, filename: &str) -> AofFile {
+        self.incr_seq += 1;
+        AofFile { name: format!("{}.{}.incr.aof", filename, self.incr_seq), seq: self.incr_seq, kind: FileKind::Incr }
+    }
+}
+
+/// A command in RESP, the way clients send it.
+pub fn encode(argv: &[String]) -> Vec<u8> {
+    let mut buf = format!("*{}\r\n", argv.len()).into_bytes();
+    for arg in argv {
+        let arg = string_to_bytes(arg);
+        buf.extend(format!("${}\r\n", arg.len()).as_bytes());
+        buf.extend(arg);
+        buf.extend(b"\r\n");
+    }
+    buf
+}
+
+/// Encodes commands that ran in the given databases for a stream that is
+/// in database `selected`, with a SELECT wherever the database changes.
+pub fn encode_selecting(commands: &[(usize, Vec<String>)], selected: &mut Option<usize>) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for (db, argv) in commands {
+        if *selected != Some(*db) {
+            buf.extend(encode(&["SELECT".to_string(), db.to_string()]));
+            *selected = Some(*db);
+        }
+        buf.extend(encode(argv));
+    }
+    buf
+}
+
+impl Aof {
+    /// Whether write commands are being logged.
+    pub fn is_on(&self) -> bool {
+        self.file.is_some()
+    }
+
+    /// Whether appendonly is in effect, including while waiting for the
+    /// rewrite that turns it on.
+    pub fn is_enabled(&self) -> bool {
+        self.is_on() || self.awaiting_base
+    }
+
+    pub fn rewrite_in_progress(&self) -> bool {
+        self.rewrite.is_some()
+    }
+
+    fn append_to(&mut self, path: &Path) -> Result<(), Error> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        self.close();
+        self.file = Some(file);
+        self.selected_db = None;
+        self.last_fsync = now_ms();
+        Ok(())
+    }
+
+    /// Switches logging to a new incr file.
+    fn open_incr(&mut self, dir: &Path, filename: &str) -> Result<u64, Error> {
+        let mut manifest = self.manifest.clone();
+        let incr = manifest.new_incr(filename);
+        self.append_to(&dir.join(&incr.name))?;
+        let seq = incr.seq;
+        manifest.incrs.push(incr);
+        self.manifest = manifest;
+        Ok(seq)
+    }
+
+    pub fn close(&mut self) {
+        if let Some(file) = self.file.take() {
+            let _ = file.sync_data();
+        }
+        self.unsynced = false;
+    }
+
+    /// Appends commands; with `always` they are on disk before the reply
+    /// goes out.
+    pub fn feed(&mut self, commands: &[(usize, Vec<String>)], fsync: AppendFsync) {
+        let Some(file) = &mut self.file else { return };
+        let buf = encode_selecting(commands, &mut self.selected_db);
+        let written = file.write_all(&buf).and_then(|_| if fsync == AppendFsync::Always { file.sync_data() } else { Ok(()) });
+        match written {
+            Ok(()) => {
+                if self.last_write_failed {
+                    println!("AOF write error looks solved, Redis can write again.");
+                }
+                self.last_write_failed = false;
+                self.unsynced = fsync != AppendFsync::Always;
+                self.current_size += buf.len() as u64;
+            }
+            Err(e) if fsync == AppendFsync::Always => {
+                // The reply would claim a write that may not be on disk.
+                eprintln!("Can't recover from AOF write error when the AOF fsync policy is 'always'. Exiting... ({})", e);
+                std::process::exit(1)
+            }
+            Err(e) => {
+                eprintln!("Error writing to the AOF file: {}", e);
+                self.last_write_failed = true;
+            }
+        }
+    }
+
+    /// With `everysec`, flushes to disk once a second, from another thread
+    /// so a slow disk doesn't hold up clients.
+    fn fsync_everysec(&mut self, fsync: AppendFsync) {
+        let now = now_ms();
+        if fsync != AppendFsync::EverySec || !self.unsynced || now - self.last_fsync < 1000 {
+            return
+        }
+        if let Some(file) = self.file.as_ref().and_then(|f| f.try_clone().ok()) {
+            std::thread::spawn(move || file.sync_data());
+            self.last_fsync = now;
+            self.unsynced = false;
+        }
+    }
+}
+
+/// The directory holding the AOF files and their manifest.
+pub fn aof_dir(state: &dbstate) -> PathBuf {
+    Path::new(&state.config.dir).join(&state.config.appenddirname)
+}
+
+fn manifest_path(dir: &Path, filename: &str) -> PathBuf {
+    dir.join(format!("{}.manifest", filename))
+}
+
+fn read_manifest(dir: &Path, filename: &str) -> Result<Option<Manifest>, Error> {
+    let path = manifest_path(dir, filename);
+    if !path.exists() {
+        return Ok(None)
+    }
+    Manifest::parse(&fs::read_to_string(&path)?).map(Some)
+}
+
+fn persist_manifest(dir: &Path, filename: &str, manifest: &Manifest) -> Result<(), Error> {
+    rdb::write_file(&manifest_path(dir, filename), manifest.encode().as_bytes())
+}
+
+/// Whether the dataset has values no command can recreate: the types only
+/// loaded from RDB, and lists and streams with a TTL.
+fn needs_rdb(snapshot: &Snapshot) -> bool {
+    snapshot.entries().any(|(_, _, value, expire_at)| match value {
+        key_value::String(_) => false,
+        key_value::List(_) | key_value::Stream(_) => expire_at.is_some(),
+        key_value::Set(_) | key_value::Hash(_) | key_value::ZSet(_) => true
+    })
+}
+
+/// The commands that rebuild a stream with its counters, groups and
+/// pending entries.
+fn stream_commands(key: &str, stream: &Stream, commands: &mut Vec<Vec<String>>) {
+    let argv = |args: &[&str]| args.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+    let entries = stream.range(Bound::Unbounded, Bound::Unbounded);
+    if entries.is_empty() {
+        // XADD can't create an empty stream: add a placeholder and trim it.
+        commands.push(argv(&["XADD", key, "MAXLEN", "0", "0-1", "x", "y"]));
+    }
+    for (id, fields) in entries {
+        let mut command = argv(&["XADD", key, &id.to_string()]);
+        command.extend(fields.into_iter().flat_map(|(field, value)| [field, value]));
+        commands.push(command);
+    }
+    commands.push(argv(&[
+        "XSETID", key, &stream.last_id().to_string(),
+        "ENTRIESADDED", &stream.entries_added().to_string(),
+        "MAXDELETEDID", &stream.max_deleted_id().to_string(),
+    ]));
+    for (name, group) in &stream.groups {
+        let entries_read = group.entries_read.map_or(-1, |n| n as i64).to_string();
+        commands.push(argv(&["XGROUP", "CREATE", key, name, &group.last_delivered.to_string(), "ENTRIESREAD", &entries_read]));
+        for (id, pending) in &group.pel {
+            commands.push(argv(&[
+                "XCLAIM", key, name, &pending.consumer, "0", &id.to_string(),
+                "TIME", &pending.delivery_time.to_string(),
+                "RETRYCOUNT", &pending.delivery_count.to_string(),
+                "JUSTID", "FORCE",
+            ]));
+        }
+        for (consumer, c) in &group.consumers {
+            if c.pending.is_empty() {
+                commands.push(argv(&["XGROUP", "CREATECONSUMER", key, name, consumer]));
+            }
+        }
+    }
+}
+
+/// The dataset as the commands that rebuild it, for base files written
+/// without RDB.
+fn rewrite_commands(snapshot: &Snapshot) -> Vec<u8> {
+    let mut buf = Vec::new();
+    let mut selected = None;
+    for (db, key, value, expire_at) in snapshot.entries() {
+        let mut commands = Vec::new();
+        match value {
+            key_value::String(v) => {
+                let mut argv = vec!["SET".to_string(), key.clone(), v.clone()];
+                if let Some(at) = expire_at {
+                    argv.extend(["PXAT".to_string(), at.to_string()]);
+                }
+                commands.push(argv);
+            }
+            key_value::List(items) => {
+                for chunk in items.chunks(REWRITE_ITEMS_PER_CMD) {
+                    commands.push([vec!["RPUSH".to_string(), key.clone()], chunk.to_vec()].concat());
+                }
+            }
+            key_value::Stream(stream) => stream_commands(key, stream, &mut commands),
+            // `needs_rdb` keeps these out of command bases.
+            key_value::Set(_) | key_value::Hash(_) | key_value::ZSet(_) => {}
+        }
+        let commands: Vec<_> = commands.into_iter().map(|argv| (db, argv)).collect();
+        buf.extend(encode_selecting(&commands, &mut selected));
+    }
+    buf
+}
+
+/// Writes a base file, returning its size.
+fn write_base(snapshot: &Snapshot, path: &Path, rdb: bool) -> Result<u64, Error> {
+    let data = if rdb { snapshot.encode(true) } else { rewrite_commands(snapshot) };
+    rdb::write_file(path, &data)?;
+    Ok(data.len() as u64)
+}
+
+fn file_size(dir: &Path, file: &AofFile) -> u64 {
+    fs::metadata(dir.join(&file.name)).map_or(0, |m| m.len())
+}
+
+/// Starts writing a new base file from a snapshot of the dataset. While the
+/// AOF is on, writes from here on go to a new incr file, which the
+/// finished rewrite keeps in front of the older ones it replaces.
+pub fn rewrite(state: &mut dbstate) -> Result<(), Error> {
+    if state.aof.rewrite_in_progress() {
+        return Err(anyhow!("a rewrite is already in progress"))
+    }
+    let dir = aof_dir(state);
+    let filename = state.config.appendfilename.clone();
+    let use_rdb = state.config.aof_use_rdb_preamble;
+    state.aof.last_rewrite_try = now_ms() / 1000;
+    fs::create_dir_all(&dir)?;
+    let aof = &mut state.aof;
+    if !aof.is_on() {
+        // Pick up where an earlier run or rewrite left the directory.
+        aof.manifest = read_manifest(&dir, &filename)?.unwrap_or_default();
+    }
+    let first_incr = if aof.is_enabled() {
+        let seq = aof.open_incr(&dir, &filename)?;
+        if !aof.awaiting_base {
+            persist_manifest(&dir, &filename, &aof.manifest)?;
+        }
+        Some(seq)
+    } else {
+        None
+    };
+    let snapshot = Snapshot::take(state);
+    let use_rdb = use_rdb || needs_rdb(&snapshot);
+    let aof = &mut state.aof;
+    let base = aof.manifest.new_base(&filename, use_rdb);
+    let path = dir.join(&base.name);
+    let handle = std::thread::spawn(move || write_base(&snapshot, &path, use_rdb));
+    aof.rewrite = Some(Rewrite { handle, base, first_incr, start: now_ms() });
+    println!("Background append only file rewriting started");
+    Ok(())
+}
+
+/// Installs a finished rewrite's base file: the manifest drops the base and
+/// incr files it replaces, and those are deleted.
+fn finish_rewrite(state: &mut dbstate) {
+    let dir = aof_dir(state);
+    let filename = state.config.appendfilename.clone();
+    let aof = &mut state.aof;
+    let Rewrite { handle, base, first_incr, start } = aof.rewrite.take().unwrap();
+    let result = handle.join().unwrap_or_else(|_| Err(anyhow!("rewrite panicked")));
+    aof.last_rewrite_time = Some((now_ms() - start) / 1000);
+    if first_incr.is_some() != aof.is_on() {
+        // appendonly was switched meanwhile, so the base doesn't go with the
+        // files being logged to.
+        println!("Background AOF rewrite discarded: appendonly changed while it ran");
+        let _ = fs::remove_file(dir.join(&base.name));
+        return
+    }
+    let mut manifest = aof.manifest.clone();
+    let new_base = base.clone();
+    let installed = result.and_then(|size| {
+        let old_base = manifest.base.replace(base);
+        let keep_from = first_incr.unwrap_or(u64::MAX);
+        let (incrs, replaced) = manifest.incrs.drain(..).partition(|f| f.seq >= keep_from);
+        manifest.incrs = incrs;
+        manifest.history.extend(old_base.into_iter().chain(replaced).map(|f| AofFile { kind: FileKind::History, ..f }));
+        persist_manifest(&dir, &filename, &manifest)?;
+        Ok(size)
+    });
+    let size = match installed {
+        Ok(size) => size,
+        Err(e) => {
+            eprintln!("Background AOF rewrite terminated with error: {}", e);
+            let _ = fs::remove_file(dir.join(&new_base.name));
+            aof.last_rewrite_failed = true;
+            // Turning the AOF on has to get its base eventually.
+            aof.rewrite_scheduled |= aof.awaiting_base;
+            return
+        }
+    };
+    for file in std::mem::take(&mut manifest.history) {
+        if let Err(e) = fs::remove_file(dir.join(&file.name)) {
+            eprintln!("Can't remove the replaced AOF file {}: {}", file.name, e);
+        }
+    }
+    // Best effort: a stale history entry only names a file that's gone.
+    let _ = persist_manifest(&dir, &filename, &manifest);
+    aof.current_size = size + manifest.incrs.iter().map(|f| file_size(&dir, f)).sum::<u64>();
+    aof.base_size = aof.current_size;
+    aof.manifest = manifest;
+    aof.awaiting_base = false;
+    aof.last_rewrite_failed = false;
+    println!("Background AOF rewrite finished successfully");
+}
+
+/// Periodic work: the everysec fsync, reaping a finished rewrite, and
+/// starting a scheduled or automatic one.
+pub fn cron(state: &mut dbstate) {
+    let fsync = state.config.appendfsync;
+    state.aof.fsync_everysec(fsync);
+    if state.aof.rewrite.as_ref().is_some_and(|r| r.handle.is_finished()) {
+        finish_rewrite(state);
+    }
+    let aof = &state.aof;
+    if aof.rewrite_in_progress() || (aof.last_rewrite_failed && now_ms() / 1000 - aof.last_rewrite_try < REWRITE_RETRY_DELAY) {
+        return
+    }
+    let percentage = state.config.auto_aof_rewrite_percentage;
+    let growth = aof.current_size.saturating_sub(aof.base_size) * 100 / aof.base_size.max(1);
+    let auto = aof.is_on() && !aof.awaiting_base && percentage > 0
+        && aof.current_size > state.config.auto_aof_rewrite_min_size && growth >= percentage;
+    if auto {
+        println!("Starting automatic rewriting of AOF on {}% growth", growth);
+    }
+    if auto || aof.rewrite_scheduled {
+        state.aof.rewrite_scheduled = false;
+        if let Err(e) = rewrite(state) {
+            eprintln!("Can't rewrite append only file in background: {}", e);
+            state.aof.last_rewrite_failed = true;
+            state.aof.rewrite_scheduled |= state.aof.awaiting_base;
+        }
+    }
+}
+
+/// Turns the AOF on for a running dataset. Writes are logged from now on,
+/// and the manifest switches over once a rewrite has written a base file
+/// with the current keys.
+pub fn start(state: &mut dbstate) -> Result<(), Error> {
+    state.aof.awaiting_base = true;
+    if state.aof.rewrite_in_progress() {
+        // The running rewrite started before the AOF was on; `cron` starts
+        // the one that counts when it's done.
+        state.aof.rewrite_scheduled = true;
+        return Ok(())
+    }
+    rewrite(state).inspect_err(|_| state.aof.awaiting_base = false)
+}
+
+pub fn stop(state: &mut dbstate) {
+    state.aof.close();
+    state.aof.awaiting_base = false;
+    state.aof.rewrite_scheduled = false;
+}
+
+/// Loads the AOF at startup and opens it for appending. A single-file AOF
+/// from before the AOF directory existed becomes the base of a new
+/// manifest; with no AOF at all, the base is a snapshot of the empty
+/// dataset.
+pub fn load(state: &mut dbstate) -> Result<(), Error> {
+    let dir = aof_dir(state);
+    let filename = state.config.appendfilename.clone();
+    let legacy = Path::new(&state.config.dir).join(&filename);
+    let mut manifest = match read_manifest(&dir, &filename)? {
+        Some(manifest) => {
+            let files: Vec<&AofFile> = manifest.base.iter().chain(&manifest.incrs).collect();
+            let mut commands = 0;
+            for (i, file) in files.iter().enumerate() {
+                let path = dir.join(&file.name);
+                if !path.exists() {
+                    return Err(anyhow!("The AOF file {} doesn't exist", file.name))
+                }
+                commands += load_file(&path, state, i + 1 == files.len())?;
+            }
+            println!("DB loaded from append only file: {} commands", commands);
+            manifest
+        }
+        None if legacy.is_file() => {
+            let commands = load_file(&legacy, state, true)?;
+            println!("DB loaded from append only file: {} commands", commands);
+            let mut preamble = [0; 5];
+            let rdb = File::open(&legacy)?.read_exact(&mut preamble).is_ok() && &preamble == b"REDIS";
+            fs::create_dir_all(&dir)?;
+            let mut manifest = Manifest::default();
+            let base = manifest.new_base(&filename, rdb);
+            fs::rename(&legacy, dir.join(&base.name))?;
+            manifest.base = Some(base);
+            persist_manifest(&dir, &filename, &manifest)?;
+            println!("Successfully migrated an old-style AOF into the AOF directory");
+            manifest
+        }
+        None => Manifest::default()
+    };
+    fs::create_dir_all(&dir)?;
+    if manifest.base.is_none() && manifest.incrs.is_empty() {
+        let snapshot = Snapshot::take(state);
+        let use_rdb = state.config.aof_use_rdb_preamble || needs_rdb(&snapshot);
+        let base = manifest.new_base(&filename, use_rdb);
+        println!("Creating AOF base file {} on server start", base.name);
+        write_base(&snapshot, &dir.join(&base.name), use_rdb)?;
+        manifest.base = Some(base);
+    }
+    state.aof.manifest = manifest;
+    match state.aof.manifest.incrs.last() {
+        Some(incr) => {
+            let path = dir.join(&incr.name);
+            state.aof.append_to(&path)?;
+        }
+        None => {
+            state.aof.open_incr(&dir, &filename)?;
+            persist_manifest(&dir, &filename, &state.aof.manifest)?;
+        }
+    }
+    let aof = &mut state.aof;
+    aof.current_size = aof.manifest.base.iter().chain(&aof.manifest.incrs).map(|f| file_size(&dir, f)).sum();
+    aof.base_size = aof.current_size;
+    Ok(())
+}
+
+enum Parsed {
+    /// A command and the bytes it took.
+    Command(Vec<String>, usize),
+    /// The data ends partway through a command.
+    Truncated,
+    Invalid,
+}
+
+fn parse_command(data: &[u8]) -> Parsed {
+    let mut pos = 0;
+    // Reads a `*<n>` or `$<n>` line whose marker the caller has checked:
+    // `None` if it isn't complete, `Some(None)` if `n` isn't a number.
+    let line = |pos: &mut usize| -> Option<Option<i64>> {
+        let end = data[*pos..].windows(2).position(|w| w == b"\r\n")? + *pos;
+        let n = std::str::from_utf8(&data[*pos + 1..end]).ok().and_then(|n| n.parse::<i64>().ok());
+        *pos = end + 2;
+        Some(n)
+    };
+    if data[0] != b'*' {
+        return Parsed::Invalid
+    }
+    let count = match line(&mut pos) {
+        None => return Parsed::Truncated,
+        Some(Some(n)) if n > 0 => n,
+        Some(_) => return Parsed::Invalid
+    };
+    let mut argv = Vec::new();
+    for _ in 0..count {
+        if pos >= data.len() {
+            return Parsed::Truncated
+        }
+        if data[pos] != b'$' {
+            return Parsed::Invalid
+        }
+        let len = match line(&mut pos) {
+            None => return Parsed::Truncated,
+            Some(Some(n)) if n >= 0 => n as usize,
+            Some(_) => return Parsed::Invalid
+        };
+        let Some(arg) = data.get(pos..pos + len) else { return Parsed::Truncated };
+        match data.get(pos + len..pos + len + 2) {
+            None => return Parsed::Truncated,
+            Some(b"\r\n") => {}
+            Some(_) => return Parsed::Invalid
+        }
+        argv.push(bytes_to_string(arg));
+        pos += len + 2;
+    }
+    Parsed::Command(argv, pos)
+}
+
+fn replay(command: &[String], state: &mut dbstate) -> Result<(), Error> {
+    let name = command[0].to_uppercase();
+    lookup(&name, &command[1..]).map_err(|_| anyhow!("Unknown command '{}' reading the append only file", command[0]))?;
+    // Errors are part of what happened the first time round.
+    if let Ok(Reply::Blocked { id, .. }) = execute(&name, &command[1..], state) {
+        state.unblock(id);
+    }
+    state.propagated.clear();
+    Ok(())
+}
+
+/// Replays one AOF file into `state`, returning the number of commands run.
+/// A command cut short at the end of the `last` file, or a MULTI without its
+/// EXEC, is dropped and the file truncated before it when
+/// aof-load-truncated is on.
+fn load_file(path: &Path, state: &mut dbstate, last: bool) -> Result<usize, Error> {
+    let data = fs::read(path)?;
+    // Each file starts in database 0, as Redis writes them.
+    state.select(0);
+    let mut pos = 0;
+    if data.starts_with(b"REDIS") {
+        let (keys, len) = crate::rdb::load_bytes(&data, state)?;
+        println!("Reading RDB preamble from AOF file: {} keys", keys);
+        pos = len;
+    }
+    let mut commands = 0;
+    // Offset of an open MULTI and the commands queued after it.
+    let mut multi: Option<(usize, Vec<Vec<String>>)> = None;
+    let mut truncated_at = None;
+    while pos < data.len() {
+        let (command, len) = match parse_command(&data[pos..]) {
+            Parsed::Command(command, len) => (command, len),
+            Parsed::Truncated => {
+                truncated_at = Some(pos);
+                break;
+            }
+            Parsed::Invalid => return Err(anyhow!("Bad file format reading the append only file {}", path.display()))
+        };
+        let start = pos;
+        pos += len;
+        commands += 1;
+        match (command[0].to_uppercase().as_str(), &mut multi) {
+            ("MULTI", None) => multi = Some((start, Vec::new())),
+            ("EXEC", Some(_)) => {
+                for queued in multi.take().unwrap().1 {
+                    replay(&queued, state)?;
+                }
+            }
+            (_, Some((_, queued))) => queued.push(command),
+            (_, None) => replay(&command, state)?
+        }
+    }
+    if let Some((start, _)) = multi {
+        truncated_at = Some(start);
+    }
+    if let Some(valid) = truncated_at {
+        if !last {
+            return Err(anyhow!("Unexpected end of file reading the append only file {}, which is not the last file", path.display()))
+        }
+        if !state.config.aof_load_truncated {
+            return Err(anyhow!("Unexpected end of file reading the append only file {}. You can: 1) Make a backup of your AOF file, then use ./redis-check-aof --fix <filename>. 2) Alternatively you can set the 'aof-load-truncated' configuration option to yes and restart the server.", path.display()))
+        }
+        eprintln!("!!! Warning: short read while loading the AOF file {} !!!", path.display());
+        eprintln!("AOF loaded anyway because aof-load-truncated is enabled; truncating it to {} bytes", valid);
+        OpenOptions::new().write(true).open(path)?.set_len(valid as u64)?;
+    }
+    state.persistence.dirty = 0;
+    Ok(commands)
+}
+
+/// AOF fields of the persistence section of INFO.
+pub fn info(state: &dbstate) -> Vec<(&'static str, String)> {
+    let aof = &state.aof;
+    let current = aof.rewrite.as_ref().map_or(-1, |r| ((now_ms() - r.start) / 1000) as i64);
+    let mut fields = vec![
+        ("aof_enabled", (aof.is_enabled() as u8).to_string()),
+        ("aof_rewrite_in_progress", (aof.rewrite_in_progress() as u8).to_string()),
+        ("aof_rewrite_scheduled", (aof.rewrite_scheduled as u8).to_string()),
+        ("aof_last_rewrite_time_sec", aof.last_rewrite_time.map_or(-1, |t| t as i64).to_string()),
+        ("aof_current_rewrite_time_sec", current.to_string()),
+        ("aof_last_bgrewrite_status", if aof.last_rewrite_failed { "err" } else { "ok" }.to_string()),
+        ("aof_last_write_status", if aof.last_write_failed { "err" } else { "ok" }.to_string()),
+    ];
+    if aof.is_on() {
+        fields.push(("aof_current_size", aof.current_size.to_string()));
+        fields.push(("aof_base_size", aof.base_size.to_string()));
+    }
+    fields
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{db, key_value};
+
+    fn argv(a: &[&str]) -> Vec<String> {
+        a.iter().map(|s| s.to_string()).collect()
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("aof-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn parse_commands() {
+        let set = encode(&argv(&["SET", "k", "a\r\nb"]));
+        assert!(matches!(parse_command(&set), Parsed::Command(c, len) if c == argv(&["SET", "k", "a\r\nb"]) && len == set.len()));
+        for cut in 1..set.len() {
+            assert!(matches!(parse_command(&set[..cut]), Parsed::Truncated), "{cut}");
+        }
+        for bad in [&b"SET k v\r\n"[..], b"*0\r\n", b"*1\r\n+OK\r\n", b"*1\r\n$x\r\n", b"*1\r\n$1\r\nab\r\n"] {
+            assert!(matches!(parse_command(bad), Parsed::Invalid), "{bad:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn load_file_replays_and_truncates() {
+        let dir = temp_dir("load");
+        let path = dir.join("appendonly.aof");
+        let mut data = encode(&argv(&["SET", "k", "v"]));
+        data.extend(encode(&argv(&["RPUSH", "l", "a", "b"])));
+        data.extend(encode(&argv(&["MULTI"])));
+        data.extend(encode(&argv(&["SET", "k", "w"])));
+        data.extend(encode(&argv(&["EXEC"])));
+        let valid = data.len();
+        data.extend(encode(&argv(&["MULTI"])));
+        data.extend(encode(&argv(&["SET", "k", "lost"])));
+        fs::write(&path, &data).unwrap();
+
+        let db = db::new();
+        let mut state = db.state.lock().await;
+        assert!(load_file(&path, &mut state, false).is_err());
+        state.config.aof_load_truncated = false;
+        assert!(load_file(&path, &mut state, true).is_err());
+        let db = db::new();
+        let mut state = db.state.lock().await;
+        assert_eq!(load_file(&path, &mut state, true).unwrap(), 7);
+        assert!(matches!(&state.kv["k"], key_value::String(v) if v == "w"));
+        assert!(matches!(&state.kv["l"], key_value::List(l) if l == &["a", "b"]));
+        assert_eq!(fs::metadata(&path).unwrap().len(), valid as u64);
+
+        fs::write(&path, encode(&argv(&["NOSUCHCOMMAND"]))).unwrap();
+        assert!(load_file(&path, &mut state, true).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn manifest_round_trip() {
+        let text = "file appendonly.aof.2.base.rdb seq 2 type b\nfile appendonly.aof.1.base.aof seq 1 type h\n\
+                    file appendonly.aof.3.incr.aof seq 3 type i\nfile appendonly.aof.4.incr.aof seq 4 type i startoffset 10\n";
+        let manifest = Manifest::parse(text).unwrap();
+        assert_eq!(manifest.base.as_ref().unwrap().name, "appendonly.aof.2.base.rdb");
+        assert_eq!((manifest.base_seq, manifest.incr_seq, manifest.incrs.len(), manifest.history.len()), (2, 4, 2, 1));
+        assert_eq!(Manifest::parse(&manifest.encode()).unwrap().encode(), manifest.encode());
+        let mut next = manifest.clone();
+        assert_eq!(next.new_base("appendonly.aof", false).name, "appendonly.aof.3.base.aof");
+        assert_eq!(next.new_incr("appendonly.aof").name, "appendonly.aof.5.incr.aof");
+    }
+
+    #[test]
+    fn manifest_rejects_bad_files() {
+        for bad in [
+            "",
+            "# only a comment\n",
+            "file a seq 1\n",
+            "file a seq x type b\n",
+            "file a seq 1 type q\n",
+            "file dir/a seq 1 type b\n",
+            "file a seq 1 type b\nfile b seq 2 type b\n",
+            "file a seq 2 type i\nfile b seq 1 type i\n",
+            "file a seq 1 type\n",
+        ] {
+            assert!(Manifest::parse(bad).is_err(), "{bad:?}");
+        }
+    }
+
+    #[test]
+    fn read_manifest_from_dir() {
+        let dir = temp_dir("manifest");
+        assert!(read_manifest(&dir, "appendonly.aof").unwrap().is_none());
+        fs::write(manifest_path(&dir, "appendonly.aof"), "file appendonly.aof.1.incr.aof seq 1 type i\n").unwrap();
+        assert_eq!(read_manifest(&dir, "appendonly.aof").unwrap().unwrap().incrs.len(), 1);
+        fs::write(manifest_path(&dir, "appendonly.aof"), "garbage\n").unwrap();
+        assert!(read_manifest(&dir, "appendonly.aof").is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Runs `cron` until the background rewrite is installed.
+    fn finish(state: &mut dbstate) {
+        while state.aof.rewrite_in_progress() {
+            cron(state);
+            std::thread::yield_now();
+        }
+    }
+
+    #[tokio::test]
+    async fn rewrite_replaces_base_and_incrs() {
+        let dir = temp_dir("rewrite");
+        let db = db::new();
+        let mut state = db.state.lock().await;
+        state.config.dir = dir.display().to_string();
+        state.config.aof_use_rdb_preamble = false;
+        load(&mut state).unwrap();
+        assert!(state.aof.is_on());
+        state.aof.feed(&[(0, argv(&["SET", "a", "1"])), (0, argv(&["RPUSH", "l", "x"]))], AppendFsync::Always);
+        state.kv.insert("a".to_string(), key_value::String("1".to_string()));
+        state.kv.insert("l".to_string(), key_value::List(vec!["x".to_string()]));
+        rewrite(&mut state).unwrap();
+        assert!(rewrite(&mut state).is_err());
+        state.aof.feed(&[(0, argv(&["SET", "b", "2"])), (1, argv(&["SET", "c", "3"]))], AppendFsync::Always);
+        finish(&mut state);
+
+        let aof_dir = aof_dir(&state);
+        let manifest = read_manifest(&aof_dir, "appendonly.aof").unwrap().unwrap();
+        assert_eq!(manifest.base.unwrap().name, "appendonly.aof.2.base.aof");
+        assert_eq!(manifest.incrs.iter().map(|f| f.name.as_str()).collect::<Vec<_>>(), ["appendonly.aof.2.incr.aof"]);
+        assert!(manifest.history.is_empty());
+        assert!(!aof_dir.join("appendonly.aof.1.incr.aof").exists());
+        stop(&mut state);
+
+        let loaded = db::new();
+        let mut loaded = loaded.state.lock().await;
+        loaded.config.dir = state.config.dir.clone();
+        load(&mut loaded).unwrap();
+        let (db0, _) = loaded.keyspace(0);
+        assert!(matches!(&db0["a"], key_value::String(v) if v == "1"));
+        assert!(matches!(&db0["b"], key_value::String(v) if v == "2"));
+        assert!(matches!(&db0["l"], key_value::List(l) if l == &["x"]));
+        assert!(!db0.contains_key("c"));
+        assert!(matches!(&loaded.keyspace(1).0["c"], key_value::String(v) if v == "3"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn load_migrates_a_single_file_aof() {
+        let dir = temp_dir("legacy");
+        fs::write(dir.join("appendonly.aof"), encode(&argv(&["SET", "k", "v"]))).unwrap();
+        let db = db::new();
+        let mut state = db.state.lock().await;
+        state.config.dir = dir.display().to_string();
+        load(&mut state).unwrap();
+        assert!(matches!(&state.kv["k"], key_value::String(v) if v == "v"));
+        assert!(!dir.join("appendonly.aof").exists());
+        let manifest = read_manifest(&aof_dir(&state), "appendonly.aof").unwrap().unwrap();
+        assert_eq!(manifest.base.unwrap().name, "appendonly.aof.1.base.aof");
+        assert_eq!(manifest.incrs.len(), 1);
+        stop(&mut state);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,1756 @@
+use std::{collections::{hash_map::RandomState, BTreeMap, HashMap, HashSet}, fs, hash::{BuildHasher, Hasher}, path::PathBuf, sync::Arc};
+
+use anyhow::{anyhow, Error};
+use tokio::{net::{TcpListener, TcpStream}, sync::{mpsc, Notify}, task::AbortHandle};
+
+use crate::{config::NOTIFY_GENERIC, database::{db, dbstate}, rdb, replication::{self, random_id}, resp::{bytes_to_string, string_to_bytes, RespHandler, Value}, stream::now_ms};
+
+pub const CLUSTER_SLOTS: u16 = 16384;
+/// The cluster bus listens on the client port plus this.
+pub const CLUSTER_PORT_INCR: u16 = 10000;
+/// Milliseconds between pings to each node.
+const PING_INTERVAL: u64 = 1000;
+/// Failure reports count for this many node timeouts.
+const FAIL_REPORT_VALIDITY_MULT: u64 = 2;
+/// A master that still serves slots stays FAIL for this many node timeouts
+/// after it comes back, giving its replicas time to fail over.
+const FAIL_UNDO_TIME_MULT: u64 = 2;
+/// Milliseconds a manual failover may take before it is given up.
+const MANUAL_FAILOVER_TIMEOUT: u64 = 5000;
+
+/// CRC16-CCITT (XMODEM), the checksum Redis Cluster hashes keys with.
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+/// Hash slot of `key`. Only the part inside the first non-empty `{...}` is
+/// hashed when there is one, so related keys can share a slot.
+pub fn key_hash_slot(key: &[u8]) -> u16 {
+    if let Some(open) = key.iter().position(|&b| b == b'{')
+        && let Some(len) = key[open + 1..].iter().position(|&b| b == b'}')
+        && len > 0 {
+        return crc16(&key[open + 1..open + 1 + len]) % CLUSTER_SLOTS
+    }
+    crc16(key) % CLUSTER_SLOTS
+}
+
+/// A slot number as given to CLUSTER subcommands.
+pub fn parse_slot(s: &str) -> Result<u16, Error> {
+    s.parse::<u16>().ok().filter(|slot| *slot < CLUSTER_SLOTS)
+        .ok_or_else(|| anyhow!("ERR Invalid or out of range slot"))
+}
+
+fn random_below(n: u64) -> u64 {
+    RandomState::new().build_hasher().finish() % n
+}
+
+/// Slot ranges as `0-5460,5461`, `-` for none.
+fn encode_ranges(ranges: &[(u16, u16)]) -> String {
+    if ranges.is_empty() {
+        return "-".to_string()
+    }
+    ranges.iter().map(|(start, end)| if start == end { start.to_string() } else { format!("{}-{}", start, end) })
+        .collect::<Vec<_>>().join(",")
+}
+
+fn parse_ranges(s: &str) -> Option<Vec<(u16, u16)>> {
+    if s == "-" {
+        return Some(Vec::new())
+    }
+    s.split(',').map(|range| {
+        let (start, end) = range.split_once('-').unwrap_or((range, range));
+        Some((parse_slot(start).ok()?, parse_slot(end).ok()?))
+    }).collect()
+}
+
+pub struct ClusterNode {
+    pub id: String,
+    pub ip: String,
+    pub port: u16,
+    /// Port of the node's cluster bus.
+    pub cport: u16,
+    /// The master this node replicates; `None` for a master.
+    pub master: Option<String>,
+    pub config_epoch: u64,
+    /// Met with CLUSTER MEET and not answered yet: `id` is made up until
+    /// the node replies with its own.
+    handshake: bool,
+    /// Unix milliseconds the node was added, for the handshake timeout.
+    ctime: u64,
+    /// We got no reply to a ping within the node timeout.
+    pfail: bool,
+    /// Enough masters agree the node is unreachable.
+    fail: bool,
+    fail_time: u64,
+    /// Masters that reported the node as failing, with when they last did.
+    fail_reports: HashMap<String, u64>,
+    /// Unix milliseconds of the oldest unanswered ping, 0 if none.
+    ping_sent: u64,
+    pong_received: u64,
+    /// Replication offset the node last told us about.
+    repl_offset: u64,
+    /// Unix milliseconds we last voted for a replica of this master.
+    voted_time: u64,
+}
+
+impl ClusterNode {
+    fn new(id: String, ip: String, port: u16, cport: u16) -> Self {
+        ClusterNode {
+            id,
+            ip,
+            port,
+            cport,
+            master: None,
+            config_epoch: 0,
+            handshake: false,
+            ctime: now_ms(),
+            pfail: false,
+            fail: false,
+            fail_time: 0,
+            fail_reports: HashMap::new(),
+            ping_sent: 0,
+            pong_received: 0,
+            repl_offset: 0,
+            voted_time: 0,
+        }
+    }
+
+    pub fn addr(&self) -> String {
+        format!("{}:{}", self.ip, self.port)
+    }
+}
+
+/// Our connection to a node's bus, written by a task that also reads the
+/// node's replies.
+struct Link {
+    tx: mpsc::UnboundedSender<Value>,
+    /// Tells links apart, since a node's link may be replaced while the
+    /// task of the old one is still winding down.
+    generation: u64,
+    ctime: u64,
+    task: AbortHandle,
+}
+
+impl Drop for Link {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// A CLUSTER FAILOVER in progress.
+enum ManualFailover {
+    /// As the master: writes are held until `end` while `replica` catches
+    /// up and takes over.
+    Master { replica: String, end: u64 },
+    /// As the replica: waiting for the offset the master stopped at, then
+    /// to have replicated up to it.
+    Replica { end: u64, master_offset: Option<u64>, can_start: bool },
+}
+
+/// A replica's attempt to get elected as the new master of its shard.
+struct Election {
+    /// Unix milliseconds to ask for votes at, after a delay that lets the
+    /// best replicated replica go first.
+    start: u64,
+    /// Epoch the votes were asked for in; 0 until they are.
+    epoch: u64,
+    votes: HashSet<String>,
+    manual: bool,
+}
+
+/// A message on the cluster bus, as an array of strings: the sender's view
+/// of itself followed by a payload that depends on the type.
+struct Header {
+    kind: String,
+    sender: String,
+    current_epoch: u64,
+    /// The sender's config epoch, or its master's for a replica.
+    config_epoch: u64,
+    master: Option<String>,
+    offset: u64,
+    port: u16,
+    cport: u16,
+    flags: Vec<String>,
+    /// Slots the sender serves, or its master's for a replica.
+    slots: Vec<(u16, u16)>,
+    payload: Vec<String>,
+}
+
+const HEADER_FIELDS: usize = 10;
+/// id, ip, port, cport and flags of each node in a gossip section.
+const GOSSIP_FIELDS: usize = 5;
+
+impl Header {
+    fn parse(message: Value) -> Option<Header> {
+        let Value::Array(items) = message else { return None };
+        let mut fields = items.into_iter().map(|item| match item {
+            Value::BulkString(s) => Some(bytes_to_string(&s)),
+            _ => None
+        }).collect::<Option<Vec<String>>>()?;
+        if fields.len() < HEADER_FIELDS {
+            return None
+        }
+        let payload = fields.split_off(HEADER_FIELDS);
+        let [kind, sender, current_epoch, config_epoch, master, offset, port, cport, flags, slots] = <[String; HEADER_FIELDS]>::try_from(fields).ok()?;
+        Some(Header {
+            kind,
+            sender,
+            current_epoch: current_epoch.parse().ok()?,
+            config_epoch: config_epoch.parse().ok()?,
+            master: (master != "-").then_some(master),
+            offset: offset.parse().ok()?,
+            port: port.parse().ok()?,
+            cport: cport.parse().ok()?,
+            flags: flags.split(',').filter(|f| *f != "-").map(str::to_string).collect(),
+            slots: parse_ranges(&slots)?,
+            payload,
+        })
+    }
+}
+
+fn message(fields: Vec<String>) -> Value {
+    Value::Array(fields.into_iter().map(Value::bulk).collect())
+}
+
+/// This node's view of the cluster: the nodes, which of them serves each
+/// slot, and the slots being moved between nodes. Persisted to
+/// cluster-config-file in the nodes.conf format whenever it changes.
+pub struct Cluster {
+    pub myself: String,
+    pub nodes: BTreeMap<String, ClusterNode>,
+    /// Node serving each slot.
+    slots: Vec<Option<String>>,
+    /// How many slots each node serves, kept by `set_slot_owner` so the
+    /// cron doesn't have to go through all of them.
+    slot_counts: HashMap<String, usize>,
+    /// Slots this node is handing over, with the node receiving them.
+    migrating: BTreeMap<u16, String>,
+    /// Slots this node is taking over, with the node they come from.
+    importing: BTreeMap<u16, String>,
+    pub current_epoch: u64,
+    last_vote_epoch: u64,
+    /// Keys of the keyspace per slot, for COUNTKEYSINSLOT and
+    /// GETKEYSINSLOT.
+    keys: HashMap<u16, HashSet<String>>,
+    config_file: PathBuf,
+    links: HashMap<String, Link>,
+    next_link: u64,
+    /// Serving queries, as recomputed by `update_state`.
+    ok: bool,
+    /// The configuration changed and has to be saved.
+    todo_save: bool,
+    manual_failover: Option<ManualFailover>,
+    /// Wakes the clients whose writes a manual failover held.
+    writes_resumed: Arc<Notify>,
+    election: Option<Election>,
+    messages_sent: u64,
+    messages_received: u64,
+}
+
+impl Cluster {
+    /// Loads the cluster configuration from `path`, or starts a cluster of
+    /// one node with a fresh ID if there is none yet. Our own address is
+    /// refreshed from `port` and the result saved.
+    pub fn load(path: PathBuf, port: u16) -> Result<Self, Error> {
+        let mut cluster = Cluster {
+            myself: String::new(),
+            nodes: BTreeMap::new(),
+            slots: vec![None; CLUSTER_SLOTS as usize],
+            slot_counts: HashMap::new(),
+            migrating: BTreeMap::new(),
+            importing: BTreeMap::new(),
+            current_epoch: 0,
+            last_vote_epoch: 0,
+            keys: HashMap::new(),
+            config_file: path,
+            links: HashMap::new(),
+            next_link: 0,
+            ok: false,
+            todo_save: false,
+            manual_failover: None,
+            writes_resumed: Arc::new(Notify::new()),
+            election: None,
+            messages_sent: 0,
+            messages_received: 0,
+        };
+        match fs::read_to_string(&cluster.config_file) {
+            Ok(contents) => {
+                for line in contents.lines().filter(|l| !l.trim().is_empty()) {
+                    cluster.parse_line(line)
+                        .ok_or_else(|| anyhow!("Unrecoverable error: corrupted cluster config file \"{}\".", line))?;
+                }
+                if cluster.myself.is_empty() {
+                    return Err(anyhow!("Unrecoverable error: corrupted cluster config file: myself node not found."))
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let id = random_id();
+                println!("No cluster configuration found, I'm {}", id);
+                cluster.nodes.insert(id.clone(), ClusterNode::new(id.clone(), "127.0.0.1".to_string(), port, 0));
+                cluster.myself = id;
+            }
+            Err(e) => return Err(e.into())
+        }
+        let me = cluster.nodes.get_mut(&cluster.myself).unwrap();
+        me.port = port;
+        me.cport = port.saturating_add(CLUSTER_PORT_INCR);
+        cluster.save()?;
+        Ok(cluster)
+    }
+
+    /// One line of nodes.conf: `vars` with the epochs, or a node.
+    fn parse_line(&mut self, line: &str) -> Option<()> {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields[0] == "vars" {
+            for pair in fields[1..].chunks(2) {
+                match pair {
+                    ["currentEpoch", n] => self.current_epoch = n.parse().ok()?,
+                    ["lastVoteEpoch", n] => self.last_vote_epoch = n.parse().ok()?,
+                    _ => {}
+                }
+            }
+            return Some(())
+        }
+        if fields.len() < 8 {
+            return None
+        }
+        let id = fields[0].to_string();
+        // ip:port@cport, possibly followed by ,hostname.
+        let addr = fields[1].split(',').next()?;
+        let (ip_port, cport) = addr.split_once('@')?;
+        let (ip, port) = ip_port.rsplit_once(':')?;
+        let flags: Vec<&str> = fields[2].split(',').collect();
+        if flags.contains(&"myself") {
+            self.myself = id.clone();
+        }
+        for range in &fields[8..] {
+            if let Some(inner) = range.strip_prefix('[').and_then(|r| r.strip_suffix(']')) {
+                if let Some((slot, to)) = inner.split_once("->-") {
+                    self.migrating.insert(parse_slot(slot).ok()?, to.to_string());
+                } else {
+                    let (slot, from) = inner.split_once("-<-")?;
+                    self.importing.insert(parse_slot(slot).ok()?, from.to_string());
+                }
+                continue;
+            }
+            for (start, end) in parse_ranges(range)? {
+                for slot in start..=end {
+                    self.set_slot_owner(slot, Some(&id));
+                }
+            }
+        }
+        let mut node = ClusterNode::new(id.clone(), ip.to_string(), port.parse().ok()?, cport.parse().ok()?);
+        node.master = (fields[3] != "-").then(|| fields[3].to_string());
+        node.config_epoch = fields[6].parse().ok()?;
+        if flags.contains(&"fail") {
+            node.fail = true;
+            node.fail_time = now_ms();
+        }
+        self.nodes.insert(id, node);
+        Some(())
+    }
+
+    pub fn save(&self) -> Result<(), Error> {
+        let nodes: String = self.nodes.values().filter(|n| !n.handshake).map(|n| self.describe(n)).collect();
+        let contents = format!("{}vars currentEpoch {} lastVoteEpoch {}\n", nodes, self.current_epoch, self.last_vote_epoch);
+        rdb::write_file(&self.config_file, contents.as_bytes())
+    }
+
+    /// Saves the configuration after a change; failing to do so is only
+    /// logged, the change stays in effect.
+    pub fn save_config(&mut self) {
+        self.todo_save = false;
+        if let Err(e) = self.save() {
+            eprintln!("Could not save the cluster configuration to {}: {}", self.config_file.display(), e);
+        }
+    }
+
+    pub fn myself(&self) -> &ClusterNode {
+        &self.nodes[&self.myself]
+    }
+
+    fn myself_mut(&mut self) -> &mut ClusterNode {
+        self.nodes.get_mut(&self.myself).unwrap()
+    }
+
+    /// Node serving `slot`, if any.
+    pub fn slot_owner(&self, slot: u16) -> Option<&ClusterNode> {
+        self.slots[slot as usize].as_ref().and_then(|id| self.nodes.get(id))
+    }
+
+    fn set_slot_owner(&mut self, slot: u16, owner: Option<&str>) {
+        let old = std::mem::replace(&mut self.slots[slot as usize], owner.map(str::to_string));
+        if let Some(old) = old
+            && let Some(count) = self.slot_counts.get_mut(&old) {
+            *count -= 1;
+            if *count == 0 {
+                self.slot_counts.remove(&old);
+            }
+        }
+        if let Some(owner) = owner {
+            *self.slot_counts.entry(owner.to_string()).or_default() += 1;
+        }
+    }
+
+    pub fn slots_assigned(&self) -> usize {
+        self.slot_counts.values().sum()
+    }
+
+    /// Assigns free slots to this node. Nothing changes unless all of them
+    /// can be taken.
+    pub fn add_slots(&mut self, slots: &[u16]) -> Result<(), Error> {
+        let mut seen = HashSet::new();
+        for &slot in slots {
+            if self.slots[slot as usize].is_some() {
+                return Err(anyhow!("ERR Slot {} is already busy", slot))
+            }
+            if !seen.insert(slot) {
+                return Err(anyhow!("ERR Slot {} specified multiple times", slot))
+            }
+        }
+        let me = self.myself.clone();
+        for &slot in slots {
+            self.set_slot_owner(slot, Some(&me));
+            self.importing.remove(&slot);
+        }
+        self.save_config();
+        Ok(())
+    }
+
+    /// The slots `id` serves, as inclusive ranges.
+    pub fn slot_ranges(&self, id: &str) -> Vec<(u16, u16)> {
+        let mut ranges: Vec<(u16, u16)> = Vec::new();
+        if !self.serves_slots(id) {
+            return ranges
+        }
+        for slot in 0..CLUSTER_SLOTS {
+            if self.slots[slot as usize].as_deref() != Some(id) {
+                continue;
+            }
+            match ranges.last_mut() {
+                Some((_, end)) if *end + 1 == slot => *end = slot,
+                _ => ranges.push((slot, slot))
+            }
+        }
+        ranges
+    }
+
+    fn remove_slots_of(&mut self, id: &str) {
+        if !self.serves_slots(id) {
+            return
+        }
+        for slot in 0..CLUSTER_SLOTS {
+            if self.slots[slot as usize].as_deref() == Some(id) {
+                self.set_slot_owner(slot, None);
+            }
+        }
+    }
+
+    pub fn replicas_of<'a>(&'a self, id: &'a str) -> impl Iterator<Item = &'a ClusterNode> {
+        self.nodes.values().filter(move |n| n.master.as_deref() == Some(id))
+    }
+
+    /// Masters serving at least one slot.
+    fn size(&self) -> usize {
+        self.slot_counts.len()
+    }
+
+    /// Masters needed to agree on a failure or elect a replica.
+    fn quorum(&self) -> usize {
+        self.size() / 2 + 1
+    }
+
+    fn serves_slots(&self, id: &str) -> bool {
+        self.slot_counts.contains_key(id)
+    }
+
+    /// One line of CLUSTER NODES and nodes.conf: the node's address, flags,
+    /// master, ping times, epoch, link and slots. Slots being moved are only
+    /// listed for this node.
+    fn describe(&self, node: &ClusterNode) -> String {
+        let mut flags = Vec::new();
+        if node.id == self.myself {
+            flags.push("myself");
+        }
+        flags.push(if node.master.is_some() { "slave" } else { "master" });
+        if node.pfail {
+            flags.push("fail?");
+        }
+        if node.fail {
+            flags.push("fail");
+        }
+        if node.handshake {
+            flags.push("handshake");
+        }
+        let connected = node.id == self.myself || (self.links.contains_key(&node.id) && !node.pfail && !node.fail);
+        let link = if connected { "connected" } else { "disconnected" };
+        let mut out = format!("{} {}:{}@{} {} {} {} {} {} {}",
+            node.id, node.ip, node.port, node.cport, flags.join(","),
+            node.master.as_deref().unwrap_or("-"), node.ping_sent, node.pong_received, node.config_epoch, link);
+        for (start, end) in self.slot_ranges(&node.id) {
+            if start == end {
+                out.push_str(&format!(" {}", start));
+            } else {
+                out.push_str(&format!(" {}-{}", start, end));
+            }
+        }
+        if node.id == self.myself {
+            for (slot, to) in &self.migrating {
+                out.push_str(&format!(" [{}->-{}]", slot, to));
+            }
+            for (slot, from) in &self.importing {
+                out.push_str(&format!(" [{}-<-{}]", slot, from));
+            }
+        }
+        out.push('\n');
+        out
+    }
+
+    /// CLUSTER NODES.
+    pub fn nodes_description(&self) -> String {
+        self.nodes.values().map(|n| self.describe(n)).collect()
+    }
+
+    pub fn key_added(&mut self, key: &str) {
+        self.keys.entry(key_hash_slot(&string_to_bytes(key))).or_default().insert(key.to_string());
+    }
+
+    pub fn key_removed(&mut self, key: &str) {
+        let slot = key_hash_slot(&string_to_bytes(key));
+        if let Some(keys) = self.keys.get_mut(&slot) {
+            keys.remove(key);
+            if keys.is_empty() {
+                self.keys.remove(&slot);
+            }
+        }
+    }
+
+    pub fn keys_flushed(&mut self) {
+        self.keys.clear();
+    }
+
+    pub fn count_keys_in_slot(&self, slot: u16) -> usize {
+        self.keys.get(&slot).map_or(0, HashSet::len)
+    }
+
+    pub fn keys_in_slot(&self, slot: u16, count: usize) -> Vec<String> {
+        self.keys.get(&slot).map_or_else(Vec::new, |keys| keys.iter().take(count).cloned().collect())
+    }
+
+    /// Moves to a new config epoch without an election, unless ours is
+    /// already the greatest in the cluster. Returns true if it changed.
+    fn bump_epoch_without_consensus(&mut self) -> bool {
+        let max_epoch = self.nodes.values().map(|n| n.config_epoch).max().unwrap_or(0).max(self.current_epoch);
+        let me = self.myself();
+        if me.config_epoch != 0 && me.config_epoch == max_epoch {
+            return false
+        }
+        self.current_epoch += 1;
+        let epoch = self.current_epoch;
+        self.myself_mut().config_epoch = epoch;
+        println!("New configEpoch set to {}", epoch);
+        true
+    }
+
+    /// CLUSTER MEET: starts a handshake with the node at this address,
+    /// under a made-up ID until it replies with its own.
+    pub fn meet(&mut self, ip: &str, port: u16, cport: u16) {
+        if self.nodes.values().any(|n| n.handshake && n.ip == ip && n.port == port) {
+            return
+        }
+        let mut node = ClusterNode::new(random_id(), ip.to_string(), port, cport);
+        node.handshake = true;
+        self.nodes.insert(node.id.clone(), node);
+    }
+
+    /// Recomputes whether the cluster can serve queries: every slot must be
+    /// served by a node that hasn't failed (unless full coverage isn't
+    /// required), and a master must be able to reach the majority of the
+    /// masters, so a minority partition stops taking writes.
+    pub fn update_state(&mut self, require_full_coverage: bool) {
+        let mut ok = !require_full_coverage
+            || (self.slots_assigned() == CLUSTER_SLOTS as usize
+                && self.slot_counts.keys().all(|id| self.nodes.get(id).is_some_and(|n| !n.fail)));
+        if self.myself().master.is_none() {
+            let reachable = self.nodes.values()
+                .filter(|n| n.master.is_none() && !n.pfail && !n.fail && self.serves_slots(&n.id))
+                .count();
+            ok &= reachable >= self.quorum();
+        }
+        if ok != self.ok {
+            println!("Cluster state changed: {}", if ok { "ok" } else { "fail" });
+            self.ok = ok;
+        }
+    }
+
+    /// Writes are held for a replica taking over in a manual failover.
+    pub fn writes_paused(&self) -> bool {
+        self.writes_pause().is_some()
+    }
+
+    /// While writes are held: what signals the end of the pause, and the
+    /// unix milliseconds it ends by at the latest.
+    pub fn writes_pause(&self) -> Option<(Arc<Notify>, u64)> {
+        match self.manual_failover {
+            Some(ManualFailover::Master { end, .. }) if now_ms() < end => Some((self.writes_resumed.clone(), end)),
+            _ => None
+        }
+    }
+
+    fn end_manual_failover(&mut self) {
+        self.manual_failover = None;
+        self.writes_resumed.notify_waiters();
+    }
+
+    /// The header of a message from us: a replica speaks for its master's
+    /// slots and config epoch.
+    fn header(&self, kind: &str, offset: u64, flags: &[&str]) -> Vec<String> {
+        let me = self.myself();
+        let shard = me.master.as_ref().and_then(|m| self.nodes.get(m)).unwrap_or(me);
+        let mut flags = flags.to_vec();
+        if self.writes_paused() {
+            flags.push("paused");
+        }
+        vec![
+            kind.to_string(),
+            me.id.clone(),
+            self.current_epoch.to_string(),
+            shard.config_epoch.to_string(),
+            me.master.clone().unwrap_or_else(|| "-".to_string()),
+            offset.to_string(),
+            me.port.to_string(),
+            me.cport.to_string(),
+            if flags.is_empty() { "-".to_string() } else { flags.join(",") },
+            encode_ranges(&self.slot_ranges(&shard.id)),
+        ]
+    }
+
+    /// A PING, PONG or MEET for node `to`, with gossip about every other
+    /// node we know.
+    fn ping_message(&self, kind: &str, to: &str, offset: u64) -> Value {
+        let mut fields = self.header(kind, offset, &[]);
+        for node in self.nodes.values().filter(|n| n.id != self.myself && n.id != to && !n.handshake) {
+            let flags = match (node.fail, node.pfail) {
+                (true, _) => "fail",
+                (_, true) => "fail?",
+                _ => "-"
+            };
+            fields.extend([node.id.clone(), node.ip.clone(), node.port.to_string(), node.cport.to_string(), flags.to_string()]);
+        }
+        message(fields)
+    }
+
+    fn send(&mut self, to: &str, message: Value) {
+        if let Some(link) = self.links.get(to) {
+            let _ = link.tx.send(message);
+            self.messages_sent += 1;
+        }
+    }
+
+    fn broadcast(&mut self, message: Value) {
+        let ids: Vec<String> = self.links.keys().cloned().collect();
+        for id in ids {
+            self.send(&id, message.clone());
+        }
+    }
+
+    fn send_ping(&mut self, to: &str, kind: &str, offset: u64) {
+        let message = self.ping_message(kind, to, offset);
+        self.send(to, message);
+        if let Some(node) = self.nodes.get_mut(to)
+            && kind != "PONG" && node.ping_sent == 0 {
+            node.ping_sent = now_ms();
+        }
+    }
+
+    fn broadcast_pong(&mut self, offset: u64) {
+        let ids: Vec<String> = self.links.keys().cloned().collect();
+        for id in ids {
+            self.send_ping(&id, "PONG", offset);
+        }
+    }
+
+    /// Opens a link to node `id`, starting with a MEET for a node in
+    /// handshake and a PING otherwise.
+    fn connect(&mut self, redisdb: &db, id: &str, offset: u64) {
+        let node = &self.nodes[id];
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.next_link += 1;
+        let generation = self.next_link;
+        let task = tokio::spawn(run_link(redisdb.clone(), node.ip.clone(), node.cport, generation, rx));
+        let kind = if node.handshake { "MEET" } else { "PING" };
+        self.links.insert(id.to_string(), Link { tx, generation, ctime: now_ms(), task: task.abort_handle() });
+        self.send_ping(id, kind, offset);
+    }
+
+    /// Turns a PFAIL into a FAIL once enough masters (including us, if we
+    /// are one) reported the node, and tells everyone.
+    fn mark_failing_if_needed(&mut self, id: &str, node_timeout: u64, offset: u64) {
+        let quorum = self.quorum();
+        let myself_master = self.myself().master.is_none();
+        let now = now_ms();
+        let Some(node) = self.nodes.get_mut(id) else { return };
+        if !node.pfail || node.fail {
+            return
+        }
+        node.fail_reports.retain(|_, at| now.saturating_sub(*at) <= node_timeout * FAIL_REPORT_VALIDITY_MULT);
+        if node.fail_reports.len() + (myself_master as usize) < quorum {
+            return
+        }
+        println!("Marking node {} as failing (quorum reached).", id);
+        node.pfail = false;
+        node.fail = true;
+        node.fail_time = now;
+        self.todo_save = true;
+        let mut fields = self.header("FAIL", offset, &[]);
+        fields.push(id.to_string());
+        self.broadcast(message(fields));
+    }
+
+    /// A failed node we hear from again is cleared if it is a replica or
+    /// serves no slots; a master that does is only cleared once its replicas
+    /// had time to replace it.
+    fn clear_failure_if_needed(&mut self, id: &str, node_timeout: u64) {
+        let serves_slots = self.serves_slots(id);
+        let Some(node) = self.nodes.get_mut(id) else { return };
+        if node.master.is_some() || !serves_slots || now_ms().saturating_sub(node.fail_time) > node_timeout * FAIL_UNDO_TIME_MULT {
+            println!("Clear FAIL state for node {}: is reachable again.", id);
+            node.fail = false;
+            self.todo_save = true;
+        }
+    }
+
+    /// Failure reports from the gossip of master `sender`, and nodes it knows
+    /// about that we don't.
+    fn process_gossip(&mut self, sender: &str, entries: &[String], node_timeout: u64, offset: u64) {
+        let sender_is_master = self.nodes.get(sender).is_some_and(|n| n.master.is_none());
+        for entry in entries.chunks_exact(GOSSIP_FIELDS) {
+            let [id, ip, port, cport, flags] = entry else { continue };
+            if *id == self.myself {
+                continue;
+            }
+            match self.nodes.get_mut(id) {
+                Some(node) if sender_is_master => {
+                    if flags == "fail" || flags == "fail?" {
+                        if node.fail_reports.insert(sender.to_string(), now_ms()).is_none() {
+                            println!("Node {} reported node {} as not reachable.", sender, id);
+                        }
+                        self.mark_failing_if_needed(id, node_timeout, offset);
+                    } else {
+                        node.fail_reports.remove(sender);
+                    }
+                }
+                Some(_) => {}
+                None => {
+                    let (Ok(port), Ok(cport)) = (port.parse(), cport.parse()) else { continue };
+                    println!("Learned about node {} at {}:{} from {}.", id, ip, port, sender);
+                    self.nodes.insert(id.clone(), ClusterNode::new(id.clone(), ip.clone(), port, cport));
+                    self.todo_save = true;
+                }
+            }
+        }
+    }
+}
+
+/// The cluster is serving queries.
+pub fn state_ok(state: &dbstate) -> bool {
+    state.cluster.as_ref().is_some_and(|c| c.ok)
+}
+
+/// Makes us a replica of node `id`: we give up any slots and replicate from
+/// it, like REPLICAOF.
+fn set_my_master(state: &mut dbstate, id: &str) {
+    let Some(cluster) = &mut state.cluster else { return };
+    let Some(master) = cluster.nodes.get(id) else { return };
+    let (ip, port) = (master.ip.clone(), master.port);
+    let me = cluster.myself.clone();
+    cluster.remove_slots_of(&me);
+    cluster.migrating.clear();
+    cluster.importing.clear();
+    cluster.myself_mut().master = Some(id.to_string());
+    cluster.end_manual_failover();
+    cluster.election = None;
+    cluster.todo_save = true;
+    println!("Configured as replica of {} ({}:{}).", id, ip, port);
+    replication::unblock_all(state);
+    state.replication.set_master(ip, port);
+}
+
+/// Takes over the slots of our master after winning an election (or on
+/// CLUSTER FAILOVER TAKEOVER) and announces it.
+fn replace_master(state: &mut dbstate) {
+    let offset = state.replication.offset;
+    let Some(cluster) = &mut state.cluster else { return };
+    let Some(old) = cluster.myself_mut().master.take() else { return };
+    let me = cluster.myself.clone();
+    for slot in 0..CLUSTER_SLOTS {
+        if cluster.slots[slot as usize].as_deref() == Some(old.as_str()) {
+            cluster.set_slot_owner(slot, Some(&me));
+        }
+    }
+    cluster.end_manual_failover();
+    cluster.election = None;
+    cluster.save_config();
+    cluster.broadcast_pong(offset);
+    println!("Failover: took over the slots of {}.", old);
+    state.replication.become_master();
+}
+
+/// Applies the slots a master claims in its messages. We take its word for
+/// slots nobody serves or whose owner has an older config epoch. When that
+/// takes the last slot of our shard, we follow the slots and replicate from
+/// the sender.
+fn update_slots(state: &mut dbstate, sender: &str, epoch: u64, claimed: &[(u16, u16)]) {
+    let Some(cluster) = &mut state.cluster else { return };
+    let me = cluster.myself.clone();
+    let shard = cluster.myself().master.clone().unwrap_or_else(|| me.clone());
+    let mut lost_shard_slot = false;
+    let mut dirty = Vec::new();
+    for &(start, end) in claimed {
+        for slot in start..=end {
+            let owner = cluster.slots[slot as usize].as_deref();
+            if owner == Some(sender) || cluster.importing.contains_key(&slot) {
+                continue;
+            }
+            if owner.and_then(|o| cluster.nodes.get(o)).is_some_and(|o| o.config_epoch >= epoch) {
+                continue;
+            }
+            if owner == Some(me.as_str()) && cluster.count_keys_in_slot(slot) > 0 {
+                dirty.push(slot);
+            }
+            lost_shard_slot |= owner == Some(shard.as_str());
+            cluster.set_slot_owner(slot, Some(sender));
+            cluster.todo_save = true;
+        }
+    }
+    if lost_shard_slot && !cluster.serves_slots(&shard) {
+        set_my_master(state, sender);
+        return
+    }
+    for slot in dirty {
+        let keys = state.cluster.as_ref().map_or_else(Vec::new, |c| c.keys_in_slot(slot, usize::MAX));
+        for key in keys {
+            state.remove(&key);
+        }
+    }
+}
+
+/// Votes for replica `sender` in the election for the epoch it asks, unless
+/// we already voted in this epoch or for another replica of the same master
+/// lately, its master hasn't failed (or stepped down in a manual failover),
+/// or its claim is older than what we know about the slots.
+fn vote(state: &mut dbstate, hdr: &Header, node_timeout: u64) {
+    let offset = state.replication.offset;
+    let Some(cluster) = &mut state.cluster else { return };
+    let now = now_ms();
+    let me = cluster.myself.clone();
+    if cluster.myself().master.is_some() || !cluster.serves_slots(&me) {
+        return
+    }
+    if hdr.current_epoch < cluster.current_epoch || cluster.last_vote_epoch == cluster.current_epoch {
+        return
+    }
+    let Some(master_id) = &hdr.master else { return };
+    let Some(master) = cluster.nodes.get(master_id) else { return };
+    if !master.fail && !hdr.flags.iter().any(|f| f == "forceack") {
+        return
+    }
+    if now.saturating_sub(master.voted_time) < node_timeout * 2 {
+        return
+    }
+    for &(start, end) in &hdr.slots {
+        for slot in start..=end {
+            if cluster.slot_owner(slot).is_some_and(|o| o.config_epoch > hdr.config_epoch) {
+                return
+            }
+        }
+    }
+    cluster.last_vote_epoch = cluster.current_epoch;
+    cluster.nodes.get_mut(master_id).unwrap().voted_time = now;
+    cluster.save_config();
+    println!("Failover auth granted to {} for epoch {}", hdr.sender, cluster.current_epoch);
+    let ack = message(cluster.header("AUTH_ACK", offset, &[]));
+    cluster.send(&hdr.sender, ack);
+}
+
+/// Handles a message from the bus, returning the reply for the connection
+/// it came on. `link` is set for replies on a link of ours.
+fn process_packet(state: &mut dbstate, msg: Value, peer_ip: &str, link: Option<u64>) -> Option<Value> {
+    let hdr = Header::parse(msg)?;
+    let node_timeout = state.config.cluster_node_timeout;
+    let offset = state.replication.offset;
+    let cluster = state.cluster.as_mut()?;
+    cluster.messages_received += 1;
+    let now = now_ms();
+
+    // A node we are in handshake with replied with its real ID.
+    if let Some(generation) = link
+        && let Some(id) = cluster.links.iter().find(|(_, l)| l.generation == generation).map(|(id, _)| id.clone())
+        && cluster.nodes.get(&id).is_some_and(|n| n.handshake)
+        && hdr.kind == "PONG" {
+        let mut node = cluster.nodes.remove(&id).unwrap();
+        let link = cluster.links.remove(&id);
+        if hdr.sender != cluster.myself && !cluster.nodes.contains_key(&hdr.sender) {
+            println!("Handshake with node {} completed.", hdr.sender);
+            node.id = hdr.sender.clone();
+            node.handshake = false;
+            cluster.nodes.insert(hdr.sender.clone(), node);
+            if let Some(link) = link {
+                cluster.links.insert(hdr.sender.clone(), link);
+            }
+        }
+        cluster.todo_save = true;
+    }
+    let reply = matches!(hdr.kind.as_str(), "PING" | "MEET")
+        .then(|| cluster.ping_message("PONG", &hdr.sender, offset));
+    if hdr.sender == cluster.myself {
+        return reply
+    }
+    if hdr.kind == "MEET" && !cluster.nodes.contains_key(&hdr.sender) {
+        println!("Met node {} at {}:{}.", hdr.sender, peer_ip, hdr.port);
+        cluster.nodes.insert(hdr.sender.clone(), ClusterNode::new(hdr.sender.clone(), peer_ip.to_string(), hdr.port, hdr.cport));
+        cluster.todo_save = true;
+    }
+    let Some(sender) = cluster.nodes.get_mut(&hdr.sender) else { return reply };
+
+    if hdr.config_epoch > sender.config_epoch {
+        sender.config_epoch = hdr.config_epoch;
+        cluster.todo_save = true;
+    }
+    sender.repl_offset = hdr.offset;
+    if (sender.ip.as_str(), sender.port, sender.cport) != (peer_ip, hdr.port, hdr.cport) {
+        println!("Address updated for node {}, now {}:{}", hdr.sender, peer_ip, hdr.port);
+        (sender.ip, sender.port, sender.cport) = (peer_ip.to_string(), hdr.port, hdr.cport);
+        cluster.links.remove(&hdr.sender);
+        cluster.todo_save = true;
+    }
+    let sender = cluster.nodes.get_mut(&hdr.sender).unwrap();
+    let mut was_failed = false;
+    if hdr.kind == "PONG" {
+        sender.pong_received = now;
+        sender.ping_sent = 0;
+        if sender.pfail {
+            sender.pfail = false;
+        } else {
+            was_failed = sender.fail;
+        }
+    }
+    let became_replica = sender.master.is_none() && hdr.master.is_some();
+    if sender.master != hdr.master {
+        sender.master = hdr.master.clone();
+        cluster.todo_save = true;
+    }
+    if became_replica {
+        cluster.remove_slots_of(&hdr.sender);
+    }
+    if was_failed {
+        cluster.clear_failure_if_needed(&hdr.sender, node_timeout);
+    }
+    if hdr.current_epoch > cluster.current_epoch {
+        cluster.current_epoch = hdr.current_epoch;
+        cluster.todo_save = true;
+    }
+
+    // Two masters with the same config epoch: the one with the smaller ID
+    // moves on to a new one, so slot conflicts always have a winner.
+    let me = cluster.myself();
+    if hdr.master.is_none() && me.master.is_none() && hdr.config_epoch == me.config_epoch && hdr.sender > me.id {
+        cluster.current_epoch += 1;
+        let epoch = cluster.current_epoch;
+        cluster.myself_mut().config_epoch = epoch;
+        cluster.todo_save = true;
+        println!("WARNING: configEpoch collision with node {}. configEpoch set to {}", hdr.sender, epoch);
+    }
+
+    // As the replica in a manual failover: the offset our master stopped at.
+    if let Some(ManualFailover::Replica { master_offset: master_offset @ None, .. }) = &mut cluster.manual_failover
+        && cluster.nodes[&cluster.myself].master.as_ref() == Some(&hdr.sender)
+        && hdr.flags.iter().any(|f| f == "paused") {
+        println!("Received replication offset for paused master manual failover: {}", hdr.offset);
+        *master_offset = Some(hdr.offset);
+    }
+
+    match hdr.kind.as_str() {
+        "PING" | "PONG" | "MEET" => {
+            cluster.process_gossip(&hdr.sender, &hdr.payload, node_timeout, offset);
+        }
+        "FAIL" => {
+            if let Some(id) = hdr.payload.first()
+                && *id != cluster.myself
+                && let Some(node) = cluster.nodes.get_mut(id)
+                && !node.fail {
+                println!("FAIL message received from {} about {}", hdr.sender, id);
+                node.fail = true;
+                node.pfail = false;
+                node.fail_time = now;
+                cluster.todo_save = true;
+            }
+        }
+        "AUTH_ACK" => {
+            let voter_serves_slots = cluster.serves_slots(&hdr.sender);
+            if let Some(election) = &mut cluster.election
+                && election.epoch > 0 && hdr.current_epoch >= election.epoch
+                && hdr.master.is_none() && voter_serves_slots {
+                election.votes.insert(hdr.sender.clone());
+            }
+        }
+        "MFSTART" if cluster.myself().master.is_none() && hdr.master.as_ref() == Some(&cluster.myself) => {
+            println!("Manual failover requested by replica {}.", hdr.sender);
+            cluster.manual_failover = Some(ManualFailover::Master { replica: hdr.sender.clone(), end: now + MANUAL_FAILOVER_TIMEOUT });
+            cluster.send_ping(&hdr.sender, "PING", offset);
+        }
+        _ => {}
+    }
+    if hdr.master.is_none() {
+        update_slots(state, &hdr.sender, hdr.config_epoch, &hdr.slots);
+    }
+    if hdr.kind == "AUTH_REQUEST" {
+        vote(state, &hdr, node_timeout);
+    }
+    reply
+}
+
+/// As a replica whose master failed, or on CLUSTER FAILOVER: waits a delay
+/// that grows with how far behind the other replicas we are, asks the
+/// masters for votes in a new epoch, and takes over once a majority agrees.
+fn handle_replica_failover(state: &mut dbstate) {
+    let node_timeout = state.config.cluster_node_timeout;
+    let offset = state.replication.offset;
+    let Some(cluster) = &mut state.cluster else { return };
+    let now = now_ms();
+    let Some(master) = cluster.myself().master.as_ref().and_then(|m| cluster.nodes.get(m)) else { return };
+    let manual = matches!(cluster.manual_failover, Some(ManualFailover::Replica { can_start: true, .. }));
+    if (!master.fail && !manual) || !cluster.serves_slots(&master.id) {
+        cluster.election = None;
+        return
+    }
+    let auth_timeout = (node_timeout * 2).max(2000);
+    let quorum = cluster.quorum();
+    if cluster.election.as_ref().is_some_and(|e| now.saturating_sub(e.start) > auth_timeout * 2) {
+        cluster.election = None;
+    }
+    let Some(election) = &mut cluster.election else {
+        let rank = cluster.replicas_of(&master.id).filter(|r| r.id != cluster.myself && r.repl_offset > offset).count() as u64;
+        let delay = if manual { 0 } else { 500 + random_below(500) + rank * 1000 };
+        println!("Start of election delayed for {} milliseconds (rank #{}, offset {}).", delay, rank, offset);
+        cluster.election = Some(Election { start: now + delay, epoch: 0, votes: HashSet::new(), manual });
+        return
+    };
+    if now < election.start || now.saturating_sub(election.start) > auth_timeout {
+        return
+    }
+    if election.epoch == 0 {
+        cluster.current_epoch += 1;
+        election.epoch = cluster.current_epoch;
+        println!("Starting a failover election for epoch {}.", election.epoch);
+        let flags: &[&str] = if election.manual { &["forceack"] } else { &[] };
+        let request = message(cluster.header("AUTH_REQUEST", offset, flags));
+        cluster.broadcast(request);
+        cluster.todo_save = true;
+        return
+    }
+    if election.votes.len() < quorum {
+        return
+    }
+    println!("Failover election won: I'm the new master.");
+    let epoch = election.epoch;
+    let me = cluster.myself_mut();
+    me.config_epoch = me.config_epoch.max(epoch);
+    replace_master(state);
+}
+
+/// Cluster bus work, ten times a second: links to every node, pings, failure
+/// detection, manual and automatic failover, and the cluster state.
+pub fn cron(redisdb: &db, state: &mut dbstate) {
+    let node_timeout = state.config.cluster_node_timeout;
+    let require_full_coverage = state.config.cluster_require_full_coverage;
+    let offset = state.replication.offset;
+    let Some(cluster) = &mut state.cluster else { return };
+    let now = now_ms();
+
+    let handshake_timeout = node_timeout.max(1000);
+    let expired: Vec<String> = cluster.nodes.values().filter(|n| n.handshake && now.saturating_sub(n.ctime) > handshake_timeout).map(|n| n.id.clone()).collect();
+    for id in expired {
+        println!("Handshake with {} timed out.", cluster.nodes[&id].addr());
+        cluster.nodes.remove(&id);
+        cluster.links.remove(&id);
+    }
+
+    let others: Vec<String> = cluster.nodes.keys().filter(|id| **id != cluster.myself).cloned().collect();
+    for id in &others {
+        let node = &cluster.nodes[id];
+        // A link that got no pong for half the timeout may be stuck: start
+        // over with a new one.
+        if node.ping_sent != 0 && now.saturating_sub(node.ping_sent) > node_timeout / 2
+            && cluster.links.get(id).is_some_and(|l| now.saturating_sub(l.ctime) > node_timeout / 2) {
+            cluster.links.remove(id);
+        }
+        if !cluster.links.contains_key(id) {
+            cluster.connect(redisdb, id, offset);
+            continue;
+        }
+        let node = &cluster.nodes[id];
+        let replica_in_failover = matches!(&cluster.manual_failover, Some(ManualFailover::Master { replica, .. }) if replica == id);
+        if (node.ping_sent == 0 && now.saturating_sub(node.pong_received) >= PING_INTERVAL) || replica_in_failover {
+            cluster.send_ping(id, "PING", offset);
+        }
+    }
+    for id in &others {
+        let node = cluster.nodes.get_mut(id).unwrap();
+        if node.ping_sent != 0 && now.saturating_sub(node.ping_sent) > node_timeout && !node.pfail && !node.fail {
+            println!("*** NODE {} possibly failing", id);
+            node.pfail = true;
+            cluster.mark_failing_if_needed(id, node_timeout, offset);
+        }
+    }
+
+    match &mut cluster.manual_failover {
+        Some(ManualFailover::Master { end, .. } | ManualFailover::Replica { end, .. }) if now >= *end => {
+            println!("Manual failover timed out.");
+            cluster.end_manual_failover();
+        }
+        Some(ManualFailover::Replica { master_offset: Some(master_offset), can_start: can_start @ false, .. }) if offset >= *master_offset => {
+            println!("All master replication stream processed, manual failover can start.");
+            *can_start = true;
+        }
+        _ => {}
+    }
+    handle_replica_failover(state);
+
+    let Some(cluster) = &mut state.cluster else { return };
+    cluster.update_state(require_full_coverage);
+    if cluster.todo_save {
+        cluster.save_config();
+    }
+}
+
+/// CLUSTER REPLICATE: becomes a replica of master `id`. Only an empty
+/// master can.
+pub fn replicate(state: &mut dbstate, id: &str) -> Result<(), Error> {
+    let Some(cluster) = &state.cluster else { return Ok(()) };
+    let Some(node) = cluster.nodes.get(id) else {
+        return Err(anyhow!("ERR Unknown node {}", id))
+    };
+    if *id == cluster.myself {
+        return Err(anyhow!("ERR Can't replicate myself"))
+    }
+    if node.master.is_some() {
+        return Err(anyhow!("ERR I can only replicate a master, not a replica."))
+    }
+    if cluster.myself().master.is_none() && (cluster.serves_slots(&cluster.myself) || !state.kv.is_empty()) {
+        return Err(anyhow!("ERR To set a master the node must be empty and without assigned slots."))
+    }
+    set_my_master(state, id);
+    if let Some(cluster) = &mut state.cluster {
+        cluster.save_config();
+    }
+    Ok(())
+}
+
+/// CLUSTER FAILOVER [FORCE|TAKEOVER], sent to a replica. By default the
+/// master stops writes until we caught up, then we get elected; FORCE
+/// skips waiting for the master, TAKEOVER the election too.
+pub fn manual_failover(state: &mut dbstate, option: Option<&str>) -> Result<(), Error> {
+    let offset = state.replication.offset;
+    let Some(cluster) = &mut state.cluster else { return Ok(()) };
+    let Some(master_id) = cluster.myself().master.clone() else {
+        return Err(anyhow!("ERR You should send CLUSTER FAILOVER to a replica"))
+    };
+    let Some(master) = cluster.nodes.get(&master_id) else {
+        return Err(anyhow!("ERR I'm a replica but my master is unknown to me"))
+    };
+    if option.is_none() && (master.fail || master.pfail || !cluster.links.contains_key(&master_id)) {
+        return Err(anyhow!("ERR Master is down or failed, please use CLUSTER FAILOVER FORCE"))
+    }
+    let end = now_ms() + MANUAL_FAILOVER_TIMEOUT;
+    cluster.election = None;
+    match option {
+        Some("TAKEOVER") => {
+            println!("Taking over the master (user request).");
+            cluster.current_epoch += 1;
+            let epoch = cluster.current_epoch;
+            cluster.myself_mut().config_epoch = epoch;
+            replace_master(state);
+        }
+        Some(_) => {
+            println!("Forced failover user request accepted.");
+            cluster.manual_failover = Some(ManualFailover::Replica { end, master_offset: None, can_start: true });
+        }
+        None => {
+            println!("Manual failover user request accepted.");
+            cluster.manual_failover = Some(ManualFailover::Replica { end, master_offset: None, can_start: false });
+            let start = message(cluster.header("MFSTART", offset, &[]));
+            cluster.send(&master_id, start);
+        }
+    }
+    Ok(())
+}
+
+/// CLUSTER SETSLOT slot MIGRATING|IMPORTING|NODE id, or STABLE: the steps
+/// of moving a slot. The source marks it migrating and the target
+/// importing; once the keys moved, NODE hands it over, the target claiming
+/// it in a new epoch so its word wins over the old owner's.
+pub fn set_slot(state: &mut dbstate, slot: u16, action: &str, id: Option<&str>) -> Result<(), Error> {
+    let offset = state.replication.offset;
+    let require_full_coverage = state.config.cluster_require_full_coverage;
+    let Some(cluster) = &mut state.cluster else { return Ok(()) };
+    if cluster.myself().master.is_some() {
+        return Err(anyhow!("ERR Please use SETSLOT only with masters."))
+    }
+    let me = cluster.myself.clone();
+    let owned = cluster.slots[slot as usize].as_deref() == Some(me.as_str());
+    let target = match id {
+        Some(id) => match cluster.nodes.get(id) {
+            Some(node) if node.master.is_some() => return Err(anyhow!("ERR Target node is not a master")),
+            Some(node) => Some(node.id.clone()),
+            None if action == "NODE" => return Err(anyhow!("ERR Unknown node {}", id)),
+            None => return Err(anyhow!("ERR I don't know about node {}", id))
+        },
+        None => None
+    };
+    let mut bumped = false;
+    match (action, target) {
+        ("MIGRATING", Some(target)) => {
+            if !owned {
+                return Err(anyhow!("ERR I'm not the owner of hash slot {}", slot))
+            }
+            cluster.migrating.insert(slot, target);
+        }
+        ("IMPORTING", Some(target)) => {
+            if owned {
+                return Err(anyhow!("ERR I'm already the owner of hash slot {}", slot))
+            }
+            cluster.importing.insert(slot, target);
+        }
+        ("NODE", Some(target)) => {
+            let keys = cluster.count_keys_in_slot(slot);
+            if owned && target != me && keys > 0 {
+                return Err(anyhow!("ERR Can't assign hashslot {} to a different node while I still hold keys for this hash slot.", slot))
+            }
+            if keys == 0 {
+                cluster.migrating.remove(&slot);
+            }
+            if target == me && cluster.importing.remove(&slot).is_some() {
+                bumped = cluster.bump_epoch_without_consensus();
+            }
+            cluster.set_slot_owner(slot, Some(&target));
+        }
+        _ => {
+            cluster.migrating.remove(&slot);
+            cluster.importing.remove(&slot);
+        }
+    }
+    cluster.save_config();
+    cluster.update_state(require_full_coverage);
+    if bumped {
+        cluster.broadcast_pong(offset);
+    }
+    Ok(())
+}
+
+/// Accepts connections from other nodes on the cluster bus port.
+pub async fn serve_bus(listener: TcpListener, redisdb: db) {
+    loop {
+        let Ok((socket, peer)) = listener.accept().await else { continue };
+        let redisdb = redisdb.clone();
+        tokio::spawn(async move {
+            let ip = peer.ip().to_string();
+            let mut handler = RespHandler::new(socket);
+            while let Ok(Some(msg)) = handler.read_value().await {
+                let reply = process_packet(&mut *redisdb.state.lock().await, msg, &ip, None);
+                if let Some(reply) = reply
+                    && handler.write_value(reply).await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+}
+
+/// Our side of a link: writes what is queued for the node and handles its
+/// replies until the connection fails, then drops the link so the cron
+/// makes a new one.
+async fn run_link(redisdb: db, ip: String, cport: u16, generation: u64, mut rx: mpsc::UnboundedReceiver<Value>) {
+    // Errors just end the link: the node may be down, and the cron keeps
+    // trying while failure detection takes it from there.
+    let _: Result<(), Error> = async {
+        let mut handler = RespHandler::new(TcpStream::connect((ip.as_str(), cport)).await?);
+        loop {
+            tokio::select! {
+                Some(msg) = rx.recv() => handler.write_value(msg).await?,
+                msg = handler.read_value() => {
+                    let Some(msg) = msg? else { return Ok(()) };
+                    process_packet(&mut *redisdb.state.lock().await, msg, &ip, Some(generation));
+                }
+            }
+        }
+    }.await;
+    if let Some(cluster) = &mut redisdb.state.lock().await.cluster {
+        cluster.links.retain(|_, l| l.generation != generation);
+    }
+}
+
+/// Checks that a command with these keys can run on this node, returning the
+/// redirection or refusal to reply with otherwise. All keys must hash to one
+/// slot; a slot served elsewhere gets MOVED, unless we are importing it and
+/// the client sent ASKING. A slot this node is migrating gets ASK for keys
+/// it no longer has. Either way, a command on several keys only some of
+/// which are here gets TRYAGAIN, and MIGRATE always runs here.
+pub fn check_keys(state: &dbstate, command: &str, keys: &[&String], asking: bool) -> Result<(), Error> {
+    let Some(cluster) = &state.cluster else { return Ok(()) };
+    let Some(first) = keys.first() else { return Ok(()) };
+    let slot = key_hash_slot(&string_to_bytes(first));
+    if keys.iter().any(|k| key_hash_slot(&string_to_bytes(k)) != slot) {
+        return Err(anyhow!("CROSSSLOT Keys in request don't hash to the same slot"))
+    }
+    if !cluster.ok {
+        return Err(anyhow!("CLUSTERDOWN The cluster is down"))
+    }
+    let Some(owner) = cluster.slot_owner(slot) else {
+        return Err(anyhow!("CLUSTERDOWN Hash slot not served"))
+    };
+    let migrating = if owner.id == cluster.myself { cluster.migrating.get(&slot) } else { None };
+    let importing = cluster.importing.contains_key(&slot);
+    if (migrating.is_some() || importing) && command == "MIGRATE" {
+        return Ok(())
+    }
+    let now = now_ms();
+    let missing = keys.iter()
+        .filter(|k| !state.kv.contains_key(k.as_str()) || state.expires.get(k.as_str()).is_some_and(|at| *at <= now))
+        .count();
+    if let Some(target) = migrating
+        && missing > 0 {
+        if missing < keys.len() {
+            return Err(anyhow!("TRYAGAIN Multiple keys request during rehashing of slot"))
+        }
+        let addr = cluster.nodes.get(target).map_or_else(String::new, ClusterNode::addr);
+        return Err(anyhow!("ASK {} {}", slot, addr))
+    }
+    if importing && asking {
+        if keys.len() > 1 && missing > 0 {
+            return Err(anyhow!("TRYAGAIN Multiple keys request during rehashing of slot"))
+        }
+        return Ok(())
+    }
+    if owner.id != cluster.myself {
+        return Err(anyhow!("MOVED {} {}", slot, owner.addr()))
+    }
+    Ok(())
+}
+
+/// MIGRATE host port key|"" destination-db timeout [COPY] [REPLACE]
+/// [AUTH password] [AUTH2 username password] [KEYS key ...]: sends the keys
+/// to another instance with RESTORE-ASKING (RESTORE outside of a cluster)
+/// and deletes them here once it accepted them, unless COPY. The keys are
+/// taken from `source_db`, the caller's database. The lock is held
+/// throughout, so to our clients the keys move at once, the way Redis
+/// blocks on MIGRATE.
+pub async fn migrate(args: &[String], source_db: usize, redisdb: &db) -> Result<Value, Error> {
+    let integer = |s: &String| s.parse::<i64>().map_err(|_| anyhow!("ERR value is not an integer or out of range"));
+    let db = integer(&args[3])?;
+    let timeout = integer(&args[4])?;
+    let timeout = std::time::Duration::from_millis(if timeout <= 0 { 1000 } else { timeout as u64 });
+    let (mut copy, mut replace, mut auth) = (false, false, None);
+    let mut keys = vec![&args[2]];
+    let mut i = 5;
+    while i < args.len() {
+        match args[i].to_uppercase().as_str() {
+            "COPY" => copy = true,
+            "REPLACE" => replace = true,
+            "AUTH" if i + 1 < args.len() => {
+                auth = Some(vec!["AUTH", args[i + 1].as_str()]);
+                i += 1;
+            }
+            "AUTH2" if i + 2 < args.len() => {
+                auth = Some(vec!["AUTH", args[i + 1].as_str(), args[i + 2].as_str()]);
+                i += 2;
+            }
+            "KEYS" => {
+                if !args[2].is_empty() {
+                    return Err(anyhow!("ERR When using MIGRATE KEYS option, the key argument must be set to the empty string"))
+                }
+                keys = args[i + 1..].iter().collect();
+                break;
+            }
+            _ => return Err(anyhow!("ERR syntax error"))
+        }
+        i += 1;
+    }
+
+    let mut lock = redisdb.state.lock().await;
+    let state = &mut *lock;
+    state.select(source_db);
+    let now = now_ms();
+    let restore = if state.cluster.is_some() { "RESTORE-ASKING" } else { "RESTORE" };
+    let mut commands = Vec::new();
+    let mut sent = Vec::new();
+    for key in keys {
+        let expire_at = state.expires.get(key).copied();
+        let Some(value) = state.kv.get(key) else { continue };
+        if expire_at.is_some_and(|at| at <= now) {
+            continue;
+        }
+        let ttl = expire_at.map_or(0, |at| at - now);
+        let mut argv = vec![restore.to_string(), key.clone(), ttl.to_string(), bytes_to_string(&rdb::dump(value))];
+        if replace {
+            argv.push("REPLACE".to_string());
+        }
+        commands.push(argv);
+        sent.push(key.clone());
+    }
+    if sent.is_empty() {
+        return Ok(Value::SimpleString("NOKEY".to_string()))
+    }
+    let argv = |args: &[&str]| args.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+    let mut setup = Vec::new();
+    if let Some(auth) = auth {
+        setup.push(argv(&auth));
+    }
+    if db != 0 {
+        setup.push(argv(&["SELECT", &db.to_string()]));
+    }
+
+    let addr = (args[0].as_str(), args[1].parse::<u16>().unwrap_or(0));
+    let mut handler = match tokio::time::timeout(timeout, TcpStream::connect(addr)).await {
+        Ok(Ok(stream)) => RespHandler::new(stream),
+        _ => return Err(anyhow!("IOERR error or timeout connecting to the client"))
+    };
+    let command = |argv: &Vec<String>| Value::Array(argv.iter().cloned().map(Value::bulk).collect());
+    for argv in setup.iter().chain(&commands) {
+        if !matches!(tokio::time::timeout(timeout, handler.write_value(command(argv))).await, Ok(Ok(()))) {
+            return Err(anyhow!("IOERR error or timeout writing to target instance"))
+        }
+    }
+    let mut replies = Vec::new();
+    for _ in 0..setup.len() + commands.len() {
+        match tokio::time::timeout(timeout, handler.read_value()).await {
+            Ok(Ok(Some(reply))) => replies.push(reply),
+            _ => return Err(anyhow!("IOERR error or timeout reading to target instance"))
+        }
+    }
+    let (setup_replies, key_replies) = replies.split_at(setup.len());
+    if let Some(Value::SimpleError(e)) = setup_replies.iter().find(|r| matches!(r, Value::SimpleError(_))) {
+        return Err(anyhow!("ERR Target instance replied with error: {}", e))
+    }
+    let mut error = None;
+    let mut deleted = Vec::new();
+    for (key, reply) in sent.into_iter().zip(key_replies) {
+        match reply {
+            Value::SimpleError(e) => error = Some(e.clone()),
+            _ if copy => {}
+            _ => {
+                state.remove(&key);
+                state.notify(NOTIFY_GENERIC, "del", &key);
+                deleted.push(key);
+            }
+        }
+    }
+    if !deleted.is_empty() {
+        state.propagate(std::iter::once("DEL".to_string()).chain(deleted).collect());
+        state.flush_propagated(false);
+    }
+    match error {
+        Some(e) => Err(anyhow!("ERR Target instance replied with error: {}", e)),
+        None => Ok(Value::SimpleString("OK".to_string()))
+    }
+}
+
+/// CLUSTER SLOTS: each range of slots with the master serving it followed
+/// by its replicas.
+pub fn slots_value(cluster: &Cluster) -> Value {
+    let node_value = |node: &ClusterNode| Value::Array(vec![
+        Value::bulk(node.ip.clone()),
+        Value::Integer(node.port as i64),
+        Value::bulk(node.id.clone()),
+        Value::EmptyArray,
+    ]);
+    let mut out = Vec::new();
+    for master in cluster.nodes.values().filter(|n| n.master.is_none()) {
+        for (start, end) in cluster.slot_ranges(&master.id) {
+            let mut range = vec![Value::Integer(start as i64), Value::Integer(end as i64), node_value(master)];
+            range.extend(cluster.replicas_of(&master.id).filter(|r| !r.fail).map(node_value));
+            out.push(Value::Array(range));
+        }
+    }
+    Value::Array(out)
+}
+
+/// CLUSTER SHARDS: each master with its slot ranges, and the nodes of its
+/// shard.
+pub fn shards_value(state: &dbstate, cluster: &Cluster) -> Value {
+    let node_value = |node: &ClusterNode| {
+        let offset = if node.id == cluster.myself { state.replication.offset } else { node.repl_offset };
+        let health = if node.fail || node.pfail { "fail" } else { "online" };
+        Value::Array(vec![
+            Value::bulk("id"), Value::bulk(node.id.clone()),
+            Value::bulk("port"), Value::Integer(node.port as i64),
+            Value::bulk("ip"), Value::bulk(node.ip.clone()),
+            Value::bulk("endpoint"), Value::bulk(node.ip.clone()),
+            Value::bulk("role"), Value::bulk(if node.master.is_some() { "replica" } else { "master" }),
+            Value::bulk("replication-offset"), Value::Integer(offset as i64),
+            Value::bulk("health"), Value::bulk(health),
+        ])
+    };
+    let mut out = Vec::new();
+    for master in cluster.nodes.values().filter(|n| n.master.is_none() && !n.handshake) {
+        let slots = cluster.slot_ranges(&master.id).into_iter()
+            .flat_map(|(start, end)| [Value::Integer(start as i64), Value::Integer(end as i64)])
+            .collect();
+        let mut nodes = vec![node_value(master)];
+        nodes.extend(cluster.replicas_of(&master.id).map(node_value));
+        out.push(Value::Array(vec![
+            Value::bulk("slots"), Value::Array(slots),
+            Value::bulk("nodes"), Value::Array(nodes),
+        ]));
+    }
+    Value::Array(out)
+}
+
+/// CLUSTER INFO.
+pub fn info_fields(cluster: &Cluster) -> Vec<(&'static str, String)> {
+    let slots_of = |failing: fn(&ClusterNode) -> bool| cluster.slot_counts.iter()
+        .filter(|(id, _)| cluster.nodes.get(*id).is_some_and(failing))
+        .map(|(_, count)| count)
+        .sum::<usize>();
+    let pfail = slots_of(|n| n.pfail);
+    let fail = slots_of(|n| n.fail);
+    let assigned = cluster.slots_assigned();
+    let me = cluster.myself();
+    let my_epoch = me.master.as_ref().and_then(|m| cluster.nodes.get(m)).unwrap_or(me).config_epoch;
+    vec![
+        ("cluster_state", if cluster.ok { "ok" } else { "fail" }.to_string()),
+        ("cluster_slots_assigned", assigned.to_string()),
+        ("cluster_slots_ok", (assigned - pfail - fail).to_string()),
+        ("cluster_slots_pfail", pfail.to_string()),
+        ("cluster_slots_fail", fail.to_string()),
+        ("cluster_known_nodes", cluster.nodes.len().to_string()),
+        ("cluster_size", cluster.size().to_string()),
+        ("cluster_current_epoch", cluster.current_epoch.to_string()),
+        ("cluster_my_epoch", my_epoch.to_string()),
+        ("cluster_stats_messages_sent", cluster.messages_sent.to_string()),
+        ("cluster_stats_messages_received", cluster.messages_received.to_string()),
+    ]
+}
+
+/// The Cluster section of INFO.
+pub fn info(state: &dbstate) -> Vec<(String, String)> {
+    vec![("cluster_enabled".to_string(), (state.cluster.is_some() as u8).to_string())]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc16_check_value() {
+        assert_eq!(crc16(b"123456789"), 0x31c3);
+    }
+
+    #[test]
+    fn hash_tags() {
+        assert_eq!(key_hash_slot(b"123456789"), 12739);
+        assert_eq!(key_hash_slot(b"{user1000}.following"), key_hash_slot(b"user1000"));
+        assert_eq!(key_hash_slot(b"foo{bar}{zap}"), key_hash_slot(b"bar"));
+        assert_eq!(key_hash_slot(b"foo{}{bar}"), crc16(b"foo{}{bar}") % CLUSTER_SLOTS);
+        assert_ne!(key_hash_slot(b"foo{}{bar}"), key_hash_slot(b"bar"));
+        assert_eq!(key_hash_slot(b"foo{bar"), crc16(b"foo{bar") % CLUSTER_SLOTS);
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("cluster-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn keys(k: &[&str]) -> Vec<String> {
+        k.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn slots_and_config_round_trip() {
+        let path = temp_dir("config").join("nodes.conf");
+        let _ = fs::remove_file(&path);
+        let mut cluster = Cluster::load(path.clone(), 7000).unwrap();
+        assert_eq!(cluster.myself().cport, 17000);
+        assert_eq!(cluster.slots_assigned(), 0);
+
+        cluster.add_slots(&[0, 1, 2, 5]).unwrap();
+        assert_eq!(cluster.add_slots(&[3, 1]).unwrap_err().to_string(), "ERR Slot 1 is already busy");
+        assert_eq!(cluster.add_slots(&[4, 4]).unwrap_err().to_string(), "ERR Slot 4 specified multiple times");
+        assert_eq!(cluster.slots_assigned(), 4);
+        assert_eq!(cluster.slot_ranges(&cluster.myself), vec![(0, 2), (5, 5)]);
+
+        let reloaded = Cluster::load(path, 7001).unwrap();
+        assert_eq!(reloaded.myself, cluster.myself);
+        assert_eq!(reloaded.myself().port, 7001);
+        assert_eq!(reloaded.slot_ranges(&reloaded.myself), vec![(0, 2), (5, 5)]);
+    }
+
+    #[test]
+    fn corrupted_config_is_refused() {
+        let path = temp_dir("corrupt").join("nodes.conf");
+        fs::write(&path, "not a node line\n").unwrap();
+        assert!(Cluster::load(path, 7000).is_err());
+    }
+
+    #[tokio::test]
+    async fn keys_are_checked_against_the_slot_owner() {
+        let path = temp_dir("routing").join("nodes.conf");
+        let _ = fs::remove_file(&path);
+        let db = crate::database::db::new();
+        let mut state = db.state.lock().await;
+        state.cluster = Some(Cluster::load(path, 7000).unwrap());
+        let key = "foo".to_string();
+        let slot = key_hash_slot(b"foo");
+
+        assert_eq!(check_keys(&state, "GET", &[&key], false).unwrap_err().to_string(), "CLUSTERDOWN The cluster is down");
+        let cluster = state.cluster.as_mut().unwrap();
+        cluster.parse_line(&format!("{} 10.0.0.2:7002@17002 master - 0 0 1 connected {}", "b".repeat(40), slot)).unwrap();
+        cluster.add_slots(&[slot + 1]).unwrap();
+        cluster.update_state(false);
+        assert_eq!(check_keys(&state, "GET", &[&key], false).unwrap_err().to_string(), format!("MOVED {} 10.0.0.2:7002", slot));
+        let unserved = "bar".to_string();
+        assert_eq!(check_keys(&state, "GET", &[&unserved], false).unwrap_err().to_string(), "CLUSTERDOWN Hash slot not served");
+
+        let cluster = state.cluster.as_mut().unwrap();
+        cluster.set_slot_owner(slot, None);
+        cluster.add_slots(&[slot]).unwrap();
+        assert!(check_keys(&state, "GET", &[&key], false).is_ok());
+        let tagged = keys(&["{foo}.a", "{foo}.b"]);
+        assert!(check_keys(&state, "GET", &tagged.iter().collect::<Vec<_>>(), false).is_ok());
+        assert_eq!(check_keys(&state, "GET", &[&key, &unserved], false).unwrap_err().to_string(),
+            "CROSSSLOT Keys in request don't hash to the same slot");
+    }
+
+    #[test]
+    fn keys_per_slot() {
+        let path = temp_dir("keys").join("nodes.conf");
+        let _ = fs::remove_file(&path);
+        let mut cluster = Cluster::load(path, 7000).unwrap();
+        let slot = key_hash_slot(b"user");
+        for key in keys(&["{user}.a", "{user}.b", "other"]) {
+            cluster.key_added(&key);
+        }
+        assert_eq!(cluster.count_keys_in_slot(slot), 2);
+        assert_eq!(cluster.keys_in_slot(slot, 1).len(), 1);
+        cluster.key_removed("{user}.a");
+        cluster.key_removed("{user}.b");
+        assert_eq!(cluster.count_keys_in_slot(slot), 0);
+        assert!(!cluster.keys.contains_key(&slot));
+    }
+
+    /// A cluster of three masters splitting the slots, seen from the first.
+    fn three_masters(name: &str) -> Cluster {
+        let path = temp_dir(name).join("nodes.conf");
+        fs::write(&path, format!("{a} 127.0.0.1:7000@17000 myself,master - 0 0 1 connected 0-5000\n\
+            {b} 127.0.0.1:7001@17001 master - 0 0 2 connected 5001-10000\n\
+            {c} 127.0.0.1:7002@17002 master - 0 0 3 connected 10001-16383\n\
+            vars currentEpoch 3 lastVoteEpoch 0\n", a = "a".repeat(40), b = "b".repeat(40), c = "c".repeat(40))).unwrap();
+        Cluster::load(path, 7000).unwrap()
+    }
+
+    #[test]
+    fn header_round_trip() {
+        let cluster = three_masters("header");
+        let Header { kind, sender, current_epoch, config_epoch, master, offset, port, cport, flags, slots, payload } =
+            Header::parse(message(cluster.header("PING", 42, &["nofailover"]))).unwrap();
+        assert_eq!((kind.as_str(), sender, current_epoch, config_epoch, master), ("PING", "a".repeat(40), 3, 1, None));
+        assert_eq!((offset, port, cport), (42, 7000, 17000));
+        assert_eq!(flags, vec!["nofailover".to_string()]);
+        assert_eq!(slots, vec![(0, 5000)]);
+        assert!(payload.is_empty());
+        assert!(Header::parse(message(vec!["PING".to_string()])).is_none());
+        assert_eq!(parse_ranges(&encode_ranges(&[(1, 3), (7, 7)])), Some(vec![(1, 3), (7, 7)]));
+    }
+
+    #[test]
+    fn gossip_carries_failure_reports_and_new_nodes() {
+        let mut cluster = three_masters("gossip");
+        let (b, c) = ("b".repeat(40), "c".repeat(40));
+        let Value::Array(fields) = cluster.ping_message("PING", &b, 0) else { panic!() };
+        assert_eq!(fields.len(), HEADER_FIELDS + GOSSIP_FIELDS);
+
+        cluster.nodes.get_mut(&c).unwrap().pfail = true;
+        let report = [c.clone(), "127.0.0.1".to_string(), "7002".to_string(), "17002".to_string(), "fail?".to_string()];
+        cluster.process_gossip(&b, &report, 15000, 0);
+        assert!(cluster.nodes[&c].fail);
+
+        let d = "d".repeat(40);
+        cluster.process_gossip(&b, &[d.clone(), "127.0.0.1".to_string(), "7003".to_string(), "17003".to_string(), "-".to_string()], 15000, 0);
+        assert_eq!(cluster.nodes[&d].port, 7003);
+    }
+
+    #[test]
+    fn state_needs_coverage_and_a_majority() {
+        let mut cluster = three_masters("state");
+        cluster.update_state(true);
+        assert!(cluster.ok);
+        cluster.nodes.get_mut(&"c".repeat(40)).unwrap().fail = true;
+        cluster.update_state(true);
+        assert!(!cluster.ok);
+        cluster.update_state(false);
+        assert!(cluster.ok);
+        cluster.nodes.get_mut(&"b".repeat(40)).unwrap().pfail = true;
+        cluster.update_state(false);
+        assert!(!cluster.ok);
+    }
+
+    #[tokio::test]
+    async fn ending_a_manual_failover_resumes_writes() {
+        let mut cluster = three_masters("failover");
+        assert!(cluster.writes_pause().is_none());
+        cluster.manual_failover = Some(ManualFailover::Master { replica: "b".repeat(40), end: now_ms() + 10000 });
+        let (resumed, _) = cluster.writes_pause().unwrap();
+        let notified = resumed.notified();
+        tokio::pin!(notified);
+        notified.as_mut().enable();
+        cluster.end_manual_failover();
+        assert!(tokio::time::timeout(std::time::Duration::from_secs(1), notified).await.is_ok());
+        assert!(!cluster.writes_paused());
+    }
+
+    #[tokio::test]
+    async fn slot_migration_redirects_with_ask() {
+        let db = crate::database::db::new();
+        let mut state = db.state.lock().await;
+        state.cluster = Some(three_masters("migration"));
+        state.cluster.as_mut().unwrap().update_state(true);
+        let (a, b) = ("a".repeat(40), "b".repeat(40));
+        let (here, gone) = ("{b}here".to_string(), "{b}gone".to_string());
+        let slot = key_hash_slot(b"b");
+        assert_eq!(slot, 3300);
+        crate::commands::execute("SET", &[here.clone(), "1".to_string()], &mut state).unwrap();
+
+        assert_eq!(set_slot(&mut state, 6000, "MIGRATING", Some(&b)).unwrap_err().to_string(), "ERR I'm not the owner of hash slot 6000");
+        assert_eq!(set_slot(&mut state, slot, "IMPORTING", Some(&b)).unwrap_err().to_string(), format!("ERR I'm already the owner of hash slot {}", slot));
+        set_slot(&mut state, slot, "MIGRATING", Some(&b)).unwrap();
+        assert!(check_keys(&state, "GET", &[&here], false).is_ok());
+        assert_eq!(check_keys(&state, "GET", &[&gone], false).unwrap_err().to_string(), format!("ASK {} 127.0.0.1:7001", slot));
+        assert_eq!(check_keys(&state, "MGET", &[&here, &gone], false).unwrap_err().to_string(),
+            "TRYAGAIN Multiple keys request during rehashing of slot");
+        assert!(check_keys(&state, "MIGRATE", &[&gone], false).is_ok());
+        assert!(set_slot(&mut state, slot, "NODE", Some(&b)).is_err());
+
+        crate::commands::execute("DEL", std::slice::from_ref(&here), &mut state).unwrap();
+        set_slot(&mut state, slot, "NODE", Some(&b)).unwrap();
+        assert_eq!(check_keys(&state, "GET", &[&here], false).unwrap_err().to_string(), format!("MOVED {} 127.0.0.1:7001", slot));
+
+        // And back, importing it as the target does.
+        set_slot(&mut state, slot, "IMPORTING", Some(&b)).unwrap();
+        assert!(check_keys(&state, "GET", &[&here], true).is_ok());
+        assert!(check_keys(&state, "GET", &[&here], false).is_err());
+        let epoch = state.cluster.as_ref().unwrap().current_epoch;
+        set_slot(&mut state, slot, "NODE", Some(&a)).unwrap();
+        let cluster = state.cluster.as_ref().unwrap();
+        assert_eq!(cluster.slot_owner(slot).unwrap().id, a);
+        assert_eq!(cluster.current_epoch, epoch + 1);
+        assert!(cluster.importing.is_empty());
+    }
+}
@@ -0,0 +1,245 @@
+use anyhow::Error;
+
+use crate::{database::{dbstate, Reply}, handlers::*, resp::Value, sentinel};
+
+/// The command only reads the keyspace.
+pub const READONLY: u32 = 1 << 0;
+/// The command may modify the keyspace, so it is logged to the AOF when it
+/// does.
+pub const WRITE: u32 = 1 << 1;
+/// In cluster mode the command may use a slot being imported without the
+/// client sending ASKING first.
+pub const ASKING: u32 = 1 << 2;
+/// Looking at the keys doesn't count as accessing them.
+pub const NO_TOUCH: u32 = 1 << 3;
+
+pub struct Command {
+    pub name: &'static str,
+    /// Argument count including the command name, Redis style: exact when
+    /// positive, a minimum when negative.
+    pub arity: i32,
+    pub flags: u32,
+    /// Positions of the key arguments, counting the command name as 0:
+    /// first, last (negative counts from the end) and step. 0 means no keys.
+    pub first_key: i32,
+    pub last_key: i32,
+    pub step: i32,
+}
+
+const fn cmd(name: &'static str, arity: i32, flags: u32, first_key: i32, last_key: i32, step: i32) -> Command {
+    Command { name, arity, flags, first_key, last_key, step }
+}
+
+pub const COMMANDS: &[Command] = &[
+    cmd("PING", -1, 0, 0, 0, 0),
+    cmd("ECHO", 2, 0, 0, 0, 0),
+    cmd("SET", -3, WRITE, 1, 1, 1),
+    cmd("GET", 2, READONLY, 1, 1, 1),
+    cmd("RPUSH", -3, WRITE, 1, 1, 1),
+    cmd("LPUSH", -3, WRITE, 1, 1, 1),
+    cmd("LRANGE", 4, READONLY, 1, 1, 1),
+    cmd("LLEN", 2, READONLY, 1, 1, 1),
+    cmd("LPOP", -2, WRITE, 1, 1, 1),
+    cmd("BLPOP", -3, WRITE, 1, -2, 1),
+    cmd("TYPE", 2, READONLY, 1, 1, 1),
+    cmd("DEL", -2, WRITE, 1, -1, 1),
+    cmd("MOVE", 3, WRITE, 1, 1, 1),
+    cmd("SELECT", 2, 0, 0, 0, 0),
+    cmd("SWAPDB", 3, WRITE, 0, 0, 0),
+    cmd("DBSIZE", 1, READONLY, 0, 0, 0),
+    cmd("FLUSHDB", -1, WRITE, 0, 0, 0),
+    cmd("FLUSHALL", -1, WRITE, 0, 0, 0),
+    cmd("DUMP", 2, READONLY, 1, 1, 1),
+    cmd("RESTORE", -4, WRITE, 1, 1, 1),
+    cmd("RESTORE-ASKING", -4, WRITE | ASKING, 1, 1, 1),
+    cmd("OBJECT", -2, READONLY | NO_TOUCH, 2, 2, 1),
+    // With the KEYS option, MIGRATE's keys follow it; see `Command::keys`.
+    cmd("MIGRATE", -6, WRITE, 3, 3, 1),
+    cmd("XADD", -5, WRITE, 1, 1, 1),
+    cmd("XLEN", 2, READONLY, 1, 1, 1),
+    cmd("XDEL", -3, WRITE, 1, 1, 1),
+    cmd("XTRIM", -4, WRITE, 1, 1, 1),
+    cmd("XSETID", -3, WRITE, 1, 1, 1),
+    cmd("XRANGE", -4, READONLY, 1, 1, 1),
+    cmd("XREVRANGE", -4, READONLY, 1, 1, 1),
+    // XREAD and XREADGROUP keys follow STREAMS; see `Command::keys`.
+    cmd("XREAD", -4, READONLY, 0, 0, 0),
+    cmd("XGROUP", -2, WRITE, 2, 2, 1),
+    cmd("XREADGROUP", -7, WRITE, 0, 0, 0),
+    cmd("XACK", -4, WRITE, 1, 1, 1),
+    cmd("XPENDING", -3, READONLY, 1, 1, 1),
+    cmd("XCLAIM", -6, WRITE, 1, 1, 1),
+    cmd("XAUTOCLAIM", -6, WRITE, 1, 1, 1),
+    cmd("XINFO", -2, READONLY, 2, 2, 1),
+    cmd("MULTI", 1, 0, 0, 0, 0),
+    cmd("EXEC", 1, 0, 0, 0, 0),
+    cmd("DISCARD", 1, 0, 0, 0, 0),
+    cmd("WATCH", -2, 0, 1, -1, 1),
+    cmd("UNWATCH", 1, 0, 0, 0, 0),
+    cmd("SUBSCRIBE", -2, 0, 0, 0, 0),
+    cmd("UNSUBSCRIBE", -1, 0, 0, 0, 0),
+    cmd("PSUBSCRIBE", -2, 0, 0, 0, 0),
+    cmd("PUNSUBSCRIBE", -1, 0, 0, 0, 0),
+    cmd("PUBLISH", 3, 0, 0, 0, 0),
+    cmd("PUBSUB", -2, 0, 0, 0, 0),
+    cmd("QUIT", -1, 0, 0, 0, 0),
+    cmd("RESET", 1, 0, 0, 0, 0),
+    cmd("SSUBSCRIBE", -2, 0, 0, 0, 0),
+    cmd("SUNSUBSCRIBE", -1, 0, 0, 0, 0),
+    cmd("SPUBLISH", 3, 0, 0, 0, 0),
+    cmd("CONFIG", -2, 0, 0, 0, 0),
+    cmd("HELLO", -1, 0, 0, 0, 0),
+    cmd("CLIENT", -2, 0, 0, 0, 0),
+    cmd("SAVE", 1, 0, 0, 0, 0),
+    cmd("BGSAVE", -1, 0, 0, 0, 0),
+    cmd("LASTSAVE", 1, 0, 0, 0, 0),
+    cmd("BGREWRITEAOF", 1, 0, 0, 0, 0),
+    cmd("REPLICAOF", 3, 0, 0, 0, 0),
+    cmd("SLAVEOF", 3, 0, 0, 0, 0),
+    cmd("REPLCONF", -1, 0, 0, 0, 0),
+    cmd("PSYNC", 3, 0, 0, 0, 0),
+    cmd("WAIT", 3, 0, 0, 0, 0),
+    cmd("INFO", -1, 0, 0, 0, 0),
+    cmd("CLUSTER", -2, 0, 0, 0, 0),
+    cmd("SENTINEL", -2, 0, 0, 0, 0),
+    cmd("ASKING", 1, 0, 0, 0, 0),
+];
+
+impl Command {
+    /// The key arguments in `args` (which exclude the command name).
+    pub fn keys<'a>(&self, args: &'a [String]) -> Vec<&'a String> {
+        if self.name == "XREAD" || self.name == "XREADGROUP" {
+            let Some(pos) = args.iter().position(|a| a.eq_ignore_ascii_case("STREAMS")) else { return Vec::new() };
+            let streams = &args[pos + 1..];
+            return streams[..streams.len() / 2].iter().collect()
+        }
+        if self.name == "MIGRATE"
+            && args[2].is_empty()
+            && let Some(pos) = args.iter().skip(5).position(|a| a.eq_ignore_ascii_case("KEYS")) {
+            return args[pos + 6..].iter().collect()
+        }
+        if self.first_key == 0 {
+            return Vec::new()
+        }
+        let argc = args.len() as i32 + 1;
+        let last = if self.last_key < 0 { argc + self.last_key } else { self.last_key };
+        (self.first_key..=last.min(argc - 1))
+            .step_by(self.step as usize)
+            .map(|i| &args[i as usize - 1])
+            .collect()
+    }
+}
+
+fn find(name: &str) -> Option<&'static Command> {
+    COMMANDS.iter().find(|c| c.name == name)
+}
+
+/// Finds `name` (already upper-cased) in the command table and checks its
+/// arity, with the error replies Redis gives for either failure.
+pub fn lookup(name: &str, args: &[String]) -> Result<&'static Command, Error> {
+    let Some(command) = find(name) else {
+        let args: String = args.iter().take(20).map(|a| format!("'{}' ", a)).collect();
+        return Err(anyhow::anyhow!("ERR unknown command '{}', with args beginning with: {}", name.to_lowercase(), args))
+    };
+    let argc = args.len() as i32 + 1;
+    if (command.arity > 0 && argc != command.arity) || argc < -command.arity {
+        return Err(anyhow::anyhow!("ERR wrong number of arguments for '{}' command", name.to_lowercase()))
+    }
+    Ok(command)
+}
+
+/// Runs a data command against the locked state, on behalf of the client in
+/// `state.current_client`.
+pub fn execute(command: &str, args: &[String], state: &mut dbstate) -> Result<Reply, Error> {
+    state.expire_stale(args);
+    let cmd = find(command);
+    if let Some(cmd) = cmd
+        && cmd.flags & NO_TOUCH == 0 {
+        state.access_keys(&cmd.keys(args));
+    }
+    let dirty = state.persistence.dirty;
+    // Blocked clients served during the command propagate after it.
+    let served = state.propagated.len();
+    state.rewrite = None;
+    state.skip_propagation = false;
+    let reply = dispatch(command, args, state);
+    if cmd.is_some_and(|c| c.flags & WRITE != 0) && state.persistence.dirty != dirty && !state.skip_propagation {
+        let argv = state.rewrite.take()
+            .unwrap_or_else(|| std::iter::once(command.to_string()).chain(args.iter().cloned()).collect());
+        state.propagated.insert(served, (state.db, argv));
+    }
+    if let Some(id) = state.current_client
+        && let Some(cmd) = cmd {
+        if reply.is_ok() && cmd.flags & READONLY != 0 {
+            state.tracking.remember(id, &cmd.keys(args));
+        }
+        // CLIENT CACHING only applies to the command right after it.
+        if command != "CLIENT" {
+            state.tracking.reset_caching(id);
+        }
+    }
+    reply
+}
+
+fn dispatch(command: &str, args: &[String], state: &mut dbstate) -> Result<Reply, Error> {
+    let value = match command {
+        "PING" => match args.first() {
+            Some(msg) => Value::bulk(msg.clone()),
+            None => Value::SimpleString("PONG".to_string())
+        },
+        "ECHO" => Value::bulk(args[0].clone()),
+        "SET" => set_handle(args, state)?,
+        "GET" => get_handle(args, state)?,
+        "RPUSH" => rpush_handle(args, state)?,
+        "LPUSH" => lpush_handle(args, state)?,
+        "LRANGE" => lrange_handle(args, state)?,
+        "LLEN" => llen_handle(args, state)?,
+        "LPOP" => lpop_handle(args, state)?,
+        "BLPOP" => return blpop_handle(args, state),
+        "TYPE" => type_handle(args, state)?,
+        "DEL" => del_handle(args, state)?,
+        "MOVE" => move_handle(args, state)?,
+        "SELECT" => select_handle(args, state)?,
+        "SWAPDB" => swapdb_handle(args, state)?,
+        "DBSIZE" => Value::Integer(state.kv.len() as i64),
+        "FLUSHDB" => flushdb_handle(args, state)?,
+        "FLUSHALL" => flushall_handle(args, state)?,
+        "DUMP" => dump_handle(args, state)?,
+        "RESTORE" | "RESTORE-ASKING" => restore_handle(args, state)?,
+        "OBJECT" => object_handle(args, state)?,
+        "XADD" => xadd_handle(args, state)?,
+        "XLEN" => xlen_handle(args, state)?,
+        "XDEL" => xdel_handle(args, state)?,
+        "XTRIM" => xtrim_handle(args, state)?,
+        "XSETID" => xsetid_handle(args, state)?,
+        "XRANGE" => xrange_handle(args, state)?,
+        "XREVRANGE" => xrevrange_handle(args, state)?,
+        "XREAD" => return xread_handle(args, state),
+        "WAIT" => return wait_handle(args, state),
+        "XGROUP" => xgroup_handle(args, state)?,
+        "XREADGROUP" => return xreadgroup_handle(args, state),
+        "XACK" => xack_handle(args, state)?,
+        "XPENDING" => xpending_handle(args, state)?,
+        "XCLAIM" => xclaim_handle(args, state)?,
+        "XAUTOCLAIM" => xautoclaim_handle(args, state)?,
+        "XINFO" => xinfo_handle(args, state)?,
+        "PUBLISH" => publish_handle(args, state)?,
+        "PUBSUB" => pubsub_handle(args, state)?,
+        "SPUBLISH" => spublish_handle(args, state)?,
+        "CONFIG" => config_handle(args, state)?,
+        "CLIENT" => client_handle(args, state)?,
+        "SAVE" => save_handle(args, state)?,
+        "BGSAVE" => bgsave_handle(args, state)?,
+        "LASTSAVE" => lastsave_handle(args, state)?,
+        "BGREWRITEAOF" => bgrewriteaof_handle(args, state)?,
+        "REPLICAOF" | "SLAVEOF" => replicaof_handle(args, state)?,
+        "INFO" => info_handle(args, state)?,
+        "CLUSTER" => cluster_handle(args, state)?,
+        "SENTINEL" => sentinel::sentinel_handle(args, state)?,
+        // Only reached when queued in MULTI: EXEC has released the watches
+        // by the time it runs, so there is nothing left to do.
+        "UNWATCH" => Value::SimpleString("OK".to_string()),
+        c => return Err(anyhow::anyhow!("ERR unknown command '{}'", c.to_lowercase()))
+    };
+    Ok(Reply::Ready(value))
+}
@@ -0,0 +1,359 @@
+use anyhow::Error;
+
+use crate::pubsub::glob_match;
+
+// Keyspace notification classes, as in Redis's notify-keyspace-events.
+pub const NOTIFY_KEYSPACE: u32 = 1 << 0;
+pub const NOTIFY_KEYEVENT: u32 = 1 << 1;
+pub const NOTIFY_GENERIC: u32 = 1 << 2;
+pub const NOTIFY_STRING: u32 = 1 << 3;
+pub const NOTIFY_LIST: u32 = 1 << 4;
+pub const NOTIFY_SET: u32 = 1 << 5;
+pub const NOTIFY_HASH: u32 = 1 << 6;
+pub const NOTIFY_ZSET: u32 = 1 << 7;
+pub const NOTIFY_EXPIRED: u32 = 1 << 8;
+pub const NOTIFY_EVICTED: u32 = 1 << 9;
+pub const NOTIFY_STREAM: u32 = 1 << 10;
+pub const NOTIFY_KEY_MISS: u32 = 1 << 11;
+pub const NOTIFY_NEW: u32 = 1 << 14;
+/// What `A` stands for; key-miss and new-key events must be asked for.
+pub const NOTIFY_ALL: u32 = NOTIFY_GENERIC | NOTIFY_STRING | NOTIFY_LIST | NOTIFY_SET | NOTIFY_HASH
+    | NOTIFY_ZSET | NOTIFY_EXPIRED | NOTIFY_EVICTED | NOTIFY_STREAM;
+
+const EVENT_CLASSES: &[(char, u32)] = &[
+    ('g', NOTIFY_GENERIC),
+    ('$', NOTIFY_STRING),
+    ('l', NOTIFY_LIST),
+    ('s', NOTIFY_SET),
+    ('h', NOTIFY_HASH),
+    ('z', NOTIFY_ZSET),
+    ('x', NOTIFY_EXPIRED),
+    ('e', NOTIFY_EVICTED),
+    ('t', NOTIFY_STREAM),
+    ('K', NOTIFY_KEYSPACE),
+    ('E', NOTIFY_KEYEVENT),
+    ('m', NOTIFY_KEY_MISS),
+    ('n', NOTIFY_NEW),
+];
+
+pub fn keyspace_events_from_str(s: &str) -> Option<u32> {
+    let mut flags = 0;
+    for c in s.chars() {
+        flags |= match c {
+            'A' => NOTIFY_ALL,
+            c => EVENT_CLASSES.iter().find(|(f, _)| *f == c)?.1
+        };
+    }
+    Some(flags)
+}
+
+pub fn keyspace_events_to_string(flags: u32) -> String {
+    let all = flags & NOTIFY_ALL == NOTIFY_ALL;
+    let mut s = if all { "A".to_string() } else { String::new() };
+    for (c, flag) in EVENT_CLASSES {
+        if flags & flag != 0 && !(all && flag & NOTIFY_ALL != 0) {
+            s.push(*c);
+        }
+    }
+    s
+}
+
+/// A byte count with an optional unit: `k`/`m`/`g` are powers of 1000 and
+/// `kb`/`mb`/`gb` powers of 1024, as in redis.conf.
+fn parse_memory(value: &str) -> Option<u64> {
+    let value = value.to_lowercase();
+    let digits = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+    let multiplier = match &value[digits..] {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => return None
+    };
+    value[..digits].parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+/// When the AOF is flushed to disk.
+#[derive(Clone, Copy, PartialEq)]
+pub enum AppendFsync {
+    Always,
+    EverySec,
+    No,
+}
+
+fn yes_no(b: bool) -> String {
+    if b { "yes" } else { "no" }.to_string()
+}
+
+/// Runtime configuration, read and changed with CONFIG GET/SET and set from
+/// `--name value...` options on the command line.
+pub struct Config {
+    pub port: u16,
+    /// Master to replicate from at startup; REPLICAOF changes it at runtime.
+    pub replicaof: Option<(String, u16)>,
+    /// Refuse writes from clients while a replica.
+    pub replica_read_only: bool,
+    /// Bytes of replication stream kept for replicas to resume from.
+    pub repl_backlog_size: u64,
+    pub notify_keyspace_events: u32,
+    /// Number of logical databases, numbered from 0, up to `MAX_DATABASES`.
+    pub databases: usize,
+    /// Directory holding the RDB file.
+    pub dir: String,
+    pub dbfilename: String,
+    /// `save` rules: snapshot after this many seconds if at least this many
+    /// changes were made.
+    pub save: Vec<(u64, u64)>,
+    pub appendonly: bool,
+    pub appendfilename: String,
+    /// Directory under `dir` holding the AOF files and their manifest.
+    pub appenddirname: String,
+    pub appendfsync: AppendFsync,
+    /// Write AOF base files as RDB rather than as commands.
+    pub aof_use_rdb_preamble: bool,
+    /// Rewrite the AOF once it grew by this percentage over its size after
+    /// the last rewrite; 0 disables automatic rewrites.
+    pub auto_aof_rewrite_percentage: u64,
+    /// ...but not while it is smaller than this many bytes.
+    pub auto_aof_rewrite_min_size: u64,
+    /// Load an AOF whose last command was cut short instead of refusing to
+    /// start.
+    pub aof_load_truncated: bool,
+    /// Run as a Redis Cluster node.
+    pub cluster_enabled: bool,
+    /// Where the node keeps its view of the cluster, under `dir`.
+    pub cluster_config_file: String,
+    /// Refuse queries while any slot is not served.
+    pub cluster_require_full_coverage: bool,
+    /// Milliseconds without a reply before a node is considered failing.
+    pub cluster_node_timeout: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            port: 6379,
+            replicaof: None,
+            replica_read_only: true,
+            repl_backlog_size: 1024 * 1024,
+            notify_keyspace_events: 0,
+            databases: 16,
+            dir: ".".to_string(),
+            dbfilename: "dump.rdb".to_string(),
+            save: vec![(3600, 1), (300, 100), (60, 10000)],
+            appendonly: false,
+            appendfilename: "appendonly.aof".to_string(),
+            appenddirname: "appendonlydir".to_string(),
+            appendfsync: AppendFsync::EverySec,
+            aof_use_rdb_preamble: true,
+            auto_aof_rewrite_percentage: 100,
+            auto_aof_rewrite_min_size: 64 * 1024 * 1024,
+            aof_load_truncated: true,
+            cluster_enabled: false,
+            cluster_config_file: "nodes.conf".to_string(),
+            cluster_require_full_coverage: true,
+            cluster_node_timeout: 15000,
+        }
+    }
+}
+
+/// Parameters only settable at startup.
+/// Databases are all allocated at startup, so their number is kept to what
+/// an empty keyspace each can afford.
+pub const MAX_DATABASES: usize = 1 << 16;
+
+const IMMUTABLE_PARAMS: &[&str] = &["port", "replicaof", "databases", "cluster-enabled", "cluster-config-file"];
+
+const PARAMS: &[&str] = &["port", "replica-read-only", "repl-backlog-size", "notify-keyspace-events", "databases", "dir", "dbfilename", "save", "appendonly", "appendfilename", "appenddirname", "appendfsync", "aof-load-truncated", "aof-use-rdb-preamble", "auto-aof-rewrite-percentage", "auto-aof-rewrite-min-size", "cluster-enabled", "cluster-config-file", "cluster-require-full-coverage", "cluster-node-timeout"];
+
+impl Config {
+    pub fn get(&self, name: &str) -> Option<String> {
+        match name {
+            "port" => Some(self.port.to_string()),
+            "replica-read-only" => Some(yes_no(self.replica_read_only)),
+            "repl-backlog-size" => Some(self.repl_backlog_size.to_string()),
+            "notify-keyspace-events" => Some(keyspace_events_to_string(self.notify_keyspace_events)),
+            "databases" => Some(self.databases.to_string()),
+            "dir" => Some(std::fs::canonicalize(&self.dir).map_or_else(|_| self.dir.clone(), |p| p.display().to_string())),
+            "dbfilename" => Some(self.dbfilename.clone()),
+            "save" => Some(self.save.iter().map(|(s, c)| format!("{} {}", s, c)).collect::<Vec<_>>().join(" ")),
+            "appendonly" => Some(yes_no(self.appendonly)),
+            "appendfilename" => Some(self.appendfilename.clone()),
+            "appendfsync" => Some(match self.appendfsync {
+                AppendFsync::Always => "always",
+                AppendFsync::EverySec => "everysec",
+                AppendFsync::No => "no"
+            }.to_string()),
+            "aof-load-truncated" => Some(yes_no(self.aof_load_truncated)),
+            "appenddirname" => Some(self.appenddirname.clone()),
+            "aof-use-rdb-preamble" => Some(yes_no(self.aof_use_rdb_preamble)),
+            "auto-aof-rewrite-percentage" => Some(self.auto_aof_rewrite_percentage.to_string()),
+            "auto-aof-rewrite-min-size" => Some(self.auto_aof_rewrite_min_size.to_string()),
+            "cluster-enabled" => Some(yes_no(self.cluster_enabled)),
+            "cluster-config-file" => Some(self.cluster_config_file.clone()),
+            "cluster-require-full-coverage" => Some(yes_no(self.cluster_require_full_coverage)),
+            "cluster-node-timeout" => Some(self.cluster_node_timeout.to_string()),
+            _ => None
+        }
+    }
+
+    /// CONFIG SET: like `set`, but parameters fixed at startup are refused.
+    pub fn set_at_runtime(&mut self, name: &str, value: &str) -> Result<(), Error> {
+        if IMMUTABLE_PARAMS.contains(&name) {
+            return Err(anyhow::anyhow!("ERR CONFIG SET failed (possibly related to argument '{}') - can't set immutable config", name))
+        }
+        self.set(name, value)
+    }
+
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), Error> {
+        let failed = |reason: &str| anyhow::anyhow!("ERR CONFIG SET failed (possibly related to argument '{}') - {}", name, reason);
+        match name {
+            "port" => {
+                self.port = value.parse().map_err(|_| failed("argument couldn't be parsed into an integer"))?;
+            }
+            "replicaof" => {
+                self.replicaof = match value.split_whitespace().collect::<Vec<_>>()[..] {
+                    [no, one] if no.eq_ignore_ascii_case("no") && one.eq_ignore_ascii_case("one") => None,
+                    [host, port] => Some((host.to_string(), port.parse().map_err(|_| failed("Invalid master port"))?)),
+                    _ => return Err(failed("wrong number of arguments"))
+                };
+            }
+            "notify-keyspace-events" => {
+                self.notify_keyspace_events = keyspace_events_from_str(value)
+                    .ok_or_else(|| failed("Invalid event class character. Use 'Ag$lshzxeKEtmn'."))?;
+            }
+            "databases" => {
+                self.databases = value.parse().ok().filter(|n| (1..=MAX_DATABASES).contains(n))
+                    .ok_or_else(|| failed(&format!("argument must be between 1 and {} inclusive", MAX_DATABASES)))?;
+            }
+            "dir" => {
+                if !std::path::Path::new(value).is_dir() {
+                    return Err(failed("No such file or directory"))
+                }
+                self.dir = value.to_string();
+            }
+            "dbfilename" => {
+                if value.contains('/') {
+                    return Err(failed("dbfilename can't be a path, just a filename"))
+                }
+                self.dbfilename = value.to_string();
+            }
+            "save" => {
+                let numbers = value.split_whitespace().map(|n| n.parse::<u64>()).collect::<Result<Vec<_>, _>>()
+                    .ok().filter(|n| n.len().is_multiple_of(2))
+                    .ok_or_else(|| failed("Invalid save parameters"))?;
+                self.save = numbers.chunks(2).map(|p| (p[0], p[1])).collect();
+            }
+            "appendonly" | "aof-load-truncated" | "aof-use-rdb-preamble" | "replica-read-only"
+            | "cluster-enabled" | "cluster-require-full-coverage" => {
+                let on = match value.to_lowercase().as_str() {
+                    "yes" => true,
+                    "no" => false,
+                    _ => return Err(failed("argument must be 'yes' or 'no'"))
+                };
+                match name {
+                    "appendonly" => self.appendonly = on,
+                    "aof-load-truncated" => self.aof_load_truncated = on,
+                    "replica-read-only" => self.replica_read_only = on,
+                    "cluster-enabled" => self.cluster_enabled = on,
+                    "cluster-require-full-coverage" => self.cluster_require_full_coverage = on,
+                    _ => self.aof_use_rdb_preamble = on
+                }
+            }
+            "cluster-config-file" => {
+                self.cluster_config_file = value.to_string();
+            }
+            "appendfilename" | "appenddirname" => {
+                if value.contains('/') {
+                    return Err(failed(&format!("{} can't be a path, just a filename", name)))
+                }
+                if name == "appendfilename" {
+                    self.appendfilename = value.to_string();
+                } else {
+                    self.appenddirname = value.to_string();
+                }
+            }
+            "auto-aof-rewrite-percentage" => {
+                self.auto_aof_rewrite_percentage = value.parse().map_err(|_| failed("argument couldn't be parsed into an integer"))?;
+            }
+            "cluster-node-timeout" => {
+                self.cluster_node_timeout = value.parse().map_err(|_| failed("argument couldn't be parsed into an integer"))?;
+            }
+            "auto-aof-rewrite-min-size" | "repl-backlog-size" => {
+                let bytes = parse_memory(value).ok_or_else(|| failed("argument must be a memory value"))?;
+                if name == "repl-backlog-size" {
+                    self.repl_backlog_size = bytes;
+                } else {
+                    self.auto_aof_rewrite_min_size = bytes;
+                }
+            }
+            "appendfsync" => {
+                self.appendfsync = match value.to_lowercase().as_str() {
+                    "always" => AppendFsync::Always,
+                    "everysec" => AppendFsync::EverySec,
+                    "no" => AppendFsync::No,
+                    _ => return Err(failed("argument(s) must be one of the following: always, everysec, no"))
+                };
+            }
+            _ => return Err(anyhow::anyhow!("ERR Unknown option or number of arguments for CONFIG SET - '{}'", name))
+        }
+        Ok(())
+    }
+
+    /// Parameters whose name matches the glob, with their values.
+    pub fn matching(&self, pattern: &str) -> Vec<(String, String)> {
+        PARAMS.iter()
+            .filter(|p| glob_match(pattern.to_lowercase().as_bytes(), p.as_bytes()))
+            .filter_map(|p| Some((p.to_string(), self.get(p)?)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keyspace_event_flags() {
+        assert_eq!(keyspace_events_from_str(""), Some(0));
+        assert_eq!(keyspace_events_from_str("KEA"), Some(NOTIFY_KEYSPACE | NOTIFY_KEYEVENT | NOTIFY_ALL));
+        assert_eq!(keyspace_events_from_str("Elx"), Some(NOTIFY_KEYEVENT | NOTIFY_LIST | NOTIFY_EXPIRED));
+        assert_eq!(keyspace_events_from_str("Kq"), None);
+        assert_eq!(keyspace_events_to_string(NOTIFY_KEYSPACE | NOTIFY_KEYEVENT | NOTIFY_ALL | NOTIFY_NEW), "AKEn");
+        assert_eq!(keyspace_events_to_string(NOTIFY_KEYEVENT | NOTIFY_LIST | NOTIFY_EXPIRED), "lxE");
+    }
+
+    #[test]
+    fn config_set_and_match() {
+        let mut config = Config::default();
+        config.set("notify-keyspace-events", "Kg$").unwrap();
+        assert_eq!(config.get("notify-keyspace-events").as_deref(), Some("g$K"));
+        assert!(config.set("notify-keyspace-events", "Z").is_err());
+        assert!(config.set("no-such-option", "1").is_err());
+        assert_eq!(config.matching("NOTIFY-*"), [("notify-keyspace-events".to_string(), "g$K".to_string())]);
+        assert!(config.matching("x*").is_empty());
+    }
+
+    #[test]
+    fn databases_are_bounded() {
+        let mut config = Config::default();
+        assert!(config.set("databases", "0").is_err() && config.set("databases", "2147483647").is_err());
+        config.set("databases", &MAX_DATABASES.to_string()).unwrap();
+        assert_eq!(config.databases, MAX_DATABASES);
+    }
+
+    #[test]
+    fn save_rules() {
+        let mut config = Config::default();
+        config.set("save", "3600 1 300 100").unwrap();
+        assert_eq!(config.save, [(3600, 1), (300, 100)]);
+        assert_eq!(config.get("save").as_deref(), Some("3600 1 300 100"));
+        config.set("save", "").unwrap();
+        assert!(config.save.is_empty());
+        assert!(config.set("save", "3600").is_err() && config.set("save", "a 1").is_err());
+        assert!(config.set("dbfilename", "a/b.rdb").is_err());
+    }
+}
@@ -1,16 +1,651 @@
-use std::{collections::{BTreeMap, HashMap}, hash::Hash, sync::Arc, time::Duration};
-use anyhow::Error;
-use tokio::{sync::Mutex, time::sleep};
+use std::{collections::{hash_map::RandomState, HashMap, HashSet, VecDeque}, hash::{BuildHasher, Hasher}, ops::Bound, sync::Arc, time::Duration};
+use tokio::sync::{oneshot, Mutex};
+
+use crate::{aof::{self, Aof}, cluster::{self, Cluster}, config::{Config, NOTIFY_EXPIRED, NOTIFY_GENERIC, NOTIFY_KEYEVENT, NOTIFY_KEYSPACE, NOTIFY_LIST, NOTIFY_NEW}, pubsub::{PubSub, Subscriber}, rdb::{self, Persistence}, replication::{self, Replication}, resp::Value, sentinel::{self, Sentinel}, stream::{now_ms, DeliveredEntry, Stream, StreamEntry, StreamId}, tracking::Tracking};
 
 
 #[derive(Clone)]
 pub enum key_value {
     String(String),
     List(Vec<String>),
-    Stream(BTreeMap<(u128, u128), HashMap<String, String>>)
+    Stream(Stream),
+    // Only loaded from RDB snapshots so far; no commands operate on them.
+    Set(HashSet<String>),
+    Hash(HashMap<String, String>),
+    ZSet(HashMap<String, f64>)
+}
+
+impl key_value {
+    /// The encoding Redis would use for this value, by its size limits
+    /// with the default configuration, as OBJECT ENCODING reports it.
+    pub fn encoding(&self) -> &'static str {
+        // The *-max-listpack-entries and *-max-listpack-value defaults.
+        fn small<'a>(len: usize, mut items: impl Iterator<Item = &'a String>) -> bool {
+            len <= 128 && items.all(|s| s.chars().count() <= 64)
+        }
+        match self {
+            key_value::String(s) if is_integer(s) => "int",
+            key_value::String(s) if s.chars().count() <= 44 => "embstr",
+            key_value::String(_) => "raw",
+            // list-max-listpack-size -2: one listpack of up to 8kb.
+            key_value::List(l) if l.iter().map(|s| s.chars().count() + 2).sum::<usize>() + 7 <= 8192 => "listpack",
+            key_value::List(_) => "quicklist",
+            key_value::Set(s) if s.len() <= 512 && s.iter().all(|m| is_integer(m)) => "intset",
+            key_value::Set(s) if small(s.len(), s.iter()) => "listpack",
+            key_value::Set(_) => "hashtable",
+            key_value::Hash(h) if small(h.len(), h.iter().flat_map(|(f, v)| [f, v])) => "listpack",
+            key_value::Hash(_) => "hashtable",
+            key_value::ZSet(z) if small(z.len(), z.keys()) => "listpack",
+            key_value::ZSet(_) => "skiplist",
+            key_value::Stream(_) => "stream",
+        }
+    }
+}
+
+/// A string Redis would store as an integer: one it reads back unchanged.
+pub fn is_integer(s: &str) -> bool {
+    s.parse::<i64>().is_ok_and(|n| n.to_string() == s)
+}
+
+/// Starting value of the access counter, so new keys aren't the first to go.
+const LFU_INIT_VAL: u8 = 5;
+/// Redis's default lfu-log-factor: about a million hits saturate the counter.
+const LFU_LOG_FACTOR: f64 = 10.0;
+/// Redis's default lfu-decay-time: the counter drops by one for every this
+/// many minutes without access.
+const LFU_DECAY_MINUTES: u64 = 1;
+
+/// When a key was last used and how often, which Redis keeps in each
+/// object's header for eviction and OBJECT IDLETIME/FREQ report.
+#[derive(Clone, Copy)]
+pub struct Access {
+    /// Unix milliseconds of the last access.
+    pub last: u64,
+    /// Logarithmic access counter, as in Redis's LFU.
+    pub counter: u8,
+    /// Unix minutes when the counter last changed.
+    counter_time: u64,
 }
+
+impl Access {
+    fn new(now: u64) -> Self {
+        Access { last: now, counter: LFU_INIT_VAL, counter_time: now / 60000 }
+    }
+
+    /// The counter with a point taken off for every decay period since it
+    /// last changed.
+    pub fn frequency(&self, now: u64) -> u8 {
+        let periods = (now / 60000).saturating_sub(self.counter_time) / LFU_DECAY_MINUTES;
+        self.counter.saturating_sub(periods.min(255) as u8)
+    }
+
+    /// An access: the counter decays, then goes up with a probability that
+    /// falls as it grows.
+    fn hit(&mut self, now: u64) {
+        let mut counter = self.frequency(now);
+        let base = counter.saturating_sub(LFU_INIT_VAL) as f64;
+        let r = RandomState::new().build_hasher().finish() as f64 / u64::MAX as f64;
+        if counter < 255 && r < 1.0 / (base * LFU_LOG_FACTOR + 1.0) {
+            counter += 1;
+        }
+        self.set_counter(counter, now);
+        self.last = now;
+    }
+
+    pub fn set_counter(&mut self, counter: u8, now: u64) {
+        self.counter = counter;
+        self.counter_time = now / 60000;
+    }
+}
+
+/// What a blocked client is waiting for once one of its keys is signalled.
+pub enum BlockedOp {
+    /// BLPOP: pop the head of the first list that becomes non-empty.
+    ListPop,
+    /// XREAD BLOCK: entries strictly after the given ID, per stream key.
+    StreamRead { after: HashMap<String, StreamId>, count: Option<usize> },
+    /// XREADGROUP BLOCK with `>`: new entries for the group, claimed for the
+    /// consumer as they are handed out.
+    GroupRead { group: String, consumer: String, count: Option<usize>, noack: bool },
+    /// WAIT: replicas to acknowledge the replication offset; not tied to
+    /// any key.
+    Wait { offset: u64, numreplicas: usize },
+}
+
+/// Outcome of a command that may block.
+pub enum Reply {
+    Ready(Value),
+    /// The client was parked with `dbstate::block`. `db::wait_blocked`
+    /// delivers its reply, or `on_timeout` if nothing arrives in time.
+    Blocked { id: u64, rx: oneshot::Receiver<Value>, timeout: Option<Duration>, on_timeout: Value },
+}
+
+pub struct BlockedClient {
+    /// The database its keys are in.
+    pub db: usize,
+    pub keys: Vec<String>,
+    pub op: BlockedOp,
+    pub reply: oneshot::Sender<Value>,
+}
+
+/// A key some client is WATCHing. `version` moves on every modification, so
+/// EXEC only has to compare it with the value seen at WATCH time.
+pub struct WatchedKey {
+    pub watchers: usize,
+    pub version: u64,
+}
+
+/// The keys of one logical database. The selected database lives in
+/// `dbstate`'s own fields, the others in `dbstate::dbs`.
+#[derive(Default)]
+pub struct Keyspace {
+    pub kv: HashMap<String, key_value>,
+    pub expires: HashMap<String, u64>,
+    pub access: HashMap<String, Access>,
+}
+
+/// A connected client, as seen by code that pushes to it.
+pub struct ClientHandle {
+    pub tx: Subscriber,
+    pub resp3: bool,
+}
+
 pub struct dbstate {
+    /// Keys of the selected database.
     pub kv: HashMap<String, key_value>,
+    /// Expiry deadlines in unix milliseconds.
+    pub expires: HashMap<String, u64>,
+    /// Access time and frequency of every key.
+    pub access: HashMap<String, Access>,
+    /// The database `kv`, `expires` and `access` belong to.
+    pub db: usize,
+    /// Every database by index, with an empty slot for the selected one.
+    pub dbs: Vec<Keyspace>,
+    /// Blocked client ids per database and key, in the order they blocked.
+    pub blocking_keys: HashMap<(usize, String), VecDeque<u64>>,
+    pub blocked_clients: HashMap<u64, BlockedClient>,
+    next_blocked_id: u64,
+    pub watched: HashMap<(usize, String), WatchedKey>,
+    pub pubsub: PubSub,
+    pub config: Config,
+    pub clients: HashMap<u64, ClientHandle>,
+    /// Client whose command is running, for commands and hooks that act on
+    /// behalf of the caller.
+    pub current_client: Option<u64>,
+    pub tracking: Tracking,
+    pub persistence: Persistence,
+    pub aof: Aof,
+    pub replication: Replication,
+    /// Set in cluster mode.
+    pub cluster: Option<Cluster>,
+    /// Set in sentinel mode.
+    pub sentinel: Option<Sentinel>,
+    /// Write commands to log for what is running now, in order, with the
+    /// database each ran in.
+    pub propagated: Vec<(usize, Vec<String>)>,
+    /// Logged instead of the running command, for commands whose effect
+    /// depends on when they run.
+    pub rewrite: Option<Vec<String>>,
+    /// The running command propagated its effects itself.
+    pub skip_propagation: bool,
+}
+
+impl dbstate {
+    pub fn set_string(&mut self, key: String, value: String, expire_at: Option<u64>) {
+        match expire_at {
+            Some(at) => self.expires.insert(key.clone(), at),
+            None => self.expires.remove(&key)
+        };
+        self.touch(&key);
+        if self.insert_key(key.clone(), key_value::String(value)).is_none() {
+            self.notify(NOTIFY_NEW, "new", &key);
+        }
+    }
+
+    /// Inserts a key that doesn't exist yet.
+    pub fn add_key(&mut self, key: String, value: key_value) {
+        self.notify(NOTIFY_NEW, "new", &key);
+        self.insert_key(key, value);
+    }
+
+    /// Inserts into the keyspace, keeping the cluster's keys per slot in
+    /// step; everything that adds a key goes through here.
+    pub fn insert_key(&mut self, key: String, value: key_value) -> Option<key_value> {
+        if let Some(cluster) = &mut self.cluster {
+            cluster.key_added(&key);
+        }
+        // An overwritten key keeps its access counter, as in Redis.
+        let now = now_ms();
+        self.access.entry(key.clone()).or_insert_with(|| Access::new(now)).last = now;
+        self.kv.insert(key, value)
+    }
+
+    /// Publishes a keyspace notification for `event` on `key` if its class
+    /// is enabled in notify-keyspace-events.
+    pub fn notify(&self, class: u32, event: &str, key: &str) {
+        let flags = self.config.notify_keyspace_events;
+        if flags & class == 0 {
+            return
+        }
+        if flags & NOTIFY_KEYSPACE != 0 {
+            self.pubsub.publish(&format!("__keyspace@{}__:{}", self.db, key), event);
+        }
+        if flags & NOTIFY_KEYEVENT != 0 {
+            self.pubsub.publish(&format!("__keyevent@{}__:{}", self.db, event), key);
+        }
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<key_value> {
+        self.expires.remove(key);
+        let removed = self.kv.remove(key);
+        if removed.is_some() {
+            self.access.remove(key);
+            if let Some(cluster) = &mut self.cluster {
+                cluster.key_removed(key);
+            }
+            self.touch(key);
+        }
+        removed
+    }
+
+    /// Deletes `key` if it holds a list that was just emptied: Redis has no
+    /// empty lists.
+    pub fn remove_if_empty_list(&mut self, key: &str) {
+        if matches!(self.kv.get(key), Some(key_value::List(list)) if list.is_empty()) {
+            self.remove(key);
+            self.notify(NOTIFY_GENERIC, "del", key);
+        }
+    }
+
+    /// Marks `key` as modified for WATCH, client-side caching and the save
+    /// rules' change counter. Writers that
+    /// change a value in place call this themselves; `set_string` and
+    /// `remove` already do.
+    pub fn touch(&mut self, key: &str) {
+        self.persistence.dirty += 1;
+        if !self.watched.is_empty()
+            && let Some(w) = self.watched.get_mut(&(self.db, key.to_string())) {
+            w.version += 1;
+        }
+        if !self.tracking.clients.is_empty() {
+            for id in self.tracking.invalidated(key, self.current_client) {
+                self.send_invalidation(id, Value::Array(vec![Value::bulk(key)]));
+            }
+        }
+    }
+
+    /// Delivers an invalidation to tracking client `id`, or to its redirect
+    /// target: an `invalidate` push for RESP3, otherwise a message on
+    /// `__redis__:invalidate` if the target is subscribed to it.
+    fn send_invalidation(&self, id: u64, keys: Value) {
+        let target = self.tracking.clients.get(&id).and_then(|c| c.redirect).unwrap_or(id);
+        match self.clients.get(&target) {
+            Some(c) if c.resp3 => {
+                let _ = c.tx.send(Value::Array(vec![Value::bulk("invalidate"), keys]));
+            }
+            Some(_) => self.pubsub.send_to(target, "__redis__:invalidate", keys),
+            None => {
+                if let Some(c) = self.clients.get(&id)
+                    && c.resp3 {
+                    let _ = c.tx.send(Value::Array(vec![Value::bulk("tracking-redirect-broken"), Value::Integer(target as i64)]));
+                }
+            }
+        }
+    }
+
+    /// Starts watching `key` and returns its current version. A key that has
+    /// already expired is dropped first, so its expiry doesn't count as a
+    /// later modification.
+    pub fn watch(&mut self, key: &str) -> u64 {
+        self.expire_if_needed(key);
+        let w = self.watched.entry((self.db, key.to_string())).or_insert(WatchedKey { watchers: 0, version: 0 });
+        w.watchers += 1;
+        w.version
+    }
+
+    pub fn unwatch(&mut self, db: usize, key: &str) {
+        let entry = (db, key.to_string());
+        if let Some(w) = self.watched.get_mut(&entry) {
+            w.watchers -= 1;
+            if w.watchers == 0 {
+                self.watched.remove(&entry);
+            }
+        }
+    }
+
+    /// True if `key` of database `db` was modified, deleted or has expired
+    /// since WATCH returned `version`.
+    pub fn is_dirty(&mut self, db: usize, key: &str, version: u64) -> bool {
+        self.in_db(db, |state| state.expire_if_needed(key));
+        self.watched.get(&(db, key.to_string())).is_none_or(|w| w.version != version)
+    }
+
+    /// Makes database `db` the one `kv`, `expires` and `access` hold.
+    pub fn select(&mut self, db: usize) {
+        if db != self.db {
+            self.park();
+            self.db = db;
+            self.unpark();
+        }
+    }
+
+    /// Moves the selected keys back to their slot in `dbs`.
+    fn park(&mut self) {
+        self.dbs[self.db] = Keyspace {
+            kv: std::mem::take(&mut self.kv),
+            expires: std::mem::take(&mut self.expires),
+            access: std::mem::take(&mut self.access),
+        };
+    }
+
+    /// Takes the keys of the selected database out of `dbs`.
+    fn unpark(&mut self) {
+        let keyspace = std::mem::take(&mut self.dbs[self.db]);
+        self.kv = keyspace.kv;
+        self.expires = keyspace.expires;
+        self.access = keyspace.access;
+    }
+
+    /// Runs `f` with database `db` selected, then selects back the one that
+    /// was.
+    pub fn in_db<T>(&mut self, db: usize, f: impl FnOnce(&mut Self) -> T) -> T {
+        let selected = self.db;
+        self.select(db);
+        let result = f(self);
+        self.select(selected);
+        result
+    }
+
+    /// The keys of database `db` with their deadlines, selected or not.
+    pub fn keyspace(&self, db: usize) -> (&HashMap<String, key_value>, &HashMap<String, u64>) {
+        if db == self.db {
+            (&self.kv, &self.expires)
+        } else {
+            (&self.dbs[db].kv, &self.dbs[db].expires)
+        }
+    }
+
+    /// Drops `key` if its deadline has passed. Returns true if it expired.
+    pub fn expire_if_needed(&mut self, key: &str) -> bool {
+        match self.expires.get(key) {
+            Some(at) if *at <= now_ms() => {
+                self.remove(key);
+                self.notify(NOTIFY_EXPIRED, "expired", key);
+                true
+            }
+            _ => false
+        }
+    }
+
+    /// Lazily expires every argument that names an expired key before a
+    /// command runs. Non-key arguments never match a deadline, and an expired
+    /// key is gone whichever argument names it.
+    pub fn expire_stale(&mut self, args: &[String]) {
+        if self.expires.is_empty() {
+            return
+        }
+        for arg in args {
+            self.expire_if_needed(arg);
+        }
+    }
+
+    /// Records an access to each of the keys that exist.
+    pub fn access_keys(&mut self, keys: &[&String]) {
+        let now = now_ms();
+        for key in keys {
+            if let Some(access) = self.access.get_mut(*key) {
+                access.hit(now);
+            }
+        }
+    }
+
+    /// Sweeps all keys whose deadline has passed, in every database.
+    pub fn active_expire(&mut self) {
+        let now = now_ms();
+        for db in 0..self.dbs.len() {
+            let expired: Vec<String> = self.keyspace(db).1.iter().filter(|(_, at)| **at <= now).map(|(k, _)| k.clone()).collect();
+            if expired.is_empty() {
+                continue;
+            }
+            self.in_db(db, |state| {
+                for key in expired {
+                    state.remove(&key);
+                    state.notify(NOTIFY_EXPIRED, "expired", &key);
+                }
+            });
+        }
+    }
+
+    pub fn propagate(&mut self, argv: Vec<String>) {
+        self.propagated.push((self.db, argv));
+    }
+
+    /// Writes what the last command, or transaction, propagated to the AOF
+    /// and, on a master, to the replicas. A replica passes its master's
+    /// stream on instead.
+    pub fn flush_propagated(&mut self, transaction: bool) {
+        if self.propagated.is_empty() {
+            return
+        }
+        let mut commands = std::mem::take(&mut self.propagated);
+        if transaction {
+            // SELECT goes before MULTI, for the database of the first command.
+            let (first, last) = (commands[0].0, commands[commands.len() - 1].0);
+            commands.insert(0, (first, vec!["MULTI".to_string()]));
+            commands.push((last, vec!["EXEC".to_string()]));
+        }
+        self.aof.feed(&commands, self.config.appendfsync);
+        if !self.replication.is_replica() {
+            let bytes = aof::encode_selecting(&commands, &mut self.replication.selected_db);
+            self.replication.feed(&bytes);
+        }
+    }
+
+    /// Empties the selected database, returning how many keys it had. With
+    /// `lazy` the memory is given back from a background thread.
+    pub fn flush_db(&mut self, lazy: bool) -> usize {
+        self.park();
+        let keyspace = std::mem::take(&mut self.dbs[self.db]);
+        let removed = keyspace.kv.len();
+        for ((db, key), w) in self.watched.iter_mut() {
+            if *db == self.db && keyspace.kv.contains_key(key) {
+                w.version += 1;
+            }
+        }
+        if let Some(cluster) = &mut self.cluster {
+            cluster.keys_flushed();
+        }
+        if !self.tracking.clients.is_empty() {
+            for id in self.tracking.flushed() {
+                self.send_invalidation(id, Value::NullArray);
+            }
+        }
+        self.persistence.dirty += removed as u64;
+        if lazy {
+            std::thread::spawn(move || drop(keyspace));
+        }
+        removed
+    }
+
+    /// Empties every database, returning how many keys they had.
+    pub fn flush_all(&mut self, lazy: bool) -> usize {
+        (0..self.dbs.len()).map(|db| self.in_db(db, |state| state.flush_db(lazy))).sum()
+    }
+
+    /// Exchanges the keys of databases `a` and `b`. Clients stay with their
+    /// database index and see the other dataset from now on: watchers of
+    /// keys in either are told about the change, and clients blocked on a
+    /// key that now exists are served.
+    pub fn swap_dbs(&mut self, a: usize, b: usize) {
+        self.park();
+        self.dbs.swap(a, b);
+        for ((db, key), w) in self.watched.iter_mut() {
+            if (*db == a || *db == b) && (self.dbs[a].kv.contains_key(key) || self.dbs[b].kv.contains_key(key)) {
+                w.version += 1;
+            }
+        }
+        self.unpark();
+        let ready: Vec<(usize, String)> = self.blocking_keys.keys()
+            .filter(|(db, key)| (*db == a || *db == b) && self.keyspace(*db).0.contains_key(key))
+            .cloned()
+            .collect();
+        for (db, key) in ready {
+            self.in_db(db, |state| state.signal_key(&key));
+        }
+    }
+
+    /// Propagates entries handed to `consumer` as XCLAIMs that recreate
+    /// their PEL entries (or XACKs for those no longer pending), followed by
+    /// the group's position, so replaying them depends neither on the clock
+    /// nor on blocking.
+    pub fn propagate_group_delivery(&mut self, key: &str, group: &str, consumer: &str, ids: &[StreamId]) {
+        let Some(key_value::Stream(stream)) = self.kv.get(key) else { return };
+        let Some(g) = stream.groups.get(group) else { return };
+        let argv = |args: &[&str]| args.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+        let last = g.last_delivered.to_string();
+        let mut commands = Vec::new();
+        for id in ids {
+            let id_str = id.to_string();
+            commands.push(match g.pel.get(id) {
+                Some(entry) => argv(&[
+                    "XCLAIM", key, group, consumer, "0", &id_str,
+                    "TIME", &entry.delivery_time.to_string(),
+                    "RETRYCOUNT", &entry.delivery_count.to_string(),
+                    "FORCE", "JUSTID", "LASTID", &last,
+                ]),
+                None => argv(&["XACK", key, group, &id_str])
+            });
+        }
+        let entries_read = g.entries_read.map_or(-1, |n| n as i64).to_string();
+        commands.push(argv(&["XGROUP", "SETID", key, group, &last, "ENTRIESREAD", &entries_read]));
+        self.propagated.extend(commands.into_iter().map(|argv| (self.db, argv)));
+    }
+
+    /// Parks a client on `keys`. The reply is delivered through the returned
+    /// receiver when a writer signals one of the keys.
+    pub fn block(&mut self, keys: Vec<String>, op: BlockedOp) -> (u64, oneshot::Receiver<Value>) {
+        let (tx, rx) = oneshot::channel();
+        let id = self.next_blocked_id;
+        self.next_blocked_id += 1;
+        for key in &keys {
+            self.blocking_keys.entry((self.db, key.clone())).or_default().push_back(id);
+        }
+        self.blocked_clients.insert(id, BlockedClient { db: self.db, keys, op, reply: tx });
+        (id, rx)
+    }
+
+    /// Removes a blocked client from every key it waits on. Returns `None` if
+    /// it was already served.
+    pub fn unblock(&mut self, id: u64) -> Option<BlockedClient> {
+        let client = self.blocked_clients.remove(&id)?;
+        for key in &client.keys {
+            let entry = (client.db, key.clone());
+            if let Some(queue) = self.blocking_keys.get_mut(&entry) {
+                queue.retain(|c| *c != id);
+                if queue.is_empty() {
+                    self.blocking_keys.remove(&entry);
+                }
+            }
+        }
+        Some(client)
+    }
+
+    /// Called by writers after they touched `key`: serves the clients blocked
+    /// on it in FIFO order for as long as the key can satisfy them.
+    pub fn signal_key(&mut self, key: &str) {
+        let waiting: Vec<u64> = match self.blocking_keys.get(&(self.db, key.to_string())) {
+            Some(queue) => queue.iter().copied().collect(),
+            None => return
+        };
+        for id in waiting {
+            let Some(client) = self.blocked_clients.get(&id) else { continue };
+            if client.reply.is_closed() {
+                self.unblock(id);
+                continue;
+            }
+            // Entries a group read hands out, for propagation.
+            let mut delivered = Vec::new();
+            let reply = match &client.op {
+                BlockedOp::ListPop => match self.kv.get_mut(key) {
+                    Some(key_value::List(list)) if !list.is_empty() => {
+                        Some(Value::Array(vec![Value::bulk(key), Value::bulk(list.remove(0))]))
+                    }
+                    _ => break
+                },
+                BlockedOp::StreamRead { after, count } => match (self.kv.get(key), after.get(key)) {
+                    (Some(key_value::Stream(stream)), Some(after)) => {
+                        let entries = stream_entries_after(stream, *after, *count);
+                        if entries.is_empty() {
+                            None
+                        } else {
+                            Some(Value::Array(vec![Value::Array(vec![Value::bulk(key), Value::Array(entries)])]))
+                        }
+                    }
+                    _ => None
+                },
+                BlockedOp::GroupRead { group, consumer, count, noack } => match self.kv.get_mut(key) {
+                    Some(key_value::Stream(stream)) => match stream.read_group(group, consumer, None, *count, *noack) {
+                        Some(entries) if entries.is_empty() => None,
+                        Some(entries) => {
+                            if !noack {
+                                delivered = entries.iter().map(|(id, _)| *id).collect();
+                            }
+                            Some(Value::Array(vec![Value::Array(vec![Value::bulk(key), Value::Array(delivered_entries_value(entries))])]))
+                        }
+                        None => Some(Value::SimpleError(format!("NOGROUP No such key '{}' or consumer group '{}' in XREADGROUP with GROUP option", key, group)))
+                    },
+                    _ => None
+                },
+                // Never queued on a key.
+                BlockedOp::Wait { .. } => None
+            };
+            if let Some(reply) = reply
+                && let Some(client) = self.unblock(id) {
+                match &client.op {
+                    BlockedOp::ListPop => {
+                        self.touch(key);
+                        self.notify(NOTIFY_LIST, "lpop", key);
+                        self.remove_if_empty_list(key);
+                        self.propagate(vec!["LPOP".to_string(), key.to_string()]);
+                    }
+                    BlockedOp::GroupRead { group, consumer, .. } => {
+                        self.propagate_group_delivery(key, group, consumer, &delivered);
+                    }
+                    BlockedOp::StreamRead { .. } | BlockedOp::Wait { .. } => {}
+                }
+                let _ = client.reply.send(reply);
+            }
+        }
+    }
+}
+
+pub fn stream_entries_after(stream: &Stream, after: StreamId, count: Option<usize>) -> Vec<Value> {
+    stream_entries_value(stream.range_count(Bound::Excluded(after), Bound::Unbounded, count))
+}
+
+pub fn stream_entries_value(entries: Vec<StreamEntry>) -> Vec<Value> {
+    let mut res = Vec::new();
+    for (id, fields) in entries {
+        let mut values = Vec::new();
+        for (k, v) in fields {
+            values.push(Value::bulk(k));
+            values.push(Value::bulk(v));
+        }
+        res.push(Value::Array(vec![Value::bulk(id.to_string()), Value::Array(values)]));
+    }
+    res
+}
+
+pub fn delivered_entries_value(entries: Vec<DeliveredEntry>) -> Vec<Value> {
+    let mut res = Vec::new();
+    for (id, fields) in entries {
+        let fields = match fields {
+            Some(fields) => Value::Array(fields.into_iter().flat_map(|(k, v)| [Value::bulk(k), Value::bulk(v)]).collect()),
+            None => Value::NullArray
+        };
+        res.push(Value::Array(vec![Value::bulk(id.to_string()), fields]));
+    }
+    res
 }
 
 #[derive(Clone)]
@@ -18,36 +653,78 @@ pub struct db {
     pub state: Arc<Mutex<dbstate>>
 }
 
+impl Default for db {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl db {
     pub fn new() -> Self {
         Self {
-            state: Arc::new(Mutex::new(dbstate { kv: HashMap::new() }))
+            state: Arc::new(Mutex::new(dbstate {
+                kv: HashMap::new(),
+                access: HashMap::new(),
+                expires: HashMap::new(),
+                db: 0,
+                dbs: (0..Config::default().databases).map(|_| Keyspace::default()).collect(),
+                blocking_keys: HashMap::new(),
+                blocked_clients: HashMap::new(),
+                next_blocked_id: 0,
+                watched: HashMap::new(),
+                pubsub: PubSub::default(),
+                config: Config::default(),
+                clients: HashMap::new(),
+                current_client: None,
+                tracking: Tracking::default(),
+                persistence: Persistence::new(),
+                aof: Aof::default(),
+                replication: Replication::new(),
+                cluster: None,
+                sentinel: None,
+                propagated: Vec::new(),
+                rewrite: None,
+                skip_propagation: false,
+            }))
         }
     }
 
-    pub async fn get(&self, key: &str) -> Option<String> {
-        let lock = self.state.lock().await;
-
-        let v = match lock.kv.get(key).cloned() {
-            Some(key_value::String(s)) => s,
-            _ => panic!("Usupported args")
-        };
-        Some(v)
+    /// Runs periodic work ten times a second, like Redis's `hz 10`: active
+    /// expiry, the background save and AOF checks, and the link to a master.
+    pub fn start_cron(&self) {
+        let temp = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_millis(100));
+            loop {
+                interval.tick().await;
+                let mut state = temp.state.lock().await;
+                state.active_expire();
+                rdb::cron(&mut state);
+                aof::cron(&mut state);
+                replication::cron(&temp, &mut state);
+                cluster::cron(&temp, &mut state);
+                sentinel::cron(&temp, &mut state);
+            }
+        });
     }
 
-    pub async fn set(&self, key: String, value: String, ttl: Option<u64>) -> Result<(), Error>{
+    /// Waits for a client parked with `dbstate::block`. Timeouts run on a
+    /// tokio timer, so an idle wait costs nothing; `None` waits forever.
+    pub async fn wait_blocked(&self, id: u64, mut rx: oneshot::Receiver<Value>, timeout: Option<Duration>) -> Option<Value> {
+        let served = match timeout {
+            Some(timeout) => tokio::time::timeout(timeout, &mut rx).await.ok(),
+            None => Some((&mut rx).await)
+        };
+        if let Some(Ok(v)) = served {
+            return Some(v)
+        }
         let mut lock = self.state.lock().await;
-
-        lock.kv.insert(key.clone(), key_value::String(String::from(value)));
-
-        let temp = self.clone();
-        if let Some(ttl) = ttl {
-            tokio::spawn(async move {
-                sleep(Duration::from_millis(ttl)).await;
-                let mut locking = temp.state.lock().await;
-                locking.kv.remove(&key);
-            });
-        }     
-        Ok(())
-    } 
-}
\ No newline at end of file
+        match lock.unblock(id) {
+            // WAIT replies with the count reached by then.
+            Some(BlockedClient { op: BlockedOp::Wait { offset, .. }, .. }) => Some(Value::Integer(lock.replication.acked(offset) as i64)),
+            Some(_) => None,
+            // Served between the timer firing and us taking the lock.
+            None => rx.try_recv().ok()
+        }
+    }
+}
@@ -1,204 +1,236 @@
-use core::{f64, panic, time};
-use std::{collections::{BTreeMap, HashMap}, hash::Hash, process::id, time::SystemTime, vec};
+use std::{collections::HashMap, ops::Bound, time::Duration};
 
 use anyhow::{Error, Ok};
 
-use crate::{database::{db, key_value}, resp::Value};
+use crate::{config::{NOTIFY_GENERIC, NOTIFY_KEY_MISS, NOTIFY_LIST, NOTIFY_STREAM, NOTIFY_STRING}, database::{dbstate, delivered_entries_value, is_integer, key_value, stream_entries_after, stream_entries_value, BlockedOp, Reply}, aof, cluster, rdb, replication, resp::{bytes_to_string, string_to_bytes, Value}, sentinel, stream::{now_ms, ClaimOptions, ConsumerGroup, DeliveredEntry, Stream, StreamEntry, StreamId, Trim, XAddId}, tracking::TrackingClient};
+
+pub const WRONGTYPE: &str = "WRONGTYPE Operation against a key holding the wrong kind of value";
 
 pub fn extract_command(value: Value) -> Result<(String, Vec<Value>), Error>{
     match value {
         Value::Array(a) => {
             Ok((
-                unpack_bulk_str(&a).unwrap().first().unwrap().to_string(),
+                unpack_bulk_str(&a)?.first().ok_or_else(|| anyhow::anyhow!("Empty command"))?.to_string(),
                 a.into_iter().skip(1).collect()
         ))
         },
         _ => Err(anyhow::anyhow!("Unexpected command format"))
     }
 }
-pub fn unpack_bulk_str(value: &Vec<Value>) -> Result<Vec<String>, Error> {
+pub fn unpack_bulk_str(value: &[Value]) -> Result<Vec<String>, Error> {
     let mut bulk_strings = Vec::new();
-    for i in 0..value.len() {
-        let v = match value[i].clone() {
-            Value::BulkString(s) => Ok(s),
+    for v in value {
+        let v = match v.clone() {
+            Value::BulkString(s) => Ok(bytes_to_string(&s)),
             _ => Err(anyhow::anyhow!("Unexpected command for a bulkstring"))
-        }.unwrap();
+        }?;
         bulk_strings.push(v);
     }
     Ok(bulk_strings)
 }
-pub async fn get_handle(args: &Vec<String>, db: &db) -> Option<String> {
-    let value = db.get(&args[0]).await;
-    if let Some(value) = value {
-        return Some(value)
-    } else {
-        None
+pub fn get_handle(args: &[String], state: &mut dbstate) -> Result<Value, Error> {
+    match state.kv.get(&args[0]) {
+        Some(key_value::String(s)) => Ok(Value::bulk(s.clone())),
+        Some(_) => Err(anyhow::anyhow!(WRONGTYPE)),
+        None => {
+            state.notify(NOTIFY_KEY_MISS, "keymiss", &args[0]);
+            Ok(Value::NullBulkString)
+        }
     }
 }
-pub async fn set_handle(args: &Vec<String>, db: &db) -> Result<(), Error> {
-    let ttl = if args.len() == 3 {
-        Some(args[2].clone())
-    } else {
-        None
+pub fn set_handle(args: &[String], state: &mut dbstate) -> Result<Value, Error> {
+    let key = &args[0];
+    let mut expire_at = None;
+    let mut keep_ttl = false;
+    let mut condition = None;
+    let mut get = false;
+    let mut i = 2;
+    while i < args.len() {
+        let option = args[i].to_uppercase();
+        match option.as_str() {
+            "NX" | "XX" if condition.is_none() => condition = Some(option),
+            "GET" => get = true,
+            "KEEPTTL" if expire_at.is_none() => keep_ttl = true,
+            "EX" | "PX" | "EXAT" | "PXAT" if expire_at.is_none() && !keep_ttl => {
+                let n = parse_integer(args.get(i + 1).ok_or_else(|| anyhow::anyhow!("ERR syntax error"))?)?;
+                let invalid = || anyhow::anyhow!("ERR invalid expire time in 'set' command");
+                if n <= 0 {
+                    return Err(invalid())
+                }
+                let n = n as u64;
+                expire_at = Some(match option.as_str() {
+                    "EX" => n.checked_mul(1000).and_then(|ms| ms.checked_add(now_ms())),
+                    "PX" => n.checked_add(now_ms()),
+                    "EXAT" => n.checked_mul(1000),
+                    _ => Some(n)
+                }.filter(|at| *at <= i64::MAX as u64).ok_or_else(invalid)?);
+                i += 1;
+            }
+            _ => return Err(anyhow::anyhow!("ERR syntax error"))
+        }
+        i += 1;
+    }
+    let old = match state.kv.get(key) {
+        Some(key_value::String(s)) => Some(s.clone()),
+        Some(_) if get => return Err(anyhow::anyhow!(WRONGTYPE)),
+        Some(_) => None,
+        None => None
     };
-    let s = if ttl.is_some() {
-        Some(ttl.unwrap().parse::<u64>().unwrap())
+    let exists = state.kv.contains_key(key);
+    let reply = |set: bool| if get {
+        old.clone().map_or(Value::NullBulkString, Value::bulk)
+    } else if set {
+        Value::SimpleString("OK".to_string())
     } else {
-        None
+        Value::NullBulkString
     };
-    db.set(args[0].clone(), args[1].clone(), s).await.unwrap();
-    Ok(())
+    match condition.as_deref() {
+        Some("NX") if exists => return Ok(reply(false)),
+        Some("XX") if !exists => return Ok(reply(false)),
+        _ => {}
+    }
+    if keep_ttl {
+        expire_at = state.expires.get(key).copied();
+    }
+    state.set_string(key.clone(), args[1].clone(), expire_at);
+    // Relative expiries are logged as the deadline they produced.
+    let mut argv = vec!["SET".to_string(), key.clone(), args[1].clone()];
+    if let Some(at) = expire_at {
+        argv.extend(["PXAT".to_string(), at.to_string()]);
+    }
+    state.rewrite = Some(argv);
+    state.notify(NOTIFY_STRING, "set", key);
+    if expire_at.is_some() && !keep_ttl {
+        state.notify(NOTIFY_GENERIC, "expire", key);
+    }
+    Ok(reply(true))
 }
 
-pub async fn rpush_handle(args: &Vec<String>, db: &db) -> Result<Value, Error> {
+pub fn rpush_handle(args: &[String], state: &mut dbstate) -> Result<Value, Error> {
     let key = args[0].clone();
-    let mut list_values: Vec<String> = Vec::new();
-    for i in 1..args.len() {
-        list_values.push(args[i].clone());
-    }
-    let mut lock = db.state.lock().await;
-    let mut v = Vec::new();
-    let v = if lock.kv.get(&key).is_some() {
-        let list = match lock.kv.get_mut(&key).unwrap() {
-            key_value::List(list )=> {
-                list.append(&mut list_values);
-                list.len()
-            }
-            _ => panic!("rpush only for lists")
-        };
-        list
-    } else {
-        v.append(&mut list_values);
-        lock.kv.insert(key, key_value::List(v.clone()));
-        v.len()
+    let v = match list_mut(state, &key)? {
+        Some(list) => {
+            list.extend_from_slice(&args[1..]);
+            list.len()
+        }
+        None => {
+            state.add_key(key.clone(), key_value::List(args[1..].to_vec()));
+            args.len() - 1
+        }
     };
-    Ok(Value::Integer(v as u32))
+    state.touch(&key);
+    state.notify(NOTIFY_LIST, "rpush", &key);
+    state.signal_key(&key);
+    Ok(Value::Integer(v as i64))
 }
 
-pub async fn lrange_handle(args: &Vec<String>, db: &db) -> Result<Value, Error> {
-    let lock = db.state.lock().await;
-    let list = match lock.kv.get(&args[0]) {
-        Some(key_value::List(l )) => Some(l),
+pub fn lrange_handle(args: &[String], state: &mut dbstate) -> Result<Value, Error> {
+    let list = match state.kv.get(&args[0]) {
+        Some(key_value::List(l)) => Some(l),
         None => None,
-        _ => panic!("lrange not supported for the given key")
+        _ => return Err(anyhow::anyhow!(WRONGTYPE))
     };
-    let start = args[1].parse::<isize>().unwrap();
-    let end = args[2].parse::<isize>().unwrap();
+    let start = parse_integer(&args[1])?;
+    let end = parse_integer(&args[2])?;
     match list {
         Some(list) => {
-            let len = list.len() as isize;
-            let norm = |i: isize|({ i + len }).max(0);
-            let s = if start >= 0 { start } else { norm(start) };
-            let e = if end > 0 { end } else { norm(end) };
+            let len = list.len() as i64;
+            // Only the start is clamped: an end before the first element
+            // leaves nothing to return.
+            let s = if start < 0 { (start + len).max(0) } else { start };
+            let e = if end < 0 { end + len } else { end.min(len - 1) };
             if s > e {
-                return Ok(Value::EmptyArray)
+                Ok(Value::EmptyArray)
             } else {
-                let list = list;
-                let mut v= Vec::new();
-                for i  in s..=e {
-                    v.push(Value::BulkString(list[i as usize].clone()));
-                }
-                return Ok(Value::Array(v))
+                Ok(Value::Array(list[s as usize..=e as usize].iter().map(|v| Value::bulk(v.clone())).collect()))
             }
         }
         None => {
-            return Ok(Value::EmptyArray)
+            state.notify(NOTIFY_KEY_MISS, "keymiss", &args[0]);
+            Ok(Value::EmptyArray)
         }
     }
 }
 
-pub async fn lpush_handle(args: &Vec<String>, db: &db) -> Result<Value, Error> {
+pub fn lpush_handle(args: &[String], state: &mut dbstate) -> Result<Value, Error> {
     let key = args[0].clone();
-    let len = args.len();
-    let mut list_values: Vec<String> = Vec::new();
-    for i in 1..args.len() {
-        list_values.push(args[len - i].clone());
-    }
-    let mut lock = db.state.lock().await;
-    let mut v = Vec::new();
-    let v = if lock.kv.get(&key).is_some() {
-        let len = match lock.kv.get_mut(&key).unwrap() {
-            key_value::List(list) => {
-                list.append(&mut list_values);
-                list.len()
-            }
-            _ => panic!("Only lists")
-        };
-        len
-    } else {
-        v.append(&mut list_values);
-        lock.kv.insert(key, key_value::List(v.clone()));
-        v.len()
+    let v = match list_mut(state, &key)? {
+        Some(list) => {
+            list.splice(0..0, args[1..].iter().rev().cloned());
+            list.len()
+        }
+        None => {
+            state.add_key(key.clone(), key_value::List(args[1..].iter().rev().cloned().collect()));
+            args.len() - 1
+        }
     };
-    Ok(Value::Integer(v as u32))
+    state.touch(&key);
+    state.notify(NOTIFY_LIST, "lpush", &key);
+    state.signal_key(&key);
+    Ok(Value::Integer(v as i64))
 }
-pub async fn llen_handle(args: &Vec<String>, db: &db) -> Result<Value, Error> {
+pub fn llen_handle(args: &[String], state: &mut dbstate) -> Result<Value, Error> {
     let key = &args[0];
-    let lock = db.state.lock().await;
-    let list = lock.kv.get(key);
-    let len = match list {
-        Some(key_value::List(list)) => {
-            list.len()
+    let len = match list_mut(state, key)? {
+        Some(list) => list.len(),
+        None => {
+            state.notify(NOTIFY_KEY_MISS, "keymiss", key);
+            0
         }
-        _ => 0
     };
-    Ok(Value::Integer(len as u32))
+    Ok(Value::Integer(len as i64))
 }
-pub async fn lpop_handle(args: &Vec<String>, db: &db) -> Result<Value, Error> {
-    let args_len = args.len();
+pub fn lpop_handle(args: &[String], state: &mut dbstate) -> Result<Value, Error> {
     let key = args[0].clone();
-    let element_count = if args_len > 1 { args[1].clone().parse::<usize>().unwrap() } else { 0 };
-    let mut lock = db.state.lock().await;
-    let list = lock.kv.get_mut(&key);
-    let v: Value = match list {
-        Some(key_value::List(list)) => {
-            let len = list.len();
-            if len == 0 { Value::NullBulkString }
-            else { 
-                if element_count == 0 {
-                    Value::BulkString(list.remove(0))
-                } else {
-                    let mut s = Vec::new();
-                    for _ in 0..element_count {
-                        s.push(Value::BulkString(list.remove(0)));
-                    }
-                    Value::Array(s)
-                }
-            }
+    let count = match args.get(1) {
+        Some(c) => match parse_integer(c)? {
+            c if c < 0 => return Err(anyhow::anyhow!("ERR value is out of range, must be positive")),
+            c => Some(c as usize)
+        },
+        None => None
+    };
+    let v = match (list_mut(state, &key)?, count) {
+        (Some(list), None) => Value::bulk(list.remove(0)),
+        (Some(list), Some(count)) => {
+            let n = count.min(list.len());
+            Value::Array(list.drain(..n).map(Value::bulk).collect())
         }
-        _ => Value::NullBulkString
+        (None, None) => Value::NullBulkString,
+        (None, Some(_)) => Value::NullArray
     };
+    if !matches!(&v, Value::NullBulkString | Value::NullArray) && !matches!(&v, Value::Array(a) if a.is_empty()) {
+        state.touch(&key);
+        state.notify(NOTIFY_LIST, "lpop", &key);
+        state.remove_if_empty_list(&key);
+    }
     Ok(v)
 }
-pub async fn blpop_handle(args: &Vec<String>, db: &db) -> Result<Value, Error> {
-    let key = args[0].clone();
-    let mut time_out = args[1].clone().parse::<f64>().unwrap();
-    let now = std::time::Instant::now();
-
-    if time_out == 0.0 {
-        time_out = f64::INFINITY;
-    }
-    
-    while now.elapsed().as_secs_f64() < time_out {
-        let mut lock = db.state.lock().await;
-        let list = match lock.kv.get_mut(&key) {
-            Some(key_value::List(l)) => l,
-            _ => continue
-        };
-        if list.len() == 0 {
-            continue;
-        } else {
+pub fn blpop_handle(args: &[String], state: &mut dbstate) -> Result<Reply, Error> {
+    let (keys, time_out) = args.split_at(args.len() - 1);
+    let time_out = time_out[0].parse::<f64>().map_err(|_| anyhow::anyhow!("ERR timeout is not a float or out of range"))?;
+    if time_out < 0.0 {
+        return Err(anyhow::anyhow!("ERR timeout is negative"))
+    }
+    let timeout = if time_out == 0.0 { None } else { Some(Duration::from_secs_f64(time_out)) };
+
+    for key in keys {
+        if let Some(list) = list_mut(state, key)?
+            && !list.is_empty() {
             let v = list.remove(0);
-            return Ok(Value::Array(vec![Value::BulkString(key), Value::BulkString(v)]));
+            state.touch(key);
+            state.notify(NOTIFY_LIST, "lpop", key);
+            state.remove_if_empty_list(key);
+            state.rewrite = Some(vec!["LPOP".to_string(), key.clone()]);
+            return Ok(Reply::Ready(Value::Array(vec![Value::bulk(key.clone()), Value::bulk(v)])));
         }
     }
-    Ok(Value::NullBulkString)
+    let (id, rx) = state.block(keys.to_vec(), BlockedOp::ListPop);
+    Ok(Reply::Blocked { id, rx, timeout, on_timeout: Value::NullArray })
 }
 
-pub async fn type_handle(args: &Vec<String>, db: &db) -> Result<Value, Error> {
+pub fn type_handle(args: &[String], state: &mut dbstate) -> Result<Value, Error> {
     let key = &args[0];
-    let lock = db.state.lock().await;
-    let value = lock.kv.get(key);
+    let value = state.kv.get(key);
 
     let s = match value {
         Some(l ) => match l {
@@ -211,189 +243,1761 @@ pub async fn type_handle(args: &Vec<String>, db: &db) -> Result<Value, Error> {
             key_value::Stream(_) => {
                 Value::SimpleString("stream".to_string())
             }
+            key_value::Set(_) => Value::SimpleString("set".to_string()),
+            key_value::Hash(_) => Value::SimpleString("hash".to_string()),
+            key_value::ZSet(_) => Value::SimpleString("zset".to_string())
         }
         None => Value::SimpleString("none".to_string())
     };
     Ok(s)
 }
-pub async fn xadd_handle(args: &Vec<String>, db: &db) -> Result<Value, Error> {
-    let key = args[0].clone();
-    let mut id = args[1].clone();
-    let (ms, sq)  = if id == "0-0" {
-        panic!("Error min valid redis ID is 0-1")
-    } else if id == "*" {
-        let newms = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis();
-        id = newms.to_string() + "-0";
-        (newms, 0)
-    } else {
-        let (m, s) = id.split_once("-").unwrap();
-        (m.parse::<u128>().unwrap(), s.parse::<u128>().unwrap())
+pub fn del_handle(args: &[String], state: &mut dbstate) -> Result<Value, Error> {
+    let mut deleted = 0;
+    for key in args {
+        if state.remove(key).is_some() {
+            state.notify(NOTIFY_GENERIC, "del", key);
+            deleted += 1;
+        }
+    }
+    Ok(Value::Integer(deleted))
+}
+
+/// A database index given to a command, which has to name one of the
+/// databases.
+fn db_index(index: i64, state: &dbstate) -> Result<usize, Error> {
+    usize::try_from(index).ok()
+        .filter(|i| *i < state.dbs.len())
+        .ok_or_else(|| anyhow::anyhow!("ERR DB index is out of range"))
+}
+
+/// SELECT index: switches the connection to another database. In cluster
+/// mode there is only database 0.
+pub fn select_handle(args: &[String], state: &mut dbstate) -> Result<Value, Error> {
+    let index = parse_integer(&args[0])?;
+    if state.cluster.is_some() && index != 0 {
+        return Err(anyhow::anyhow!("ERR SELECT is not allowed in cluster mode"))
+    }
+    let db = db_index(index, state)?;
+    state.select(db);
+    Ok(Value::SimpleString("OK".to_string()))
+}
+
+/// MOVE key db: moves a key with its TTL to another database, unless it is
+/// already there.
+pub fn move_handle(args: &[String], state: &mut dbstate) -> Result<Value, Error> {
+    if state.cluster.is_some() {
+        return Err(anyhow::anyhow!("ERR MOVE is not allowed in cluster mode"))
+    }
+    let key = &args[0];
+    let db = db_index(parse_integer(&args[1])?, state)?;
+    if db == state.db {
+        return Err(anyhow::anyhow!("ERR source and destination objects are the same"))
+    }
+    let exists_in_target = state.in_db(db, |state| {
+        state.expire_if_needed(key);
+        state.kv.contains_key(key)
+    });
+    if exists_in_target {
+        return Ok(Value::Integer(0))
+    }
+    let expire_at = state.expires.get(key).copied();
+    let access = state.access.get(key).copied();
+    let Some(value) = state.remove(key) else { return Ok(Value::Integer(0)) };
+    state.notify(NOTIFY_GENERIC, "move_from", key);
+    state.in_db(db, |state| {
+        if let Some(at) = expire_at {
+            state.expires.insert(key.clone(), at);
+        }
+        state.add_key(key.clone(), value);
+        if let Some(access) = access {
+            state.access.insert(key.clone(), access);
+        }
+        state.touch(key);
+        state.notify(NOTIFY_GENERIC, "move_to", key);
+        state.signal_key(key);
+    });
+    Ok(Value::Integer(1))
+}
+
+/// SWAPDB index1 index2: exchanges the contents of two databases.
+pub fn swapdb_handle(args: &[String], state: &mut dbstate) -> Result<Value, Error> {
+    if state.cluster.is_some() {
+        return Err(anyhow::anyhow!("ERR SWAPDB is not allowed in cluster mode"))
+    }
+    let a = args[0].parse::<i64>().map_err(|_| anyhow::anyhow!("ERR invalid first DB index"))?;
+    let b = args[1].parse::<i64>().map_err(|_| anyhow::anyhow!("ERR invalid second DB index"))?;
+    let (a, b) = (db_index(a, state)?, db_index(b, state)?);
+    if a != b {
+        state.swap_dbs(a, b);
+    }
+    state.persistence.dirty += 1;
+    Ok(Value::SimpleString("OK".to_string()))
+}
+
+/// The ASYNC or SYNC option of FLUSHDB and FLUSHALL: whether to free the
+/// memory in the background.
+fn parse_flush_mode(args: &[String]) -> Result<bool, Error> {
+    match args {
+        [] => Ok(false),
+        [mode] if mode.eq_ignore_ascii_case("ASYNC") => Ok(true),
+        [mode] if mode.eq_ignore_ascii_case("SYNC") => Ok(false),
+        _ => Err(anyhow::anyhow!("ERR syntax error"))
+    }
+}
+
+/// FLUSHDB [ASYNC|SYNC]: deletes every key of the selected database.
+pub fn flushdb_handle(args: &[String], state: &mut dbstate) -> Result<Value, Error> {
+    let lazy = parse_flush_mode(args)?;
+    state.flush_db(lazy);
+    // Logged even when there was nothing to delete.
+    state.persistence.dirty += 1;
+    Ok(Value::SimpleString("OK".to_string()))
+}
+
+/// FLUSHALL [ASYNC|SYNC]: deletes every key of every database. With save
+/// rules configured, the RDB file is rewritten empty right away, as Redis
+/// does.
+pub fn flushall_handle(args: &[String], state: &mut dbstate) -> Result<Value, Error> {
+    let lazy = parse_flush_mode(args)?;
+    state.flush_all(lazy);
+    if !state.config.save.is_empty() {
+        let _ = rdb::save(state);
+    }
+    // The save resets the change counter, so it can't tell whether to log
+    // the command.
+    let mut argv = vec!["FLUSHALL".to_string()];
+    argv.extend(args.iter().cloned());
+    state.propagate(argv);
+    state.skip_propagation = true;
+    Ok(Value::SimpleString("OK".to_string()))
+}
+
+/// DUMP key: the value serialized the way RESTORE reads it.
+pub fn dump_handle(args: &[String], state: &mut dbstate) -> Result<Value, Error> {
+    state.expire_if_needed(&args[0]);
+    Ok(match state.kv.get(&args[0]) {
+        Some(value) => Value::BulkString(rdb::dump(value)),
+        None => Value::NullBulkString
+    })
+}
+
+/// RESTORE key ttl serialized-value [REPLACE] [ABSTTL] [IDLETIME seconds]
+/// [FREQ frequency], and RESTORE-ASKING which MIGRATE sends: recreates a key
+/// from a DUMP payload. A relative TTL is logged as the deadline it produced.
+pub fn restore_handle(args: &[String], state: &mut dbstate) -> Result<Value, Error> {
+    let key = &args[0];
+    let ttl = parse_integer(&args[1])?;
+    let (mut replace, mut absttl, mut idletime, mut freq) = (false, false, None, None);
+    let mut i = 3;
+    while i < args.len() {
+        match args[i].to_uppercase().as_str() {
+            "REPLACE" => replace = true,
+            "ABSTTL" => absttl = true,
+            "IDLETIME" if i + 1 < args.len() && freq.is_none() => {
+                i += 1;
+                let seconds = parse_integer(&args[i])?;
+                if seconds < 0 {
+                    return Err(anyhow::anyhow!("ERR Invalid IDLETIME value, must be >= 0"))
+                }
+                let ms = (seconds as u64).checked_mul(1000)
+                    .ok_or_else(|| anyhow::anyhow!("ERR Invalid IDLETIME value, out of range"))?;
+                idletime = Some(ms);
+            }
+            "FREQ" if i + 1 < args.len() && idletime.is_none() => {
+                i += 1;
+                let frequency = parse_integer(&args[i])?;
+                if !(0..=255).contains(&frequency) {
+                    return Err(anyhow::anyhow!("ERR Invalid FREQ value, must be >= 0 and <= 255"))
+                }
+                freq = Some(frequency as u8);
+            }
+            _ => return Err(anyhow::anyhow!("ERR syntax error"))
+        }
+        i += 1;
+    }
+    let ttl = u64::try_from(ttl).map_err(|_| anyhow::anyhow!("ERR Invalid TTL value, must be >= 0"))?;
+    if !replace && state.kv.contains_key(key) {
+        return Err(anyhow::anyhow!("BUSYKEY Target key name already exists."))
+    }
+    let value = rdb::undump(&string_to_bytes(&args[2]))?;
+    let expire_at = match ttl {
+        0 => None,
+        at if absttl => Some(at),
+        ttl => Some(ttl.checked_add(now_ms())
+            .filter(|at| *at <= i64::MAX as u64)
+            .ok_or_else(|| anyhow::anyhow!("ERR invalid expire time in 'restore' command"))?)
+    };
+    let deleted = state.remove(key).is_some();
+    if expire_at.is_some_and(|at| at <= now_ms()) {
+        // Already expired: there is nothing to create.
+        if deleted {
+            state.notify(NOTIFY_GENERIC, "del", key);
+            state.rewrite = Some(vec!["DEL".to_string(), key.clone()]);
+        }
+        return Ok(Value::SimpleString("OK".to_string()))
+    }
+    state.add_key(key.clone(), value);
+    state.touch(key);
+    if let Some(access) = state.access.get_mut(key) {
+        let now = now_ms();
+        if let Some(ms) = idletime {
+            access.last = now.saturating_sub(ms);
+        }
+        if let Some(freq) = freq {
+            access.set_counter(freq, now);
+        }
+    }
+    if let Some(at) = expire_at {
+        state.expires.insert(key.clone(), at);
+        let mut argv = vec!["RESTORE".to_string(), key.clone(), at.to_string(), args[2].clone(), "ABSTTL".to_string()];
+        argv.extend(args[3..].iter().filter(|a| !a.eq_ignore_ascii_case("ABSTTL")).cloned());
+        state.rewrite = Some(argv);
+    }
+    state.notify(NOTIFY_GENERIC, "restore", key);
+    Ok(Value::SimpleString("OK".to_string()))
+}
+
+/// OBJECT ENCODING | FREQ | IDLETIME | REFCOUNT key. With no maxmemory
+/// policy to choose between them, both access time and frequency are kept.
+pub fn object_handle(args: &[String], state: &mut dbstate) -> Result<Value, Error> {
+    let subcommand = args[0].to_uppercase();
+    match (subcommand.as_str(), &args[1..]) {
+        ("ENCODING" | "FREQ" | "IDLETIME" | "REFCOUNT", [key]) => {
+            let (Some(value), Some(access)) = (state.kv.get(key), state.access.get(key)) else {
+                return Ok(Value::NullBulkString)
+            };
+            let now = now_ms();
+            Ok(match subcommand.as_str() {
+                "ENCODING" => Value::bulk(value.encoding()),
+                "FREQ" => Value::Integer(access.frequency(now) as i64),
+                "IDLETIME" => Value::Integer((now.saturating_sub(access.last) / 1000) as i64),
+                // Small integers are shared objects in Redis.
+                _ => Value::Integer(match value {
+                    key_value::String(s) if is_integer(s) && (0..10000).contains(&s.parse::<i64>().unwrap_or(-1)) => i32::MAX as i64,
+                    _ => 1
+                })
+            })
+        }
+        ("ENCODING" | "FREQ" | "IDLETIME" | "REFCOUNT", _) => {
+            Err(anyhow::anyhow!("ERR wrong number of arguments for 'object|{}' command", subcommand.to_lowercase()))
+        }
+        _ => Err(anyhow::anyhow!("ERR unknown subcommand '{}'. Try OBJECT HELP.", args[0]))
+    }
+}
+
+pub fn xadd_handle(args: &[String], state: &mut dbstate) -> Result<Value, Error> {
+    let key = &args[0];
+    let mut nomkstream = false;
+    let mut trim = None;
+    let mut i = 1;
+    while i < args.len() {
+        match args[i].to_uppercase().as_str() {
+            "NOMKSTREAM" => {
+                nomkstream = true;
+                i += 1;
+            }
+            "MAXLEN" | "MINID" => trim = Some(Trim::parse(args, &mut i)?),
+            _ => break
+        }
+    }
+    if args.len() < i + 3 || !(args.len() - i - 1).is_multiple_of(2) {
+        return Err(anyhow::anyhow!("ERR wrong number of arguments for 'xadd' command"))
+    }
+    let spec = XAddId::parse(&args[i])?;
+    let mut fields = Vec::new();
+    for pair in args[i + 1..].chunks(2) {
+        fields.push((pair[0].clone(), pair[1].clone()));
+    }
+    let id = match state.kv.get(key) {
+        Some(key_value::Stream(s)) => s.next_id(spec)?,
+        Some(_) => return Err(anyhow::anyhow!(WRONGTYPE)),
+        None if nomkstream => return Ok(Value::NullBulkString),
+        None => Stream::new().next_id(spec)?
+    };
+    if !state.kv.contains_key(key) {
+        state.add_key(key.clone(), key_value::Stream(Stream::new()));
+    }
+    let mut trimmed = 0;
+    if let Some(key_value::Stream(stream)) = state.kv.get_mut(key) {
+        stream.insert(id, fields);
+        if let Some(trim) = trim {
+            trimmed = stream.trim(&trim);
+        }
+        // Log the ID that was generated, and an approximate trim as the
+        // exact one it amounted to.
+        let mut argv = vec!["XADD".to_string(), key.clone()];
+        if nomkstream {
+            argv.push("NOMKSTREAM".to_string());
+        }
+        if let Some(trim) = trim {
+            argv.extend(propagated_trim(stream, &trim, &args[1..i]));
+        }
+        argv.push(id.to_string());
+        argv.extend(args[i + 1..].iter().cloned());
+        state.rewrite = Some(argv);
+    }
+    state.touch(key);
+    state.notify(NOTIFY_STREAM, "xadd", key);
+    if trimmed > 0 {
+        state.notify(NOTIFY_STREAM, "xtrim", key);
+    }
+    state.signal_key(key);
+    Ok(Value::bulk(id.to_string()))
+}
+pub fn xlen_handle(args: &[String], state: &mut dbstate) -> Result<Value, Error> {
+    match state.kv.get(&args[0]) {
+        Some(key_value::Stream(s)) => Ok(Value::Integer(s.len() as i64)),
+        Some(_) => Err(anyhow::anyhow!(WRONGTYPE)),
+        None => {
+            state.notify(NOTIFY_KEY_MISS, "keymiss", &args[0]);
+            Ok(Value::Integer(0))
+        }
+    }
+}
+pub fn xdel_handle(args: &[String], state: &mut dbstate) -> Result<Value, Error> {
+    let mut ids = Vec::new();
+    for id in &args[1..] {
+        ids.push(StreamId::parse(id, 0, true)?);
+    }
+    let stream = match state.kv.get_mut(&args[0]) {
+        Some(key_value::Stream(s)) => s,
+        Some(_) => return Err(anyhow::anyhow!(WRONGTYPE)),
+        None => return Ok(Value::Integer(0))
     };
-    let len = args.len();
-    let mut lock = db.state.lock().await;
-    let last = match lock
-                                                                .kv
-                                                                .entry(key.clone())
-                                                                .or_insert_with(|| key_value::Stream(BTreeMap::<(u128, u128), HashMap<String, String>>::new())){
-                                                                    key_value::Stream(s) => s,
-                                                                    _ => panic!("Error only supports streams")
-                                                                };
-    let (last_ms, last_sq) = if last.is_empty() {
-        (0,0)
+    let deleted = ids.into_iter().filter(|id| stream.delete(*id)).count();
+    if deleted > 0 {
+        state.touch(&args[0]);
+        state.notify(NOTIFY_STREAM, "xdel", &args[0]);
+    }
+    Ok(Value::Integer(deleted as i64))
+}
+/// The trim clause to log for `trim` as given in `clause`. An approximate
+/// trim depends on how entries are packed in blocks, so it is logged as the
+/// exact length it left.
+fn propagated_trim(stream: &Stream, trim: &Trim, clause: &[String]) -> Vec<String> {
+    let clause: Vec<String> = clause.iter().filter(|a| !a.eq_ignore_ascii_case("NOMKSTREAM")).cloned().collect();
+    if trim.approx {
+        vec!["MAXLEN".to_string(), "=".to_string(), stream.len().to_string()]
     } else {
-        let t = last.last_key_value().unwrap();
-        (t.0.0 , t.0.1)
+        clause
+    }
+}
+pub fn xtrim_handle(args: &[String], state: &mut dbstate) -> Result<Value, Error> {
+    let mut i = 1;
+    let trim = match args.get(i) {
+        Some(a) if a.eq_ignore_ascii_case("MAXLEN") || a.eq_ignore_ascii_case("MINID") => Trim::parse(args, &mut i)?,
+        _ => return Err(anyhow::anyhow!("ERR syntax error"))
+    };
+    if i != args.len() {
+        return Err(anyhow::anyhow!("ERR syntax error"))
+    }
+    let trimmed = match state.kv.get_mut(&args[0]) {
+        Some(key_value::Stream(s)) => {
+            let trimmed = s.trim(&trim);
+            let mut argv = vec!["XTRIM".to_string(), args[0].clone()];
+            argv.extend(propagated_trim(s, &trim, &args[1..]));
+            state.rewrite = Some(argv);
+            trimmed
+        }
+        Some(_) => return Err(anyhow::anyhow!(WRONGTYPE)),
+        None => 0
+    };
+    if trimmed > 0 {
+        state.touch(&args[0]);
+        state.notify(NOTIFY_STREAM, "xtrim", &args[0]);
+    }
+    Ok(Value::Integer(trimmed as i64))
+}
+pub fn xsetid_handle(args: &[String], state: &mut dbstate) -> Result<Value, Error> {
+    let id = StreamId::parse(&args[1], 0, true)?;
+    let mut entries_added = None;
+    let mut max_deleted_id = None;
+    let mut i = 2;
+    while i < args.len() {
+        let value = args.get(i + 1).ok_or_else(|| anyhow::anyhow!("ERR syntax error"))?;
+        match args[i].to_uppercase().as_str() {
+            "ENTRIESADDED" => {
+                let n = value.parse::<i64>().map_err(|_| anyhow::anyhow!("ERR value is not an integer or out of range"))?;
+                if n < 0 {
+                    return Err(anyhow::anyhow!("ERR entries_added must be positive"))
+                }
+                entries_added = Some(n as u64);
+            }
+            "MAXDELETEDID" => {
+                max_deleted_id = Some(StreamId::parse(value, 0, true)?);
+            }
+            _ => return Err(anyhow::anyhow!("ERR syntax error"))
+        }
+        i += 2;
+    }
+    match state.kv.get_mut(&args[0]) {
+        Some(key_value::Stream(s)) => s.set_id(id, entries_added, max_deleted_id)?,
+        Some(_) => return Err(anyhow::anyhow!(WRONGTYPE)),
+        None => return Err(anyhow::anyhow!("ERR no such key"))
+    }
+    state.touch(&args[0]);
+    state.notify(NOTIFY_STREAM, "xsetid", &args[0]);
+    Ok(Value::SimpleString("OK".to_string()))
+}
+/// Parses the optional `COUNT n` trailing XRANGE and XREVRANGE.
+fn parse_range_count(args: &[String]) -> Result<Option<usize>, Error> {
+    match args {
+        [] => Ok(None),
+        [opt, n] if opt.eq_ignore_ascii_case("COUNT") => Ok(Some(parse_integer(n)?.max(0) as usize)),
+        _ => Err(anyhow::anyhow!("ERR syntax error"))
+    }
+}
+pub fn xrange_handle(args: &[String], state: &mut dbstate) -> Result<Value, Error> {
+    let key = &args[0];
+    let start = StreamId::parse_bound(&args[1], true)?;
+    let end = StreamId::parse_bound(&args[2], false)?;
+    let count = parse_range_count(&args[3..])?;
+
+    let stream = match state.kv.get(key) {
+        Some(key_value::Stream(l)) => l,
+        Some(_) => return Err(anyhow::anyhow!(WRONGTYPE)),
+        None => {
+            state.notify(NOTIFY_KEY_MISS, "keymiss", key);
+            return Ok(Value::EmptyArray)
+        }
     };
-    if &args[1] == "*" && last_ms == ms {
-         id = ms.to_string() + "-" + &(last_sq + 1).to_string()
-    } else if (last_ms, last_sq) >= (ms, sq) {
-        panic!("Error the ID is equal to less than the previous entry")
-    }
-    let mut s = HashMap::new();
-    for i in (2..len).step_by(2) {
-        s.insert(args[i].clone(), args[i+1].clone());
-    }
-    let (d,q) = id.split_once("-").unwrap();
-    let final_id = d.parse::<u128>().unwrap();
-    let final_seq = q.parse::<u128>().unwrap();
-    last.insert((final_id, final_seq), s);
-    Ok(Value::BulkString(id))
-}
-pub async fn xrange_handle(args: &Vec<String>, db: &db) -> Result<Value, Error> {
+    Ok(Value::Array(stream_entries_value(stream.range_count(start, end, count))))
+}
+pub fn xrevrange_handle(args: &[String], state: &mut dbstate) -> Result<Value, Error> {
     let key = &args[0];
-    fn parse_id(s: &str, default_seq: u128) -> (u128, u128) {
-        println!("{:?}", s);
-        match s.split_once('-') {
-            Some((ms, seq)) => (ms.parse().unwrap_or(default_seq), seq.parse().unwrap_or(default_seq)),
-            None => if s == "+" {
-                (default_seq, default_seq)
+    let end = StreamId::parse_bound(&args[1], false)?;
+    let start = StreamId::parse_bound(&args[2], true)?;
+    let count = parse_range_count(&args[3..])?;
+
+    let stream = match state.kv.get(key) {
+        Some(key_value::Stream(l)) => l,
+        Some(_) => return Err(anyhow::anyhow!(WRONGTYPE)),
+        None => {
+            state.notify(NOTIFY_KEY_MISS, "keymiss", key);
+            return Ok(Value::EmptyArray)
+        }
+    };
+    Ok(Value::Array(stream_entries_value(stream.rev_range(start, end, count))))
+}
+struct XReadArgs {
+    count: Option<usize>,
+    /// BLOCK timeout in milliseconds.
+    block: Option<u64>,
+    /// (key, ID) pairs; entries are returned strictly after each ID. `None`
+    /// is `$`, the last ID of the stream when the command runs.
+    streams: Vec<(String, Option<StreamId>)>,
+}
+/// Parses `[COUNT n] [BLOCK ms] STREAMS key... id...`.
+fn parse_xread_args(args: &[String]) -> Result<XReadArgs, Error> {
+    let mut count = None;
+    let mut block = None;
+    let mut i = 0;
+    loop {
+        let value = || args.get(i + 1).ok_or_else(|| anyhow::anyhow!("ERR syntax error"));
+        match args.get(i).map(|a| a.to_uppercase()).as_deref() {
+            Some("COUNT") => {
+                let n = parse_integer(value()?)?;
+                count = (n > 0).then_some(n as usize);
+                i += 2;
+            }
+            Some("BLOCK") => {
+                let ms = parse_integer(value()?)?;
+                if ms < 0 {
+                    return Err(anyhow::anyhow!("ERR timeout is negative"))
+                }
+                block = Some(ms as u64);
+                i += 2;
+            }
+            Some("STREAMS") => {
+                i += 1;
+                break;
+            }
+            _ => return Err(anyhow::anyhow!("ERR syntax error"))
+        }
+    }
+    let streams = &args[i..];
+    if streams.is_empty() || !streams.len().is_multiple_of(2) {
+        return Err(anyhow::anyhow!("ERR Unbalanced 'xread' list of streams: for each stream key an ID or '$' must be specified."))
+    }
+    let (keys, ids) = streams.split_at(streams.len() / 2);
+    let mut res = Vec::new();
+    for (key, id) in keys.iter().zip(ids) {
+        let id = if id == "$" { None } else { Some(StreamId::parse(id, 0, true)?) };
+        res.push((key.clone(), id));
+    }
+    Ok(XReadArgs { count, block, streams: res })
+}
+pub fn xread_handle(args: &[String], state: &mut dbstate) -> Result<Reply, Error> {
+    let XReadArgs { count, block, streams } = parse_xread_args(args)?;
+    let mut after = Vec::new();
+    for (key, id) in streams {
+        let stream = match state.kv.get(&key) {
+            Some(key_value::Stream(s)) => Some(s),
+            Some(_) => return Err(anyhow::anyhow!(WRONGTYPE)),
+            None => None
+        };
+        let id = id.unwrap_or_else(|| stream.map(|s| s.last_id()).unwrap_or_default());
+        after.push((key, id));
+    }
+    let mut fin = Vec::new();
+    for (key, id) in &after {
+        if let Some(key_value::Stream(stream)) = state.kv.get(key) {
+            let entries = stream_entries_after(stream, *id, count);
+            if !entries.is_empty() {
+                fin.push(Value::Array(vec![Value::bulk(key.clone()), Value::Array(entries)]));
+            }
+        }
+    }
+    if !fin.is_empty() {
+        return Ok(Reply::Ready(Value::Array(fin)))
+    }
+    let Some(block) = block else {
+        return Ok(Reply::Ready(Value::NullArray))
+    };
+    let keys = after.iter().map(|(k, _)| k.clone()).collect();
+    let (id, rx) = state.block(keys, BlockedOp::StreamRead { after: after.into_iter().collect(), count });
+    let timeout = (block > 0).then(|| Duration::from_millis(block));
+    Ok(Reply::Blocked { id, rx, timeout, on_timeout: Value::NullArray })
+}
+fn parse_integer(s: &str) -> Result<i64, Error> {
+    s.parse::<i64>().map_err(|_| anyhow::anyhow!("ERR value is not an integer or out of range"))
+}
+fn list_mut<'a>(state: &'a mut dbstate, key: &str) -> Result<Option<&'a mut Vec<String>>, Error> {
+    match state.kv.get_mut(key) {
+        Some(key_value::List(l)) => Ok(Some(l)),
+        Some(_) => Err(anyhow::anyhow!(WRONGTYPE)),
+        None => Ok(None)
+    }
+}
+fn stream_mut<'a>(state: &'a mut dbstate, key: &str) -> Result<Option<&'a mut Stream>, Error> {
+    match state.kv.get_mut(key) {
+        Some(key_value::Stream(s)) => Ok(Some(s)),
+        Some(_) => Err(anyhow::anyhow!(WRONGTYPE)),
+        None => Ok(None)
+    }
+}
+/// Commands that create consumers implicitly check this before and after, to
+/// fire xgroup-createconsumer.
+fn has_consumer(state: &dbstate, key: &str, group: &str, consumer: &str) -> bool {
+    matches!(state.kv.get(key), Some(key_value::Stream(s)) if s.groups.get(group).is_some_and(|g| g.consumers.contains_key(consumer)))
+}
+fn notify_consumer_created(state: &dbstate, existed: bool, key: &str, group: &str, consumer: &str) {
+    if !existed && has_consumer(state, key, group, consumer) {
+        state.notify(NOTIFY_STREAM, "xgroup-createconsumer", key);
+    }
+}
+fn no_group(key: &str, group: &str) -> Error {
+    anyhow::anyhow!("NOGROUP No such key '{}' or consumer group '{}'", key, group)
+}
+pub fn xgroup_handle(args: &[String], state: &mut dbstate) -> Result<Value, Error> {
+    let subcommand = args[0].to_uppercase();
+    let arity_ok = match subcommand.as_str() {
+        "CREATE" | "SETID" => args.len() >= 4,
+        "DESTROY" => args.len() == 3,
+        "CREATECONSUMER" | "DELCONSUMER" => args.len() == 4,
+        _ => return Err(anyhow::anyhow!("ERR unknown subcommand '{}'. Try XGROUP HELP.", args[0]))
+    };
+    if !arity_ok {
+        return Err(anyhow::anyhow!("ERR wrong number of arguments for 'xgroup|{}' command", subcommand.to_lowercase()))
+    }
+    let (key, group) = (&args[1], &args[2]);
+    if subcommand == "CREATE" && args[4..].iter().any(|a| a.eq_ignore_ascii_case("MKSTREAM")) && !state.kv.contains_key(key) {
+        state.add_key(key.clone(), key_value::Stream(Stream::new()));
+    }
+    let Some(stream) = stream_mut(state, key)? else {
+        return Err(anyhow::anyhow!("ERR The XGROUP subcommand requires the key to exist. Note that for CREATE you may want to use the MKSTREAM option to create an empty stream automatically."))
+    };
+    let reply = match subcommand.as_str() {
+        "CREATE" | "SETID" => {
+            let id = match args[3].as_str() {
+                "$" => stream.last_id(),
+                id => StreamId::parse(id, 0, true)?
+            };
+            let mut entries_read = None;
+            let mut i = 4;
+            while i < args.len() {
+                match args[i].to_uppercase().as_str() {
+                    "MKSTREAM" if subcommand == "CREATE" => i += 1,
+                    "ENTRIESREAD" => {
+                        let n = parse_integer(args.get(i + 1).ok_or_else(|| anyhow::anyhow!("ERR syntax error"))?)?;
+                        if n < -1 {
+                            return Err(anyhow::anyhow!("ERR value for ENTRIESREAD must be positive or -1"))
+                        }
+                        entries_read = (n >= 0).then_some(n as u64);
+                        i += 2;
+                    }
+                    _ => return Err(anyhow::anyhow!("ERR syntax error"))
+                }
+            }
+            if subcommand == "CREATE" {
+                if stream.groups.contains_key(group) {
+                    return Err(anyhow::anyhow!("BUSYGROUP Consumer Group name already exists"))
+                }
+                stream.groups.insert(group.clone(), ConsumerGroup::new(id, entries_read));
+            } else {
+                let Some(g) = stream.groups.get_mut(group) else {
+                    return Err(anyhow::anyhow!("NOGROUP No such consumer group '{}' for key name '{}'", group, key))
+                };
+                g.last_delivered = id;
+                g.entries_read = entries_read;
+            }
+            Ok(Value::SimpleString("OK".to_string()))
+        }
+        "DESTROY" => {
+            let destroyed = stream.groups.remove(group).is_some();
+            if destroyed {
+                // Clients blocked on the group get their NOGROUP error now.
+                state.signal_key(key);
+            }
+            Ok(Value::Integer(destroyed as i64))
+        }
+        "CREATECONSUMER" | "DELCONSUMER" => {
+            let consumer = &args[3];
+            let Some(g) = stream.groups.get_mut(group) else {
+                return Err(anyhow::anyhow!("NOGROUP No such consumer group '{}' for key name '{}'", group, key))
+            };
+            if subcommand == "CREATECONSUMER" {
+                Ok(Value::Integer(g.create_consumer(consumer, now_ms()) as i64))
             } else {
-                (s.parse::<u128>().unwrap(), default_seq)
+                Ok(Value::Integer(g.delete_consumer(consumer) as i64))
             }
         }
+        _ => Err(anyhow::anyhow!("ERR unknown subcommand '{}'. Try XGROUP HELP.", args[0]))
+    }?;
+    state.touch(key);
+    // CREATECONSUMER replies 0 when the consumer already existed.
+    if subcommand != "CREATECONSUMER" || matches!(reply, Value::Integer(1)) {
+        state.notify(NOTIFY_STREAM, &format!("xgroup-{}", subcommand.to_lowercase()), key);
+    }
+    Ok(reply)
+}
+pub fn xreadgroup_handle(args: &[String], state: &mut dbstate) -> Result<Reply, Error> {
+    if args.len() < 3 || !args[0].eq_ignore_ascii_case("GROUP") {
+        return Err(anyhow::anyhow!("ERR syntax error"))
+    }
+    let (group, consumer) = (args[1].clone(), args[2].clone());
+    let mut count = None;
+    let mut timeout = None;
+    let mut noack = false;
+    let mut i = 3;
+    loop {
+        match args.get(i).map(|a| a.to_uppercase()).as_deref() {
+            Some("COUNT") => {
+                let n = parse_integer(args.get(i + 1).ok_or_else(|| anyhow::anyhow!("ERR syntax error"))?)?;
+                count = (n > 0).then_some(n as usize);
+                i += 2;
+            }
+            Some("BLOCK") => {
+                let ms = parse_integer(args.get(i + 1).ok_or_else(|| anyhow::anyhow!("ERR syntax error"))?)?;
+                if ms < 0 {
+                    return Err(anyhow::anyhow!("ERR timeout is negative"))
+                }
+                timeout = Some(ms as u64);
+                i += 2;
+            }
+            Some("NOACK") => {
+                noack = true;
+                i += 1;
+            }
+            Some("STREAMS") => {
+                i += 1;
+                break;
+            }
+            _ => return Err(anyhow::anyhow!("ERR syntax error"))
+        }
+    }
+    let streams = &args[i..];
+    if streams.is_empty() || !streams.len().is_multiple_of(2) {
+        return Err(anyhow::anyhow!("ERR Unbalanced 'xreadgroup' list of streams: for each stream key an ID or '>' must be specified."))
+    }
+    let (keys, ids) = streams.split_at(streams.len() / 2);
+    let mut after = Vec::new();
+    for id in ids {
+        after.push(if id == ">" { None } else { Some(StreamId::parse(id, 0, true)?) });
     }
-    
-    let (start_ms, start_sq) = parse_id(&args[1], 0);
-    let (end_ms, end_sq)   = parse_id(&args[2], u128::MAX);
 
-    let lock = db.state.lock().await;
-    let stream = match lock.kv.get(key) {
-        Some(s) => match s {
-            key_value::Stream(l) => l,
-            _ => panic!("Only streams are supported for this cmd")
+    let mut fin = Vec::new();
+    for (key, after) in keys.iter().zip(&after) {
+        let existed = has_consumer(state, key, &group, &consumer);
+        let entries = stream_mut(state, key)?
+            .and_then(|s| s.read_group(&group, &consumer, *after, count, noack))
+            .ok_or_else(|| anyhow::anyhow!("NOGROUP No such key '{}' or consumer group '{}' in XREADGROUP with GROUP option", key, group))?;
+        notify_consumer_created(state, existed, key, &group, &consumer);
+        if !entries.is_empty() {
+            state.touch(key);
+            let ids: Vec<StreamId> = if noack { Vec::new() } else { entries.iter().map(|(id, _)| *id).collect() };
+            state.propagate_group_delivery(key, &group, &consumer, &ids);
+            state.skip_propagation = true;
+        }
+        if after.is_some() || !entries.is_empty() {
+            fin.push(Value::Array(vec![Value::bulk(key.clone()), Value::Array(delivered_entries_value(entries))]));
         }
-        None => return Ok(Value::BulkError("Key not found".to_string()))
+    }
+    if !fin.is_empty() {
+        return Ok(Reply::Ready(Value::Array(fin)))
+    }
+    let Some(timeout) = timeout else {
+        return Ok(Reply::Ready(Value::NullArray))
+    };
+    let op = BlockedOp::GroupRead { group, consumer, count, noack };
+    let (id, rx) = state.block(keys.to_vec(), op);
+    let timeout = (timeout > 0).then(|| Duration::from_millis(timeout));
+    Ok(Reply::Blocked { id, rx, timeout, on_timeout: Value::NullArray })
+}
+pub fn xack_handle(args: &[String], state: &mut dbstate) -> Result<Value, Error> {
+    let mut ids = Vec::new();
+    for id in &args[2..] {
+        ids.push(StreamId::parse(id, 0, true)?);
+    }
+    let Some(group) = stream_mut(state, &args[0])?.and_then(|s| s.groups.get_mut(&args[1])) else {
+        return Ok(Value::Integer(0))
+    };
+    let acked = ids.into_iter().filter(|id| group.ack(*id)).count();
+    if acked > 0 {
+        state.touch(&args[0]);
+    }
+    Ok(Value::Integer(acked as i64))
+}
+pub fn xpending_handle(args: &[String], state: &mut dbstate) -> Result<Value, Error> {
+    let (key, group_name) = (&args[0], &args[1]);
+    let mut min_idle = 0;
+    let mut i = 2;
+    if args.get(i).is_some_and(|a| a.eq_ignore_ascii_case("IDLE")) {
+        min_idle = parse_integer(args.get(i + 1).ok_or_else(|| anyhow::anyhow!("ERR syntax error"))?)?.max(0) as u64;
+        i += 2;
+    }
+    let extended = match args.len() - i {
+        0 if i == 2 => None,
+        3 | 4 => Some((
+            StreamId::parse_bound(&args[i], true)?,
+            StreamId::parse_bound(&args[i + 1], false)?,
+            parse_integer(&args[i + 2])?.max(0) as usize,
+            args.get(i + 3),
+        )),
+        _ => return Err(anyhow::anyhow!("ERR syntax error"))
+    };
+    let group = stream_mut(state, key)?
+        .and_then(|s| s.groups.get(group_name))
+        .ok_or_else(|| no_group(key, group_name))?;
+    let Some((start, end, count, consumer)) = extended else {
+        let (Some(first), Some(last)) = (group.pel.first_key_value(), group.pel.last_key_value()) else {
+            return Ok(Value::Array(vec![Value::Integer(0), Value::NullBulkString, Value::NullBulkString, Value::NullArray]))
+        };
+        let consumers = group.consumers.iter()
+            .filter(|(_, c)| !c.pending.is_empty())
+            .map(|(name, c)| Value::Array(vec![Value::bulk(name.clone()), Value::bulk(c.pending.len().to_string())]))
+            .collect();
+        return Ok(Value::Array(vec![
+            Value::Integer(group.pel.len() as i64),
+            Value::bulk(first.0.to_string()),
+            Value::bulk(last.0.to_string()),
+            Value::Array(consumers),
+        ]))
+    };
+    // BTreeMap::range panics on an interval that ends before it starts.
+    let empty = match (start, end) {
+        (Bound::Excluded(s), Bound::Excluded(e)) => s >= e,
+        (Bound::Included(s) | Bound::Excluded(s), Bound::Included(e) | Bound::Excluded(e)) => s > e,
+        _ => false
     };
+    if empty {
+        return Ok(Value::EmptyArray)
+    }
+    let now = now_ms();
     let mut res = Vec::new();
-    for (id, field) in stream.range((start_ms, start_sq)..=(end_ms, end_sq)){
-        let mut values = Vec::new();
-        for i in field {
-            values.push(Value::BulkString(i.0.to_string()));
-            values.push(Value::BulkString(i.1.to_string()));
+    for (id, entry) in group.pel.range((start, end)) {
+        if res.len() >= count {
+            break;
+        }
+        let idle = now.saturating_sub(entry.delivery_time);
+        if idle < min_idle || consumer.is_some_and(|c| *c != entry.consumer) {
+            continue;
         }
-        res.push(Value::Array(vec![Value::BulkString(id.0.to_string() + "-" + &id.1.to_string()), Value::Array(values)]));
+        res.push(Value::Array(vec![
+            Value::bulk(id.to_string()),
+            Value::bulk(entry.consumer.clone()),
+            Value::Integer(idle as i64),
+            Value::Integer(entry.delivery_count as i64),
+        ]));
     }
     Ok(Value::Array(res))
 }
-pub async fn xread_handle(args: &Vec<String>, db: &db) -> Result<Value, Error> {
-    let len = args.len();
-    let mut keys = Vec::new();
+fn claimed_value(entries: Vec<DeliveredEntry>, just_id: bool) -> Value {
+    if just_id {
+        Value::Array(entries.into_iter().map(|(id, _)| Value::bulk(id.to_string())).collect())
+    } else {
+        Value::Array(delivered_entries_value(entries))
+    }
+}
+pub fn xclaim_handle(args: &[String], state: &mut dbstate) -> Result<Value, Error> {
+    if args.len() < 5 {
+        return Err(anyhow::anyhow!("ERR wrong number of arguments for 'xclaim' command"))
+    }
+    let (key, group, consumer) = (&args[0], &args[1], &args[2]);
+    let min_idle = parse_integer(&args[3]).map_err(|_| anyhow::anyhow!("ERR Invalid min-idle-time argument for XCLAIM"))?.max(0) as u64;
     let mut ids = Vec::new();
-    for i in 1..len {
-        if i <= len/2 {
-            keys.push(args[i].clone());
-        } else {
-            ids.push(args[i].clone());
+    let mut i = 4;
+    while let Some(std::result::Result::Ok(id)) = args.get(i).map(|a| StreamId::parse(a, 0, true)) {
+        ids.push(id);
+        i += 1;
+    }
+    let mut opts = ClaimOptions::default();
+    let mut last_id = None;
+    while i < args.len() {
+        let value = args.get(i + 1);
+        let needs_value = || value.ok_or_else(|| anyhow::anyhow!("ERR syntax error"));
+        match args[i].to_uppercase().as_str() {
+            "FORCE" => opts.force = true,
+            "JUSTID" => opts.just_id = true,
+            "IDLE" => {
+                opts.time = Some(now_ms().saturating_sub(parse_integer(needs_value()?)?.max(0) as u64));
+                i += 1;
+            }
+            "TIME" => {
+                opts.time = Some(parse_integer(needs_value()?)?.max(0) as u64);
+                i += 1;
+            }
+            "RETRYCOUNT" => {
+                opts.retry_count = Some(parse_integer(needs_value()?)?.max(0) as u64);
+                i += 1;
+            }
+            "LASTID" => {
+                last_id = Some(StreamId::parse(needs_value()?, 0, true)?);
+                i += 1;
+            }
+            _ => return Err(anyhow::anyhow!("ERR Unrecognized XCLAIM option '{}'", args[i]))
         }
+        i += 1;
     }
-    let lock = db.state.lock().await;
-    let mut fin = Vec::new();
-    for i in 0..keys.len(){
-        let (start_ms, start_sq) = match ids[i].split_once("-") {
-            Some((s,q)) => (s.parse::<u128>().unwrap(), q.parse::<u128>().unwrap() + 1),
-            None =>  (ids[i].parse::<u128>().unwrap(), 1)
-        };
-        let (end_ms, end_sq) = (u128::MAX, u128::MAX);
-        let stream = match lock.kv.get(&keys[i]) {
-            Some(s) => match s {
-                key_value::Stream(l) => l,
-                _ => panic!("Only streams are supported for this cmd")
+    let existed = has_consumer(state, key, group, consumer);
+    let stream = stream_mut(state, key)?
+        .filter(|s| s.groups.contains_key(group))
+        .ok_or_else(|| no_group(key, group))?;
+    if let Some(last_id) = last_id
+        && let Some(g) = stream.groups.get_mut(group)
+        && last_id > g.last_delivered {
+        g.last_delivered = last_id;
+    }
+    let claimed = stream.claim(group, consumer, min_idle, &ids, opts).unwrap_or_default();
+    // Entries claimed, or dropped for having been deleted from the stream.
+    let changed: Vec<StreamId> = ids.iter()
+        .filter(|id| claimed.iter().any(|(c, _)| c == *id) || stream.groups.get(group).is_some_and(|g| !g.pel.contains_key(id)))
+        .copied()
+        .collect();
+    state.touch(key);
+    state.propagate_group_delivery(key, group, consumer, &changed);
+    state.skip_propagation = true;
+    notify_consumer_created(state, existed, key, group, consumer);
+    Ok(claimed_value(claimed, opts.just_id))
+}
+pub fn xautoclaim_handle(args: &[String], state: &mut dbstate) -> Result<Value, Error> {
+    if args.len() < 5 {
+        return Err(anyhow::anyhow!("ERR wrong number of arguments for 'xautoclaim' command"))
+    }
+    let (key, group, consumer) = (&args[0], &args[1], &args[2]);
+    let min_idle = parse_integer(&args[3]).map_err(|_| anyhow::anyhow!("ERR Invalid min-idle-time argument for XAUTOCLAIM"))?.max(0) as u64;
+    let start = match StreamId::parse_bound(&args[4], true)? {
+        Bound::Included(id) => id,
+        Bound::Excluded(id) => id.incr().ok_or_else(|| anyhow::anyhow!("ERR invalid start ID for the interval"))?,
+        Bound::Unbounded => StreamId::MIN
+    };
+    let mut count = 100;
+    let mut just_id = false;
+    let mut i = 5;
+    while i < args.len() {
+        match args[i].to_uppercase().as_str() {
+            "COUNT" => {
+                let n = parse_integer(args.get(i + 1).ok_or_else(|| anyhow::anyhow!("ERR syntax error"))?)?;
+                if n < 1 {
+                    return Err(anyhow::anyhow!("ERR COUNT must be > 0"))
+                }
+                count = n as usize;
+                i += 2;
             }
-            None => return Ok(Value::BulkError("Key not found".to_string()))
-        };
-        
-        let mut res = Vec::new();
-        res.push(Value::BulkString(keys[i].clone()));
-        let mut nes = Vec::new();
-        for (id, field) in stream.range((start_ms, start_sq)..=(end_ms, end_sq)){
-            let mut values = Vec::new();
-            for i in field {
-                values.push(Value::BulkString(i.0.to_string()));
-                values.push(Value::BulkString(i.1.to_string()));
-            }
-            nes.push(Value::Array(vec![Value::BulkString(id.0.to_string() + "-" + &id.1.to_string()), Value::Array(values)]));
-        }
-        res.push(Value::Array(nes));
-        fin.push(Value::Array(res));
+            "JUSTID" => {
+                just_id = true;
+                i += 1;
+            }
+            _ => return Err(anyhow::anyhow!("ERR syntax error"))
+        }
+    }
+    let existed = has_consumer(state, key, group, consumer);
+    let (next, claimed, deleted) = stream_mut(state, key)?
+        .and_then(|s| s.autoclaim(group, consumer, min_idle, start, count, just_id))
+        .ok_or_else(|| no_group(key, group))?;
+    state.touch(key);
+    let changed: Vec<StreamId> = claimed.iter().map(|(id, _)| *id).chain(deleted.iter().copied()).collect();
+    state.propagate_group_delivery(key, group, consumer, &changed);
+    state.skip_propagation = true;
+    notify_consumer_created(state, existed, key, group, consumer);
+    Ok(Value::Array(vec![
+        Value::bulk(next.to_string()),
+        claimed_value(claimed, just_id),
+        Value::Array(deleted.into_iter().map(|id| Value::bulk(id.to_string())).collect()),
+    ]))
+}
+fn entry_value(entry: Option<StreamEntry>) -> Value {
+    match entry {
+        Some(entry) => stream_entries_value(vec![entry]).remove(0),
+        None => Value::NullBulkString
+    }
+}
+fn info_value(pairs: Vec<(&str, Value)>) -> Value {
+    Value::Array(pairs.into_iter().flat_map(|(k, v)| [Value::bulk(k), v]).collect())
+}
+fn optional_integer(n: Option<u64>) -> Value {
+    match n {
+        Some(n) => Value::Integer(n as i64),
+        None => Value::NullBulkString
+    }
+}
+fn xinfo_stream(stream: &Stream, full: Option<usize>) -> Value {
+    let mut info = vec![
+        ("length", Value::Integer(stream.len() as i64)),
+        ("radix-tree-keys", Value::Integer(stream.block_count() as i64)),
+        ("radix-tree-nodes", Value::Integer(stream.block_count() as i64)),
+        ("last-generated-id", Value::bulk(stream.last_id().to_string())),
+        ("max-deleted-entry-id", Value::bulk(stream.max_deleted_id().to_string())),
+        ("entries-added", Value::Integer(stream.entries_added() as i64)),
+        ("recorded-first-entry-id", Value::bulk(stream.first_id().to_string())),
+    ];
+    // FULL's COUNT caps entries and PEL listings; 0 means everything.
+    let Some(count) = full else {
+        info.push(("groups", Value::Integer(stream.groups.len() as i64)));
+        info.push(("first-entry", entry_value(stream.first_entry())));
+        info.push(("last-entry", entry_value(stream.last_entry())));
+        return info_value(info)
     };
-    Ok(Value::Array(fin))
+    let limit = (count > 0).then_some(count);
+    let entries = stream.range_count(Bound::Unbounded, Bound::Unbounded, limit);
+    info.push(("entries", Value::Array(stream_entries_value(entries))));
+    let mut groups = Vec::new();
+    for (name, group) in &stream.groups {
+        let pel = group.pel.iter().take(limit.unwrap_or(usize::MAX)).map(|(id, e)| Value::Array(vec![
+            Value::bulk(id.to_string()),
+            Value::bulk(e.consumer.clone()),
+            Value::Integer(e.delivery_time as i64),
+            Value::Integer(e.delivery_count as i64),
+        ])).collect();
+        let consumers = group.consumers.iter().map(|(cname, c)| {
+            let pending = c.pending.iter().take(limit.unwrap_or(usize::MAX)).filter_map(|id| group.pel.get(id).map(|e| Value::Array(vec![
+                Value::bulk(id.to_string()),
+                Value::Integer(e.delivery_time as i64),
+                Value::Integer(e.delivery_count as i64),
+            ]))).collect();
+            info_value(vec![
+                ("name", Value::bulk(cname.clone())),
+                ("seen-time", Value::Integer(c.seen_time as i64)),
+                ("active-time", Value::Integer(c.active_time.map_or(-1, |t| t as i64))),
+                ("pel-count", Value::Integer(c.pending.len() as i64)),
+                ("pending", Value::Array(pending)),
+            ])
+        }).collect();
+        groups.push(info_value(vec![
+            ("name", Value::bulk(name.clone())),
+            ("last-delivered-id", Value::bulk(group.last_delivered.to_string())),
+            ("entries-read", optional_integer(group.entries_read)),
+            ("lag", optional_integer(stream.lag(group))),
+            ("pel-count", Value::Integer(group.pel.len() as i64)),
+            ("pending", Value::Array(pel)),
+            ("consumers", Value::Array(consumers)),
+        ]));
+    }
+    info.push(("groups", Value::Array(groups)));
+    info_value(info)
 }
-pub async fn xread_block_handle(args: &Vec<String>, db: &db) -> Result<Value, Error> {
-    let mut timeout = args[1].clone().parse::<f64>().unwrap();
-    let len = args.len();
-    let mut keys = Vec::new();
-    let mut ids = Vec::new();
-    let now = std::time::Instant::now();
-    for i in 3..len{
-        if i <= (len+1)/2 {
-            keys.push(args[i].clone())
-        } else {
-            ids.push(args[i].clone());
+pub fn xinfo_handle(args: &[String], state: &mut dbstate) -> Result<Value, Error> {
+    let subcommand = args[0].to_uppercase();
+    let Some(key) = args.get(1) else {
+        return Err(anyhow::anyhow!("ERR wrong number of arguments for 'xinfo|{}' command", subcommand.to_lowercase()))
+    };
+    let Some(stream) = stream_mut(state, key)? else {
+        return Err(anyhow::anyhow!("ERR no such key"))
+    };
+    let now = now_ms();
+    match subcommand.as_str() {
+        "STREAM" => {
+            let full = match &args[2..] {
+                [] => None,
+                [f] if f.eq_ignore_ascii_case("FULL") => Some(10),
+                [f, c, n] if f.eq_ignore_ascii_case("FULL") && c.eq_ignore_ascii_case("COUNT") => Some(parse_integer(n)?.max(0) as usize),
+                _ => return Err(anyhow::anyhow!("ERR syntax error"))
+            };
+            Ok(xinfo_stream(stream, full))
+        }
+        "GROUPS" => {
+            let groups = stream.groups.iter().map(|(name, group)| info_value(vec![
+                ("name", Value::bulk(name.clone())),
+                ("consumers", Value::Integer(group.consumers.len() as i64)),
+                ("pending", Value::Integer(group.pel.len() as i64)),
+                ("last-delivered-id", Value::bulk(group.last_delivered.to_string())),
+                ("entries-read", optional_integer(group.entries_read)),
+                ("lag", optional_integer(stream.lag(group))),
+            ])).collect();
+            Ok(Value::Array(groups))
+        }
+        "CONSUMERS" => {
+            let Some(group_name) = args.get(2) else {
+                return Err(anyhow::anyhow!("ERR wrong number of arguments for 'xinfo|consumers' command"))
+            };
+            let Some(group) = stream.groups.get(group_name) else {
+                return Err(anyhow::anyhow!("NOGROUP No such consumer group '{}' for key name '{}'", group_name, key))
+            };
+            let consumers = group.consumers.iter().map(|(name, c)| info_value(vec![
+                ("name", Value::bulk(name.clone())),
+                ("pending", Value::Integer(c.pending.len() as i64)),
+                ("idle", Value::Integer(now.saturating_sub(c.seen_time) as i64)),
+                ("inactive", Value::Integer(c.active_time.map_or(-1, |t| now.saturating_sub(t) as i64))),
+            ])).collect();
+            Ok(Value::Array(consumers))
         }
+        _ => Err(anyhow::anyhow!("ERR unknown subcommand '{}'. Try XINFO HELP.", args[0]))
     }
-    println!("{:?}{:?}",keys,ids);
-    if timeout == 0.0 {
-        timeout = f64::INFINITY;
+}
+pub fn publish_handle(args: &[String], state: &mut dbstate) -> Result<Value, Error> {
+    Ok(Value::Integer(state.pubsub.publish(&args[0], &args[1]) as i64))
+}
+pub fn spublish_handle(args: &[String], state: &mut dbstate) -> Result<Value, Error> {
+    Ok(Value::Integer(state.pubsub.spublish(&args[0], &args[1]) as i64))
+}
+pub fn pubsub_handle(args: &[String], state: &mut dbstate) -> Result<Value, Error> {
+    let subcommand = args[0].to_uppercase();
+    match (subcommand.as_str(), &args[1..]) {
+        ("CHANNELS", [] | [_]) => {
+            let channels = state.pubsub.channels(args.get(1).map(|p| p.as_str()));
+            Ok(Value::Array(channels.into_iter().map(Value::bulk).collect()))
+        }
+        ("NUMSUB", channels) => Ok(Value::Array(channels.iter().flat_map(|c| [
+            Value::bulk(c.clone()),
+            Value::Integer(state.pubsub.numsub(c) as i64),
+        ]).collect())),
+        ("SHARDCHANNELS", [] | [_]) => {
+            let channels = state.pubsub.shard_channels(args.get(1).map(|p| p.as_str()));
+            Ok(Value::Array(channels.into_iter().map(Value::bulk).collect()))
+        }
+        ("SHARDNUMSUB", channels) => Ok(Value::Array(channels.iter().flat_map(|c| [
+            Value::bulk(c.clone()),
+            Value::Integer(state.pubsub.shard_numsub(c) as i64),
+        ]).collect())),
+        ("NUMPAT", []) => Ok(Value::Integer(state.pubsub.numpat() as i64)),
+        ("CHANNELS" | "SHARDCHANNELS" | "NUMPAT", _) => Err(anyhow::anyhow!("ERR wrong number of arguments for 'pubsub|{}' command", subcommand.to_lowercase())),
+        _ => Err(anyhow::anyhow!("ERR unknown subcommand '{}'. Try PUBSUB HELP.", args[0]))
     }
-    let mut fin = Vec::new();
-    while now.elapsed().as_secs_f64() < timeout {
-        let lock = db.state.lock().await;
-        for i in 0..keys.len(){
-            let (start_ms, start_sq) = match ids[i].split_once("-") {
-                Some((s,q)) => (s.parse::<u128>().unwrap(), q.parse::<u128>().unwrap() + 1),
-                None =>  if ids[i] == "$"{
-                    (0, 0)
+}
+pub fn config_handle(args: &[String], state: &mut dbstate) -> Result<Value, Error> {
+    let subcommand = args[0].to_uppercase();
+    match subcommand.as_str() {
+        "GET" if args.len() >= 2 => {
+            let mut params = Vec::new();
+            for pattern in &args[1..] {
+                for (name, value) in state.config.matching(pattern) {
+                    if !params.contains(&name) {
+                        params.push(name);
+                        params.push(value);
+                    }
+                }
+            }
+            // Configuration is text, such as paths, unlike keys and values.
+            Ok(Value::Array(params.into_iter().map(|p| Value::BulkString(p.into_bytes())).collect()))
+        }
+        "SET" if args.len() >= 3 && !args.len().is_multiple_of(2) => {
+            for pair in args[1..].chunks(2) {
+                let value = String::from_utf8_lossy(&string_to_bytes(&pair[1])).into_owned();
+                state.config.set_at_runtime(&pair[0].to_lowercase(), &value)?;
+            }
+            let backlog_size = state.config.repl_backlog_size;
+            state.replication.resize_backlog(backlog_size);
+            // appendonly takes effect right away.
+            if state.config.appendonly != state.aof.is_enabled() {
+                if state.config.appendonly {
+                    aof::start(state).map_err(|e| {
+                        state.config.appendonly = false;
+                        anyhow::anyhow!("ERR CONFIG SET failed (possibly related to argument 'appendonly') - {}", e)
+                    })?;
                 } else {
-                    (ids[i].parse::<u128>().unwrap(), 1)
+                    aof::stop(state);
                 }
-            };
-            let (end_ms, end_sq) = (u128::MAX, u128::MAX);
-            let stream = match lock.kv.get(&keys[i]) {
-                Some(s) => match s {
-                    key_value::Stream(l) => l,
-                    _ => panic!("Only streams are supported for this cmd")
+            }
+            Ok(Value::SimpleString("OK".to_string()))
+        }
+        "GET" | "SET" => Err(anyhow::anyhow!("ERR wrong number of arguments for 'config|{}' command", subcommand.to_lowercase())),
+        _ => Err(anyhow::anyhow!("ERR unknown subcommand '{}'. Try CONFIG HELP.", args[0]))
+    }
+}
+fn client_tracking(args: &[String], id: u64, state: &mut dbstate) -> Result<Value, Error> {
+    let on = match args.first().map(|a| a.to_uppercase()).as_deref() {
+        Some("ON") => true,
+        Some("OFF") => false,
+        _ => return Err(anyhow::anyhow!("ERR syntax error"))
+    };
+    let mut options = TrackingClient::default();
+    let mut i = 1;
+    while i < args.len() {
+        match args[i].to_uppercase().as_str() {
+            "REDIRECT" => {
+                let target = args.get(i + 1).ok_or_else(|| anyhow::anyhow!("ERR syntax error"))?;
+                let target = target.parse::<u64>().map_err(|_| anyhow::anyhow!("ERR value is not an integer or out of range"))?;
+                if !state.clients.contains_key(&target) {
+                    return Err(anyhow::anyhow!("ERR The client ID you want redirect to does not exist"))
                 }
-                None => continue
+                options.redirect = Some(target);
+                i += 1;
+            }
+            "PREFIX" => {
+                options.prefixes.push(args.get(i + 1).ok_or_else(|| anyhow::anyhow!("ERR syntax error"))?.clone());
+                i += 1;
+            }
+            "BCAST" => options.bcast = true,
+            "OPTIN" => options.optin = true,
+            "OPTOUT" => options.optout = true,
+            "NOLOOP" => options.noloop = true,
+            _ => return Err(anyhow::anyhow!("ERR syntax error"))
+        }
+        i += 1;
+    }
+    if !on {
+        state.tracking.disable(id);
+        return Ok(Value::SimpleString("OK".to_string()))
+    }
+    if !options.bcast && !options.prefixes.is_empty() {
+        return Err(anyhow::anyhow!("ERR PREFIX option requires BCAST mode to be enabled"))
+    }
+    if options.optin && options.optout {
+        return Err(anyhow::anyhow!("ERR You can't use both OPTIN and OPTOUT"))
+    }
+    if options.bcast && (options.optin || options.optout) {
+        return Err(anyhow::anyhow!("ERR OPTIN and OPTOUT are not compatible with BCAST"))
+    }
+    state.tracking.enable(id, options)?;
+    Ok(Value::SimpleString("OK".to_string()))
+}
+pub fn client_handle(args: &[String], state: &mut dbstate) -> Result<Value, Error> {
+    let id = state.current_client.ok_or_else(|| anyhow::anyhow!("ERR no client"))?;
+    let subcommand = args[0].to_uppercase();
+    match (subcommand.as_str(), &args[1..]) {
+        ("ID", []) => Ok(Value::Integer(id as i64)),
+        ("TRACKING", [_, ..]) => client_tracking(&args[1..], id, state),
+        ("CACHING", [yes_no]) => {
+            let yes = match yes_no.to_uppercase().as_str() {
+                "YES" => true,
+                "NO" => false,
+                _ => return Err(anyhow::anyhow!("ERR syntax error"))
+            };
+            let Some(c) = state.tracking.clients.get_mut(&id).filter(|c| c.optin || c.optout) else {
+                return Err(anyhow::anyhow!("ERR CLIENT CACHING can be called only when the client is in tracking mode with OPTIN or OPTOUT mode enabled"))
             };
-            let mut res = Vec::new();
-            res.push(Value::BulkString(keys[i].clone()));
-            let mut nes = Vec::new();
-            for (id, field) in stream.range((start_ms, start_sq)..=(end_ms, end_sq)){
-                let mut values = Vec::new();
-                for i in field {
-                    values.push(Value::BulkString(i.0.to_string()));
-                    values.push(Value::BulkString(i.1.to_string()));
+            if yes && !c.optin {
+                return Err(anyhow::anyhow!("ERR CLIENT CACHING YES is only valid when tracking is enabled in OPTIN mode."))
+            }
+            if !yes && !c.optout {
+                return Err(anyhow::anyhow!("ERR CLIENT CACHING NO is only valid when tracking is enabled in OPTOUT mode."))
+            }
+            c.caching = Some(yes);
+            Ok(Value::SimpleString("OK".to_string()))
+        }
+        ("GETREDIR", []) => Ok(Value::Integer(match state.tracking.clients.get(&id) {
+            Some(c) => c.redirect.map_or(0, |r| r as i64),
+            None => -1
+        })),
+        ("TRACKINGINFO", []) => {
+            let c = state.tracking.clients.get(&id);
+            let mut flags = Vec::new();
+            match c {
+                None => flags.push("off"),
+                Some(c) => {
+                    flags.push("on");
+                    for (set, name) in [(c.bcast, "bcast"), (c.optin, "optin"), (c.optout, "optout"), (c.noloop, "noloop")] {
+                        if set {
+                            flags.push(name);
+                        }
+                    }
+                    match c.caching {
+                        Some(true) => flags.push("caching-yes"),
+                        Some(false) => flags.push("caching-no"),
+                        None => {}
+                    }
+                    if c.redirect.is_some_and(|r| !state.clients.contains_key(&r)) {
+                        flags.push("broken_redirect");
+                    }
                 }
-                nes.push(Value::Array(vec![Value::BulkString(id.0.to_string() + "-" + &id.1.to_string()), Value::Array(values)]));
             }
-            res.push(Value::Array(nes));
-            fin.push(Value::Array(res));
+            let redirect = c.map_or(-1, |c| c.redirect.map_or(0, |r| r as i64));
+            let prefixes = c.map(|c| c.prefixes.iter().cloned().map(Value::bulk).collect()).unwrap_or_default();
+            Ok(info_value(vec![
+                ("flags", Value::Array(flags.into_iter().map(Value::bulk).collect())),
+                ("redirect", Value::Integer(redirect)),
+                ("prefixes", Value::Array(prefixes)),
+            ]))
+        }
+        ("ID" | "TRACKING" | "CACHING" | "GETREDIR" | "TRACKINGINFO", _) => {
+            Err(anyhow::anyhow!("ERR wrong number of arguments for 'client|{}' command", subcommand.to_lowercase()))
+        }
+        _ => Err(anyhow::anyhow!("ERR unknown subcommand '{}'. Try CLIENT HELP.", args[0]))
+    }
+}
+
+pub fn save_handle(_args: &[String], state: &mut dbstate) -> Result<Value, Error> {
+    rdb::save(state)?;
+    Ok(Value::SimpleString("OK".to_string()))
+}
+
+pub fn bgsave_handle(args: &[String], state: &mut dbstate) -> Result<Value, Error> {
+    let schedule = match args.first() {
+        Some(a) if a.eq_ignore_ascii_case("SCHEDULE") => true,
+        Some(_) => return Err(anyhow::anyhow!("ERR syntax error")),
+        None => false
+    };
+    if schedule && state.persistence.bgsave_in_progress() {
+        state.persistence.bgsave_scheduled = true;
+        return Ok(Value::SimpleString("Background saving scheduled".to_string()))
+    }
+    rdb::bgsave(state)?;
+    Ok(Value::SimpleString("Background saving started".to_string()))
+}
+
+pub fn bgrewriteaof_handle(_args: &[String], state: &mut dbstate) -> Result<Value, Error> {
+    if state.aof.rewrite_in_progress() {
+        return Err(anyhow::anyhow!("ERR Background append only file rewriting already in progress"))
+    }
+    aof::rewrite(state).map_err(|e| {
+        eprintln!("Can't rewrite append only file in background: {}", e);
+        anyhow::anyhow!("ERR Can't execute an AOF background rewriting. Please check the server logs for more information.")
+    })?;
+    Ok(Value::SimpleString("Background append only file rewriting started".to_string()))
+}
+
+/// WAIT numreplicas timeout: blocks until that many replicas acknowledged
+/// the writes made so far, or the timeout (in milliseconds, 0 for none)
+/// passes, and replies with how many did.
+pub fn wait_handle(args: &[String], state: &mut dbstate) -> Result<Reply, Error> {
+    if state.replication.is_replica() {
+        return Err(anyhow::anyhow!("ERR WAIT cannot be used with replica instances. Please also note that since Redis 4.0 if a replica is configured to be writable (which is not the default) writes to replicas are just local and are not propagated."))
+    }
+    let numreplicas = parse_integer(&args[0])?.max(0) as usize;
+    let timeout = parse_integer(&args[1])?;
+    if timeout < 0 {
+        return Err(anyhow::anyhow!("ERR timeout is negative"))
+    }
+    let offset = state.replication.offset;
+    let acked = state.replication.acked(offset);
+    if acked >= numreplicas {
+        return Ok(Reply::Ready(Value::Integer(acked as i64)))
+    }
+    state.replication.request_acks();
+    let (id, rx) = state.block(Vec::new(), BlockedOp::Wait { offset, numreplicas });
+    let timeout = (timeout > 0).then(|| Duration::from_millis(timeout as u64));
+    Ok(Reply::Blocked { id, rx, timeout, on_timeout: Value::Integer(acked as i64) })
+}
+
+/// REPLICAOF host port | NO ONE.
+pub fn replicaof_handle(args: &[String], state: &mut dbstate) -> Result<Value, Error> {
+    if state.cluster.is_some() {
+        return Err(anyhow::anyhow!("ERR REPLICAOF not allowed in cluster mode."))
+    }
+    if args[0].eq_ignore_ascii_case("NO") && args[1].eq_ignore_ascii_case("ONE") {
+        if state.replication.is_replica() {
+            state.replication.become_master();
+            state.config.replicaof = None;
+            println!("MASTER MODE enabled (user request)");
+        }
+        return Ok(Value::SimpleString("OK".to_string()))
+    }
+    let port = args[1].parse::<u16>().map_err(|_| anyhow::anyhow!("ERR Invalid master port"))?;
+    if state.replication.is_master(&args[0], port) {
+        return Ok(Value::SimpleString("OK Already connected to specified master".to_string()))
+    }
+    replication::unblock_all(state);
+    state.replication.set_master(args[0].clone(), port);
+    state.config.replicaof = Some((args[0].clone(), port));
+    println!("REPLICAOF {}:{} enabled (user request)", args[0], port);
+    Ok(Value::SimpleString("OK".to_string()))
+}
+
+pub fn lastsave_handle(_args: &[String], state: &mut dbstate) -> Result<Value, Error> {
+    Ok(Value::Integer(state.persistence.lastsave as i64))
+}
+
+/// The `field:value` lines of one INFO section.
+type InfoSection = fn(&dbstate) -> Vec<(String, String)>;
+
+/// Key counts of the databases that have keys, with the average time
+/// left in milliseconds of those with a TTL.
+fn keyspace_info(state: &dbstate) -> Vec<(String, String)> {
+    let now = now_ms();
+    (0..state.dbs.len()).filter_map(|db| {
+        let (kv, expires) = state.keyspace(db);
+        if kv.is_empty() {
+            return None
+        }
+        let avg_ttl = match expires.len() as u64 {
+            0 => 0,
+            n => expires.values().map(|at| at.saturating_sub(now)).sum::<u64>() / n
         };
-        if fin.len() == 1 {
-            return Ok(Value::Array(fin))
-        } else {
+        Some((format!("db{}", db), format!("keys={},expires={},avg_ttl={}", kv.len(), expires.len(), avg_ttl)))
+    }).collect()
+}
+
+/// INFO [section ...]. No arguments, `default`, `all` and `everything` give
+/// every section.
+pub fn info_handle(args: &[String], state: &mut dbstate) -> Result<Value, Error> {
+    let all = args.is_empty() || args.iter().any(|a| ["default", "all", "everything"].iter().any(|s| a.eq_ignore_ascii_case(s)));
+    let sections: [(&str, InfoSection); 5] = [
+        ("Persistence", |state| [rdb::info(state), aof::info(state)].concat().into_iter().map(|(k, v)| (k.to_string(), v)).collect()),
+        ("Replication", replication::info),
+        ("Cluster", cluster::info),
+        ("Sentinel", sentinel::info),
+        ("Keyspace", keyspace_info),
+    ];
+    let mut out = Vec::new();
+    for (name, fields) in sections {
+        if !all && !args.iter().any(|a| a.eq_ignore_ascii_case(name)) {
             continue;
         }
+        // A sentinel holds no data, so that's all there is to say.
+        if (name == "Sentinel") != state.sentinel.is_some() {
+            continue;
+        }
+        let mut section = format!("# {}\r\n", name);
+        for (field, value) in fields(state) {
+            section.push_str(&format!("{}:{}\r\n", field, value));
+        }
+        out.push(section);
+    }
+    Ok(Value::bulk(out.join("\r\n")))
+}
+
+/// CLUSTER subcommand [args]. Only available with cluster-enabled.
+pub fn cluster_handle(args: &[String], state: &mut dbstate) -> Result<Value, Error> {
+    let subcommand = args[0].to_uppercase();
+    let Some(c) = &state.cluster else {
+        return Err(anyhow::anyhow!("ERR This instance has cluster support disabled"))
+    };
+    match (subcommand.as_str(), &args[1..]) {
+        ("MYID", []) => Ok(Value::bulk(c.myself.clone())),
+        ("KEYSLOT", [key]) => Ok(Value::Integer(cluster::key_hash_slot(&string_to_bytes(key)) as i64)),
+        ("COUNTKEYSINSLOT", [slot]) => {
+            let slot = cluster::parse_slot(slot).map_err(|_| anyhow::anyhow!("ERR Invalid slot"))?;
+            Ok(Value::Integer(c.count_keys_in_slot(slot) as i64))
+        }
+        ("GETKEYSINSLOT", [slot, count]) => {
+            let invalid = || anyhow::anyhow!("ERR Invalid slot or number of keys");
+            let slot = cluster::parse_slot(slot).map_err(|_| invalid())?;
+            let count = count.parse::<usize>().map_err(|_| invalid())?;
+            Ok(Value::Array(c.keys_in_slot(slot, count).into_iter().map(Value::bulk).collect()))
+        }
+        ("SLOTS", []) => Ok(cluster::slots_value(c)),
+        ("SHARDS", []) => Ok(cluster::shards_value(state, c)),
+        ("NODES", []) => Ok(Value::bulk(c.nodes_description())),
+        ("INFO", []) => {
+            let fields = cluster::info_fields(c);
+            Ok(Value::bulk(fields.iter().map(|(k, v)| format!("{}:{}\r\n", k, v)).collect::<String>()))
+        }
+        ("ADDSLOTS", slots) if !slots.is_empty() => {
+            let slots = slots.iter().map(|s| cluster::parse_slot(s)).collect::<Result<Vec<_>, _>>()?;
+            add_slots(state, &slots)
+        }
+        ("ADDSLOTSRANGE", ranges) if !ranges.is_empty() && ranges.len().is_multiple_of(2) => {
+            let mut slots = Vec::new();
+            for range in ranges.chunks(2) {
+                let (start, end) = (cluster::parse_slot(&range[0])?, cluster::parse_slot(&range[1])?);
+                if start > end {
+                    return Err(anyhow::anyhow!("ERR start slot number {} is greater than end slot number {}", start, end))
+                }
+                slots.extend(start..=end);
+            }
+            add_slots(state, &slots)
+        }
+        ("MEET", [ip, port] | [ip, port, _]) => {
+            let invalid = || anyhow::anyhow!("ERR Invalid node address specified: {}:{}", ip, port);
+            ip.parse::<std::net::IpAddr>().map_err(|_| invalid())?;
+            let port = port.parse::<u16>().map_err(|_| invalid())?;
+            let cport = match args.get(3) {
+                Some(cport) => cport.parse::<u16>().map_err(|_| invalid())?,
+                None => port.checked_add(cluster::CLUSTER_PORT_INCR).ok_or_else(invalid)?
+            };
+            state.cluster.as_mut().unwrap().meet(ip, port, cport);
+            Ok(Value::SimpleString("OK".to_string()))
+        }
+        ("REPLICATE", [id]) => {
+            cluster::replicate(state, id)?;
+            Ok(Value::SimpleString("OK".to_string()))
+        }
+        ("FAILOVER", [] | [_]) => {
+            let option = args.get(1).map(|o| o.to_uppercase());
+            if option.as_deref().is_some_and(|o| o != "FORCE" && o != "TAKEOVER") {
+                return Err(anyhow::anyhow!("ERR syntax error"))
+            }
+            cluster::manual_failover(state, option.as_deref())?;
+            Ok(Value::SimpleString("OK".to_string()))
+        }
+        ("SETSLOT", [slot, action, rest @ ..]) => {
+            let slot = cluster::parse_slot(slot)?;
+            let action = action.to_uppercase();
+            let id = match (action.as_str(), rest) {
+                ("STABLE", []) => None,
+                ("MIGRATING" | "IMPORTING" | "NODE", [id]) => Some(id.as_str()),
+                _ => return Err(anyhow::anyhow!("ERR Invalid CLUSTER SETSLOT action or number of arguments. Try CLUSTER HELP"))
+            };
+            cluster::set_slot(state, slot, &action, id)?;
+            Ok(Value::SimpleString("OK".to_string()))
+        }
+        ("MYID" | "KEYSLOT" | "COUNTKEYSINSLOT" | "GETKEYSINSLOT" | "SLOTS" | "SHARDS" | "NODES" | "INFO" | "ADDSLOTS" | "ADDSLOTSRANGE" | "MEET" | "REPLICATE" | "FAILOVER" | "SETSLOT", _) => {
+            Err(anyhow::anyhow!("ERR wrong number of arguments for 'cluster|{}' command", subcommand.to_lowercase()))
+        }
+        _ => Err(anyhow::anyhow!("ERR unknown subcommand '{}'. Try CLUSTER HELP.", args[0]))
+    }
+}
+
+fn add_slots(state: &mut dbstate, slots: &[u16]) -> Result<Value, Error> {
+    let require_full_coverage = state.config.cluster_require_full_coverage;
+    let c = state.cluster.as_mut().unwrap();
+    c.add_slots(slots)?;
+    c.update_state(require_full_coverage);
+    Ok(Value::SimpleString("OK".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::result::Result::Ok;
+
+    use tokio::sync::oneshot;
+
+    use super::*;
+    use crate::{commands::execute, database::db};
+
+    fn args(a: &[&str]) -> Vec<String> {
+        a.iter().map(|s| s.to_string()).collect()
+    }
+
+    fn bulk(s: &str) -> Value {
+        Value::bulk(s)
+    }
+
+    fn bulks(a: &[&str]) -> Value {
+        Value::Array(a.iter().map(|s| bulk(s)).collect())
+    }
+
+    /// Runs a command that must not block, with errors as their reply.
+    fn run(state: &mut dbstate, command: &[&str]) -> Value {
+        match execute(command[0], &args(&command[1..]), state) {
+            Ok(Reply::Ready(v)) => v,
+            Ok(Reply::Blocked { .. }) => panic!("{command:?} blocked"),
+            Err(e) => Value::SimpleError(e.to_string())
+        }
+    }
+
+    /// Runs a command that must block and returns its reply channel along
+    /// with the reply it gives on timeout.
+    fn run_blocked(state: &mut dbstate, command: &[&str]) -> (oneshot::Receiver<Value>, Value) {
+        match execute(command[0], &args(&command[1..]), state) {
+            Ok(Reply::Blocked { rx, on_timeout, .. }) => (rx, on_timeout),
+            _ => panic!("{command:?} didn't block")
+        }
+    }
+
+    /// The `key => [ids]` pairs of an XREAD reply.
+    fn xread_ids(reply: Value) -> Vec<(String, Vec<String>)> {
+        let Value::Array(streams) = reply else { panic!("not an array: {reply:?}") };
+        streams.into_iter().map(|stream| {
+            let Value::Array(pair) = stream else { panic!() };
+            let [Value::BulkString(key), Value::Array(entries)] = &pair[..] else { panic!() };
+            let ids = entries.iter().map(|entry| match entry {
+                Value::Array(e) => match &e[0] { Value::BulkString(id) => bytes_to_string(id), _ => panic!() },
+                _ => panic!()
+            }).collect();
+            (bytes_to_string(key), ids)
+        }).collect()
+    }
+
+    #[tokio::test]
+    async fn blpop_wakes_on_push() {
+        let db = db::new();
+        let mut state = db.state.lock().await;
+        let (mut rx, _) = run_blocked(&mut state, &["BLPOP", "a", "b", "0"]);
+        run(&mut state, &["RPUSH", "b", "x", "y"]);
+        assert_eq!(rx.try_recv().unwrap(), bulks(&["b", "x"]));
+        assert!(state.blocking_keys.is_empty());
+        assert_eq!(run(&mut state, &["LRANGE", "b", "0", "-1"]), bulks(&["y"]));
+    }
+
+    #[tokio::test]
+    async fn blpop_serves_clients_in_order() {
+        let db = db::new();
+        let mut state = db.state.lock().await;
+        let (mut first, _) = run_blocked(&mut state, &["BLPOP", "l", "0"]);
+        let (mut second, _) = run_blocked(&mut state, &["BLPOP", "l", "0"]);
+        run(&mut state, &["RPUSH", "l", "1", "2"]);
+        assert_eq!(first.try_recv().unwrap(), bulks(&["l", "1"]));
+        assert_eq!(second.try_recv().unwrap(), bulks(&["l", "2"]));
+    }
+
+    #[tokio::test]
+    async fn blpop_served_on_push_counts_as_a_pop() {
+        let db = db::new();
+        let mut state = db.state.lock().await;
+        let (tx, mut events) = tokio::sync::mpsc::unbounded_channel();
+        state.pubsub.psubscribe(1, &tx, "__keyevent@0__:*");
+        state.config.set("notify-keyspace-events", "Elg").unwrap();
+        let (mut first, _) = run_blocked(&mut state, &["BLPOP", "l", "0"]);
+        let (mut second, _) = run_blocked(&mut state, &["BLPOP", "l", "0"]);
+        let dirty = state.persistence.dirty;
+        run(&mut state, &["RPUSH", "l", "x", "y"]);
+        assert_eq!(first.try_recv().unwrap(), bulks(&["l", "x"]));
+        assert_eq!(second.try_recv().unwrap(), bulks(&["l", "y"]));
+        // The push, then each pop, the last of which deletes the list.
+        assert_eq!(state.persistence.dirty, dirty + 4);
+        let mut names = Vec::new();
+        while let Ok(Value::Array(message)) = events.try_recv() {
+            names.push(message[2].clone());
+        }
+        assert_eq!(names, [bulk("__keyevent@0__:rpush"), bulk("__keyevent@0__:lpop"), bulk("__keyevent@0__:lpop"), bulk("__keyevent@0__:del")]);
+    }
+
+    #[tokio::test]
+    async fn blpop_times_out() {
+        let db = db::new();
+        let Ok(Reply::Blocked { id, rx, timeout, on_timeout }) = execute("BLPOP", &args(&["l", "0.01"]), &mut *db.state.lock().await) else {
+            panic!("BLPOP didn't block")
+        };
+        assert_eq!(db.wait_blocked(id, rx, timeout).await, None);
+        assert_eq!(on_timeout, Value::NullArray);
+        let lock = db.state.lock().await;
+        assert!(lock.blocked_clients.is_empty() && lock.blocking_keys.is_empty());
+    }
+
+    #[tokio::test]
+    async fn blpop_pops_right_away() {
+        let db = db::new();
+        let mut state = db.state.lock().await;
+        run(&mut state, &["RPUSH", "l", "x"]);
+        assert_eq!(run(&mut state, &["BLPOP", "missing", "l", "0"]), bulks(&["l", "x"]));
+    }
+
+    #[tokio::test]
+    async fn xread_returns_only_streams_with_data() {
+        let db = db::new();
+        let mut state = db.state.lock().await;
+        run(&mut state, &["XADD", "a", "1-1", "f", "v"]);
+        run(&mut state, &["XADD", "b", "1-1", "f", "v"]);
+        let reply = run(&mut state, &["XREAD", "STREAMS", "a", "missing", "b", "0", "0", "1-1"]);
+        assert_eq!(xread_ids(reply), [("a".to_string(), vec!["1-1".to_string()])]);
+        assert_eq!(run(&mut state, &["XREAD", "STREAMS", "a", "$"]), Value::NullArray);
+    }
+
+    #[tokio::test]
+    async fn xread_block_dollar_waits_for_new_entries() {
+        let db = db::new();
+        let mut state = db.state.lock().await;
+        run(&mut state, &["XADD", "s", "1-1", "f", "v"]);
+        let (mut rx, on_timeout) = run_blocked(&mut state, &["XREAD", "BLOCK", "0", "STREAMS", "s", "$"]);
+        run(&mut state, &["XADD", "s", "2-1", "f", "v"]);
+        assert_eq!(xread_ids(rx.try_recv().unwrap()), [("s".to_string(), vec!["2-1".to_string()])]);
+        assert_eq!(on_timeout, Value::NullArray);
+    }
+
+    #[tokio::test]
+    async fn set_conditions_and_get() {
+        let db = db::new();
+        let mut state = db.state.lock().await;
+        let ok = Value::SimpleString("OK".to_string());
+        assert_eq!(run(&mut state, &["SET", "k", "1", "XX"]), Value::NullBulkString);
+        assert_eq!(run(&mut state, &["SET", "k", "1", "NX"]), ok);
+        assert_eq!(run(&mut state, &["SET", "k", "2", "NX", "GET"]), bulk("1"));
+        assert_eq!(run(&mut state, &["SET", "k", "3", "XX", "GET"]), bulk("1"));
+        assert_eq!(run(&mut state, &["GET", "k"]), bulk("3"));
+        assert!(matches!(run(&mut state, &["SET", "k", "v", "NX", "XX"]), Value::SimpleError(_)));
+        run(&mut state, &["RPUSH", "l", "x"]);
+        assert_eq!(run(&mut state, &["SET", "l", "v", "GET"]), Value::SimpleError(WRONGTYPE.to_string()));
+    }
+
+    #[tokio::test]
+    async fn set_expiry() {
+        let db = db::new();
+        let mut state = db.state.lock().await;
+        run(&mut state, &["SET", "k", "v", "PX", "100000"]);
+        let deadline = state.expires["k"];
+        run(&mut state, &["SET", "k", "w", "KEEPTTL"]);
+        assert_eq!(state.expires["k"], deadline);
+        run(&mut state, &["SET", "k", "x"]);
+        assert!(!state.expires.contains_key("k"));
+        run(&mut state, &["SET", "k", "v", "PXAT", "1"]);
+        assert_eq!(run(&mut state, &["GET", "k"]), Value::NullBulkString);
+        let invalid = Value::SimpleError("ERR invalid expire time in 'set' command".to_string());
+        assert_eq!(run(&mut state, &["SET", "k", "v", "EX", "0"]), invalid);
+        assert!(matches!(run(&mut state, &["SET", "k", "v", "EX", "1", "KEEPTTL"]), Value::SimpleError(_)));
+        for option in ["EX", "PX", "EXAT"] {
+            assert_eq!(run(&mut state, &["SET", "k", "v", option, &i64::MAX.to_string()]), invalid);
+        }
+    }
+
+    #[tokio::test]
+    async fn keyspace_notifications() {
+        let db = db::new();
+        let mut state = db.state.lock().await;
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        state.pubsub.psubscribe(1, &tx, "__key*__:*");
+        run(&mut state, &["SET", "k", "v"]);
+        assert!(rx.try_recv().is_err());
+        state.config.set("notify-keyspace-events", "KEl").unwrap();
+        run(&mut state, &["SET", "k", "v"]);
+        run(&mut state, &["RPUSH", "l", "a"]);
+        let mut events = Vec::new();
+        while let Ok(Value::Array(message)) = rx.try_recv() {
+            events.push(message[2..].to_vec());
+        }
+        assert_eq!(events, [
+            vec![bulk("__keyspace@0__:l"), bulk("rpush")],
+            vec![bulk("__keyevent@0__:rpush"), bulk("l")],
+        ]);
+    }
+
+    #[tokio::test]
+    async fn dump_and_restore() {
+        let db = db::new();
+        let mut state = db.state.lock().await;
+        run(&mut state, &["RPUSH", "l", "a", "b"]);
+        let Value::BulkString(payload) = run(&mut state, &["DUMP", "l"]) else { panic!("no payload") };
+        let payload = bytes_to_string(&payload);
+        let max = i64::MAX.to_string();
+        assert_eq!(run(&mut state, &["DUMP", "missing"]), Value::NullBulkString);
+
+        assert_eq!(run(&mut state, &["RESTORE", "copy", "0", &payload]), Value::SimpleString("OK".to_string()));
+        assert_eq!(run(&mut state, &["LRANGE", "copy", "0", "-1"]), bulks(&["a", "b"]));
+        assert_eq!(run(&mut state, &["RESTORE", "copy", "0", &payload]), Value::SimpleError("BUSYKEY Target key name already exists.".to_string()));
+        run(&mut state, &["RESTORE", "copy", "100000", &payload, "REPLACE", "IDLETIME", "1000"]);
+        assert!(state.expires["copy"] > now_ms() + 99000);
+        assert_eq!(run(&mut state, &["OBJECT", "IDLETIME", "copy"]), Value::Integer(1000));
+
+        // An absolute TTL in the past restores nothing.
+        run(&mut state, &["RESTORE", "copy", "1", &payload, "REPLACE", "ABSTTL"]);
+        assert_eq!(run(&mut state, &["TYPE", "copy"]), Value::SimpleString("none".to_string()));
+
+        for (args, err) in [
+            (vec!["-1", &payload], "ERR Invalid TTL value, must be >= 0"),
+            (vec!["0", &payload, "IDLETIME", "-1"], "ERR Invalid IDLETIME value, must be >= 0"),
+            (vec!["0", &payload, "IDLETIME", &max], "ERR Invalid IDLETIME value, out of range"),
+            (vec![&max, &payload], "ERR invalid expire time in 'restore' command"),
+            (vec!["0", &payload, "FREQ", "256"], "ERR Invalid FREQ value, must be >= 0 and <= 255"),
+            (vec!["0", &payload, "IDLETIME", "1", "FREQ", "1"], "ERR syntax error"),
+            (vec!["0", "00"], "ERR DUMP payload version or checksum are wrong"),
+        ] {
+            let mut command = vec!["RESTORE", "other"];
+            command.extend(args);
+            assert_eq!(run(&mut state, &command), Value::SimpleError(err.to_string()));
+        }
+    }
+
+    #[tokio::test]
+    async fn object_encoding_and_refcount() {
+        let db = db::new();
+        let mut state = db.state.lock().await;
+        let long = "x".repeat(45);
+        let huge = "x".repeat(8192);
+        run(&mut state, &["SET", "int", "12"]);
+        run(&mut state, &["SET", "short", "hello"]);
+        run(&mut state, &["SET", "long", &long]);
+        run(&mut state, &["RPUSH", "small", "a", "b"]);
+        run(&mut state, &["RPUSH", "big", &huge]);
+        for (key, encoding) in [("int", "int"), ("short", "embstr"), ("long", "raw"), ("small", "listpack"), ("big", "quicklist")] {
+            assert_eq!(run(&mut state, &["OBJECT", "ENCODING", key]), bulk(encoding), "{key}");
+        }
+        assert_eq!(run(&mut state, &["OBJECT", "REFCOUNT", "int"]), Value::Integer(i32::MAX as i64));
+        assert_eq!(run(&mut state, &["OBJECT", "REFCOUNT", "short"]), Value::Integer(1));
+        assert_eq!(run(&mut state, &["OBJECT", "ENCODING", "missing"]), Value::NullBulkString);
+        assert!(matches!(run(&mut state, &["OBJECT", "FREQ", "int"]), Value::Integer(_)));
+    }
+
+    #[tokio::test]
+    async fn encodings_count_bytes_not_utf8() {
+        let db = db::new();
+        let mut state = db.state.lock().await;
+        let all: Vec<u8> = (0..=255).collect();
+        assert_eq!(string_to_bytes(&bytes_to_string(&all)), all);
+        let short = bytes_to_string(&[0xe9; 44]);
+        let long = bytes_to_string(&[0xe9; 45]);
+        run(&mut state, &["SET", "short", &short]);
+        run(&mut state, &["SET", "long", &long]);
+        assert_eq!(run(&mut state, &["OBJECT", "ENCODING", "short"]), bulk("embstr"));
+        assert_eq!(run(&mut state, &["OBJECT", "ENCODING", "long"]), bulk("raw"));
+        assert_eq!(run(&mut state, &["GET", "short"]), Value::BulkString(vec![0xe9; 44]));
+    }
+
+    #[tokio::test]
+    async fn select_move_and_swapdb() {
+        let db = db::new();
+        let mut state = db.state.lock().await;
+        // FLUSHALL would save to the working directory otherwise.
+        state.config.save.clear();
+        let ok = Value::SimpleString("OK".to_string());
+        run(&mut state, &["SET", "k", "v", "PX", "100000"]);
+        assert_eq!(run(&mut state, &["SELECT", "16"]), Value::SimpleError("ERR DB index is out of range".to_string()));
+        assert_eq!(run(&mut state, &["MOVE", "k", "0"]), Value::SimpleError("ERR source and destination objects are the same".to_string()));
+
+        assert_eq!(run(&mut state, &["MOVE", "k", "1"]), Value::Integer(1));
+        assert_eq!(run(&mut state, &["GET", "k"]), Value::NullBulkString);
+        assert_eq!(run(&mut state, &["SELECT", "1"]), ok);
+        assert_eq!(run(&mut state, &["GET", "k"]), bulk("v"));
+        assert!(state.expires.contains_key("k"));
+        run(&mut state, &["SELECT", "0"]);
+        run(&mut state, &["SET", "k", "other"]);
+        assert_eq!(run(&mut state, &["MOVE", "k", "1"]), Value::Integer(0));
+        assert_eq!(run(&mut state, &["MOVE", "missing", "1"]), Value::Integer(0));
+
+        assert_eq!(run(&mut state, &["SWAPDB", "0", "1"]), ok);
+        assert_eq!(run(&mut state, &["GET", "k"]), bulk("v"));
+        assert_eq!(run(&mut state, &["SWAPDB", "0", "x"]), Value::SimpleError("ERR invalid second DB index".to_string()));
+
+        assert_eq!(run(&mut state, &["FLUSHDB"]), ok);
+        assert_eq!(run(&mut state, &["DBSIZE"]), Value::Integer(0));
+        run(&mut state, &["SELECT", "1"]);
+        assert_eq!(run(&mut state, &["DBSIZE"]), Value::Integer(1));
+        assert_eq!(run(&mut state, &["FLUSHALL", "ASYNC"]), ok);
+        assert_eq!(run(&mut state, &["DBSIZE"]), Value::Integer(0));
+        assert_eq!(run(&mut state, &["FLUSHDB", "LATER"]), Value::SimpleError("ERR syntax error".to_string()));
+    }
+
+    #[tokio::test]
+    async fn emptied_lists_are_deleted() {
+        let db = db::new();
+        let mut state = db.state.lock().await;
+        run(&mut state, &["RPUSH", "l", "a", "b"]);
+        assert_eq!(run(&mut state, &["LPOP", "l", "2"]), bulks(&["a", "b"]));
+        assert_eq!(run(&mut state, &["TYPE", "l"]), Value::SimpleString("none".to_string()));
+        run(&mut state, &["RPUSH", "l", "a"]);
+        assert_eq!(run(&mut state, &["BLPOP", "l", "0"]), bulks(&["l", "a"]));
+        assert!(!state.kv.contains_key("l"));
+    }
+
+    #[tokio::test]
+    async fn list_commands_reject_bad_input() {
+        let db = db::new();
+        let mut state = db.state.lock().await;
+        let wrongtype = Value::SimpleError(WRONGTYPE.to_string());
+        run(&mut state, &["SET", "s", "v"]);
+        for command in [&["LPUSH", "s", "a"][..], &["RPUSH", "s", "a"], &["LRANGE", "s", "0", "-1"], &["LLEN", "s"], &["LPOP", "s"]] {
+            assert_eq!(run(&mut state, command), wrongtype, "{command:?}");
+        }
+        run(&mut state, &["RPUSH", "l", "b", "c"]);
+        assert_eq!(run(&mut state, &["LPUSH", "l", "a"]), Value::Integer(3));
+        assert_eq!(run(&mut state, &["LRANGE", "l", "x", "1"]), Value::SimpleError("ERR value is not an integer or out of range".to_string()));
+        assert_eq!(run(&mut state, &["LRANGE", "l", "1", "10"]), bulks(&["b", "c"]));
+        assert_eq!(run(&mut state, &["LRANGE", "l", "0", "-100"]), Value::EmptyArray);
+        assert_eq!(run(&mut state, &["LRANGE", "l", "-100", "0"]), bulks(&["a"]));
+        assert_eq!(run(&mut state, &["LPOP", "l", "-1"]), Value::SimpleError("ERR value is out of range, must be positive".to_string()));
+        assert_eq!(run(&mut state, &["LPOP", "l", "10"]), bulks(&["a", "b", "c"]));
+    }
+
+    #[tokio::test]
+    async fn xpending_empty_intervals() {
+        let db = db::new();
+        let mut state = db.state.lock().await;
+        run(&mut state, &["XADD", "s", "1-0", "f", "v"]);
+        run(&mut state, &["XGROUP", "CREATE", "s", "g", "0"]);
+        run(&mut state, &["XREADGROUP", "GROUP", "g", "c", "STREAMS", "s", ">"]);
+        for (start, end) in [("+", "-"), ("(1-0", "(1-0"), ("2", "1")] {
+            assert_eq!(run(&mut state, &["XPENDING", "s", "g", start, end, "10"]), Value::EmptyArray, "{start} {end}");
+        }
+        let Value::Array(pending) = run(&mut state, &["XPENDING", "s", "g", "1-0", "1-0", "10"]) else { panic!() };
+        assert_eq!(pending.len(), 1);
     }
-    Ok(Value::Array(fin))
 }
@@ -1,20 +1,151 @@
-#![allow(unused_imports)]
+#![allow(unused_imports, non_camel_case_types)]
 use core::panic;
-use std::{any, collections::btree_map::Values, env::args_os};
 use anyhow::{Error, Ok};
-use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::{TcpListener, TcpStream}};
-use crate::{database::db, handlers::{blpop_handle, extract_command, get_handle, llen_handle, lpop_handle, lpush_handle, lrange_handle, rpush_handle, set_handle, type_handle, unpack_bulk_str, xadd_handle, xrange_handle, xread_block_handle, xread_handle}, resp::Value};
+use std::{collections::HashSet, sync::atomic::{AtomicU64, Ordering}};
+use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::{TcpListener, TcpStream}, sync::mpsc};
+use crate::{commands::{execute, lookup, ASKING, WRITE}, database::{db, ClientHandle, Reply}, handlers::{extract_command, unpack_bulk_str}, pubsub::Subscriber, resp::{bytes_to_string, RespHandler, Value}};
 pub mod resp;
 pub mod database;
 pub mod handlers;
+pub mod stream;
+pub mod commands;
+pub mod pubsub;
+pub mod cluster;
+pub mod sentinel;
+pub mod config;
+pub mod tracking;
+pub mod rdb;
+pub mod aof;
+pub mod replication;
 
 
 #[tokio::main]
 async fn main() {
-    let listener = TcpListener::bind("127.0.0.1:6379").await.unwrap();
     let redisdb = db::new();
+    let listener = {
+        let mut state = redisdb.state.lock().await;
+        // `--name value...`: an option takes the arguments up to the next
+        // one, joined by spaces, as in `--replicaof host port`.
+        // `--sentinel` alone turns on sentinel mode; with values it is a
+        // sentinel directive, such as `--sentinel monitor name ip port quorum`.
+        let args: Vec<String> = std::env::args().skip(1).collect();
+        let mut sentinel_mode = false;
+        let mut sentinel_directives = Vec::new();
+        let mut port_given = false;
+        let mut i = 0;
+        while i < args.len() {
+            let Some(name) = args[i].strip_prefix("--") else {
+                eprintln!("Invalid option '{}'", args[i]);
+                std::process::exit(1)
+            };
+            let values: Vec<&str> = args[i + 1..].iter().take_while(|a| !a.starts_with("--")).map(String::as_str).collect();
+            if name.eq_ignore_ascii_case("sentinel") {
+                sentinel_mode = true;
+                i += 1 + values.len();
+                if !values.is_empty() {
+                    sentinel_directives.push(values.iter().map(|v| bytes_to_string(v.as_bytes())).collect::<Vec<_>>());
+                }
+                continue;
+            }
+            port_given |= name.eq_ignore_ascii_case("port");
+            if values.is_empty() {
+                eprintln!("Missing value for option '{}'", name);
+                std::process::exit(1)
+            }
+            i += 1 + values.len();
+            if let Err(e) = state.config.set(&name.to_lowercase(), &values.join(" ")) {
+                eprintln!("{}", e);
+                std::process::exit(1)
+            }
+        }
+        let databases = state.config.databases;
+        state.dbs.resize_with(databases, Default::default);
+        if sentinel_mode {
+            if state.config.cluster_enabled {
+                eprintln!("Sentinel mode can't be used with cluster-enabled");
+                std::process::exit(1)
+            }
+            if !port_given {
+                state.config.port = sentinel::SENTINEL_PORT;
+            }
+            let mut sentinel = sentinel::Sentinel::new(state.config.port);
+            for directive in &sentinel_directives {
+                if let Err(e) = sentinel.directive(&directive.iter().map(String::as_str).collect::<Vec<_>>()) {
+                    eprintln!("Error in sentinel directive '{}': {}", directive.join(" "), e);
+                    std::process::exit(1)
+                }
+            }
+            println!("Sentinel ID is {}", sentinel.myid);
+            state.sentinel = Some(sentinel);
+        }
+        let listener = match TcpListener::bind(("127.0.0.1", state.config.port)).await {
+            std::result::Result::Ok(listener) => listener,
+            Err(e) => {
+                eprintln!("Could not create server TCP listening socket 127.0.0.1:{}: {}", state.config.port, e);
+                std::process::exit(1)
+            }
+        };
+        if state.config.cluster_enabled {
+            if state.config.replicaof.is_some() {
+                eprintln!("replicaof directive not allowed in cluster mode");
+                std::process::exit(1)
+            }
+            let path = std::path::Path::new(&state.config.dir).join(&state.config.cluster_config_file);
+            match cluster::Cluster::load(path, state.config.port) {
+                std::result::Result::Ok(mut c) => {
+                    c.update_state(state.config.cluster_require_full_coverage);
+                    state.cluster = Some(c)
+                }
+                Err(e) => {
+                    eprintln!("{}", e);
+                    std::process::exit(1)
+                }
+            }
+        }
+        // With appendonly on, the AOF is the source of truth and the RDB file
+        // is ignored. A sentinel has no data to load.
+        if state.sentinel.is_some() {
+            state.config.appendonly = false;
+            state.config.replicaof = None;
+        } else if state.config.appendonly && let Err(e) = aof::load(&mut state) {
+            eprintln!("Error loading the append only file from {}: {}", aof::aof_dir(&state).display(), e);
+            std::process::exit(1)
+        }
+        let path = rdb::rdb_path(&state);
+        if state.sentinel.is_none() && !state.config.appendonly && path.exists() {
+            match rdb::load(&path, &mut state) {
+                core::result::Result::Ok(keys) => println!("DB loaded from disk: {} keys", keys),
+                Err(e) => {
+                    eprintln!("Error loading {}: {}", path.display(), e);
+                    std::process::exit(1)
+                }
+            }
+        }
+        if let Some((host, port)) = state.config.replicaof.clone() {
+            state.replication.set_master(host, port);
+        }
+        if let Some(c) = &state.cluster
+            && let Some(master) = c.myself().master.as_ref().and_then(|m| c.nodes.get(m)) {
+            let (host, port) = (master.ip.clone(), master.port);
+            state.replication.set_master(host, port);
+        }
+        if let Some(c) = &state.cluster {
+            let cport = c.myself().cport;
+            match TcpListener::bind(("127.0.0.1", cport)).await {
+                std::result::Result::Ok(bus) => {
+                    tokio::spawn(cluster::serve_bus(bus, redisdb.clone()));
+                }
+                Err(e) => {
+                    eprintln!("Could not create the cluster bus socket 127.0.0.1:{}: {}", cport, e);
+                    std::process::exit(1)
+                }
+            }
+        }
+        listener
+    };
+    redisdb.start_cron();
     loop {
-        let (mut socket, _) = listener.accept().await.unwrap();
+        let (socket, _) = listener.accept().await.unwrap();
         let redisdb = redisdb.clone();
         tokio::spawn(async move {
             handle_connection(socket, redisdb).await
@@ -22,71 +153,787 @@ async fn main() {
     }
 }
 
-async fn handle_connection(mut socket: TcpStream, redisdb: db) {
-    let mut handler = resp::RespHandler::new(socket);
+/// Handler errors carry the Redis error text and go back to the client as an
+/// error reply.
+fn error_reply(e: Error) -> Value {
+    Value::SimpleError(e.to_string())
+}
 
-    loop {
-        let value = handler.read_value().await.unwrap();
-        println!("{:?}",value);
-        let response = if let Some(v) = value {
-            let (command, vec_args) = extract_command(v).unwrap();
-            let args = unpack_bulk_str(&vec_args).unwrap();
-            println!("{:?} {:?}", command, args);
-            match command.as_str() {
-                "ping" => Value::SimpleString("PONG".to_string()),
-                "echo" => Value::BulkString(args[0].clone()),
-                "SET" => {
-                    set_handle(&args, &redisdb).await.unwrap();
-                    Value::SimpleString("OK".to_string())
-                }
-                "GET" => {
-                    if let Some(value) = get_handle(&args, &redisdb).await {
-                        Value::SimpleString(value)
-                    } else {
-                        Value::NullBulkString
-                    }
-                }
-                "RPUSH" => {
-                    rpush_handle(&args, &redisdb).await.unwrap()
-                }
-                "LRANGE" => {
-                    lrange_handle(&args, &redisdb).await.unwrap()
-                }
-                "LPUSH" => {
-                    lpush_handle(&args, &redisdb).await.unwrap()
-                }
-                "LLEN" => {
-                    llen_handle(&args, &redisdb).await.unwrap()
-                }
-                "LPOP" => {
-                    lpop_handle(&args, &redisdb).await.unwrap()
-                }
-                "BLPOP" => {
-                    blpop_handle(&args, &redisdb).await.unwrap()
-                }
-                "TYPE" => {
-                    type_handle(&args, &redisdb).await.unwrap()
-                }
-                "XADD" => {
-                    xadd_handle(&args, &redisdb).await.unwrap()
-                }
-                "XRANGE" => {
-                    xrange_handle(&args, &redisdb).await.unwrap()
+static NEXT_CLIENT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Commands a connection may still send while it has subscriptions.
+const SUBSCRIBED_MODE_COMMANDS: &[&str] = &["SUBSCRIBE", "UNSUBSCRIBE", "PSUBSCRIBE", "PUNSUBSCRIBE", "SSUBSCRIBE", "SUNSUBSCRIBE", "PING", "QUIT", "RESET"];
+
+/// All a sentinel serves.
+const SENTINEL_MODE_COMMANDS: &[&str] = &["PING", "SENTINEL", "SUBSCRIBE", "UNSUBSCRIBE", "PSUBSCRIBE", "PUNSUBSCRIBE", "PUBLISH", "INFO", "CLIENT", "HELLO", "QUIT", "RESET"];
+
+/// Per-connection state.
+struct Client {
+    id: u64,
+    /// Handed to the pub/sub registry; messages come out of the connection's
+    /// receiver and are written between replies.
+    tx: Subscriber,
+    /// Commands queued since MULTI; `None` outside a transaction.
+    multi: Option<Vec<(String, Vec<String>)>>,
+    /// A command failed to queue, so EXEC has to abort.
+    multi_error: bool,
+    /// Database the connection's commands run in, switched by SELECT.
+    db: usize,
+    /// WATCHed keys by database, with the version they had when watched.
+    watched: Vec<(usize, String, u64)>,
+    channels: HashSet<String>,
+    patterns: HashSet<String>,
+    shard_channels: HashSet<String>,
+    /// Switched by HELLO; RESP3 clients get pushes and no subscribed mode.
+    resp3: bool,
+    /// Set by a replica with REPLCONF listening-port.
+    listening_port: Option<u16>,
+    /// Sent ASKING: the next command (or transaction) may use a slot this
+    /// node is importing.
+    asking: bool,
+}
+
+impl Client {
+    fn new(tx: Subscriber) -> Self {
+        Client {
+            id: NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed),
+            tx,
+            multi: None,
+            multi_error: false,
+            db: 0,
+            watched: Vec::new(),
+            channels: HashSet::new(),
+            patterns: HashSet::new(),
+            shard_channels: HashSet::new(),
+            resp3: false,
+            listening_port: None,
+            asking: false,
+        }
+    }
+
+    /// The count (P)SUBSCRIBE replies report; shard channels are counted
+    /// separately, as in Redis.
+    fn subscriptions(&self) -> usize {
+        self.channels.len() + self.patterns.len()
+    }
+
+    fn is_subscribed(&self) -> bool {
+        self.subscriptions() + self.shard_channels.len() > 0
+    }
+
+    async fn unwatch_all(&mut self, redisdb: &db) {
+        if self.watched.is_empty() {
+            return
+        }
+        let mut lock = redisdb.state.lock().await;
+        for (db, key, _) in self.watched.drain(..) {
+            lock.unwatch(db, &key);
+        }
+    }
+
+    /// Out-of-band messages are pushes in RESP3 and plain arrays in RESP2.
+    fn push(&self, message: Value) -> Value {
+        match message {
+            Value::Array(items) if self.resp3 => Value::Push(items),
+            message => message
+        }
+    }
+
+    async fn set_protocol(&mut self, resp3: bool, redisdb: &db) {
+        self.resp3 = resp3;
+        if let Some(handle) = redisdb.state.lock().await.clients.get_mut(&self.id) {
+            handle.resp3 = resp3;
+        }
+    }
+
+    async fn unsubscribe_all(&mut self, redisdb: &db) {
+        if !self.is_subscribed() {
+            return
+        }
+        let mut lock = redisdb.state.lock().await;
+        for channel in self.channels.drain() {
+            lock.pubsub.unsubscribe(self.id, &channel);
+        }
+        for pattern in self.patterns.drain() {
+            lock.pubsub.punsubscribe(self.id, &pattern);
+        }
+        for channel in self.shard_channels.drain() {
+            lock.pubsub.sunsubscribe(self.id, &channel);
+        }
+    }
+}
+
+/// Runs one command for client `id` in its database `db`, which the
+/// command may switch, waiting outside the lock if it blocks. A client that
+/// disconnects while blocked is unblocked, so nothing is served to it.
+async fn run_command(command: &str, args: &[String], id: u64, db: &mut usize, handler: &mut RespHandler, redisdb: &db) -> Value {
+    let reply = {
+        let mut lock = redisdb.state.lock().await;
+        lock.current_client = Some(id);
+        lock.select(*db);
+        let reply = execute(command, args, &mut lock);
+        *db = lock.db;
+        lock.current_client = None;
+        lock.flush_propagated(false);
+        reply
+    };
+    match reply {
+        std::result::Result::Ok(Reply::Ready(v)) => v,
+        std::result::Result::Ok(Reply::Blocked { id, rx, timeout, on_timeout }) => tokio::select! {
+            v = redisdb.wait_blocked(id, rx, timeout) => v.unwrap_or(on_timeout),
+            _ = handler.closed() => {
+                redisdb.state.lock().await.unblock(id);
+                on_timeout
+            }
+        },
+        Err(e) => error_reply(e)
+    }
+}
+
+/// EXEC: runs the queued commands under a single lock, or replies with a null
+/// array if a watched key changed. Blocking commands behave as if their
+/// timeout had already expired, like in Redis.
+async fn exec_transaction(queue: Vec<(String, Vec<String>)>, watched: Vec<(usize, String, u64)>, id: u64, db: &mut usize, redisdb: &db) -> Value {
+    let mut lock = redisdb.state.lock().await;
+    let mut dirty = false;
+    for (watched_db, key, version) in &watched {
+        dirty |= lock.is_dirty(*watched_db, key, *version);
+        lock.unwatch(*watched_db, key);
+    }
+    if dirty {
+        return Value::NullArray
+    }
+    lock.current_client = Some(id);
+    lock.select(*db);
+    let mut results = Vec::new();
+    for (command, args) in queue {
+        let v = match execute(&command, &args, &mut lock) {
+            std::result::Result::Ok(Reply::Ready(v)) => v,
+            std::result::Result::Ok(Reply::Blocked { id, on_timeout, .. }) => {
+                lock.unblock(id);
+                on_timeout
+            }
+            Err(e) => error_reply(e)
+        };
+        results.push(v);
+    }
+    *db = lock.db;
+    lock.current_client = None;
+    lock.flush_propagated(true);
+    Value::Array(results)
+}
+
+/// HELLO [protover [AUTH username password] [SETNAME name]]: switches the
+/// protocol and describes the server. There are no users, so AUTH always
+/// succeeds.
+async fn hello(client: &mut Client, args: &[String], redisdb: &db) -> Result<Value, Error> {
+    let mut resp3 = client.resp3;
+    if let Some(version) = args.first() {
+        match version.parse::<i64>() {
+            std::result::Result::Ok(2) => resp3 = false,
+            std::result::Result::Ok(3) => resp3 = true,
+            std::result::Result::Ok(_) => return Err(anyhow::anyhow!("NOPROTO unsupported protocol version")),
+            Err(_) => return Err(anyhow::anyhow!("ERR Protocol version is not an integer or out of range"))
+        }
+    }
+    let mut i = 1;
+    while i < args.len() {
+        match args[i].to_uppercase().as_str() {
+            "AUTH" if i + 2 < args.len() => i += 3,
+            "SETNAME" if i + 1 < args.len() => i += 2,
+            _ => return Err(anyhow::anyhow!("ERR Syntax error in HELLO option '{}'", args[i]))
+        }
+    }
+    client.set_protocol(resp3, redisdb).await;
+    let (role, mode) = {
+        let lock = redisdb.state.lock().await;
+        (if lock.replication.is_replica() { "replica" } else { "master" }, if lock.cluster.is_some() { "cluster" } else { "standalone" })
+    };
+    let fields = vec![
+        ("server", Value::bulk("redis")),
+        ("version", Value::bulk("7.2.0")),
+        ("proto", Value::Integer(if resp3 { 3 } else { 2 })),
+        ("id", Value::Integer(client.id as i64)),
+        ("mode", Value::bulk(mode)),
+        ("role", Value::bulk(role)),
+        ("modules", Value::EmptyArray),
+    ];
+    let fields = fields.into_iter().map(|(k, v)| (Value::bulk(k), v));
+    Ok(if resp3 {
+        Value::Map(fields.collect())
+    } else {
+        Value::Array(fields.flat_map(|(k, v)| [k, v]).collect())
+    })
+}
+
+/// (P|S)SUBSCRIBE and (P|S)UNSUBSCRIBE reply once per channel, each time with
+/// the connection's subscription count after that step. Unsubscribing with no
+/// arguments drops every channel (or pattern).
+async fn subscription_command(client: &mut Client, command: &str, args: Vec<String>, redisdb: &db) -> Vec<Value> {
+    let mut lock = redisdb.state.lock().await;
+    let kind = command.to_lowercase();
+    let names = match command {
+        "UNSUBSCRIBE" if args.is_empty() => client.channels.iter().cloned().collect(),
+        "PUNSUBSCRIBE" if args.is_empty() => client.patterns.iter().cloned().collect(),
+        "SUNSUBSCRIBE" if args.is_empty() => client.shard_channels.iter().cloned().collect(),
+        _ => args
+    };
+    let count = |client: &Client| if matches!(command, "SSUBSCRIBE" | "SUNSUBSCRIBE") {
+        client.shard_channels.len() as i64
+    } else {
+        client.subscriptions() as i64
+    };
+    if names.is_empty() {
+        return vec![Value::Array(vec![Value::bulk(kind), Value::NullBulkString, Value::Integer(count(client))])]
+    }
+    let mut replies = Vec::new();
+    for name in names {
+        match command {
+            "SUBSCRIBE" => if client.channels.insert(name.clone()) {
+                lock.pubsub.subscribe(client.id, &client.tx, &name);
+            },
+            "UNSUBSCRIBE" => if client.channels.remove(&name) {
+                lock.pubsub.unsubscribe(client.id, &name);
+            },
+            "PSUBSCRIBE" => if client.patterns.insert(name.clone()) {
+                lock.pubsub.psubscribe(client.id, &client.tx, &name);
+            },
+            "PUNSUBSCRIBE" => if client.patterns.remove(&name) {
+                lock.pubsub.punsubscribe(client.id, &name);
+            },
+            "SSUBSCRIBE" => if client.shard_channels.insert(name.clone()) {
+                lock.pubsub.ssubscribe(client.id, &client.tx, &name);
+            },
+            _ => if client.shard_channels.remove(&name) {
+                lock.pubsub.sunsubscribe(client.id, &name);
+            }
+        }
+        replies.push(Value::Array(vec![Value::bulk(kind.clone()), Value::bulk(name), Value::Integer(count(client))]));
+    }
+    replies
+}
+
+/// REPLCONF option value ...: what a replica tells its master during the
+/// handshake. Acknowledgements get no reply.
+fn replconf(client: &mut Client, args: &[String]) -> Vec<Value> {
+    if !args.len().is_multiple_of(2) {
+        return vec![Value::SimpleError("ERR syntax error".to_string())]
+    }
+    for pair in args.chunks(2) {
+        match pair[0].to_lowercase().as_str() {
+            "listening-port" => match pair[1].parse::<u16>() {
+                std::result::Result::Ok(port) => client.listening_port = Some(port),
+                Err(_) => return vec![Value::SimpleError("ERR value is not an integer or out of range".to_string())]
+            },
+            "capa" => {}
+            "ack" | "getack" => return Vec::new(),
+            _ => return vec![Value::SimpleError(format!("ERR Unrecognized REPLCONF option: {}", pair[0]))]
+        }
+    }
+    vec![Value::SimpleString("OK".to_string())]
+}
+
+/// Handles connection-level commands (transactions, WATCH, subscriptions)
+/// and hands the rest to `execute`, queuing them while in MULTI.
+async fn client_command(client: &mut Client, command: &str, args: Vec<String>, handler: &mut RespHandler, redisdb: &db) -> Vec<Value> {
+    let cmd = match lookup(command, &args) {
+        std::result::Result::Ok(cmd) => cmd,
+        Err(e) => {
+            if client.multi.is_some() {
+                client.multi_error = true;
+            }
+            return vec![error_reply(e)]
+        }
+    };
+    if client.is_subscribed() && !client.resp3 && !SUBSCRIBED_MODE_COMMANDS.contains(&command) {
+        return vec![Value::SimpleError(format!("ERR Can't execute '{}': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context", command.to_lowercase()))]
+    }
+    // ASKING lasts for one command, or until the end of a transaction
+    // started after it.
+    let asking = client.asking || cmd.flags & ASKING != 0;
+    client.asking = command == "ASKING"
+        || (client.asking && (command == "MULTI" || (client.multi.is_some() && command != "EXEC" && command != "DISCARD")));
+    // A master handing over to a replica in a manual failover holds writes
+    // until the replica took over; they are redirected to it after.
+    if cmd.flags & WRITE != 0 {
+        loop {
+            let lock = redisdb.state.lock().await;
+            let Some((resumed, end)) = lock.cluster.as_ref().and_then(|c| c.writes_pause()) else { break };
+            // Registered before the lock is released, so the end of the
+            // pause can't be missed.
+            let notified = resumed.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            drop(lock);
+            let timeout = std::time::Duration::from_millis(end.saturating_sub(stream::now_ms()));
+            tokio::select! {
+                _ = notified => {}
+                _ = tokio::time::sleep(timeout) => {}
+            }
+        }
+    }
+    let refused = {
+        let lock = redisdb.state.lock().await;
+        if lock.sentinel.is_some() && !SENTINEL_MODE_COMMANDS.contains(&command) {
+            let args: String = args.iter().take(20).map(|a| format!("'{}' ", a)).collect();
+            Some(anyhow::anyhow!("ERR unknown command '{}', with args beginning with: {}", command.to_lowercase(), args))
+        } else if lock.cluster.is_some() {
+            // In cluster mode replicas serve no slots, so writes to them are
+            // redirected rather than refused.
+            // EXEC is checked against the keys of the whole transaction.
+            let keys: Vec<&String> = match (command, &client.multi) {
+                ("EXEC", Some(queue)) => queue.iter()
+                    .filter_map(|(c, a)| lookup(c, a).ok().map(|cmd| cmd.keys(a)))
+                    .flatten()
+                    .collect(),
+                _ => cmd.keys(&args)
+            };
+            cluster::check_keys(&lock, command, &keys, asking).err()
+        } else if cmd.flags & WRITE != 0 && lock.replication.is_replica() && lock.config.replica_read_only {
+            Some(anyhow::anyhow!("READONLY You can't write against a read only replica."))
+        } else {
+            None
+        }
+    };
+    if let Some(e) = refused {
+        if command == "EXEC" {
+            client.multi = None;
+            client.multi_error = false;
+            client.unwatch_all(redisdb).await;
+        } else if client.multi.is_some() {
+            client.multi_error = true;
+        }
+        return vec![error_reply(e)]
+    }
+    let reply = match command {
+        "QUIT" => Value::SimpleString("OK".to_string()),
+        "RESET" => {
+            client.multi = None;
+            client.asking = false;
+            client.multi_error = false;
+            client.db = 0;
+            client.unwatch_all(redisdb).await;
+            client.unsubscribe_all(redisdb).await;
+            client.set_protocol(false, redisdb).await;
+            redisdb.state.lock().await.tracking.disable(client.id);
+            Value::SimpleString("RESET".to_string())
+        }
+        "REPLCONF" => return replconf(client, &args),
+        "PSYNC" => {
+            // Outside MULTI, `handle_connection` hands the connection over.
+            client.multi_error = true;
+            Value::SimpleError("ERR Command not allowed inside a transaction".to_string())
+        }
+        "ASKING" => {
+            if redisdb.state.lock().await.cluster.is_some() {
+                Value::SimpleString("OK".to_string())
+            } else {
+                client.asking = false;
+                Value::SimpleError("ERR This instance has cluster support disabled".to_string())
+            }
+        }
+        "MIGRATE" if client.multi.is_some() => {
+            client.multi_error = true;
+            Value::SimpleError("ERR Command not allowed inside a transaction".to_string())
+        }
+        "MIGRATE" => match cluster::migrate(&args, client.db, redisdb).await {
+            std::result::Result::Ok(v) => v,
+            Err(e) => error_reply(e)
+        },
+        "HELLO" => match hello(client, &args, redisdb).await {
+            std::result::Result::Ok(v) => v,
+            Err(e) => error_reply(e)
+        },
+        "SUBSCRIBE" | "UNSUBSCRIBE" | "PSUBSCRIBE" | "PUNSUBSCRIBE" | "SSUBSCRIBE" | "SUNSUBSCRIBE" if client.multi.is_some() => {
+            client.multi_error = true;
+            Value::SimpleError("ERR Command not allowed inside a transaction".to_string())
+        }
+        "SUBSCRIBE" | "UNSUBSCRIBE" | "PSUBSCRIBE" | "PUNSUBSCRIBE" | "SSUBSCRIBE" | "SUNSUBSCRIBE" => {
+            let replies = subscription_command(client, command, args, redisdb).await;
+            return replies.into_iter().map(|r| client.push(r)).collect()
+        }
+        "PING" if client.is_subscribed() && !client.resp3 => Value::Array(vec![
+            Value::bulk("pong"),
+            Value::bulk(args.first().cloned().unwrap_or_default()),
+        ]),
+        "MULTI" => {
+            if client.multi.is_some() {
+                Value::SimpleError("ERR MULTI calls can not be nested".to_string())
+            } else {
+                client.multi = Some(Vec::new());
+                Value::SimpleString("OK".to_string())
+            }
+        }
+        "EXEC" => match client.multi.take() {
+            None => Value::SimpleError("ERR EXEC without MULTI".to_string()),
+            Some(_) if std::mem::take(&mut client.multi_error) => {
+                client.unwatch_all(redisdb).await;
+                Value::SimpleError("EXECABORT Transaction discarded because of previous errors.".to_string())
+            }
+            Some(queue) => exec_transaction(queue, std::mem::take(&mut client.watched), client.id, &mut client.db, redisdb).await
+        },
+        "DISCARD" => {
+            if client.multi.take().is_some() {
+                client.multi_error = false;
+                client.unwatch_all(redisdb).await;
+                Value::SimpleString("OK".to_string())
+            } else {
+                Value::SimpleError("ERR DISCARD without MULTI".to_string())
+            }
+        }
+        "WATCH" if client.multi.is_some() => {
+            Value::SimpleError("ERR WATCH inside MULTI is not allowed".to_string())
+        }
+        "WATCH" => {
+            let mut lock = redisdb.state.lock().await;
+            lock.select(client.db);
+            for key in args {
+                if !client.watched.iter().any(|(db, k, _)| *db == client.db && *k == key) {
+                    let version = lock.watch(&key);
+                    client.watched.push((client.db, key, version));
                 }
-                "XREAD" => {
-                    if args[0] == "STREAMS" {
-                        xread_handle(&args, &redisdb).await.unwrap()
-                    } else {
-                        xread_block_handle(&args, &redisdb).await.unwrap()
-                    }
+            }
+            Value::SimpleString("OK".to_string())
+        }
+        "UNWATCH" if client.multi.is_none() => {
+            client.unwatch_all(redisdb).await;
+            Value::SimpleString("OK".to_string())
+        }
+        _ => match client.multi.as_mut() {
+            Some(queue) => {
+                queue.push((command.to_string(), args));
+                Value::SimpleString("QUEUED".to_string())
+            }
+            None => run_command(command, &args, client.id, &mut client.db, handler, redisdb).await
+        }
+    };
+    vec![reply]
+}
+
+async fn handle_connection(socket: TcpStream, redisdb: db) {
+    let mut handler = RespHandler::new(socket);
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let mut client = Client::new(tx.clone());
+    redisdb.state.lock().await.clients.insert(client.id, ClientHandle { tx, resp3: false });
+
+    loop {
+        let value = tokio::select! {
+            // A broken connection ends the loop like a close, so the
+            // client's watches and subscriptions are still released.
+            value = handler.read_value() => value,
+            Some(message) = rx.recv() => {
+                if handler.write_value(client.push(message)).await.is_err() {
+                    break;
                 }
-                c => panic!("Cannot handle command {:?}",c)
+                continue;
+            }
+        };
+        // Anything but a broken connection is the client's fault: it is told
+        // so and, as the stream can't be trusted past that point, dropped.
+        let request = match value {
+            std::result::Result::Ok(Some(v)) => extract_command(v).and_then(|(command, args)| Ok((command, unpack_bulk_str(&args)?))),
+            std::result::Result::Ok(None) => break,
+            Err(e) if e.is::<std::io::Error>() => break,
+            Err(e) => Err(e)
+        };
+        let (command, args) = match request {
+            std::result::Result::Ok(request) => request,
+            Err(e) => {
+                let _ = handler.write_value(Value::SimpleError(format!("ERR Protocol error: {}", e))).await;
+                break;
             }
+        };
+        let command = command.to_uppercase();
+        if command == "PSYNC" && client.multi.is_none() && lookup(&command, &args).is_ok() {
+            // From here on the connection carries the replication stream.
+            if let Err(e) = replication::serve_replica(&mut handler, client.id, client.listening_port.unwrap_or(0), &args, &redisdb).await {
+                let _ = handler.write_value(error_reply(e)).await;
+            }
+            break;
+        }
+        let mut closed = false;
+        for response in client_command(&mut client, &command, args, &mut handler, &redisdb).await {
+            closed |= handler.write_value(response).await.is_err();
         }
-        else {
+        if closed || command == "QUIT" {
             break;
-        };
-        handler.write_value(response).await.unwrap();
+        }
     }
+    client.unwatch_all(&redisdb).await;
+    client.unsubscribe_all(&redisdb).await;
+    let mut lock = redisdb.state.lock().await;
+    lock.tracking.disable(client.id);
+    lock.clients.remove(&client.id);
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Serves a fresh connection on an ephemeral port.
+    async fn connect(redisdb: &db) -> TcpStream {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let redisdb = redisdb.clone();
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            handle_connection(socket, redisdb).await
+        });
+        TcpStream::connect(addr).await.unwrap()
+    }
+
+    /// Serves every connection to an ephemeral port, like `main`.
+    async fn serve(redisdb: &db) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let redisdb = redisdb.clone();
+        tokio::spawn(async move {
+            loop {
+                let (socket, _) = listener.accept().await.unwrap();
+                tokio::spawn(handle_connection(socket, redisdb.clone()));
+            }
+        });
+        port
+    }
+
+    /// Polls until `done` holds for the state.
+    async fn wait_for(redisdb: &db, done: impl Fn(&database::dbstate) -> bool) {
+        for _ in 0..500 {
+            if done(&*redisdb.state.lock().await) {
+                return
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        std::panic!("timed out");
+    }
+
+    /// Sends a command and checks the raw reply.
+    async fn expect(stream: &mut TcpStream, command: &[&str], reply: &str) {
+        let args = command.iter().map(Value::bulk).collect();
+        stream.write_all(&Value::Array(args).serialize()).await.unwrap();
+        expect_push(stream, reply).await;
+    }
+
+    /// Checks the next thing the server writes.
+    async fn expect_push(stream: &mut TcpStream, reply: &str) {
+        let mut buf = vec![0; reply.len()];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(String::from_utf8(buf).unwrap(), reply);
+    }
+
+    #[tokio::test]
+    async fn exec_runs_queued_commands() {
+        let redisdb = db::new();
+        let mut c = connect(&redisdb).await;
+        expect(&mut c, &["MULTI"], "+OK\r\n").await;
+        expect(&mut c, &["SET", "k", "v"], "+QUEUED\r\n").await;
+        expect(&mut c, &["XADD", "s", "0-0", "f", "v"], "+QUEUED\r\n").await;
+        expect(&mut c, &["BLPOP", "l", "0"], "+QUEUED\r\n").await;
+        expect(&mut c, &["GET", "k"], "+QUEUED\r\n").await;
+        expect(&mut c, &["EXEC"], "*4\r\n+OK\r\n-ERR The ID specified in XADD must be greater than 0-0\r\n*-1\r\n$1\r\nv\r\n").await;
+        expect(&mut c, &["EXEC"], "-ERR EXEC without MULTI\r\n").await;
+    }
+
+    #[tokio::test]
+    async fn queueing_errors_abort_exec() {
+        let redisdb = db::new();
+        let mut c = connect(&redisdb).await;
+        expect(&mut c, &["MULTI"], "+OK\r\n").await;
+        expect(&mut c, &["MULTI"], "-ERR MULTI calls can not be nested\r\n").await;
+        expect(&mut c, &["SET", "k"], "-ERR wrong number of arguments for 'set' command\r\n").await;
+        expect(&mut c, &["SET", "k", "v"], "+QUEUED\r\n").await;
+        expect(&mut c, &["EXEC"], "-EXECABORT Transaction discarded because of previous errors.\r\n").await;
+        expect(&mut c, &["GET", "k"], "$-1\r\n").await;
+    }
+
+    #[tokio::test]
+    async fn discard_drops_the_queue() {
+        let redisdb = db::new();
+        let mut c = connect(&redisdb).await;
+        expect(&mut c, &["DISCARD"], "-ERR DISCARD without MULTI\r\n").await;
+        expect(&mut c, &["MULTI"], "+OK\r\n").await;
+        expect(&mut c, &["SET", "k", "v"], "+QUEUED\r\n").await;
+        expect(&mut c, &["DISCARD"], "+OK\r\n").await;
+        expect(&mut c, &["GET", "k"], "$-1\r\n").await;
+    }
+
+    #[tokio::test]
+    async fn watch_aborts_exec_after_a_write() {
+        let redisdb = db::new();
+        let mut c = connect(&redisdb).await;
+        let mut other = connect(&redisdb).await;
+        expect(&mut c, &["WATCH", "k", "l"], "+OK\r\n").await;
+        expect(&mut other, &["RPUSH", "l", "x"], ":1\r\n").await;
+        expect(&mut c, &["MULTI"], "+OK\r\n").await;
+        expect(&mut c, &["WATCH", "k"], "-ERR WATCH inside MULTI is not allowed\r\n").await;
+        expect(&mut c, &["SET", "k", "v"], "+QUEUED\r\n").await;
+        expect(&mut c, &["EXEC"], "*-1\r\n").await;
+        expect(&mut c, &["GET", "k"], "$-1\r\n").await;
+        // EXEC released the watches.
+        expect(&mut c, &["MULTI"], "+OK\r\n").await;
+        expect(&mut c, &["SET", "k", "v"], "+QUEUED\r\n").await;
+        expect(&mut c, &["EXEC"], "*1\r\n+OK\r\n").await;
+        assert!(redisdb.state.lock().await.watched.is_empty());
+    }
+
+    #[tokio::test]
+    async fn unwatch_and_expiry() {
+        let redisdb = db::new();
+        let mut c = connect(&redisdb).await;
+        expect(&mut c, &["WATCH", "k"], "+OK\r\n").await;
+        expect(&mut c, &["SET", "k", "v"], "+OK\r\n").await;
+        expect(&mut c, &["UNWATCH"], "+OK\r\n").await;
+        expect(&mut c, &["MULTI"], "+OK\r\n").await;
+        expect(&mut c, &["GET", "k"], "+QUEUED\r\n").await;
+        expect(&mut c, &["EXEC"], "*1\r\n$1\r\nv\r\n").await;
+        expect(&mut c, &["SET", "t", "v", "PX", "50"], "+OK\r\n").await;
+        expect(&mut c, &["WATCH", "t"], "+OK\r\n").await;
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        expect(&mut c, &["MULTI"], "+OK\r\n").await;
+        expect(&mut c, &["GET", "t"], "+QUEUED\r\n").await;
+        expect(&mut c, &["EXEC"], "*-1\r\n").await;
+    }
+
+    #[tokio::test]
+    async fn subscribed_connections_get_messages() {
+        let redisdb = db::new();
+        let mut sub = connect(&redisdb).await;
+        let mut publisher = connect(&redisdb).await;
+        expect(&mut sub, &["SUBSCRIBE", "a", "b"], "*3\r\n$9\r\nsubscribe\r\n$1\r\na\r\n:1\r\n*3\r\n$9\r\nsubscribe\r\n$1\r\nb\r\n:2\r\n").await;
+        expect(&mut sub, &["PSUBSCRIBE", "c*"], "*3\r\n$10\r\npsubscribe\r\n$2\r\nc*\r\n:3\r\n").await;
+        expect(&mut sub, &["GET", "k"], "-ERR Can't execute 'get': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context\r\n").await;
+        expect(&mut sub, &["PING"], "*2\r\n$4\r\npong\r\n$0\r\n\r\n").await;
+        expect(&mut publisher, &["PUBLISH", "b", "hi"], ":1\r\n").await;
+        expect_push(&mut sub, "*3\r\n$7\r\nmessage\r\n$1\r\nb\r\n$2\r\nhi\r\n").await;
+        expect(&mut sub, &["UNSUBSCRIBE", "a", "b"], "*3\r\n$11\r\nunsubscribe\r\n$1\r\na\r\n:2\r\n*3\r\n$11\r\nunsubscribe\r\n$1\r\nb\r\n:1\r\n").await;
+        expect(&mut publisher, &["PUBLISH", "cat", "x"], ":1\r\n").await;
+        expect(&mut publisher, &["PUBLISH", "b", "x"], ":0\r\n").await;
+    }
+
+    #[tokio::test]
+    async fn tracking_redirects_invalidations() {
+        let redisdb = db::new();
+        let mut sink = connect(&redisdb).await;
+        let mut reader = connect(&redisdb).await;
+        let mut writer = connect(&redisdb).await;
+        let id = Value::Array(vec![Value::bulk("CLIENT"), Value::bulk("ID")]);
+        sink.write_all(&id.serialize()).await.unwrap();
+        let mut buf = vec![0; 32];
+        let n = sink.read(&mut buf).await.unwrap();
+        let id = std::str::from_utf8(&buf[1..n - 2]).unwrap().to_string();
+        expect(&mut sink, &["SUBSCRIBE", "__redis__:invalidate"], "*3\r\n$9\r\nsubscribe\r\n$20\r\n__redis__:invalidate\r\n:1\r\n").await;
+        expect(&mut reader, &["CLIENT", "TRACKING", "ON", "REDIRECT", &id], "+OK\r\n").await;
+        expect(&mut reader, &["CLIENT", "GETREDIR"], &format!(":{id}\r\n")).await;
+        expect(&mut reader, &["GET", "k"], "$-1\r\n").await;
+        expect(&mut writer, &["SET", "k", "v"], "+OK\r\n").await;
+        expect_push(&mut sink, "*3\r\n$7\r\nmessage\r\n$20\r\n__redis__:invalidate\r\n*1\r\n$1\r\nk\r\n").await;
+        // The key has to be read again before it is invalidated again.
+        expect(&mut writer, &["SET", "k", "w"], "+OK\r\n").await;
+        expect(&mut writer, &["PUBLISH", "__redis__:invalidate", "x"], ":1\r\n").await;
+        expect_push(&mut sink, "*3\r\n$7\r\nmessage\r\n$20\r\n__redis__:invalidate\r\n$1\r\nx\r\n").await;
+    }
+
+    #[tokio::test]
+    async fn replica_syncs_and_follows_the_stream() {
+        let master = db::new();
+        let master_port = serve(&master).await;
+        let mut m = TcpStream::connect(("127.0.0.1", master_port)).await.unwrap();
+        expect(&mut m, &["SET", "before", "1"], "+OK\r\n").await;
+
+        let replica = db::new();
+        replica.start_cron();
+        let replica_port = serve(&replica).await;
+        let mut r = TcpStream::connect(("127.0.0.1", replica_port)).await.unwrap();
+        expect(&mut r, &["REPLICAOF", "127.0.0.1", &master_port.to_string()], "+OK\r\n").await;
+        wait_for(&replica, |s| s.replication.link_up()).await;
+        expect(&mut r, &["GET", "before"], "$1\r\n1\r\n").await;
+
+        expect(&mut m, &["MULTI"], "+OK\r\n").await;
+        expect(&mut m, &["SET", "after", "2"], "+QUEUED\r\n").await;
+        expect(&mut m, &["RPUSH", "l", "x"], "+QUEUED\r\n").await;
+        expect(&mut m, &["EXEC"], "*2\r\n+OK\r\n:1\r\n").await;
+        wait_for(&replica, |s| s.kv.contains_key("l")).await;
+        expect(&mut r, &["GET", "after"], "$1\r\n2\r\n").await;
+        expect(&mut r, &["SET", "k", "v"], "-READONLY You can't write against a read only replica.\r\n").await;
+        let (master_offset, master_replid) = {
+            let lock = master.state.lock().await;
+            (lock.replication.offset, lock.replication.replid.clone())
+        };
+        wait_for(&replica, |s| s.replication.offset == master_offset).await;
+        assert_eq!(replica.state.lock().await.replication.replid, master_replid);
+
+        expect(&mut r, &["REPLICAOF", "NO", "ONE"], "+OK\r\n").await;
+        expect(&mut r, &["SET", "k", "v"], "+OK\r\n").await;
+    }
+
+    #[tokio::test]
+    async fn wait_and_partial_resync() {
+        let master = db::new();
+        let master_port = serve(&master).await;
+        let mut m = TcpStream::connect(("127.0.0.1", master_port)).await.unwrap();
+        expect(&mut m, &["WAIT", "1", "10"], ":0\r\n").await;
+
+        let replica = db::new();
+        replica.start_cron();
+        replica.state.lock().await.replication.set_master("127.0.0.1".to_string(), master_port);
+        wait_for(&replica, |s| s.replication.link_up()).await;
+        expect(&mut m, &["SET", "k", "v"], "+OK\r\n").await;
+        expect(&mut m, &["WAIT", "1", "0"], ":1\r\n").await;
+
+        // A replica that already has the whole stream just continues.
+        let (replid, offset) = {
+            let lock = master.state.lock().await;
+            (lock.replication.replid.clone(), lock.replication.offset)
+        };
+        let mut again = TcpStream::connect(("127.0.0.1", master_port)).await.unwrap();
+        expect(&mut again, &["PSYNC", &replid, &(offset + 1).to_string()], &format!("+CONTINUE {replid}\r\n")).await;
+        let mut other = TcpStream::connect(("127.0.0.1", master_port)).await.unwrap();
+        expect(&mut other, &["PSYNC", &"0".repeat(40), "1"], &format!("+FULLRESYNC {replid} {offset}\r\n")).await;
+    }
+
+    #[tokio::test]
+    async fn each_connection_has_its_own_database() {
+        let db = db::new();
+        let mut first = connect(&db).await;
+        let mut second = connect(&db).await;
+        expect(&mut first, &["SELECT", "3"], "+OK\r\n").await;
+        expect(&mut first, &["SET", "k", "3"], "+OK\r\n").await;
+        expect(&mut second, &["GET", "k"], "$-1\r\n").await;
+        expect(&mut second, &["SET", "k", "0"], "+OK\r\n").await;
+        expect(&mut first, &["GET", "k"], "$1\r\n3\r\n").await;
+        expect(&mut second, &["SWAPDB", "0", "3"], "+OK\r\n").await;
+        expect(&mut first, &["GET", "k"], "$1\r\n0\r\n").await;
+    }
+
+    #[tokio::test]
+    async fn bulk_strings_are_binary_safe() {
+        let db = db::new();
+        let mut c = connect(&db).await;
+        c.write_all(b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$4\r\n\xff\x00\r\n\r\n").await.unwrap();
+        expect_push(&mut c, "+OK\r\n").await;
+        c.write_all(b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n").await.unwrap();
+        let mut buf = [0; 10];
+        c.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"$4\r\n\xff\x00\r\n\r\n");
+    }
+
+    #[tokio::test]
+    async fn malformed_requests_get_a_protocol_error() {
+        let db = db::new();
+        for (request, reply) in [
+            (&b"!oops\r\n"[..], "-ERR Protocol error: unknown type byte '!'\r\n"),
+            (b"*1\r\n!\r\n", "-ERR Protocol error: unknown type byte '!'\r\n"),
+            (b"*x\r\n", "-ERR Protocol error: invalid digit found in string\r\n"),
+            (b"+PING\r\n", "-ERR Protocol error: Unexpected command format\r\n"),
+            (b"*1\r\n:1\r\n", "-ERR Protocol error: Unexpected command for a bulkstring\r\n"),
+            (b"*0\r\n", "-ERR Protocol error: Empty command\r\n"),
+        ] {
+            let mut c = connect(&db).await;
+            c.write_all(request).await.unwrap();
+            expect_push(&mut c, reply).await;
+            assert_eq!(c.read(&mut [0; 1]).await.unwrap(), 0, "{reply}");
+        }
+        let mut c = connect(&db).await;
+        expect(&mut c, &["PING"], "+PONG\r\n").await;
+    }
+}
@@ -0,0 +1,287 @@
+use std::collections::HashMap;
+
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::{cluster::key_hash_slot, resp::{string_to_bytes, Value}};
+
+/// Where a connection receives the messages pushed to it.
+pub type Subscriber = UnboundedSender<Value>;
+
+/// Subscribers per channel and per glob pattern, keyed by client id.
+#[derive(Default)]
+pub struct PubSub {
+    channels: HashMap<String, HashMap<u64, Subscriber>>,
+    patterns: HashMap<String, HashMap<u64, Subscriber>>,
+    /// Shard channels, grouped by the hash slot of their name.
+    shard_channels: HashMap<u16, HashMap<String, HashMap<u64, Subscriber>>>,
+}
+
+fn add(map: &mut HashMap<String, HashMap<u64, Subscriber>>, name: &str, id: u64, tx: &Subscriber) {
+    map.entry(name.to_string()).or_default().insert(id, tx.clone());
+}
+
+fn del(map: &mut HashMap<String, HashMap<u64, Subscriber>>, name: &str, id: u64) {
+    if let Some(subs) = map.get_mut(name) {
+        subs.remove(&id);
+        if subs.is_empty() {
+            map.remove(name);
+        }
+    }
+}
+
+impl PubSub {
+    pub fn subscribe(&mut self, id: u64, tx: &Subscriber, channel: &str) {
+        add(&mut self.channels, channel, id, tx);
+    }
+
+    pub fn unsubscribe(&mut self, id: u64, channel: &str) {
+        del(&mut self.channels, channel, id);
+    }
+
+    pub fn psubscribe(&mut self, id: u64, tx: &Subscriber, pattern: &str) {
+        add(&mut self.patterns, pattern, id, tx);
+    }
+
+    pub fn punsubscribe(&mut self, id: u64, pattern: &str) {
+        del(&mut self.patterns, pattern, id);
+    }
+
+    pub fn ssubscribe(&mut self, id: u64, tx: &Subscriber, channel: &str) {
+        add(self.shard_channels.entry(key_hash_slot(&string_to_bytes(channel))).or_default(), channel, id, tx);
+    }
+
+    pub fn sunsubscribe(&mut self, id: u64, channel: &str) {
+        let slot = key_hash_slot(&string_to_bytes(channel));
+        if let Some(channels) = self.shard_channels.get_mut(&slot) {
+            del(channels, channel, id);
+            if channels.is_empty() {
+                self.shard_channels.remove(&slot);
+            }
+        }
+    }
+
+    fn shard_subscribers(&self, channel: &str) -> Option<&HashMap<u64, Subscriber>> {
+        self.shard_channels.get(&key_hash_slot(&string_to_bytes(channel)))?.get(channel)
+    }
+
+    /// Pushes `message` to a shard channel's subscribers; patterns never
+    /// match shard channels.
+    pub fn spublish(&self, channel: &str, message: &str) -> usize {
+        let Some(subs) = self.shard_subscribers(channel) else { return 0 };
+        for tx in subs.values() {
+            let _ = tx.send(Value::Array(vec![
+                Value::bulk("smessage"),
+                Value::bulk(channel),
+                Value::bulk(message),
+            ]));
+        }
+        subs.len()
+    }
+
+    /// Pushes `message` to the channel's subscribers and to every pattern
+    /// matching it. Returns how many deliveries were made.
+    pub fn publish(&self, channel: &str, message: &str) -> usize {
+        let mut receivers = 0;
+        if let Some(subs) = self.channels.get(channel) {
+            for tx in subs.values() {
+                let _ = tx.send(Value::Array(vec![
+                    Value::bulk("message"),
+                    Value::bulk(channel),
+                    Value::bulk(message),
+                ]));
+                receivers += 1;
+            }
+        }
+        for (pattern, subs) in &self.patterns {
+            if !glob_match(&string_to_bytes(pattern), &string_to_bytes(channel)) {
+                continue;
+            }
+            for tx in subs.values() {
+                let _ = tx.send(Value::Array(vec![
+                    Value::bulk("pmessage"),
+                    Value::bulk(pattern.clone()),
+                    Value::bulk(channel),
+                    Value::bulk(message),
+                ]));
+                receivers += 1;
+            }
+        }
+        receivers
+    }
+
+    /// Sends a message on `channel` to client `id` alone, if it subscribes to
+    /// it.
+    pub fn send_to(&self, id: u64, channel: &str, payload: Value) {
+        if let Some(tx) = self.channels.get(channel).and_then(|subs| subs.get(&id)) {
+            let _ = tx.send(Value::Array(vec![
+                Value::bulk("message"),
+                Value::bulk(channel),
+                payload,
+            ]));
+        }
+    }
+
+    /// Channels with at least one subscriber, optionally filtered by a glob.
+    pub fn channels(&self, pattern: Option<&str>) -> Vec<String> {
+        self.channels.keys()
+            .filter(|c| pattern.is_none_or(|p| glob_match(&string_to_bytes(p), &string_to_bytes(c))))
+            .cloned()
+            .collect()
+    }
+
+    pub fn numsub(&self, channel: &str) -> usize {
+        self.channels.get(channel).map_or(0, |subs| subs.len())
+    }
+
+    pub fn shard_channels(&self, pattern: Option<&str>) -> Vec<String> {
+        self.shard_channels.values()
+            .flat_map(|channels| channels.keys())
+            .filter(|c| pattern.is_none_or(|p| glob_match(&string_to_bytes(p), &string_to_bytes(c))))
+            .cloned()
+            .collect()
+    }
+
+    pub fn shard_numsub(&self, channel: &str) -> usize {
+        self.shard_subscribers(channel).map_or(0, |subs| subs.len())
+    }
+
+    /// Number of distinct patterns subscribed to.
+    pub fn numpat(&self) -> usize {
+        self.patterns.len()
+    }
+}
+
+/// Redis glob matching: `*`, `?`, `[...]` with `^` negation and `a-z`
+/// ranges, and `\` escapes.
+pub fn glob_match(pattern: &[u8], string: &[u8]) -> bool {
+    let (mut p, mut s) = (0, 0);
+    while p < pattern.len() {
+        match pattern[p] {
+            b'*' => {
+                while pattern.get(p + 1) == Some(&b'*') {
+                    p += 1;
+                }
+                if p + 1 == pattern.len() {
+                    return true
+                }
+                return (s..=string.len()).any(|i| glob_match(&pattern[p + 1..], &string[i..]))
+            }
+            b'?' => {
+                if s == string.len() {
+                    return false
+                }
+                s += 1;
+            }
+            b'[' => {
+                let Some(&c) = string.get(s) else { return false };
+                p += 1;
+                let not = pattern.get(p) == Some(&b'^');
+                if not {
+                    p += 1;
+                }
+                let mut matched = false;
+                loop {
+                    match pattern.get(p) {
+                        // An unterminated class runs to the end of the pattern.
+                        None => {
+                            p -= 1;
+                            break;
+                        }
+                        Some(b']') => break,
+                        Some(b'\\') if p + 1 < pattern.len() => {
+                            p += 1;
+                            matched |= pattern[p] == c;
+                        }
+                        Some(&lo) if pattern.get(p + 1) == Some(&b'-') && p + 2 < pattern.len() => {
+                            let hi = pattern[p + 2];
+                            let (lo, hi) = if lo <= hi { (lo, hi) } else { (hi, lo) };
+                            matched |= (lo..=hi).contains(&c);
+                            p += 2;
+                        }
+                        Some(&x) => matched |= x == c
+                    }
+                    p += 1;
+                }
+                if matched == not {
+                    return false
+                }
+                s += 1;
+            }
+            b'\\' if p + 1 < pattern.len() => {
+                p += 1;
+                if string.get(s) != Some(&pattern[p]) {
+                    return false
+                }
+                s += 1;
+            }
+            c => {
+                if string.get(s) != Some(&c) {
+                    return false
+                }
+                s += 1;
+            }
+        }
+        p += 1;
+    }
+    s == string.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc;
+
+    use super::*;
+
+    fn bulks(a: &[&str]) -> Value {
+        Value::Array(a.iter().map(Value::bulk).collect())
+    }
+
+    #[test]
+    fn glob() {
+        for (pattern, string) in [("*", ""), ("h?llo", "hello"), ("h*o", "hello"), ("h[ae]llo", "hallo"), ("h[^e]llo", "hallo"), ("h[a-b]llo", "hbllo"), ("h[b-a]llo", "hallo"), ("h\\*", "h*"), ("news.*", "news.art"), ("a[bc", "ab")] {
+            assert!(glob_match(pattern.as_bytes(), string.as_bytes()), "{pattern} {string}");
+        }
+        for (pattern, string) in [("h?llo", "hllo"), ("h[ae]llo", "hillo"), ("h[^e]llo", "hello"), ("h\\*", "hx"), ("news.*", "news"), ("a", "ab")] {
+            assert!(!glob_match(pattern.as_bytes(), string.as_bytes()), "{pattern} {string}");
+        }
+    }
+
+    #[test]
+    fn publish_reaches_channels_and_patterns() {
+        let mut pubsub = PubSub::default();
+        let (tx1, mut rx1) = mpsc::unbounded_channel();
+        let (tx2, mut rx2) = mpsc::unbounded_channel();
+        pubsub.subscribe(1, &tx1, "news.art");
+        pubsub.psubscribe(2, &tx2, "news.*");
+        pubsub.psubscribe(1, &tx1, "sport.*");
+        assert_eq!(pubsub.publish("news.art", "hi"), 2);
+        assert_eq!(rx1.try_recv().unwrap(), bulks(&["message", "news.art", "hi"]));
+        assert_eq!(rx2.try_recv().unwrap(), bulks(&["pmessage", "news.*", "news.art", "hi"]));
+        assert!(rx1.try_recv().is_err());
+        assert_eq!(pubsub.publish("weather", "x"), 0);
+        assert_eq!((pubsub.numsub("news.art"), pubsub.numpat()), (1, 2));
+        assert_eq!(pubsub.channels(Some("n*")), ["news.art"]);
+        pubsub.unsubscribe(1, "news.art");
+        pubsub.punsubscribe(1, "sport.*");
+        assert!(pubsub.channels(None).is_empty());
+        assert_eq!(pubsub.numpat(), 1);
+    }
+
+    #[test]
+    fn shard_channels_are_separate() {
+        let mut pubsub = PubSub::default();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        pubsub.ssubscribe(1, &tx, "{user}.a");
+        pubsub.ssubscribe(2, &tx, "{user}.b");
+        pubsub.psubscribe(3, &tx, "*");
+        assert_eq!(pubsub.spublish("{user}.a", "hi"), 1);
+        assert_eq!(rx.try_recv().unwrap(), bulks(&["smessage", "{user}.a", "hi"]));
+        assert!(rx.try_recv().is_err());
+        assert_eq!(pubsub.publish("{user}.a", "x"), 1);
+        assert_eq!((pubsub.shard_numsub("{user}.b"), pubsub.numsub("{user}.a")), (1, 0));
+        pubsub.sunsubscribe(1, "{user}.a");
+        assert_eq!(pubsub.shard_channels(None), ["{user}.b"]);
+        pubsub.sunsubscribe(2, "{user}.b");
+        assert!(pubsub.shard_channels.is_empty());
+    }
+}
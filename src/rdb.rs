@@ -0,0 +1,1278 @@
+use std::{collections::HashMap, fs, io::Write, ops::Bound, path::Path, thread::JoinHandle};
+
+use anyhow::{anyhow, Error};
+
+use crate::{database::{dbstate, key_value}, resp::{bytes_to_string, string_to_bytes}, stream::{now_ms, ConsumerGroup, Consumer, PendingEntry, Stream, StreamId}};
+
+/// Newest RDB format version we can read (Redis 7.4).
+pub const RDB_MAX_VERSION: u32 = 12;
+/// Version written by SAVE and BGSAVE, the one Redis 7.2 uses.
+pub const RDB_VERSION: u32 = 11;
+
+const OPCODE_SLOT_INFO: u8 = 0xF4;
+const OPCODE_FUNCTION2: u8 = 0xF5;
+const OPCODE_MODULE_AUX: u8 = 0xF7;
+const OPCODE_IDLE: u8 = 0xF8;
+const OPCODE_FREQ: u8 = 0xF9;
+const OPCODE_AUX: u8 = 0xFA;
+const OPCODE_RESIZEDB: u8 = 0xFB;
+const OPCODE_EXPIRETIME_MS: u8 = 0xFC;
+const OPCODE_EXPIRETIME: u8 = 0xFD;
+const OPCODE_SELECTDB: u8 = 0xFE;
+const OPCODE_EOF: u8 = 0xFF;
+
+const TYPE_STRING: u8 = 0;
+const TYPE_LIST: u8 = 1;
+const TYPE_SET: u8 = 2;
+const TYPE_ZSET: u8 = 3;
+const TYPE_HASH: u8 = 4;
+const TYPE_ZSET_2: u8 = 5;
+const TYPE_HASH_ZIPMAP: u8 = 9;
+const TYPE_LIST_ZIPLIST: u8 = 10;
+const TYPE_SET_INTSET: u8 = 11;
+const TYPE_ZSET_ZIPLIST: u8 = 12;
+const TYPE_HASH_ZIPLIST: u8 = 13;
+const TYPE_LIST_QUICKLIST: u8 = 14;
+const TYPE_STREAM_LISTPACKS: u8 = 15;
+const TYPE_HASH_LISTPACK: u8 = 16;
+const TYPE_ZSET_LISTPACK: u8 = 17;
+const TYPE_LIST_QUICKLIST_2: u8 = 18;
+const TYPE_STREAM_LISTPACKS_2: u8 = 19;
+const TYPE_SET_LISTPACK: u8 = 20;
+const TYPE_STREAM_LISTPACKS_3: u8 = 21;
+
+// Special string encodings, flagged by the top two bits of a length.
+const ENC_INT8: u8 = 0;
+const ENC_INT16: u8 = 1;
+const ENC_INT32: u8 = 2;
+const ENC_LZF: u8 = 3;
+
+const QUICKLIST_NODE_PLAIN: u64 = 1;
+const QUICKLIST_NODE_PACKED: u64 = 2;
+/// Elements per listpack when writing lists and streams.
+const NODE_MAX_ENTRIES: usize = 128;
+/// How long to wait before retrying a failed background save from the save
+/// rules, in seconds.
+const BGSAVE_RETRY_DELAY: u64 = 5;
+
+// Flags of entries inside a stream listpack.
+const STREAM_ITEM_DELETED: i64 = 1 << 0;
+const STREAM_ITEM_SAMEFIELDS: i64 = 1 << 1;
+
+/// Byte-at-a-time lookup table for `crc64`.
+static CRC64_TABLE: [u64; 256] = {
+    let mut table = [0u64; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u64;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0x95ac_9329_ac4b_c9b5 } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// CRC-64/Jones, reflected, as used for the RDB trailer.
+pub fn crc64(data: &[u8]) -> u64 {
+    data.iter().fold(0, |crc, &b| CRC64_TABLE[((crc ^ b as u64) & 0xff) as usize] ^ (crc >> 8))
+}
+
+pub fn lzf_decompress(input: &[u8], out_len: usize) -> Result<Vec<u8>, Error> {
+    let corrupt = || anyhow!("Invalid LZF compressed string");
+    let mut out = Vec::with_capacity(out_len);
+    let mut i = 0;
+    while i < input.len() {
+        let ctrl = input[i] as usize;
+        i += 1;
+        if ctrl < 32 {
+            let run = input.get(i..i + ctrl + 1).ok_or_else(corrupt)?;
+            out.extend_from_slice(run);
+            i += ctrl + 1;
+        } else {
+            let mut len = ctrl >> 5;
+            if len == 7 {
+                len += *input.get(i).ok_or_else(corrupt)? as usize;
+                i += 1;
+            }
+            let back = ((ctrl & 0x1f) << 8) + *input.get(i).ok_or_else(corrupt)? as usize + 1;
+            i += 1;
+            let start = out.len().checked_sub(back).ok_or_else(corrupt)?;
+            // The reference may overlap what is being written.
+            for k in 0..len + 2 {
+                out.push(out[start + k]);
+            }
+        }
+    }
+    if out.len() != out_len {
+        return Err(corrupt())
+    }
+    Ok(out)
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+enum Length {
+    Len(u64),
+    /// A string stored with one of the `ENC_*` encodings.
+    Encoded(u8),
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Reader { data, pos: 0 }
+    }
+
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], Error> {
+        let bytes = self.data.get(self.pos..self.pos.saturating_add(n)).ok_or_else(|| anyhow!("Short read loading DB"))?;
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], Error> {
+        Ok(self.bytes(N)?.try_into().unwrap())
+    }
+
+    fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u32_le(&mut self) -> Result<u32, Error> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    fn u64_le(&mut self) -> Result<u64, Error> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    fn length(&mut self) -> Result<Length, Error> {
+        let first = self.u8()?;
+        Ok(match first >> 6 {
+            0 => Length::Len((first & 0x3f) as u64),
+            1 => Length::Len((((first & 0x3f) as u64) << 8) | self.u8()? as u64),
+            2 => match first {
+                0x80 => Length::Len(u32::from_be_bytes(self.array()?) as u64),
+                0x81 => Length::Len(u64::from_be_bytes(self.array()?)),
+                _ => return Err(anyhow!("Unknown length encoding {} in rdbLoadLen()", first))
+            },
+            _ => Length::Encoded(first & 0x3f)
+        })
+    }
+
+    fn len(&mut self) -> Result<u64, Error> {
+        match self.length()? {
+            Length::Len(n) => Ok(n),
+            Length::Encoded(_) => Err(anyhow!("Unexpected string encoding where a length was expected"))
+        }
+    }
+
+    fn raw_string(&mut self) -> Result<Vec<u8>, Error> {
+        match self.length()? {
+            Length::Len(n) => Ok(self.bytes(n as usize)?.to_vec()),
+            Length::Encoded(ENC_INT8) => Ok((self.u8()? as i8).to_string().into_bytes()),
+            Length::Encoded(ENC_INT16) => Ok(i16::from_le_bytes(self.array()?).to_string().into_bytes()),
+            Length::Encoded(ENC_INT32) => Ok(i32::from_le_bytes(self.array()?).to_string().into_bytes()),
+            Length::Encoded(ENC_LZF) => {
+                let compressed = self.len()? as usize;
+                let len = self.len()? as usize;
+                lzf_decompress(self.bytes(compressed)?, len)
+            }
+            Length::Encoded(e) => Err(anyhow!("Unknown RDB string encoding type {}", e))
+        }
+    }
+
+    fn string(&mut self) -> Result<String, Error> {
+        Ok(bytes_to_string(&self.raw_string()?))
+    }
+
+    /// Old-style ZSET score: a length byte with special values for NaN and
+    /// the infinities, then the number as text.
+    fn double_str(&mut self) -> Result<f64, Error> {
+        match self.u8()? {
+            253 => Ok(f64::NAN),
+            254 => Ok(f64::INFINITY),
+            255 => Ok(f64::NEG_INFINITY),
+            len => parse_score(&bytes_to_string(self.bytes(len as usize)?))
+        }
+    }
+
+    fn stream_id(&mut self) -> Result<StreamId, Error> {
+        let raw: [u8; 16] = self.array()?;
+        Ok(StreamId::new(u64::from_be_bytes(raw[..8].try_into().unwrap()), u64::from_be_bytes(raw[8..].try_into().unwrap())))
+    }
+}
+
+fn parse_score(s: &str) -> Result<f64, Error> {
+    match s {
+        "inf" | "+inf" => Ok(f64::INFINITY),
+        "-inf" => Ok(f64::NEG_INFINITY),
+        s => s.parse::<f64>().map_err(|_| anyhow!("Invalid zset score '{}'", s))
+    }
+}
+
+fn ziplist(data: &[u8]) -> Result<Vec<String>, Error> {
+    let mut r = Reader::new(data);
+    r.bytes(10)?;
+    let mut items = Vec::new();
+    loop {
+        // Each entry starts with the previous entry's length; 0xFF ends the
+        // list instead.
+        match r.u8()? {
+            0xFF => break,
+            0xFE => {
+                r.bytes(4)?;
+            }
+            _ => {}
+        }
+        let enc = r.u8()?;
+        let item = match enc >> 6 {
+            0 => bytes_to_string(r.bytes((enc & 0x3f) as usize)?),
+            1 => {
+                let len = (((enc & 0x3f) as usize) << 8) | r.u8()? as usize;
+                bytes_to_string(r.bytes(len)?)
+            }
+            2 => {
+                let len = u32::from_be_bytes(r.array()?) as usize;
+                bytes_to_string(r.bytes(len)?)
+            }
+            _ => match enc {
+                0xC0 => i16::from_le_bytes(r.array()?).to_string(),
+                0xD0 => i32::from_le_bytes(r.array()?).to_string(),
+                0xE0 => i64::from_le_bytes(r.array()?).to_string(),
+                0xF0 => {
+                    let [a, b, c] = r.array()?;
+                    (i32::from_le_bytes([0, a, b, c]) >> 8).to_string()
+                }
+                0xFE => (r.u8()? as i8).to_string(),
+                0xF1..=0xFD => ((enc & 0x0f) - 1).to_string(),
+                _ => return Err(anyhow!("Invalid ziplist entry encoding {}", enc))
+            }
+        };
+        items.push(item);
+    }
+    Ok(items)
+}
+
+/// Size of a listpack entry's backward length, for an entry of `size`
+/// bytes.
+fn backlen(size: usize) -> usize {
+    match size {
+        0..=127 => 1,
+        128..16383 => 2,
+        16383..2097151 => 3,
+        2097151..268435455 => 4,
+        _ => 5
+    }
+}
+
+fn listpack(data: &[u8]) -> Result<Vec<String>, Error> {
+    let mut r = Reader::new(data);
+    r.bytes(6)?;
+    let mut items = Vec::new();
+    loop {
+        let enc = r.u8()?;
+        let (item, size) = match enc {
+            0xFF => break,
+            e if e & 0x80 == 0 => ((e & 0x7f).to_string(), 1),
+            e if e & 0xC0 == 0x80 => {
+                let len = (e & 0x3f) as usize;
+                (bytes_to_string(r.bytes(len)?), 1 + len)
+            }
+            e if e & 0xE0 == 0xC0 => {
+                let v = (((e & 0x1f) as i64) << 8) | r.u8()? as i64;
+                let v = if v >= 1 << 12 { v - (1 << 13) } else { v };
+                (v.to_string(), 2)
+            }
+            e if e & 0xF0 == 0xE0 => {
+                let len = (((e & 0x0f) as usize) << 8) | r.u8()? as usize;
+                (bytes_to_string(r.bytes(len)?), 2 + len)
+            }
+            0xF0 => {
+                let len = r.u32_le()? as usize;
+                (bytes_to_string(r.bytes(len)?), 5 + len)
+            }
+            0xF1 => (i16::from_le_bytes(r.array()?).to_string(), 3),
+            0xF2 => {
+                let [a, b, c] = r.array()?;
+                ((i32::from_le_bytes([0, a, b, c]) >> 8).to_string(), 4)
+            }
+            0xF3 => (i32::from_le_bytes(r.array()?).to_string(), 5),
+            0xF4 => (i64::from_le_bytes(r.array()?).to_string(), 9),
+            e => return Err(anyhow!("Invalid listpack entry encoding {}", e))
+        };
+        // Skip the backward length, which grows with the entry size.
+        r.bytes(backlen(size))?;
+        items.push(item);
+    }
+    Ok(items)
+}
+
+fn intset(data: &[u8]) -> Result<Vec<String>, Error> {
+    let mut r = Reader::new(data);
+    let width = r.u32_le()? as usize;
+    let len = r.u32_le()?;
+    let mut items = Vec::new();
+    for _ in 0..len {
+        let v = match width {
+            2 => i16::from_le_bytes(r.array()?) as i64,
+            4 => i32::from_le_bytes(r.array()?) as i64,
+            8 => i64::from_le_bytes(r.array()?),
+            _ => return Err(anyhow!("Invalid intset encoding {}", width))
+        };
+        items.push(v.to_string());
+    }
+    Ok(items)
+}
+
+fn zipmap(data: &[u8]) -> Result<Vec<String>, Error> {
+    let mut r = Reader::new(data);
+    r.u8()?;
+    let mut items = Vec::new();
+    let read_len = |r: &mut Reader| -> Result<Option<usize>, Error> {
+        Ok(match r.u8()? {
+            255 => None,
+            254 => Some(r.u32_le()? as usize),
+            n => Some(n as usize)
+        })
+    };
+    while let Some(len) = read_len(&mut r)? {
+        items.push(bytes_to_string(r.bytes(len)?));
+        let len = read_len(&mut r)?.ok_or_else(|| anyhow!("Invalid zipmap"))?;
+        let free = r.u8()? as usize;
+        items.push(bytes_to_string(r.bytes(len)?));
+        r.bytes(free)?;
+    }
+    Ok(items)
+}
+
+fn pairs(items: Vec<String>) -> Result<Vec<(String, String)>, Error> {
+    if !items.len().is_multiple_of(2) {
+        return Err(anyhow!("Odd number of elements in a key-value encoding"))
+    }
+    let mut it = items.into_iter();
+    let mut res = Vec::new();
+    while let (Some(k), Some(v)) = (it.next(), it.next()) {
+        res.push((k, v));
+    }
+    Ok(res)
+}
+
+fn zset(items: Vec<String>) -> Result<key_value, Error> {
+    let mut zset = HashMap::new();
+    for (member, score) in pairs(items)? {
+        zset.insert(member, parse_score(&score)?);
+    }
+    Ok(key_value::ZSet(zset))
+}
+
+/// Decodes one stream listpack node into `stream`. The node starts with a
+/// master entry (live count, deleted count, master fields, 0); each entry
+/// after it is flags, ID deltas from `master`, its fields or, with
+/// SAMEFIELDS, just the values, and a trailing element count.
+fn stream_node(stream: &mut Stream, master: StreamId, lp: Vec<String>) -> Result<(), Error> {
+    let mut it = lp.into_iter();
+    let mut next = || it.next().ok_or_else(|| anyhow!("Truncated stream listpack"));
+    let int = |s: String| s.parse::<i64>().map_err(|_| anyhow!("Invalid integer in stream listpack"));
+    int(next()?)?;
+    int(next()?)?;
+    let master_fields = (0..int(next()?)?).map(|_| next()).collect::<Result<Vec<_>, _>>()?;
+    next()?;
+    loop {
+        let flags = match next() {
+            Ok(flags) => int(flags)?,
+            Err(_) => break
+        };
+        let id = StreamId::new(
+            master.ms.wrapping_add(int(next()?)? as u64),
+            master.seq.wrapping_add(int(next()?)? as u64),
+        );
+        let fields = if flags & STREAM_ITEM_SAMEFIELDS != 0 {
+            master_fields.iter().map(|f| Ok((f.clone(), next()?))).collect::<Result<Vec<_>, Error>>()?
+        } else {
+            (0..int(next()?)?).map(|_| Ok((next()?, next()?))).collect::<Result<Vec<_>, Error>>()?
+        };
+        next()?;
+        if flags & STREAM_ITEM_DELETED == 0 {
+            stream.insert(id, fields);
+        }
+    }
+    Ok(())
+}
+
+fn read_stream(r: &mut Reader, ty: u8) -> Result<Stream, Error> {
+    let mut stream = Stream::new();
+    for _ in 0..r.len()? {
+        let master = r.raw_string()?;
+        if master.len() != 16 {
+            return Err(anyhow!("Stream node key entry is not the size of a stream ID"))
+        }
+        let master = Reader::new(&master).stream_id()?;
+        stream_node(&mut stream, master, listpack(&r.raw_string()?)?)?;
+    }
+    let length = r.len()?;
+    let last_id = StreamId::new(r.len()?, r.len()?);
+    let (mut entries_added, mut max_deleted) = (length, StreamId::MIN);
+    if ty >= TYPE_STREAM_LISTPACKS_2 {
+        // The recorded first ID is derived from the entries instead.
+        r.len()?;
+        r.len()?;
+        max_deleted = StreamId::new(r.len()?, r.len()?);
+        entries_added = r.len()?;
+    }
+    stream.set_id(last_id, Some(entries_added), Some(max_deleted))?;
+
+    for _ in 0..r.len()? {
+        let name = r.string()?;
+        let last_delivered = StreamId::new(r.len()?, r.len()?);
+        let entries_read = if ty >= TYPE_STREAM_LISTPACKS_2 {
+            // -1 (unknown) is saved as an all-ones length.
+            Some(r.len()?).filter(|n| *n != u64::MAX)
+        } else {
+            None
+        };
+        let mut group = ConsumerGroup::new(last_delivered, entries_read);
+        for _ in 0..r.len()? {
+            let id = r.stream_id()?;
+            let delivery_time = r.u64_le()?;
+            let delivery_count = r.len()?;
+            group.pel.insert(id, PendingEntry { consumer: String::new(), delivery_time, delivery_count });
+        }
+        for _ in 0..r.len()? {
+            let cname = r.string()?;
+            let seen_time = r.u64_le()?;
+            let active_time = if ty >= TYPE_STREAM_LISTPACKS_3 {
+                Some(r.u64_le()?).filter(|t| *t != u64::MAX)
+            } else {
+                Some(seen_time)
+            };
+            let mut consumer = Consumer { seen_time, active_time, pending: Default::default() };
+            for _ in 0..r.len()? {
+                let id = r.stream_id()?;
+                let entry = group.pel.get_mut(&id).ok_or_else(|| anyhow!("Consumer pending entry not found in the group PEL"))?;
+                entry.consumer = cname.clone();
+                consumer.pending.insert(id);
+            }
+            group.consumers.insert(cname, consumer);
+        }
+        stream.groups.insert(name, group);
+    }
+    Ok(stream)
+}
+
+fn read_object(r: &mut Reader, ty: u8) -> Result<key_value, Error> {
+    let strings = |r: &mut Reader, n: u64| (0..n).map(|_| r.string()).collect::<Result<Vec<_>, Error>>();
+    Ok(match ty {
+        TYPE_STRING => key_value::String(r.string()?),
+        TYPE_LIST => {
+            let n = r.len()?;
+            key_value::List(strings(r, n)?)
+        }
+        TYPE_SET => {
+            let n = r.len()?;
+            key_value::Set(strings(r, n)?.into_iter().collect())
+        }
+        TYPE_ZSET | TYPE_ZSET_2 => {
+            let mut zset = HashMap::new();
+            for _ in 0..r.len()? {
+                let member = r.string()?;
+                let score = if ty == TYPE_ZSET_2 { f64::from_le_bytes(r.array()?) } else { r.double_str()? };
+                zset.insert(member, score);
+            }
+            key_value::ZSet(zset)
+        }
+        TYPE_HASH => {
+            let n = r.len()?;
+            key_value::Hash(pairs(strings(r, n * 2)?)?.into_iter().collect())
+        }
+        TYPE_HASH_ZIPMAP => key_value::Hash(pairs(zipmap(&r.raw_string()?)?)?.into_iter().collect()),
+        TYPE_LIST_ZIPLIST => key_value::List(ziplist(&r.raw_string()?)?),
+        TYPE_SET_INTSET => key_value::Set(intset(&r.raw_string()?)?.into_iter().collect()),
+        TYPE_SET_LISTPACK => key_value::Set(listpack(&r.raw_string()?)?.into_iter().collect()),
+        TYPE_ZSET_ZIPLIST => zset(ziplist(&r.raw_string()?)?)?,
+        TYPE_ZSET_LISTPACK => zset(listpack(&r.raw_string()?)?)?,
+        TYPE_HASH_ZIPLIST => key_value::Hash(pairs(ziplist(&r.raw_string()?)?)?.into_iter().collect()),
+        TYPE_HASH_LISTPACK => key_value::Hash(pairs(listpack(&r.raw_string()?)?)?.into_iter().collect()),
+        TYPE_LIST_QUICKLIST => {
+            let mut list = Vec::new();
+            for _ in 0..r.len()? {
+                list.extend(ziplist(&r.raw_string()?)?);
+            }
+            key_value::List(list)
+        }
+        TYPE_LIST_QUICKLIST_2 => {
+            let mut list = Vec::new();
+            for _ in 0..r.len()? {
+                if r.len()? == QUICKLIST_NODE_PLAIN {
+                    list.push(r.string()?);
+                } else {
+                    list.extend(listpack(&r.raw_string()?)?);
+                }
+            }
+            key_value::List(list)
+        }
+        TYPE_STREAM_LISTPACKS | TYPE_STREAM_LISTPACKS_2 | TYPE_STREAM_LISTPACKS_3 => key_value::Stream(read_stream(r, ty)?),
+        _ => return Err(anyhow!("Unknown RDB encoding type {}", ty))
+    })
+}
+
+/// Loads an RDB snapshot into `state`, returning the number of keys read.
+/// Keys that have already expired are skipped, as a primary does.
+pub fn load(path: &Path, state: &mut dbstate) -> Result<usize, Error> {
+    load_bytes(&fs::read(path)?, state).map(|(keys, _)| keys)
+}
+
+/// Loads the RDB at the start of `data`, returning the number of keys read
+/// and how many bytes the RDB took, for AOFs that start with one. Keys go
+/// to the database they were saved from.
+pub fn load_bytes(data: &[u8], state: &mut dbstate) -> Result<(usize, usize), Error> {
+    state.in_db(0, |state| load_keys(data, state))
+}
+
+fn load_keys(data: &[u8], state: &mut dbstate) -> Result<(usize, usize), Error> {
+    let mut r = Reader::new(data);
+    if r.bytes(5).ok() != Some(b"REDIS".as_slice()) {
+        return Err(anyhow!("Wrong signature trying to load DB from file"))
+    }
+    let version = std::str::from_utf8(r.bytes(4)?).ok().and_then(|v| v.parse::<u32>().ok())
+        .filter(|v| (1..=RDB_MAX_VERSION).contains(v))
+        .ok_or_else(|| anyhow!("Can't handle RDB format version"))?;
+
+    let now = now_ms();
+    let mut expire_at = None;
+    let mut loaded = 0;
+    loop {
+        match r.u8()? {
+            OPCODE_EOF => {
+                if version >= 5 {
+                    let expected = crc64(&data[..r.pos]);
+                    let stored = r.u64_le()?;
+                    // A zero checksum means the writer had checksums disabled.
+                    if stored != 0 && stored != expected {
+                        return Err(anyhow!("Wrong RDB checksum expected: ({:x}) got: ({:x})", expected, stored))
+                    }
+                }
+                break;
+            }
+            OPCODE_SELECTDB => {
+                let db = r.len()? as usize;
+                if db >= state.dbs.len() {
+                    return Err(anyhow!("FATAL: Data file was created with a Redis server configured to handle more than {} databases. Exiting", state.dbs.len()))
+                }
+                state.select(db);
+            }
+            OPCODE_EXPIRETIME => expire_at = Some(r.u32_le()? as u64 * 1000),
+            OPCODE_EXPIRETIME_MS => expire_at = Some(r.u64_le()?),
+            OPCODE_RESIZEDB => {
+                r.len()?;
+                r.len()?;
+            }
+            OPCODE_AUX => {
+                let name = r.string()?;
+                let value = r.string()?;
+                if name == "repl-stream-db" {
+                    state.replication.master_db = value.parse().unwrap_or(0);
+                }
+            }
+            OPCODE_IDLE => {
+                r.len()?;
+            }
+            OPCODE_FREQ => {
+                r.u8()?;
+            }
+            // Function libraries; there are no functions to restore them into.
+            OPCODE_FUNCTION2 => {
+                r.string()?;
+            }
+            OPCODE_SLOT_INFO => {
+                r.len()?;
+                r.len()?;
+                r.len()?;
+            }
+            OPCODE_MODULE_AUX => return Err(anyhow!("Module data in RDB is not supported")),
+            ty => {
+                let key = r.string()?;
+                let value = read_object(&mut r, ty)?;
+                let expire = expire_at.take();
+                if expire.is_some_and(|at| at <= now) {
+                    continue;
+                }
+                if let Some(at) = expire {
+                    state.expires.insert(key.clone(), at);
+                }
+                state.insert_key(key, value);
+                loaded += 1;
+            }
+        }
+    }
+    Ok((loaded, r.pos))
+}
+
+
+/// Builds a listpack. Elements that are canonical integers are stored as
+/// such, like Redis does.
+fn listpack_encode(items: &[String]) -> Vec<u8> {
+    let mut body = Vec::new();
+    for item in items {
+        let start = body.len();
+        match item.parse::<i64>() {
+            Ok(v) if v.to_string() == *item => match v {
+                0..=127 => body.push(v as u8),
+                -4096..=4095 => {
+                    let v = v as u16 & 0x1fff;
+                    body.extend([0xC0 | (v >> 8) as u8, v as u8]);
+                }
+                -32768..=32767 => {
+                    body.push(0xF1);
+                    body.extend((v as i16).to_le_bytes());
+                }
+                -8388608..=8388607 => {
+                    body.push(0xF2);
+                    body.extend(&(v as i32).to_le_bytes()[..3]);
+                }
+                -2147483648..=2147483647 => {
+                    body.push(0xF3);
+                    body.extend((v as i32).to_le_bytes());
+                }
+                _ => {
+                    body.push(0xF4);
+                    body.extend(v.to_le_bytes());
+                }
+            },
+            _ => {
+                let item = string_to_bytes(item);
+                let len = item.len();
+                match len {
+                    0..64 => body.push(0x80 | len as u8),
+                    64..4096 => body.extend([0xE0 | (len >> 8) as u8, len as u8]),
+                    _ => {
+                        body.push(0xF0);
+                        body.extend((len as u32).to_le_bytes());
+                    }
+                }
+                body.extend(item);
+            }
+        }
+        // The backward length holds the entry size in 7-bit groups, most
+        // significant first, with the high bit set on all but the first.
+        let size = body.len() - start;
+        let n = backlen(size);
+        for i in 0..n {
+            let group = ((size >> (7 * (n - 1 - i))) & 127) as u8;
+            body.push(if i == 0 { group } else { group | 128 });
+        }
+    }
+    let mut lp = Vec::with_capacity(body.len() + 7);
+    lp.extend(((body.len() + 7) as u32).to_le_bytes());
+    // 65535 means the count has to be computed by walking the listpack.
+    lp.extend((items.len().min(65535) as u16).to_le_bytes());
+    lp.extend(body);
+    lp.push(0xFF);
+    lp
+}
+
+/// The RDB type each kind of value is written as.
+fn object_type(value: &key_value) -> u8 {
+    match value {
+        key_value::String(_) => TYPE_STRING,
+        key_value::List(_) => TYPE_LIST_QUICKLIST_2,
+        key_value::Set(_) => TYPE_SET,
+        key_value::Hash(_) => TYPE_HASH,
+        key_value::ZSet(_) => TYPE_ZSET_2,
+        key_value::Stream(_) => TYPE_STREAM_LISTPACKS_3,
+    }
+}
+
+struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    fn len(&mut self, n: u64) {
+        match n {
+            0..64 => self.buf.push(n as u8),
+            64..16384 => self.buf.extend([0x40 | (n >> 8) as u8, n as u8]),
+            _ if n <= u32::MAX as u64 => {
+                self.buf.push(0x80);
+                self.buf.extend((n as u32).to_be_bytes());
+            }
+            _ => {
+                self.buf.push(0x81);
+                self.buf.extend(n.to_be_bytes());
+            }
+        }
+    }
+
+    /// Writes a string, using an integer encoding when it is the canonical
+    /// form of a number that fits one.
+    fn string(&mut self, s: &str) {
+        if s.len() <= 11
+            && let Ok(v) = s.parse::<i32>()
+            && v.to_string() == s {
+            match v {
+                -128..=127 => self.buf.extend([0xC0 | ENC_INT8, v as u8]),
+                -32768..=32767 => {
+                    self.buf.push(0xC0 | ENC_INT16);
+                    self.buf.extend((v as i16).to_le_bytes());
+                }
+                _ => {
+                    self.buf.push(0xC0 | ENC_INT32);
+                    self.buf.extend(v.to_le_bytes());
+                }
+            }
+            return
+        }
+        self.bytes(&string_to_bytes(s));
+    }
+
+    fn bytes(&mut self, b: &[u8]) {
+        self.len(b.len() as u64);
+        self.buf.extend(b);
+    }
+
+    fn stream_id(&mut self, id: StreamId) {
+        self.buf.extend(id.ms.to_be_bytes());
+        self.buf.extend(id.seq.to_be_bytes());
+    }
+
+    fn aux(&mut self, name: &str, value: &str) {
+        self.buf.push(OPCODE_AUX);
+        self.string(name);
+        self.string(value);
+    }
+
+    /// Writes the type byte, the key and the encoded value.
+    fn object(&mut self, key: &str, value: &key_value) {
+        self.buf.push(object_type(value));
+        self.string(key);
+        self.value(value);
+    }
+
+    fn value(&mut self, value: &key_value) {
+        match value {
+            key_value::String(s) => self.string(s),
+            key_value::List(list) => {
+                self.len(list.len().div_ceil(NODE_MAX_ENTRIES) as u64);
+                for chunk in list.chunks(NODE_MAX_ENTRIES) {
+                    self.len(QUICKLIST_NODE_PACKED);
+                    self.bytes(&listpack_encode(chunk));
+                }
+            }
+            key_value::Set(set) => {
+                self.len(set.len() as u64);
+                for member in set {
+                    self.string(member);
+                }
+            }
+            key_value::Hash(hash) => {
+                self.len(hash.len() as u64);
+                for (field, value) in hash {
+                    self.string(field);
+                    self.string(value);
+                }
+            }
+            key_value::ZSet(zset) => {
+                self.len(zset.len() as u64);
+                for (member, score) in zset {
+                    self.string(member);
+                    self.buf.extend(score.to_le_bytes());
+                }
+            }
+            key_value::Stream(stream) => self.stream(stream),
+        }
+    }
+
+    /// Streams are written as listpack nodes in the layout `stream_node`
+    /// reads, each using its first entry's fields as the master fields.
+    fn stream(&mut self, stream: &Stream) {
+        let entries = stream.range(Bound::Unbounded, Bound::Unbounded);
+        self.len(entries.len().div_ceil(NODE_MAX_ENTRIES) as u64);
+        for chunk in entries.chunks(NODE_MAX_ENTRIES) {
+            let master = chunk[0].0;
+            let master_fields: Vec<&String> = chunk[0].1.iter().map(|(f, _)| f).collect();
+            let mut lp = vec![chunk.len().to_string(), "0".to_string(), master_fields.len().to_string()];
+            lp.extend(master_fields.iter().map(|f| f.to_string()));
+            lp.push("0".to_string());
+            for (id, fields) in chunk {
+                let same = fields.len() == master_fields.len() && fields.iter().zip(&master_fields).all(|((f, _), m)| f == *m);
+                let flags = if same { STREAM_ITEM_SAMEFIELDS } else { 0 };
+                lp.push(flags.to_string());
+                lp.push((id.ms.wrapping_sub(master.ms) as i64).to_string());
+                lp.push((id.seq.wrapping_sub(master.seq) as i64).to_string());
+                if same {
+                    lp.extend(fields.iter().map(|(_, v)| v.clone()));
+                    lp.push((fields.len() + 3).to_string());
+                } else {
+                    lp.push(fields.len().to_string());
+                    for (f, v) in fields {
+                        lp.push(f.clone());
+                        lp.push(v.clone());
+                    }
+                    lp.push((fields.len() * 2 + 4).to_string());
+                }
+            }
+            let mut master_key = Vec::with_capacity(16);
+            master_key.extend(master.ms.to_be_bytes());
+            master_key.extend(master.seq.to_be_bytes());
+            self.bytes(&master_key);
+            self.bytes(&listpack_encode(&lp));
+        }
+        let first = stream.first_id();
+        let last = stream.last_id();
+        let max_deleted = stream.max_deleted_id();
+        for n in [stream.len() as u64, last.ms, last.seq, first.ms, first.seq, max_deleted.ms, max_deleted.seq, stream.entries_added()] {
+            self.len(n);
+        }
+        self.len(stream.groups.len() as u64);
+        for (name, group) in &stream.groups {
+            self.string(name);
+            self.len(group.last_delivered.ms);
+            self.len(group.last_delivered.seq);
+            self.len(group.entries_read.unwrap_or(u64::MAX));
+            self.len(group.pel.len() as u64);
+            for (id, entry) in &group.pel {
+                self.stream_id(*id);
+                self.buf.extend(entry.delivery_time.to_le_bytes());
+                self.len(entry.delivery_count);
+            }
+            self.len(group.consumers.len() as u64);
+            for (cname, consumer) in &group.consumers {
+                self.string(cname);
+                self.buf.extend(consumer.seen_time.to_le_bytes());
+                self.buf.extend(consumer.active_time.unwrap_or(u64::MAX).to_le_bytes());
+                self.len(consumer.pending.len() as u64);
+                for id in &consumer.pending {
+                    self.stream_id(*id);
+                }
+            }
+        }
+    }
+}
+
+/// Serializes a value the way DUMP does: its RDB type and encoding, then
+/// the RDB version (2 bytes) and a CRC64 of everything before (8 bytes),
+/// both little endian.
+pub fn dump(value: &key_value) -> Vec<u8> {
+    let mut w = Writer { buf: vec![object_type(value)] };
+    w.value(value);
+    w.buf.extend((RDB_VERSION as u16).to_le_bytes());
+    let checksum = crc64(&w.buf);
+    w.buf.extend(checksum.to_le_bytes());
+    w.buf
+}
+
+/// Reads back a `dump` payload, checking its version and checksum.
+pub fn undump(payload: &[u8]) -> Result<key_value, Error> {
+    let wrong = || anyhow!("ERR DUMP payload version or checksum are wrong");
+    let Some(body_len) = payload.len().checked_sub(10) else { return Err(wrong()) };
+    let (body, footer) = payload.split_at(body_len);
+    let version = u16::from_le_bytes([footer[0], footer[1]]) as u32;
+    let checksum = u64::from_le_bytes(footer[2..].try_into().unwrap());
+    if version > RDB_MAX_VERSION || checksum != crc64(&payload[..body_len + 2]) {
+        return Err(wrong())
+    }
+    let mut r = Reader::new(body);
+    let value = r.u8().and_then(|ty| read_object(&mut r, ty));
+    match value {
+        Ok(value) if r.pos == body.len() => Ok(value),
+        _ => Err(anyhow!("ERR Bad data format"))
+    }
+}
+
+/// A point-in-time copy of the keyspace. BGSAVE takes one under the lock and
+/// writes it out from another thread.
+pub struct Snapshot {
+    /// Keys and expiry deadlines of every database, by index.
+    dbs: Vec<(HashMap<String, key_value>, HashMap<String, u64>)>,
+    /// Database the replication stream is in at this point, for a replica
+    /// that continues with the stream after loading the snapshot.
+    stream_db: usize,
+}
+
+impl Snapshot {
+    pub fn take(state: &dbstate) -> Self {
+        let dbs = (0..state.dbs.len()).map(|db| {
+            let (kv, expires) = state.keyspace(db);
+            (kv.clone(), expires.clone())
+        }).collect();
+        Snapshot { dbs, stream_db: state.replication.stream_db() }
+    }
+
+    /// Keys with their database, values and expiry deadlines, database by
+    /// database, leaving out those that have expired but weren't reclaimed
+    /// yet.
+    pub fn entries(&self) -> impl Iterator<Item = (usize, &String, &key_value, Option<u64>)> {
+        let now = now_ms();
+        self.dbs.iter().enumerate()
+            .flat_map(|(db, (kv, expires))| kv.iter().map(move |(key, value)| (db, key, value, expires.get(key).copied())))
+            .filter(move |(_, _, _, at)| at.is_none_or(|at| at > now))
+    }
+
+    /// The RDB bytes; `aof_base` marks an AOF base or preamble.
+    pub fn encode(&self, aof_base: bool) -> Vec<u8> {
+        let now = now_ms();
+        let mut w = Writer { buf: format!("REDIS{:04}", RDB_VERSION).into_bytes() };
+        w.aux("redis-ver", "7.2.0");
+        w.aux("redis-bits", "64");
+        w.aux("ctime", &(now / 1000).to_string());
+        w.aux("aof-base", if aof_base { "1" } else { "0" });
+        w.aux("repl-stream-db", &self.stream_db.to_string());
+        let mut selected = None;
+        for (db, key, value, expire_at) in self.entries() {
+            if selected != Some(db) {
+                let (kv, expires) = &self.dbs[db];
+                w.buf.push(OPCODE_SELECTDB);
+                w.len(db as u64);
+                w.buf.push(OPCODE_RESIZEDB);
+                w.len(kv.len() as u64);
+                w.len(expires.len() as u64);
+                selected = Some(db);
+            }
+            if let Some(at) = expire_at {
+                w.buf.push(OPCODE_EXPIRETIME_MS);
+                w.buf.extend(at.to_le_bytes());
+            }
+            w.object(key, value);
+        }
+        w.buf.push(OPCODE_EOF);
+        let checksum = crc64(&w.buf);
+        w.buf.extend(checksum.to_le_bytes());
+        w.buf
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        write_file(path, &self.encode(false))
+    }
+}
+
+/// Writes `data` to `path` through a temporary file in the same directory,
+/// so the old file stays intact until the new one is complete.
+pub fn write_file(path: &Path, data: &[u8]) -> Result<(), Error> {
+    let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    let tmp = path.with_file_name(format!("temp-{}-{}", std::process::id(), name));
+    let write = || -> Result<(), Error> {
+        let mut file = fs::File::create(&tmp)?;
+        file.write_all(data)?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    };
+    write().inspect_err(|_| {
+        let _ = fs::remove_file(&tmp);
+    })
+}
+
+/// Bookkeeping for SAVE, BGSAVE and the save rules.
+pub struct Persistence {
+    /// Changes to the keyspace since the last successful save.
+    pub dirty: u64,
+    /// `dirty` when the running background save took its snapshot; those
+    /// changes are the ones it persists.
+    dirty_at_bgsave: u64,
+    /// Unix seconds of the last successful save.
+    pub lastsave: u64,
+    bgsave: Option<JoinHandle<Result<(), Error>>>,
+    /// Unix milliseconds when the running background save started.
+    bgsave_start: u64,
+    /// Unix seconds of the last background save attempt.
+    last_bgsave_try: u64,
+    /// A BGSAVE SCHEDULE is waiting for the running one to finish.
+    pub bgsave_scheduled: bool,
+    pub last_bgsave_ok: bool,
+    last_bgsave_time: Option<u64>,
+    saves: u64,
+}
+
+impl Persistence {
+    pub fn new() -> Self {
+        Persistence {
+            dirty: 0,
+            dirty_at_bgsave: 0,
+            lastsave: now_ms() / 1000,
+            bgsave: None,
+            bgsave_start: 0,
+            last_bgsave_try: 0,
+            bgsave_scheduled: false,
+            last_bgsave_ok: true,
+            last_bgsave_time: None,
+            saves: 0,
+        }
+    }
+
+    pub fn bgsave_in_progress(&self) -> bool {
+        self.bgsave.is_some()
+    }
+}
+
+impl Default for Persistence {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub fn rdb_path(state: &dbstate) -> std::path::PathBuf {
+    Path::new(&state.config.dir).join(&state.config.dbfilename)
+}
+
+/// SAVE: writes the snapshot while holding the lock.
+pub fn save(state: &mut dbstate) -> Result<(), Error> {
+    if state.persistence.bgsave_in_progress() {
+        return Err(anyhow!("ERR Background save already in progress"))
+    }
+    Snapshot::take(state).save(&rdb_path(state)).map_err(|e| {
+        eprintln!("Error saving DB on disk: {}", e);
+        anyhow!("ERR")
+    })?;
+    let p = &mut state.persistence;
+    p.dirty = 0;
+    p.lastsave = now_ms() / 1000;
+    p.saves += 1;
+    Ok(())
+}
+
+/// BGSAVE: copies the keyspace and writes it from another thread. Completion
+/// is picked up by `cron`.
+pub fn bgsave(state: &mut dbstate) -> Result<(), Error> {
+    if state.persistence.bgsave_in_progress() {
+        return Err(anyhow!("ERR Background save already in progress"))
+    }
+    let snapshot = Snapshot::take(state);
+    let path = rdb_path(state);
+    let p = &mut state.persistence;
+    p.dirty_at_bgsave = p.dirty;
+    p.bgsave_start = now_ms();
+    p.last_bgsave_try = p.bgsave_start / 1000;
+    p.bgsave = Some(std::thread::spawn(move || snapshot.save(&path)));
+    println!("Background saving started");
+    Ok(())
+}
+
+/// Periodic work: reaps a finished background save and starts a new one
+/// when a save rule or a BGSAVE SCHEDULE calls for it.
+pub fn cron(state: &mut dbstate) {
+    let now = now_ms() / 1000;
+    let p = &mut state.persistence;
+    if p.bgsave.as_ref().is_some_and(|h| h.is_finished()) {
+        let result = p.bgsave.take().unwrap().join().unwrap_or_else(|_| Err(anyhow!("background save panicked")));
+        p.last_bgsave_time = Some((now_ms() - p.bgsave_start) / 1000);
+        match result {
+            Ok(()) => {
+                println!("Background saving terminated with success");
+                p.dirty -= p.dirty_at_bgsave;
+                p.lastsave = now;
+                p.last_bgsave_ok = true;
+                p.saves += 1;
+            }
+            Err(e) => {
+                eprintln!("Background saving error: {}", e);
+                p.last_bgsave_ok = false;
+            }
+        }
+    }
+    if p.bgsave_in_progress() {
+        return
+    }
+    let rule_hit = state.config.save.iter().any(|(seconds, changes)| {
+        p.dirty >= *changes && now.saturating_sub(p.lastsave) >= *seconds
+            && (p.last_bgsave_ok || now.saturating_sub(p.last_bgsave_try) > BGSAVE_RETRY_DELAY)
+    });
+    if rule_hit || p.bgsave_scheduled {
+        p.bgsave_scheduled = false;
+        let _ = bgsave(state);
+    }
+}
+
+/// Fields of the persistence section of INFO.
+pub fn info(state: &dbstate) -> Vec<(&'static str, String)> {
+    let p = &state.persistence;
+    let current = if p.bgsave_in_progress() { ((now_ms() - p.bgsave_start) / 1000) as i64 } else { -1 };
+    vec![
+        ("loading", "0".to_string()),
+        ("async_loading", "0".to_string()),
+        ("rdb_changes_since_last_save", p.dirty.to_string()),
+        ("rdb_bgsave_in_progress", (p.bgsave_in_progress() as u8).to_string()),
+        ("rdb_last_save_time", p.lastsave.to_string()),
+        ("rdb_last_bgsave_status", if p.last_bgsave_ok { "ok" } else { "err" }.to_string()),
+        ("rdb_last_bgsave_time_sec", p.last_bgsave_time.map_or(-1, |t| t as i64).to_string()),
+        ("rdb_current_bgsave_time_sec", current.to_string()),
+        ("rdb_saves", p.saves.to_string()),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::db;
+
+    #[test]
+    fn crc64_check_value() {
+        assert_eq!(crc64(b"123456789"), 0xe9c6_d914_c4b8_d9ca);
+    }
+
+    #[test]
+    fn lzf() {
+        assert_eq!(lzf_decompress(&[0x02, b'a', b'b', b'c'], 3).unwrap(), b"abc");
+        assert_eq!(lzf_decompress(&[0x03, b'a', b'b', b'c', b'd', 0x40, 0x03], 8).unwrap(), b"abcdabcd");
+        // The back reference overlaps the bytes it produces.
+        assert_eq!(lzf_decompress(&[0x02, b'a', b'b', b'c', 0xE0, 0x00, 0x02], 12).unwrap(), b"abcabcabcabc");
+        assert_eq!(lzf_decompress(&[0x00, b'x', 0xE0, 0xFF, 0x00, 0xE0, 26, 0x00], 300).unwrap(), vec![b'x'; 300]);
+        for corrupt in [&[0x40, 0x00][..], &[0x05, b'a'], &[0x00, b'a', 0x40]] {
+            assert!(lzf_decompress(corrupt, 2).is_err(), "{corrupt:?}");
+        }
+        assert!(lzf_decompress(&[0x02, b'a', b'b', b'c'], 4).is_err());
+    }
+
+    #[test]
+    fn encoded_strings() {
+        let lzf = [0xC0 | ENC_LZF, 7, 12, 0x02, b'a', b'b', b'c', 0xE0, 0x00, 0x02];
+        assert_eq!(Reader::new(&lzf).string().unwrap(), "abcabcabcabc");
+        assert_eq!(Reader::new(&[0xC0 | ENC_INT8, 0xFF]).string().unwrap(), "-1");
+        assert_eq!(Reader::new(&[0xC0 | ENC_INT16, 0x39, 0x30]).string().unwrap(), "12345");
+        assert_eq!(Reader::new(&[0x40, 0x02, b'h', b'i']).string().unwrap(), "hi");
+        assert!(Reader::new(&[0x05, b'a']).string().is_err());
+    }
+
+    #[test]
+    fn compact_encodings() {
+        assert_eq!(intset(&[2, 0, 0, 0, 2, 0, 0, 0, 0xFF, 0xFF, 0x07, 0x00]).unwrap(), ["-1", "7"]);
+        let lp = [0, 0, 0, 0, 2, 0, 0x05, 0x01, 0x82, b'a', b'b', 0x03, 0xC1, 0x00, 0x02, 0xFF];
+        assert_eq!(listpack(&lp).unwrap(), ["5", "ab", "256"]);
+        let zl = [0, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0x00, 0x02, b'h', b'i', 0x04, 0xF3, 0xFF];
+        assert_eq!(ziplist(&zl).unwrap(), ["hi", "2"]);
+    }
+
+    fn string(buf: &mut Vec<u8>, s: &str) {
+        buf.push(s.len() as u8);
+        buf.extend_from_slice(s.as_bytes());
+    }
+
+    #[tokio::test]
+    async fn load_snapshot() {
+        let mut data = b"REDIS0009".to_vec();
+        data.push(OPCODE_AUX);
+        string(&mut data, "redis-ver");
+        string(&mut data, "7.2.0");
+        data.extend_from_slice(&[OPCODE_SELECTDB, 0, OPCODE_RESIZEDB, 3, 1]);
+        data.push(OPCODE_EXPIRETIME_MS);
+        data.extend_from_slice(&(now_ms() + 60_000).to_le_bytes());
+        data.push(TYPE_STRING);
+        string(&mut data, "k");
+        string(&mut data, "v");
+        data.push(OPCODE_EXPIRETIME_MS);
+        data.extend_from_slice(&1u64.to_le_bytes());
+        data.push(TYPE_STRING);
+        string(&mut data, "gone");
+        string(&mut data, "v");
+        data.push(TYPE_LIST);
+        string(&mut data, "l");
+        data.push(2);
+        string(&mut data, "a");
+        string(&mut data, "b");
+        data.push(OPCODE_EOF);
+        data.extend_from_slice(&crc64(&data).to_le_bytes());
+
+        let path = std::env::temp_dir().join(format!("rdb-load-{}.rdb", std::process::id()));
+        fs::write(&path, &data).unwrap();
+        let db = db::new();
+        let mut state = db.state.lock().await;
+        assert_eq!(load(&path, &mut state).unwrap(), 2);
+        assert!(matches!(&state.kv["k"], key_value::String(v) if v == "v"));
+        assert!(matches!(&state.kv["l"], key_value::List(l) if l == &["a", "b"]));
+        assert!(state.expires.contains_key("k") && !state.kv.contains_key("gone"));
+
+        let last = data.len() - 1;
+        data[last] ^= 1;
+        fs::write(&path, &data).unwrap();
+        assert!(load(&path, &mut state).unwrap_err().to_string().starts_with("Wrong RDB checksum"));
+        fs::write(&path, b"REDIS0099").unwrap();
+        assert!(load(&path, &mut state).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("rdb-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[tokio::test]
+    async fn save_and_load_round_trip() {
+        let db = db::new();
+        let mut state = db.state.lock().await;
+        let dir = temp_dir("round-trip");
+        state.config.dir = dir.display().to_string();
+        let list: Vec<String> = (0..NODE_MAX_ENTRIES * 2 + 1).map(|i| i.to_string()).collect();
+        state.kv.insert("s".to_string(), key_value::String(crate::resp::bytes_to_string(b"\xc3\xa9\xff\r\n\0")));
+        state.kv.insert("l".to_string(), key_value::List(list.clone()));
+        state.kv.insert("set".to_string(), key_value::Set(["a".to_string(), "1".to_string()].into()));
+        state.kv.insert("h".to_string(), key_value::Hash([("f".to_string(), "v".to_string())].into()));
+        state.kv.insert("z".to_string(), key_value::ZSet([("m".to_string(), 1.5), ("inf".to_string(), f64::INFINITY)].into()));
+        let mut stream = Stream::new();
+        stream.insert(StreamId::new(1, 1), vec![("f".to_string(), "v".to_string())]);
+        stream.insert(StreamId::new(2, 0), vec![("g".to_string(), "w".to_string())]);
+        stream.groups.insert("g".to_string(), crate::stream::ConsumerGroup::new(StreamId::new(1, 1), Some(1)));
+        state.kv.insert("x".to_string(), key_value::Stream(stream));
+        state.expires.insert("s".to_string(), now_ms() + 60_000);
+        state.kv.insert("old".to_string(), key_value::String("v".to_string()));
+        state.expires.insert("old".to_string(), 1);
+        state.persistence.dirty = 3;
+        save(&mut state).unwrap();
+        assert_eq!(state.persistence.dirty, 0);
+
+        let loaded = db::new();
+        let mut loaded = loaded.state.lock().await;
+        assert_eq!(load(&rdb_path(&state), &mut loaded).unwrap(), 6);
+        assert!(matches!(&loaded.kv["s"], key_value::String(v) if v.as_str() == crate::resp::bytes_to_string(b"\xc3\xa9\xff\r\n\0")));
+        assert_eq!(loaded.expires["s"], state.expires["s"]);
+        assert!(matches!(&loaded.kv["l"], key_value::List(l) if *l == list));
+        assert!(matches!(&loaded.kv["set"], key_value::Set(s) if s.len() == 2 && s.contains("a")));
+        assert!(matches!(&loaded.kv["h"], key_value::Hash(h) if h["f"] == "v"));
+        assert!(matches!(&loaded.kv["z"], key_value::ZSet(z) if z["m"] == 1.5 && z["inf"] == f64::INFINITY));
+        let key_value::Stream(stream) = &loaded.kv["x"] else { panic!("not a stream") };
+        assert_eq!((stream.len(), stream.last_id()), (2, StreamId::new(2, 0)));
+        assert_eq!(stream.get(StreamId::new(1, 1)).unwrap(), [("f".to_string(), "v".to_string())]);
+        assert_eq!(stream.groups["g"].last_delivered, StreamId::new(1, 1));
+        assert!(!loaded.kv.contains_key("old"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn bgsave_is_reaped_by_cron() {
+        let db = db::new();
+        let mut state = db.state.lock().await;
+        let dir = temp_dir("bgsave");
+        state.config.dir = dir.display().to_string();
+        state.config.save = Vec::new();
+        state.kv.insert("k".to_string(), key_value::String("v".to_string()));
+        state.persistence.dirty = 1;
+        bgsave(&mut state).unwrap();
+        assert!(bgsave(&mut state).is_err() && save(&mut state).is_err());
+        state.persistence.dirty += 1;
+        while state.persistence.bgsave_in_progress() {
+            cron(&mut state);
+            std::thread::yield_now();
+        }
+        // The write made during the save is still to be persisted.
+        assert_eq!((state.persistence.dirty, state.persistence.last_bgsave_ok), (1, true));
+        assert!(rdb_path(&state).exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn dump_round_trip_keeps_bytes() {
+        let value = crate::resp::bytes_to_string(b"\x00\xff\xfe\r\n\xc3\xa9");
+        let payload = dump(&key_value::String(value.clone()));
+        assert_eq!(&payload[..8], b"\x00\x07\x00\xff\xfe\r\n\xc3");
+        assert!(matches!(undump(&payload), Ok(key_value::String(s)) if s == value));
+
+        let mut corrupt = payload.clone();
+        corrupt[2] ^= 1;
+        assert!(undump(&corrupt).is_err());
+        assert!(undump(&payload[..9]).is_err());
+    }
+}
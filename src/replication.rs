@@ -0,0 +1,648 @@
+use std::{collections::{HashMap, VecDeque}, hash::{BuildHasher, Hasher}, time::Duration};
+
+use anyhow::{anyhow, Error};
+use tokio::{net::TcpStream, sync::mpsc, task::AbortHandle};
+
+use crate::{aof, commands::{execute, lookup}, database::{db, dbstate, BlockedOp, Reply}, handlers::{extract_command, unpack_bulk_str}, rdb::{self, Snapshot}, resp::{RespHandler, Value}, stream::now_ms};
+
+/// Milliseconds between attempts to reach the master.
+const CONNECT_RETRY_DELAY: u64 = 1000;
+/// How often a replica reports its offset to its master.
+const ACK_PERIOD: Duration = Duration::from_secs(1);
+/// Smallest backlog kept whatever repl-backlog-size says.
+const REPL_BACKLOG_MIN_SIZE: u64 = 16 * 1024;
+/// The secondary ID when there is none.
+const NO_REPLID: &str = "0000000000000000000000000000000000000000";
+
+/// Replication state. A master streams the commands it propagates to its
+/// replicas; a replica keeps a link to its master, loads the snapshot the
+/// master sends on sync and then applies the master's stream.
+pub struct Replication {
+    /// Names the history of the dataset; replicas take their master's.
+    pub replid: String,
+    /// Bytes of replication stream produced as a master, or applied as a
+    /// replica.
+    pub offset: u64,
+    /// The ID this history had before the last switch of master, valid up
+    /// to `second_offset`, so replicas of the old master can continue with
+    /// us after a failover.
+    replid2: String,
+    second_offset: Option<u64>,
+    /// Created once there is a stream to keep: when the first replica
+    /// attaches, or on sync as a replica. Offsets only advance with it.
+    backlog: Option<Backlog>,
+    /// Database our stream's last SELECT chose, as a master; `None` when
+    /// the next command has to select its database again.
+    pub selected_db: Option<usize>,
+    /// Database the master's stream is in, as a replica.
+    pub master_db: usize,
+    master: Option<MasterLink>,
+    replicas: HashMap<u64, ReplicaHandle>,
+}
+
+/// The tail of the replication stream, ending at `Replication::offset`, so
+/// a replica that reconnects can resume from its offset rather than sync
+/// from scratch.
+struct Backlog {
+    buf: VecDeque<u8>,
+    size: usize,
+}
+
+impl Backlog {
+    fn new(size: u64) -> Self {
+        Backlog { buf: VecDeque::new(), size: size.max(REPL_BACKLOG_MIN_SIZE) as usize }
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        self.buf.extend(bytes);
+        self.trim();
+    }
+
+    fn trim(&mut self) {
+        if self.buf.len() > self.size {
+            let excess = self.buf.len() - self.size;
+            self.buf.drain(..excess);
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum LinkState {
+    /// Waiting for `cron` to connect.
+    Connect,
+    /// Connected and going through the handshake.
+    Handshake,
+    /// Receiving the snapshot.
+    Transfer,
+    Connected,
+}
+
+struct MasterLink {
+    host: String,
+    port: u16,
+    state: LinkState,
+    /// Unix milliseconds of the last connection attempt.
+    last_attempt: u64,
+    /// Unix milliseconds of the last data received from the master.
+    last_io: u64,
+    task: Option<AbortHandle>,
+}
+
+impl Drop for MasterLink {
+    fn drop(&mut self) {
+        if let Some(task) = &self.task {
+            task.abort();
+        }
+    }
+}
+
+/// A replica connected to us.
+struct ReplicaHandle {
+    /// Stream bytes for the connection to write.
+    tx: mpsc::UnboundedSender<Vec<u8>>,
+    ip: String,
+    /// The port the replica listens on, from REPLCONF listening-port.
+    port: u16,
+    /// Past the snapshot and receiving the stream.
+    online: bool,
+    /// Offset from the last REPLCONF ACK.
+    ack_offset: u64,
+    /// Unix milliseconds of the last REPLCONF ACK.
+    last_ack: u64,
+}
+
+/// 40 random hex characters.
+pub fn random_id() -> String {
+    (0..3).map(|i| {
+        let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+        hasher.write_u64(now_ms() ^ i);
+        format!("{:016x}", hasher.finish())
+    }).collect::<String>()[..40].to_string()
+}
+
+impl Replication {
+    pub fn new() -> Self {
+        Replication {
+            replid: random_id(),
+            offset: 0,
+            replid2: NO_REPLID.to_string(),
+            second_offset: None,
+            backlog: None,
+            selected_db: None,
+            master_db: 0,
+            master: None,
+            replicas: HashMap::new(),
+        }
+    }
+
+    pub fn is_replica(&self) -> bool {
+        self.master.is_some()
+    }
+
+    /// Whether the link to the master is up and past the sync.
+    pub fn link_up(&self) -> bool {
+        self.master.as_ref().is_some_and(|m| m.state == LinkState::Connected)
+    }
+
+    /// Makes this instance a replica of `host:port`; `cron` connects.
+    /// Replicas of ours are dropped so they sync again with the new data.
+    pub fn set_master(&mut self, host: String, port: u16) {
+        self.master = Some(MasterLink { host, port, state: LinkState::Connect, last_attempt: 0, last_io: 0, task: None });
+        self.replicas.clear();
+    }
+
+    /// Whether `host:port` is already the master.
+    pub fn is_master(&self, host: &str, port: u16) -> bool {
+        self.master.as_ref().is_some_and(|m| m.host == host && m.port == port)
+    }
+
+    /// REPLICAOF NO ONE: drops the link and starts a new history, since
+    /// writes may now diverge from the old master's. Our replicas are
+    /// dropped to learn the new ID, and can continue from where they were.
+    pub fn become_master(&mut self) {
+        self.master = None;
+        self.selected_db = None;
+        self.shift_replid(random_id());
+        self.replicas.clear();
+    }
+
+    /// Database the stream is in at its current offset, for a snapshot
+    /// that a replica continues the stream from.
+    pub fn stream_db(&self) -> usize {
+        if self.is_replica() { self.master_db } else { self.selected_db.unwrap_or(0) }
+    }
+
+    /// Switches to a new ID, keeping the old one valid up to our offset.
+    fn shift_replid(&mut self, replid: String) {
+        self.replid2 = std::mem::replace(&mut self.replid, replid);
+        self.second_offset = Some(self.offset + 1);
+        println!("Setting secondary replication ID to {}, valid up to offset: {}. New replication ID is {}", self.replid2, self.offset + 1, self.replid);
+    }
+
+    /// Starts a new history after a full sync from a master.
+    fn reset_history(&mut self, replid: String, offset: u64, backlog_size: u64) {
+        self.replid = replid;
+        self.offset = offset;
+        self.replid2 = NO_REPLID.to_string();
+        self.second_offset = None;
+        self.backlog = Some(Backlog::new(backlog_size));
+    }
+
+    pub fn resize_backlog(&mut self, size: u64) {
+        if let Some(backlog) = &mut self.backlog {
+            backlog.size = size.max(REPL_BACKLOG_MIN_SIZE) as usize;
+            backlog.trim();
+        }
+    }
+
+    /// Offset of the oldest byte in the backlog.
+    fn first_byte_offset(&self) -> u64 {
+        self.offset + 1 - self.backlog.as_ref().map_or(0, |b| b.buf.len() as u64)
+    }
+
+    /// The stream from `from` on, if PSYNC `replid` `from` names a point of
+    /// our history that is still in the backlog.
+    fn backlog_since(&self, replid: &str, from: u64) -> Option<Vec<u8>> {
+        let backlog = self.backlog.as_ref()?;
+        let ours = replid == self.replid || (replid == self.replid2 && self.second_offset.is_some_and(|o| from <= o));
+        if !ours || from < self.first_byte_offset() || from > self.offset + 1 {
+            return None
+        }
+        let skip = (from - self.first_byte_offset()) as usize;
+        Some(backlog.buf.range(skip..).copied().collect())
+    }
+
+    /// Appends RESP bytes to the stream and hands them to every replica.
+    pub fn feed(&mut self, bytes: &[u8]) {
+        let Some(backlog) = &mut self.backlog else { return };
+        backlog.push(bytes);
+        self.offset += bytes.len() as u64;
+        for replica in self.replicas.values() {
+            let _ = replica.tx.send(bytes.to_vec());
+        }
+    }
+
+    /// Replicas that acknowledged at least `offset`.
+    pub fn acked(&self, offset: u64) -> usize {
+        self.replicas.values().filter(|r| r.online && r.ack_offset >= offset).count()
+    }
+
+    /// Asks every replica for its offset right away.
+    pub fn request_acks(&mut self) {
+        self.feed(&aof::encode(&["REPLCONF".to_string(), "GETACK".to_string(), "*".to_string()]));
+    }
+}
+
+impl Default for Replication {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Unblocks every blocked client with an error: once a replica, the writes
+/// they wait for come from the master, which serves its own clients.
+pub fn unblock_all(state: &mut dbstate) {
+    let ids: Vec<u64> = state.blocked_clients.keys().copied().collect();
+    for id in ids {
+        if let Some(client) = state.unblock(id) {
+            let _ = client.reply.send(Value::SimpleError("UNBLOCKED force unblock from blocking operation, instance state changed (master -> replica?)".to_string()));
+        }
+    }
+}
+
+/// Replies to WAITs that enough replicas have now acknowledged.
+fn serve_waits(state: &mut dbstate) {
+    let served: Vec<(u64, usize)> = state.blocked_clients.iter().filter_map(|(id, client)| match client.op {
+        BlockedOp::Wait { offset, numreplicas } => {
+            let acked = state.replication.acked(offset);
+            (acked >= numreplicas).then_some((*id, acked))
+        }
+        _ => None
+    }).collect();
+    for (id, acked) in served {
+        if let Some(client) = state.unblock(id) {
+            let _ = client.reply.send(Value::Integer(acked as i64));
+        }
+    }
+}
+
+/// Periodic work: starts connecting to the master when the link is down.
+pub fn cron(redisdb: &db, state: &mut dbstate) {
+    let now = now_ms();
+    let Some(link) = &mut state.replication.master else { return };
+    if link.state != LinkState::Connect || now - link.last_attempt < CONNECT_RETRY_DELAY {
+        return
+    }
+    println!("Connecting to MASTER {}:{}", link.host, link.port);
+    link.last_attempt = now;
+    link.state = LinkState::Handshake;
+    let (host, port, my_port) = (link.host.clone(), link.port, state.config.port);
+    let redisdb = redisdb.clone();
+    let task = tokio::spawn(async move {
+        let result = sync_with_master(&redisdb, &host, port, my_port).await;
+        let mut lock = redisdb.state.lock().await;
+        if let Err(e) = result {
+            eprintln!("Error with the link to MASTER {}:{}: {}", host, port, e);
+        }
+        if let Some(link) = &mut lock.replication.master {
+            link.state = LinkState::Connect;
+        }
+    });
+    link.task = Some(task.abort_handle());
+}
+
+fn command(args: &[&str]) -> Value {
+    Value::Array(args.iter().map(Value::bulk).collect())
+}
+
+/// Sends a handshake command and checks the reply is a simple string.
+async fn handshake(handler: &mut RespHandler, args: &[&str]) -> Result<String, Error> {
+    handler.write_value(command(args)).await?;
+    match handler.read_value().await? {
+        Some(Value::SimpleString(s)) => Ok(s),
+        Some(Value::SimpleError(e)) => Err(anyhow!("Error reply to {}: {}", args[0], e)),
+        Some(_) => Err(anyhow!("Unexpected reply to {}", args[0])),
+        None => Err(anyhow!("Connection lost during the handshake"))
+    }
+}
+
+/// The replica side of the link: handshake, a partial resync from where we
+/// left off or else a full sync, then applying the master's stream until
+/// the connection drops.
+async fn sync_with_master(redisdb: &db, host: &str, port: u16, my_port: u16) -> Result<(), Error> {
+    let mut handler = RespHandler::new(TcpStream::connect((host, port)).await?);
+    println!("MASTER <-> REPLICA sync started");
+    handshake(&mut handler, &["PING"]).await?;
+    handshake(&mut handler, &["REPLCONF", "listening-port", &my_port.to_string()]).await?;
+    handshake(&mut handler, &["REPLCONF", "capa", "psync2"]).await?;
+    // With no backlog there is no history of ours a master could continue.
+    let (replid, from) = {
+        let lock = redisdb.state.lock().await;
+        let r = &lock.replication;
+        match r.backlog {
+            Some(_) => (r.replid.clone(), (r.offset + 1).to_string()),
+            None => ("?".to_string(), "-1".to_string())
+        }
+    };
+    let reply = handshake(&mut handler, &["PSYNC", &replid, &from]).await?;
+    let (replid, offset) = match reply.split_whitespace().collect::<Vec<_>>()[..] {
+        ["FULLRESYNC", replid, offset] => (replid.to_string(), offset.parse::<u64>()?),
+        ["CONTINUE", ref rest @ ..] if rest.len() <= 1 => {
+            let mut lock = redisdb.state.lock().await;
+            let r = &mut lock.replication;
+            if let Some(&new_id) = rest.first() && new_id != r.replid {
+                // The master was promoted from a fellow replica.
+                r.shift_replid(new_id.to_string());
+                r.replicas.clear();
+            }
+            if let Some(link) = &mut r.master {
+                link.state = LinkState::Connected;
+                link.last_io = now_ms();
+            }
+            println!("MASTER <-> REPLICA sync: Master accepted a Partial Resynchronization.");
+            drop(lock);
+            return apply_stream(redisdb, &mut handler).await
+        }
+        _ => return Err(anyhow!("Unexpected reply to PSYNC: {}", reply))
+    };
+    println!("Full resync from master: {}:{}", replid, offset);
+    set_link_state(redisdb, LinkState::Transfer).await;
+    let data = handler.read_rdb().await?;
+    println!("MASTER <-> REPLICA sync: receiving {} bytes from master", data.len());
+    {
+        let mut lock = redisdb.state.lock().await;
+        let state = &mut *lock;
+        state.flush_all(false);
+        // Unless the snapshot says otherwise, the stream starts in database 0.
+        state.replication.master_db = 0;
+        rdb::load_bytes(&data, state)?;
+        let r = &mut state.replication;
+        r.reset_history(replid, offset, state.config.repl_backlog_size);
+        // Our own replicas have the old data.
+        r.replicas.clear();
+        if let Some(link) = &mut r.master {
+            link.state = LinkState::Connected;
+            link.last_io = now_ms();
+        }
+        if state.aof.is_on() {
+            // The AOF has to start over from the new dataset.
+            aof::stop(state);
+            aof::start(state)?;
+        }
+        println!("MASTER <-> REPLICA sync: Finished with success");
+    }
+    apply_stream(redisdb, &mut handler).await
+}
+
+/// Applies the master's stream and acknowledges it, once a second and when
+/// asked with REPLCONF GETACK.
+async fn apply_stream(redisdb: &db, handler: &mut RespHandler) -> Result<(), Error> {
+    let mut ack = tokio::time::interval(ACK_PERIOD);
+    // MULTI ... EXEC from the master is applied at once.
+    let mut transaction: Option<Vec<Vec<String>>> = None;
+    loop {
+        let value = tokio::select! {
+            value = handler.read_value() => value?,
+            _ = ack.tick() => {
+                let offset = redisdb.state.lock().await.replication.offset;
+                handler.write_value(command(&["REPLCONF", "ACK", &offset.to_string()])).await?;
+                continue;
+            }
+        };
+        let Some(value) = value else { return Err(anyhow!("Connection with master lost")) };
+        let (name, args) = extract_command(value)?;
+        let mut argv = vec![name];
+        argv.extend(unpack_bulk_str(&args)?);
+        let bytes = aof::encode(&argv);
+        let mut lock = redisdb.state.lock().await;
+        let state = &mut *lock;
+        if let Some(link) = &mut state.replication.master {
+            link.last_io = now_ms();
+        }
+        let name = argv[0].to_uppercase();
+        match (name.as_str(), &mut transaction) {
+            ("REPLCONF", _) if argv.get(1).is_some_and(|a| a.eq_ignore_ascii_case("GETACK")) => {
+                // The offset acknowledged doesn't include the GETACK itself.
+                let offset = state.replication.offset.to_string();
+                state.replication.feed(&bytes);
+                drop(lock);
+                handler.write_value(command(&["REPLCONF", "ACK", &offset])).await?;
+                continue;
+            }
+            ("MULTI", None) => transaction = Some(Vec::new()),
+            ("EXEC", Some(_)) => {
+                for queued in transaction.take().unwrap() {
+                    apply(&queued, state);
+                }
+                state.flush_propagated(true);
+            }
+            (_, Some(queued)) => queued.push(argv),
+            (_, None) => {
+                apply(&argv, state);
+                state.flush_propagated(false);
+            }
+        }
+        // Replicas of ours get the master's stream as is.
+        state.replication.feed(&bytes);
+    }
+}
+
+async fn set_link_state(redisdb: &db, link_state: LinkState) {
+    if let Some(link) = &mut redisdb.state.lock().await.replication.master {
+        link.state = link_state;
+    }
+}
+
+/// Runs a command from the master. Its reply goes nowhere.
+fn apply(argv: &[String], state: &mut dbstate) {
+    let name = argv[0].to_uppercase();
+    if lookup(&name, &argv[1..]).is_err() {
+        eprintln!("Unknown command '{}' from MASTER", argv[0]);
+        return
+    }
+    let selected = state.db;
+    state.select(state.replication.master_db);
+    if let Ok(Reply::Blocked { id, .. }) = execute(&name, &argv[1..], state) {
+        state.unblock(id);
+    }
+    state.replication.master_db = state.db;
+    state.select(selected);
+}
+
+/// How a PSYNC is answered.
+enum Sync {
+    /// The stream from the replica's offset, out of the backlog.
+    Partial(Vec<u8>),
+    /// A snapshot with the stream from the moment it was taken.
+    Full(Snapshot),
+}
+
+/// The master side of PSYNC replid offset: resumes the replica's stream
+/// from the backlog when its history is ours and recent enough, or sends a
+/// snapshot first. Then streams what we propagate until the replica
+/// disconnects, reading its REPLCONF ACKs along the way.
+pub async fn serve_replica(handler: &mut RespHandler, id: u64, port: u16, psync: &[String], redisdb: &db) -> Result<(), Error> {
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let ip = handler.peer_addr().map_or_else(|| "?".to_string(), |a| a.ip().to_string());
+    let (replid, offset, sync) = {
+        let mut lock = redisdb.state.lock().await;
+        let state = &mut *lock;
+        let r = &mut state.replication;
+        if r.is_replica() && !r.link_up() {
+            return Err(anyhow!("NOMASTERLINK Can't SYNC while not connected with my master"))
+        }
+        let from = psync[1].parse::<u64>().ok();
+        let sync = match from.and_then(|from| r.backlog_since(&psync[0], from)) {
+            Some(bytes) => {
+                println!("Partial resynchronization request from {}:{} accepted. Sending {} bytes of backlog starting from offset {}.",
+                    ip, port, bytes.len(), from.unwrap_or(0));
+                Sync::Partial(bytes)
+            }
+            None => {
+                println!("Replica {}:{} asks for synchronization", ip, port);
+                if r.backlog.is_none() {
+                    r.backlog = Some(Backlog::new(state.config.repl_backlog_size));
+                }
+                // So the stream selects a database again for the replica.
+                if !r.is_replica() {
+                    r.selected_db = None;
+                }
+                Sync::Full(Snapshot::take(state))
+            }
+        };
+        // Registered under the same lock as the snapshot, so the stream
+        // picks up exactly where the snapshot, or the backlog, ends.
+        let online = matches!(sync, Sync::Partial(_));
+        let r = &mut state.replication;
+        let handle = ReplicaHandle { tx, ip: ip.clone(), port, online, ack_offset: 0, last_ack: now_ms() };
+        r.replicas.insert(id, handle);
+        (r.replid.clone(), r.offset, sync)
+    };
+    let result = async {
+        match sync {
+            Sync::Partial(bytes) => {
+                handler.write_value(Value::SimpleString(format!("CONTINUE {}", replid))).await?;
+                handler.write_bytes(&bytes).await?;
+            }
+            Sync::Full(snapshot) => {
+                handler.write_value(Value::SimpleString(format!("FULLRESYNC {} {}", replid, offset))).await?;
+                let data = tokio::task::spawn_blocking(move || snapshot.encode(false)).await?;
+                handler.write_bytes(format!("${}\r\n", data.len()).as_bytes()).await?;
+                handler.write_bytes(&data).await?;
+                if let Some(replica) = redisdb.state.lock().await.replication.replicas.get_mut(&id) {
+                    replica.online = true;
+                }
+                println!("Synchronization with replica succeeded");
+            }
+        }
+        loop {
+            tokio::select! {
+                bytes = rx.recv() => match bytes {
+                    Some(bytes) => handler.write_bytes(&bytes).await?,
+                    // Dropped from the replica list.
+                    None => return Ok(())
+                },
+                value = handler.read_value() => {
+                    let Some(value) = value? else { return Ok(()) };
+                    let (name, args) = extract_command(value)?;
+                    let args = unpack_bulk_str(&args)?;
+                    if name.eq_ignore_ascii_case("REPLCONF") && args.len() == 2 && args[0].eq_ignore_ascii_case("ACK") {
+                        let mut lock = redisdb.state.lock().await;
+                        if let Some(replica) = lock.replication.replicas.get_mut(&id) {
+                            replica.ack_offset = args[1].parse().unwrap_or(replica.ack_offset);
+                            replica.last_ack = now_ms();
+                        }
+                        serve_waits(&mut lock);
+                    }
+                }
+            }
+        }
+    }.await;
+    redisdb.state.lock().await.replication.replicas.remove(&id);
+    println!("Connection with replica {}:{} lost", ip, port);
+    result
+}
+
+/// The replication section of INFO.
+pub fn info(state: &dbstate) -> Vec<(String, String)> {
+    let r = &state.replication;
+    let now = now_ms();
+    let mut fields = Vec::new();
+    let mut field = |name: &str, value: String| fields.push((name.to_string(), value));
+    match &r.master {
+        Some(link) => {
+            field("role", "slave".to_string());
+            field("master_host", link.host.clone());
+            field("master_port", link.port.to_string());
+            field("master_link_status", if link.state == LinkState::Connected { "up" } else { "down" }.to_string());
+            let io = if link.state == LinkState::Connected { ((now - link.last_io) / 1000) as i64 } else { -1 };
+            field("master_last_io_seconds_ago", io.to_string());
+            field("master_sync_in_progress", ((link.state == LinkState::Transfer) as u8).to_string());
+            field("slave_read_repl_offset", r.offset.to_string());
+            field("slave_repl_offset", r.offset.to_string());
+            field("slave_priority", "100".to_string());
+            field("slave_read_only", (state.config.replica_read_only as u8).to_string());
+            field("replica_announced", "1".to_string());
+        }
+        None => field("role", "master".to_string())
+    }
+    field("connected_slaves", r.replicas.len().to_string());
+    let mut replicas: Vec<_> = r.replicas.iter().collect();
+    replicas.sort_by_key(|(id, _)| **id);
+    for (i, (_, replica)) in replicas.into_iter().enumerate() {
+        let state = if replica.online { "online" } else { "wait_bgsave" };
+        field(&format!("slave{}", i), format!("ip={},port={},state={},offset={},lag={}",
+            replica.ip, replica.port, state, replica.ack_offset, (now - replica.last_ack) / 1000));
+    }
+    field("master_replid", r.replid.clone());
+    field("master_replid2", r.replid2.clone());
+    field("master_repl_offset", r.offset.to_string());
+    field("second_repl_offset", r.second_offset.map_or(-1, |o| o as i64).to_string());
+    field("repl_backlog_active", (r.backlog.is_some() as u8).to_string());
+    field("repl_backlog_size", r.backlog.as_ref().map_or(state.config.repl_backlog_size, |b| b.size as u64).to_string());
+    field("repl_backlog_first_byte_offset", if r.backlog.is_some() { r.first_byte_offset() } else { 0 }.to_string());
+    field("repl_backlog_histlen", r.backlog.as_ref().map_or(0, |b| b.buf.len()).to_string());
+    fields
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_backlog(size: u64) -> Replication {
+        let mut r = Replication::new();
+        r.reset_history("a".repeat(40), 0, size);
+        r
+    }
+
+    #[test]
+    fn backlog_serves_known_offsets() {
+        let mut r = with_backlog(0);
+        r.feed(b"hello");
+        r.feed(b"world");
+        assert_eq!(r.offset, 10);
+        let replid = r.replid.clone();
+        assert_eq!(r.backlog_since(&replid, 1).unwrap(), b"helloworld");
+        assert_eq!(r.backlog_since(&replid, 6).unwrap(), b"world");
+        assert_eq!(r.backlog_since(&replid, 11).unwrap(), b"");
+        assert!(r.backlog_since(&replid, 12).is_none());
+        assert!(r.backlog_since(&"b".repeat(40), 1).is_none());
+    }
+
+    #[test]
+    fn backlog_drops_its_oldest_bytes() {
+        let mut r = with_backlog(0);
+        let chunk = vec![b'x'; REPL_BACKLOG_MIN_SIZE as usize];
+        r.feed(&chunk);
+        r.feed(b"tail");
+        let replid = r.replid.clone();
+        assert_eq!(r.first_byte_offset(), 5);
+        assert!(r.backlog_since(&replid, 4).is_none());
+        assert_eq!(r.backlog_since(&replid, r.offset - 3).unwrap(), b"tail");
+    }
+
+    #[test]
+    fn secondary_replid_is_valid_up_to_the_switch() {
+        let mut r = with_backlog(0);
+        r.feed(b"before");
+        let old = r.replid.clone();
+        r.become_master();
+        r.feed(b"after");
+        assert_ne!(r.replid, old);
+        assert_eq!(r.second_offset, Some(7));
+        assert_eq!(r.backlog_since(&old, 7).unwrap(), b"after");
+        assert_eq!(r.backlog_since(&old, 3).unwrap(), b"foreafter");
+        assert!(r.backlog_since(&old, 8).is_none());
+        let new = r.replid.clone();
+        assert_eq!(r.backlog_since(&new, 7).unwrap(), b"after");
+    }
+
+    #[test]
+    fn no_backlog_no_stream() {
+        let mut r = Replication::new();
+        r.feed(b"dropped");
+        assert_eq!(r.offset, 0);
+        let replid = r.replid.clone();
+        assert!(r.backlog_since(&replid, 1).is_none());
+    }
+}
@@ -4,38 +4,73 @@ use anyhow::{Error, Ok, Result};
 use bytes::{BytesMut, buf};
 use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpStream};
 
-#[derive(Clone,Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum Value{
     SimpleString(String),
-    BulkString(String),
+    BulkString(Vec<u8>),
     NullBulkString,
+    NullArray,
     Array(Vec<Value>),
-    Integer(u32),
+    Integer(i64),
     BulkError(String),
-    EmptyArray
+    SimpleError(String),
+    EmptyArray,
+    /// RESP3 out-of-band data, such as pub/sub messages and invalidations.
+    Push(Vec<Value>),
+    Map(Vec<(Value, Value)>)
 }
 
 impl Value {
-    pub fn serialize(&self) -> String {
+    /// A bulk string holding `s`, which is one of our byte strings.
+    pub fn bulk(s: impl AsRef<str>) -> Value {
+        Value::BulkString(string_to_bytes(s.as_ref()))
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
         match self {
-            Value::SimpleString(s) => format!("+{}\r\n",s),
-            Value::BulkString(s) => format!("${}\r\n{}\r\n",s.chars().count(),s),
-            Value::NullBulkString => format!("$-1\r\n"),
-            Value::Integer(s) => format!(":{}\r\n", s),
+            Value::SimpleString(s) => format!("+{}\r\n",s).into_bytes(),
+            Value::BulkString(s) => [format!("${}\r\n", s.len()).as_bytes(), s, b"\r\n"].concat(),
+            Value::NullBulkString => b"$-1\r\n".to_vec(),
+            Value::NullArray => b"*-1\r\n".to_vec(),
+            Value::Integer(s) => format!(":{}\r\n", s).into_bytes(),
             Value::Array(s) => {
-                let mut v =  String::new();
-                for i in 0..s.len(){
-                    v.push_str(&s[i].serialize());
+                let mut v = format!("*{}\r\n", s.len()).into_bytes();
+                for item in s {
+                    v.extend(item.serialize());
                 }
-                format!("*{}\r\n{}",s.len(),v)
+                v
             },
-            Value::BulkError(s) => format!("!{}\r\n{}\r\n", s.chars().count(), s),
-            Value::EmptyArray => format!("*0\r\n"),
-            _ => panic!("Unsupported value for serialized")
+            Value::SimpleError(s) => format!("-{}\r\n", s).into_bytes(),
+            Value::BulkError(s) => format!("!{}\r\n{}\r\n", s.len(), s).into_bytes(),
+            Value::EmptyArray => b"*0\r\n".to_vec(),
+            Value::Push(s) => {
+                let mut v = format!(">{}\r\n", s.len()).into_bytes();
+                v.extend(s.iter().flat_map(|item| item.serialize()));
+                v
+            }
+            Value::Map(s) => {
+                let mut v = format!("%{}\r\n", s.len()).into_bytes();
+                v.extend(s.iter().flat_map(|(k, v)| [k.serialize(), v.serialize()].concat()));
+                v
+            }
         }
     }
 }
 
+/// Keys and values are kept as strings with one char per byte, so any bytes a
+/// client sends survive. This is the way in, at the protocol and file edges,
+/// and for text of our own that ends up next to client data, such as sentinel
+/// directives from the command line.
+pub fn bytes_to_string(bytes: &[u8]) -> String {
+    bytes.iter().map(|&b| b as char).collect()
+}
+
+/// The way out again. Every char is one byte, so a string's size on the wire
+/// is its `chars().count()`, not its `len()`.
+pub fn string_to_bytes(s: &str) -> Vec<u8> {
+    s.chars().map(|c| u8::try_from(c).unwrap_or(b'?')).collect()
+}
+
 pub struct RespHandler {
     stream: TcpStream,
     buffer: BytesMut
@@ -47,19 +82,93 @@ impl RespHandler {
     }
 
     pub async fn read_value(&mut self) -> Result<Option<Value>> {
-        let bytes_read = self.stream.read_buf(&mut self.buffer).await?;
+        loop {
+            if let Some(len) = frame_len(&self.buffer) {
+                let (v, _) = parse_message(self.buffer.split_to(len))?;
+                return Ok(Some(v))
+            }
+            let bytes_read = self.stream.read_buf(&mut self.buffer).await?;
 
-        if bytes_read == 0 {
-            return Ok(None)
+            if bytes_read == 0 {
+                return Ok(None)
+            }
         }
-
-        let (v, _) = parse_message(self.buffer.split())?;
-        Ok(Some(v))
     }
+    /// Resolves once the peer closes the connection, or it breaks. What the
+    /// peer sends meanwhile is kept for `read_value`.
+    pub async fn closed(&mut self) {
+        while let std::result::Result::Ok(n) = self.stream.read_buf(&mut self.buffer).await {
+            if n == 0 {
+                return
+            }
+        }
+    }
+
     pub async fn write_value(&mut self, value: Value) -> Result<(), Error>{
-        self.stream.write_all(value.serialize().as_bytes()).await?;
+        self.stream.write_all(&value.serialize()).await?;
         Ok(())
     }
+
+    /// Writes bytes that are already RESP, such as a replication stream.
+    pub async fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        self.stream.write_all(bytes).await?;
+        Ok(())
+    }
+
+    /// Reads an RDB payload sent as `$<len>\r\n` and the raw bytes, which
+    /// unlike a bulk string don't end with CRLF. Newlines a master sends to
+    /// keep the link alive before it are skipped.
+    pub async fn read_rdb(&mut self) -> Result<Vec<u8>> {
+        loop {
+            while self.buffer.first() == Some(&b'\n') {
+                let _ = self.buffer.split_to(1);
+            }
+            if let Some((line, header)) = self.buffer.get(1..).and_then(match_until_crlf) {
+                if self.buffer[0] != b'$' {
+                    return Err(anyhow::anyhow!("Bad protocol from MASTER, the first byte is not '$'"))
+                }
+                let len = parse_int(line)? as usize;
+                if self.buffer.len() >= header + 1 + len {
+                    let _ = self.buffer.split_to(header + 1);
+                    return Ok(self.buffer.split_to(len).to_vec())
+                }
+            }
+            if self.stream.read_buf(&mut self.buffer).await? == 0 {
+                return Err(anyhow::anyhow!("connection lost during the transfer"))
+            }
+        }
+    }
+
+    pub fn peer_addr(&self) -> Option<std::net::SocketAddr> {
+        self.stream.peer_addr().ok()
+    }
+}
+
+/// Length of the first complete frame in `buffer`, or `None` while more bytes
+/// are needed.
+fn frame_len(buffer: &[u8]) -> Option<usize> {
+    let (line, len) = match_until_crlf(buffer.get(1..)?)?;
+    let header = len + 1;
+    match buffer[0] {
+        // A length that doesn't parse is left for `parse_message` to report.
+        b'$' => {
+            let Some(len) = parse_int(line).ok() else { return Some(header) };
+            if len < 0 {
+                return Some(header)
+            }
+            let total = header + len as usize + 2;
+            (buffer.len() >= total).then_some(total)
+        }
+        b'*' => {
+            let mut consumed = header;
+            let Some(count) = parse_int(line).ok() else { return Some(header) };
+            for _ in 0..count {
+                consumed += frame_len(&buffer[consumed..])?;
+            }
+            Some(consumed)
+        }
+        _ => Some(header)
+    }
 }
 
 fn parse_message(buffer: BytesMut) -> Result<(Value, usize)> {
@@ -67,17 +176,25 @@ fn parse_message(buffer: BytesMut) -> Result<(Value, usize)> {
         '+' => parse_simple_string(buffer),
         '*' => parse_arrays(buffer),
         '$' => parse_bulk_strings(buffer),
-        _ =>  panic!("Not a known value type {}", buffer[0])
+        ':' => parse_simple_string(buffer).and_then(|(v, len)| match v {
+            Value::SimpleString(s) => Ok((Value::Integer(s.parse()?), len)),
+            v => Ok((v, len))
+        }),
+        '-' => parse_simple_string(buffer).map(|(v, len)| match v {
+            Value::SimpleString(s) => (Value::SimpleError(s), len),
+            v => (v, len)
+        }),
+        _ => Err(anyhow::anyhow!("unknown type byte '{}'", buffer[0].escape_ascii()))
     }
 }
 
 fn parse_simple_string(buffer: BytesMut) -> Result<(Value, usize)> {
     if let Some((line, len)) = match_until_crlf(&buffer[1..]){
-        let string = String::from_utf8(line.to_vec()).unwrap();
+        let string = String::from_utf8(line.to_vec())?;
 
-        return Ok((Value::SimpleString(string), len));
+        return Ok((Value::SimpleString(string), len + 1));
     }
-    return Err(anyhow::anyhow!("Invalid simple string {:?}", buffer));
+    Err(anyhow::anyhow!("Invalid simple string {:?}", buffer))
 }
 
 fn parse_bulk_strings(buffer: BytesMut) -> Result<(Value, usize)> {
@@ -87,9 +204,12 @@ fn parse_bulk_strings(buffer: BytesMut) -> Result<(Value, usize)> {
         } else {
             return Err(anyhow::anyhow!("Error invalid bulk string"));
         };
+    if string_length < 0 {
+        return Ok((Value::NullBulkString, bytes_consumed))
+    }
     let end_of_bulk_str = string_length as usize + bytes_consumed ;
     let total_parsed = end_of_bulk_str + 2;
-    Ok((Value::BulkString(String::from_utf8(buffer[bytes_consumed..end_of_bulk_str].to_vec())?), total_parsed))
+    Ok((Value::BulkString(buffer[bytes_consumed..end_of_bulk_str].to_vec()), total_parsed))
     
 }
 
@@ -100,13 +220,16 @@ fn parse_arrays(buffer: BytesMut) -> Result<(Value, usize)> {
     } else {
         return Err(anyhow::anyhow!("Error invalid array"));
     };
+    if array_length < 0 {
+        return Ok((Value::NullArray, bytes_consumed))
+    }
     let mut items = vec![];
     for _ in 0..array_length {
         let (item, length) = parse_message(BytesMut::from(&buffer[bytes_consumed..]))?;
         items.push(item);
         bytes_consumed += length;
     }
-    return Ok((Value::Array(items), bytes_consumed));
+    Ok((Value::Array(items), bytes_consumed))
 }
 
 pub fn parse_int(buffer: &[u8]) -> Result<i64, Error>{
@@ -119,5 +242,5 @@ fn match_until_crlf(buffer: &[u8]) -> Option<(&[u8], usize)> {
             return  Some((&buffer[..(i-1)], i+1));
         }
     }
-    return None
+    None
 }
\ No newline at end of file
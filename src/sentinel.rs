@@ -0,0 +1,1232 @@
+use std::{collections::{BTreeMap, HashMap, VecDeque, hash_map::RandomState}, hash::{BuildHasher, Hasher}};
+use anyhow::{anyhow, Error};
+use tokio::{net::TcpStream, sync::mpsc, task::AbortHandle};
+
+use crate::{database::{db, dbstate}, pubsub::PubSub, replication::random_id, resp::{bytes_to_string, RespHandler, Value}, stream::now_ms};
+
+pub const SENTINEL_PORT: u16 = 26379;
+/// Where sentinels announce themselves and the configuration they know,
+/// on every monitored master and replica.
+const HELLO_CHANNEL: &str = "__sentinel__:hello";
+
+const PING_PERIOD: u64 = 1000;
+const INFO_PERIOD: u64 = 10000;
+const PUBLISH_PERIOD: u64 = 2000;
+const ASK_PERIOD: u64 = 1000;
+const RECONNECT_PERIOD: u64 = 1000;
+/// A link is only replaced for missing pongs once it is this old.
+const MIN_LINK_RECONNECT_PERIOD: u64 = 15000;
+const SLAVE_RECONF_TIMEOUT: u64 = 10000;
+const ELECTION_TIMEOUT: u64 = 10000;
+/// Random delay added to failover attempts, so that sentinels seeing the
+/// master fail at once don't all ask for votes at once.
+const MAX_DESYNC: u64 = 1000;
+
+const DEFAULT_DOWN_AFTER: u64 = 30000;
+const DEFAULT_FAILOVER_TIMEOUT: u64 = 180000;
+const DEFAULT_PARALLEL_SYNCS: usize = 1;
+
+fn random_below(n: u64) -> u64 {
+    RandomState::new().build_hasher().finish() % n
+}
+
+/// What a reply on a command link answers.
+#[derive(Clone, Copy)]
+enum Request {
+    Ping,
+    Info,
+    IsMasterDown,
+    /// Replies nobody waits for, such as to REPLICAOF or SUBSCRIBE.
+    Other,
+}
+
+/// Our connection to an instance, written by a task that also reads its
+/// replies in order.
+struct Link {
+    tx: mpsc::UnboundedSender<(Vec<String>, Request)>,
+    /// Tells links apart, since an instance's link may be replaced while the
+    /// task of the old one is still winding down.
+    generation: u64,
+    ctime: u64,
+    task: AbortHandle,
+}
+
+impl Drop for Link {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// An instance of a monitored master's group, as links know it.
+#[derive(Clone, PartialEq)]
+enum Target {
+    Master,
+    /// By `ip:port`.
+    Replica(String),
+    /// By run ID.
+    Sentinel(String),
+}
+
+/// Where a replica is in being pointed at the promoted replica.
+#[derive(Clone, Copy, PartialEq)]
+enum Reconf {
+    None,
+    /// REPLICAOF sent at this time.
+    Sent(u64),
+    /// The replica reports the new master but isn't in sync yet.
+    InProgress,
+    Done,
+}
+
+/// A master, replica or other sentinel, and what we know of it.
+struct Instance {
+    ip: String,
+    port: u16,
+    /// For sentinels, the ID they announce in hellos.
+    run_id: String,
+    link: Option<Link>,
+    /// Subscribed to the hello channel; masters and replicas only.
+    pubsub: Option<Link>,
+    last_connect: u64,
+    /// When the oldest unanswered PING was sent, 0 if none is.
+    ping_sent: u64,
+    last_ping: u64,
+    /// Last valid reply to a PING; the instance is subjectively down once
+    /// this is older than down-after-milliseconds.
+    last_avail: u64,
+    sdown_since: u64,
+    last_info: u64,
+    /// Last INFO reply.
+    info_refresh: u64,
+    last_hello: u64,
+    /// `master` or `slave` as INFO last reported, and when that changed.
+    role: String,
+    role_reported_time: u64,
+    master_host: String,
+    master_port: u16,
+    master_link_up: bool,
+    offset: u64,
+    priority: u64,
+    reconf: Reconf,
+    /// For sentinels: last hello heard from them.
+    last_hello_heard: u64,
+    /// For sentinels: whether they last said the master is down, and when.
+    master_down: bool,
+    down_asked: u64,
+    last_down_reply: u64,
+    /// For sentinels: whom they voted for as failover leader, in which epoch.
+    leader: Option<String>,
+    leader_epoch: u64,
+}
+
+impl Instance {
+    fn new(ip: String, port: u16) -> Self {
+        let now = now_ms();
+        Instance {
+            ip,
+            port,
+            run_id: String::new(),
+            link: None,
+            pubsub: None,
+            last_connect: 0,
+            ping_sent: 0,
+            last_ping: 0,
+            last_avail: now,
+            sdown_since: 0,
+            last_info: 0,
+            info_refresh: 0,
+            last_hello: 0,
+            role: String::new(),
+            role_reported_time: now,
+            master_host: String::new(),
+            master_port: 0,
+            master_link_up: false,
+            offset: 0,
+            priority: 100,
+            reconf: Reconf::None,
+            last_hello_heard: 0,
+            master_down: false,
+            down_asked: 0,
+            last_down_reply: 0,
+            leader: None,
+            leader_epoch: 0,
+        }
+    }
+
+    /// Queues a command on the link, if there is one.
+    fn send(&self, args: &[&str], request: Request) -> bool {
+        let Some(link) = &self.link else { return false };
+        link.tx.send((args.iter().map(|a| a.to_string()).collect(), request)).is_ok()
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+enum FailoverState {
+    /// Waiting to be elected leader for the failover epoch.
+    WaitStart,
+    SelectSlave,
+    SendSlaveofNoOne,
+    WaitPromotion,
+    ReconfSlaves,
+    /// Done: switch to the promoted replica on the next cron.
+    UpdateConfig,
+}
+
+struct Failover {
+    state: FailoverState,
+    epoch: u64,
+    /// When `state` was entered.
+    since: u64,
+    /// SENTINEL FAILOVER: no agreement or election needed.
+    forced: bool,
+    /// Address of the replica picked for promotion.
+    promoted: Option<String>,
+}
+
+struct Master {
+    name: String,
+    instance: Instance,
+    quorum: usize,
+    down_after: u64,
+    failover_timeout: u64,
+    parallel_syncs: usize,
+    /// Epoch of the failover that made this master, spread with hellos so
+    /// that every sentinel ends up with the newest configuration.
+    config_epoch: u64,
+    odown_since: u64,
+    replicas: BTreeMap<String, Instance>,
+    sentinels: BTreeMap<String, Instance>,
+    /// Our vote for failover leader, and its epoch.
+    leader: Option<String>,
+    leader_epoch: u64,
+    failover: Option<Failover>,
+    /// When our last failover attempt started, or when we voted for someone
+    /// else's; no new attempt for twice the failover timeout.
+    failover_start: u64,
+}
+
+impl Master {
+    fn new(name: String, ip: String, port: u16, quorum: usize) -> Self {
+        Master {
+            name,
+            instance: Instance::new(ip, port),
+            quorum,
+            down_after: DEFAULT_DOWN_AFTER,
+            failover_timeout: DEFAULT_FAILOVER_TIMEOUT,
+            parallel_syncs: DEFAULT_PARALLEL_SYNCS,
+            config_epoch: 0,
+            odown_since: 0,
+            replicas: BTreeMap::new(),
+            sentinels: BTreeMap::new(),
+            leader: None,
+            leader_epoch: 0,
+            failover: None,
+            failover_start: 0,
+        }
+    }
+
+    fn instance(&self, target: &Target) -> Option<&Instance> {
+        match target {
+            Target::Master => Some(&self.instance),
+            Target::Replica(addr) => self.replicas.get(addr),
+            Target::Sentinel(id) => self.sentinels.get(id),
+        }
+    }
+
+    fn instance_mut(&mut self, target: &Target) -> Option<&mut Instance> {
+        match target {
+            Target::Master => Some(&mut self.instance),
+            Target::Replica(addr) => self.replicas.get_mut(addr),
+            Target::Sentinel(id) => self.sentinels.get_mut(id),
+        }
+    }
+
+    fn targets(&self) -> Vec<Target> {
+        std::iter::once(Target::Master)
+            .chain(self.replicas.keys().map(|a| Target::Replica(a.clone())))
+            .chain(self.sentinels.keys().map(|id| Target::Sentinel(id.clone())))
+            .collect()
+    }
+
+    /// The address clients should use: the promoted replica's once a
+    /// failover got as far as reconfiguring the other replicas.
+    fn current_addr(&self) -> (String, u16) {
+        if let Some(f) = &self.failover
+            && f.state >= FailoverState::ReconfSlaves
+            && let Some(promoted) = f.promoted.as_ref().and_then(|a| self.replicas.get(a)) {
+            return (promoted.ip.clone(), promoted.port)
+        }
+        (self.instance.ip.clone(), self.instance.port)
+    }
+
+    /// How an instance appears in events: `master <name> <ip> <port>`, or
+    /// `slave|sentinel <name> <ip> <port> @ <master> <ip> <port>`.
+    fn describe(&self, target: &Target) -> String {
+        let at = format!("{} {} {}", self.name, self.instance.ip, self.instance.port);
+        match (target, self.instance(target)) {
+            (Target::Master, _) => format!("master {}", at),
+            (Target::Replica(addr), Some(r)) => format!("slave {} {} {} @ {}", addr, r.ip, r.port, at),
+            (Target::Sentinel(id), Some(s)) => format!("sentinel {} {} {} @ {}", id, s.ip, s.port, at),
+            (_, None) => at,
+        }
+    }
+
+    /// Can be trusted as the group's master: up, reporting itself master,
+    /// and with recent INFO.
+    fn looks_sane(&self, now: u64) -> bool {
+        self.instance.role == "master" && self.instance.sdown_since == 0 && self.odown_since == 0
+            && now.saturating_sub(self.instance.info_refresh) < INFO_PERIOD * 2
+    }
+
+    fn flags(&self, target: &Target) -> String {
+        let Some(i) = self.instance(target) else { return String::new() };
+        let mut flags = vec![match target {
+            Target::Master => "master",
+            Target::Replica(_) => "slave",
+            Target::Sentinel(_) => "sentinel",
+        }];
+        if i.sdown_since != 0 {
+            flags.push("s_down");
+        }
+        if *target == Target::Master && self.odown_since != 0 {
+            flags.push("o_down");
+        }
+        if i.link.is_none() {
+            flags.push("disconnected");
+        }
+        if *target == Target::Master && self.failover.is_some() {
+            flags.push("failover_in_progress");
+        }
+        if let Target::Replica(addr) = target
+            && self.failover.as_ref().is_some_and(|f| f.promoted.as_ref() == Some(addr)) {
+            flags.push("promoted");
+        }
+        if i.master_down {
+            flags.push("master_down");
+        }
+        flags.join(",")
+    }
+}
+
+/// Sentinel mode: this process monitors masters instead of holding data.
+/// There is no config file, so what a sentinel learns lasts until it
+/// restarts.
+pub struct Sentinel {
+    pub myid: String,
+    /// Port announced in hellos.
+    port: u16,
+    current_epoch: u64,
+    masters: BTreeMap<String, Master>,
+    next_link: u64,
+}
+
+/// Logs an event and publishes it on the channel named after it, for
+/// clients of the sentinel to follow.
+fn event(pubsub: &PubSub, kind: &str, msg: String) {
+    println!("{} {}", kind, msg);
+    pubsub.publish(kind, &msg);
+}
+
+impl Sentinel {
+    pub fn new(port: u16) -> Self {
+        Sentinel { myid: random_id(), port, current_epoch: 0, masters: BTreeMap::new(), next_link: 0 }
+    }
+
+    /// A `--sentinel` line from the command line, as in sentinel.conf:
+    /// `monitor <name> <ip> <port> <quorum>` or `<option> <name> <value>`.
+    pub fn directive(&mut self, args: &[&str]) -> Result<(), Error> {
+        match args {
+            [monitor, name, ip, port, quorum] if monitor.eq_ignore_ascii_case("monitor") => self.monitor(name, ip, port, quorum),
+            [option, name, value] => self.set(name, option, value),
+            _ => Err(anyhow!("Unrecognized sentinel configuration statement"))
+        }
+    }
+
+    fn monitor(&mut self, name: &str, ip: &str, port: &str, quorum: &str) -> Result<(), Error> {
+        let quorum = quorum.parse::<i64>().map_err(|_| anyhow!("ERR value is not an integer or out of range"))?;
+        if quorum <= 0 {
+            return Err(anyhow!("ERR Quorum must be 1 or greater."))
+        }
+        let port = port.parse::<u16>().ok().filter(|p| *p != 0).ok_or_else(|| anyhow!("ERR Invalid port"))?;
+        ip.parse::<std::net::IpAddr>().map_err(|_| anyhow!("ERR Invalid IP address or hostname specified"))?;
+        if self.masters.contains_key(name) {
+            return Err(anyhow!("ERR Duplicated master name"))
+        }
+        self.masters.insert(name.to_string(), Master::new(name.to_string(), ip.to_string(), port, quorum as usize));
+        Ok(())
+    }
+
+    fn master(&self, name: &str) -> Result<&Master, Error> {
+        self.masters.get(name).ok_or_else(|| anyhow!("ERR No such master with that name"))
+    }
+
+    fn set(&mut self, name: &str, option: &str, value: &str) -> Result<(), Error> {
+        let m = self.masters.get_mut(name).ok_or_else(|| anyhow!("ERR No such master with that name"))?;
+        let invalid = || anyhow!("ERR Invalid argument '{}' for SENTINEL SET '{}'", value, option);
+        let number = value.parse::<u64>().ok().filter(|n| *n > 0);
+        match option.to_lowercase().as_str() {
+            "down-after-milliseconds" => m.down_after = number.ok_or_else(invalid)?,
+            "failover-timeout" => m.failover_timeout = number.ok_or_else(invalid)?,
+            "parallel-syncs" => m.parallel_syncs = number.ok_or_else(invalid)? as usize,
+            "quorum" => m.quorum = number.ok_or_else(invalid)? as usize,
+            _ => return Err(anyhow!("ERR Unknown option or number of arguments for SENTINEL SET '{}'", option))
+        }
+        Ok(())
+    }
+
+    fn hello(&self, m: &Master) -> String {
+        let (ip, port) = m.current_addr();
+        format!("127.0.0.1,{},{},{},{},{},{},{}", self.port, self.myid, self.current_epoch, m.name, ip, port, m.config_epoch)
+    }
+
+    fn connect(&mut self, redisdb: &db, name: &str, target: &Target, subscribe: bool) -> Option<Link> {
+        let i = self.masters.get(name)?.instance(target)?;
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.next_link += 1;
+        let generation = self.next_link;
+        let task = tokio::spawn(run_link(redisdb.clone(), name.to_string(), target.clone(), i.ip.clone(), i.port, generation, rx));
+        if subscribe {
+            let _ = tx.send((vec!["SUBSCRIBE".to_string(), HELLO_CHANNEL.to_string()], Request::Other));
+        }
+        Some(Link { tx, generation, ctime: now_ms(), task: task.abort_handle() })
+    }
+
+    /// Links, PINGs, INFO, hellos and subjective down state for one
+    /// instance of a master's group.
+    fn handle_instance(&mut self, redisdb: &db, pubsub: &PubSub, name: &str, target: &Target, now: u64) {
+        let hello = self.hello(&self.masters[name]);
+        let Some(m) = self.masters.get_mut(name) else { return };
+        let (down_after, fast_info) = (m.down_after, m.odown_since != 0 || m.failover.is_some());
+        let Some(i) = m.instance_mut(target) else { return };
+        // A link that got no pong for half of down-after may be stuck.
+        if i.ping_sent != 0 && now.saturating_sub(i.ping_sent) > down_after / 2
+            && i.link.as_ref().is_some_and(|l| now.saturating_sub(l.ctime) > MIN_LINK_RECONNECT_PERIOD) {
+            i.link = None;
+            i.ping_sent = 0;
+        }
+        let (need_link, need_pubsub) = (i.link.is_none(), i.pubsub.is_none() && !matches!(target, Target::Sentinel(_)));
+        if (need_link || need_pubsub) && now.saturating_sub(i.last_connect) >= RECONNECT_PERIOD {
+            i.last_connect = now;
+            let link = if need_link { self.connect(redisdb, name, target, false) } else { None };
+            let pubsub_link = if need_pubsub { self.connect(redisdb, name, target, true) } else { None };
+            let Some(i) = self.masters.get_mut(name).and_then(|m| m.instance_mut(target)) else { return };
+            if link.is_some() {
+                i.link = link;
+                i.ping_sent = 0;
+            }
+            if pubsub_link.is_some() {
+                i.pubsub = pubsub_link;
+            }
+        }
+
+        let Some(m) = self.masters.get_mut(name) else { return };
+        let Some(i) = m.instance_mut(target) else { return };
+        if i.link.is_some() {
+            if i.ping_sent == 0 && now.saturating_sub(i.last_ping) >= PING_PERIOD.min(down_after) {
+                i.send(&["PING"], Request::Ping);
+                i.ping_sent = now;
+                i.last_ping = now;
+            }
+            if !matches!(target, Target::Sentinel(_)) {
+                let info_period = if matches!(target, Target::Replica(_)) && fast_info { 1000 } else { INFO_PERIOD };
+                if now.saturating_sub(i.last_info) >= info_period {
+                    i.send(&["INFO"], Request::Info);
+                    i.last_info = now;
+                }
+                if now.saturating_sub(i.last_hello) >= PUBLISH_PERIOD {
+                    i.send(&["PUBLISH", HELLO_CHANNEL, &hello], Request::Other);
+                    i.last_hello = now;
+                }
+            }
+        }
+
+        let down = now.saturating_sub(i.last_avail) > down_after
+            // A master that says it's a replica for too long is no master.
+            || (*target == Target::Master && i.role == "slave" && now.saturating_sub(i.role_reported_time) > down_after + INFO_PERIOD * 2);
+        if down && i.sdown_since == 0 {
+            i.sdown_since = now;
+            event(pubsub, "+sdown", m.describe(target));
+        } else if !down && i.sdown_since != 0 {
+            i.sdown_since = 0;
+            event(pubsub, "-sdown", m.describe(target));
+        }
+    }
+
+    /// Objective down: we think the master is down and enough other
+    /// sentinels told us lately that they do too.
+    fn check_odown(&mut self, pubsub: &PubSub, name: &str, now: u64) {
+        let Some(m) = self.masters.get_mut(name) else { return };
+        let votes = 1 + m.sentinels.values().filter(|s| s.master_down).count();
+        let odown = m.instance.sdown_since != 0 && votes >= m.quorum;
+        if odown && m.odown_since == 0 {
+            m.odown_since = now;
+            event(pubsub, "+odown", format!("{} #quorum {}/{}", m.describe(&Target::Master), votes, m.quorum));
+        } else if !odown && m.odown_since != 0 {
+            m.odown_since = 0;
+            event(pubsub, "-odown", m.describe(&Target::Master));
+        }
+    }
+
+    /// While the master is subjectively down, asks the other sentinels
+    /// whether they agree, and during our failover for their vote.
+    fn ask_other_sentinels(&mut self, name: &str, now: u64) {
+        let myid = self.myid.clone();
+        let epoch = self.current_epoch.to_string();
+        let Some(m) = self.masters.get_mut(name) else { return };
+        let sdown = m.instance.sdown_since != 0;
+        let candidate = if m.failover.is_some() { myid } else { "*".to_string() };
+        let (ip, port) = (m.instance.ip.clone(), m.instance.port.to_string());
+        for s in m.sentinels.values_mut() {
+            // Answers go stale.
+            if now.saturating_sub(s.last_down_reply) > ASK_PERIOD * 5 {
+                s.master_down = false;
+                s.leader = None;
+            }
+            if !sdown || now.saturating_sub(s.down_asked) < ASK_PERIOD {
+                continue
+            }
+            if s.send(&["SENTINEL", "is-master-down-by-addr", &ip, &port, &epoch, &candidate], Request::IsMasterDown) {
+                s.down_asked = now;
+            }
+        }
+    }
+
+    /// Gives our vote for the failover leader of `epoch`: the first
+    /// candidate asking in an epoch gets it. Returns whom we voted for last
+    /// and in which epoch.
+    fn vote_leader(&mut self, pubsub: &PubSub, name: &str, epoch: u64, candidate: &str) -> (Option<String>, u64) {
+        if epoch > self.current_epoch {
+            self.current_epoch = epoch;
+            event(pubsub, "+new-epoch", epoch.to_string());
+        }
+        let current_epoch = self.current_epoch;
+        let myid = self.myid.clone();
+        let Some(m) = self.masters.get_mut(name) else { return (None, 0) };
+        if m.leader_epoch < epoch && current_epoch <= epoch {
+            m.leader = Some(candidate.to_string());
+            m.leader_epoch = current_epoch;
+            event(pubsub, "+vote-for-leader", format!("{} {}", candidate, current_epoch));
+            // Someone else is failing over: don't get in their way.
+            if candidate != myid {
+                m.failover_start = now_ms() + random_below(MAX_DESYNC);
+            }
+        }
+        (m.leader.clone(), m.leader_epoch)
+    }
+
+    /// The sentinel with the votes of a majority of the sentinels, and at
+    /// least a quorum of them, in `epoch`, if there is one. We vote too:
+    /// for the one with the most votes so far, or else for ourselves.
+    fn get_leader(&mut self, pubsub: &PubSub, name: &str, epoch: u64) -> Option<String> {
+        let m = self.masters.get(name)?;
+        let voters = m.sentinels.len() + 1;
+        let quorum = m.quorum;
+        let mut counters: HashMap<String, usize> = HashMap::new();
+        for s in m.sentinels.values() {
+            if let Some(leader) = &s.leader && s.leader_epoch == epoch {
+                *counters.entry(leader.clone()).or_default() += 1;
+            }
+        }
+        let best = |counters: &HashMap<String, usize>| counters.iter()
+            .max_by(|a, b| a.1.cmp(b.1).then_with(|| b.0.cmp(a.0)))
+            .map(|(id, votes)| (id.clone(), *votes));
+        let candidate = best(&counters).map_or_else(|| self.myid.clone(), |(id, _)| id);
+        let (vote, vote_epoch) = self.vote_leader(pubsub, name, epoch, &candidate);
+        if let Some(vote) = vote && vote_epoch == epoch {
+            *counters.entry(vote).or_default() += 1;
+        }
+        best(&counters).filter(|(_, votes)| *votes >= quorum.max(voters / 2 + 1)).map(|(id, _)| id)
+    }
+
+    fn start_failover(&mut self, pubsub: &PubSub, name: &str, forced: bool, now: u64) {
+        self.current_epoch += 1;
+        let epoch = self.current_epoch;
+        let Some(m) = self.masters.get_mut(name) else { return };
+        m.failover = Some(Failover { state: FailoverState::WaitStart, epoch, since: now, forced, promoted: None });
+        m.failover_start = now + if forced { 0 } else { random_below(MAX_DESYNC) };
+        for s in m.sentinels.values_mut() {
+            s.down_asked = 0;
+        }
+        event(pubsub, "+new-epoch", epoch.to_string());
+        event(pubsub, "+try-failover", m.describe(&Target::Master));
+    }
+
+    fn abort_failover(pubsub: &PubSub, m: &mut Master, reason: &str) {
+        event(pubsub, &format!("-failover-abort-{}", reason), m.describe(&Target::Master));
+        m.failover = None;
+        for r in m.replicas.values_mut() {
+            r.reconf = Reconf::None;
+        }
+    }
+
+    /// The replica to promote: reachable, with fresh INFO and a non-zero
+    /// priority; the lowest priority wins, then the most data.
+    fn select_replica(m: &Master, now: u64) -> Option<String> {
+        let info_validity = if m.instance.sdown_since != 0 { PING_PERIOD * 5 } else { INFO_PERIOD * 3 };
+        m.replicas.iter()
+            .filter(|(_, r)| r.sdown_since == 0 && r.link.is_some() && r.priority != 0
+                && now.saturating_sub(r.last_avail) <= PING_PERIOD * 5
+                && now.saturating_sub(r.info_refresh) <= info_validity)
+            .min_by(|(a_addr, a), (b_addr, b)| a.priority.cmp(&b.priority)
+                .then(b.offset.cmp(&a.offset))
+                .then(a_addr.cmp(b_addr)))
+            .map(|(addr, _)| addr.clone())
+    }
+
+    /// Moves a failover along as far as it can go now.
+    fn run_failover(&mut self, pubsub: &PubSub, name: &str, now: u64) {
+        let Some(m) = self.masters.get(name) else { return };
+        let Some(f) = &m.failover else { return };
+        let (state, epoch, forced) = (f.state, f.epoch, f.forced);
+        if state == FailoverState::WaitStart {
+            let leader = self.get_leader(pubsub, name, epoch);
+            let myid = self.myid.clone();
+            let Some(m) = self.masters.get_mut(name) else { return };
+            if leader.as_ref() != Some(&myid) && !forced {
+                let election_timeout = ELECTION_TIMEOUT.min(m.failover_timeout);
+                if now.saturating_sub(m.failover_start) > election_timeout {
+                    Sentinel::abort_failover(pubsub, m, "not-elected");
+                }
+                return
+            }
+            event(pubsub, "+elected-leader", m.describe(&Target::Master));
+            event(pubsub, "+failover-state-select-slave", m.describe(&Target::Master));
+            let f = m.failover.as_mut().unwrap();
+            f.state = FailoverState::SelectSlave;
+            f.since = now;
+        }
+
+        let Some(m) = self.masters.get_mut(name) else { return };
+        let state = m.failover.as_ref().unwrap().state;
+        match state {
+            FailoverState::SelectSlave => match Sentinel::select_replica(m, now) {
+                None => Sentinel::abort_failover(pubsub, m, "no-good-slave"),
+                Some(addr) => {
+                    let target = Target::Replica(addr.clone());
+                    event(pubsub, "+selected-slave", m.describe(&target));
+                    event(pubsub, "+failover-state-send-slaveof-noone", m.describe(&target));
+                    let f = m.failover.as_mut().unwrap();
+                    f.promoted = Some(addr);
+                    f.state = FailoverState::SendSlaveofNoOne;
+                    f.since = now;
+                }
+            },
+            FailoverState::SendSlaveofNoOne => {
+                let f = m.failover.as_ref().unwrap();
+                let (since, promoted) = (f.since, f.promoted.clone().unwrap_or_default());
+                let sent = m.replicas.get(&promoted).is_some_and(|r| r.send(&["REPLICAOF", "NO", "ONE"], Request::Other));
+                if sent {
+                    event(pubsub, "+failover-state-wait-promotion", m.describe(&Target::Replica(promoted)));
+                    let f = m.failover.as_mut().unwrap();
+                    f.state = FailoverState::WaitPromotion;
+                    f.since = now;
+                } else if now.saturating_sub(since) > m.failover_timeout {
+                    Sentinel::abort_failover(pubsub, m, "slave-timeout");
+                }
+            }
+            // `process_info` moves on once the replica reports it's a master.
+            FailoverState::WaitPromotion => {
+                if now.saturating_sub(m.failover.as_ref().unwrap().since) > m.failover_timeout {
+                    Sentinel::abort_failover(pubsub, m, "slave-timeout");
+                }
+            }
+            FailoverState::ReconfSlaves => Sentinel::reconf_replicas(pubsub, m, now),
+            FailoverState::UpdateConfig => {
+                let promoted = m.failover.as_ref().and_then(|f| f.promoted.as_ref()).and_then(|a| m.replicas.get(a));
+                if let Some((ip, port)) = promoted.map(|r| (r.ip.clone(), r.port)) {
+                    self.switch_master(pubsub, name, ip, port);
+                } else {
+                    m.failover = None;
+                }
+            }
+            FailoverState::WaitStart => {}
+        }
+    }
+
+    /// Points the remaining replicas at the promoted one, `parallel-syncs`
+    /// at a time, and ends the failover once they all follow it or the
+    /// failover timeout passed.
+    fn reconf_replicas(pubsub: &PubSub, m: &mut Master, now: u64) {
+        let f = m.failover.as_ref().unwrap();
+        let (since, promoted) = (f.since, f.promoted.clone().unwrap_or_default());
+        let Some((ip, port)) = m.replicas.get(&promoted).map(|r| (r.ip.clone(), r.port.to_string())) else { return };
+        let mut in_progress = m.replicas.values().filter(|r| matches!(r.reconf, Reconf::Sent(_) | Reconf::InProgress)).count();
+        let addrs: Vec<String> = m.replicas.keys().filter(|a| **a != promoted).cloned().collect();
+        for addr in &addrs {
+            let target = Target::Replica(addr.clone());
+            let r = &m.replicas[addr];
+            if let Reconf::Sent(at) = r.reconf && now.saturating_sub(at) > SLAVE_RECONF_TIMEOUT {
+                event(pubsub, "-slave-reconf-sent-timeout", m.describe(&target));
+                m.replicas.get_mut(addr).unwrap().reconf = Reconf::Done;
+                continue
+            }
+            if in_progress >= m.parallel_syncs {
+                break
+            }
+            if r.reconf == Reconf::None && r.sdown_since == 0 && r.send(&["REPLICAOF", &ip, &port], Request::Other) {
+                m.replicas.get_mut(addr).unwrap().reconf = Reconf::Sent(now);
+                in_progress += 1;
+                event(pubsub, "+slave-reconf-sent", m.describe(&target));
+            }
+        }
+
+        let pending = addrs.iter().filter(|a| m.replicas[*a].sdown_since == 0 && m.replicas[*a].reconf != Reconf::Done).count();
+        let timeout = now.saturating_sub(since) > m.failover_timeout;
+        if pending == 0 || timeout {
+            if timeout {
+                event(pubsub, "+failover-end-for-timeout", m.describe(&Target::Master));
+                // Best effort for the rest.
+                for addr in &addrs {
+                    let r = &m.replicas[addr];
+                    if r.reconf == Reconf::None {
+                        r.send(&["REPLICAOF", &ip, &port], Request::Other);
+                    }
+                }
+            }
+            event(pubsub, "+failover-end", m.describe(&Target::Master));
+            let f = m.failover.as_mut().unwrap();
+            f.state = FailoverState::UpdateConfig;
+            f.since = now;
+        }
+    }
+
+    /// Starts monitoring `ip:port` as the master, with the old master and
+    /// its replicas as replicas.
+    fn switch_master(&mut self, pubsub: &PubSub, name: &str, ip: String, port: u16) {
+        let Some(m) = self.masters.get_mut(name) else { return };
+        let (old_ip, old_port) = (m.instance.ip.clone(), m.instance.port);
+        let mut replicas: Vec<(String, u16)> = m.replicas.values()
+            .filter(|r| r.ip != ip || r.port != port)
+            .map(|r| (r.ip.clone(), r.port))
+            .collect();
+        if old_ip != ip || old_port != port {
+            replicas.push((old_ip.clone(), old_port));
+        }
+        m.instance = Instance::new(ip.clone(), port);
+        m.replicas = replicas.into_iter().map(|(ip, port)| (format!("{}:{}", ip, port), Instance::new(ip, port))).collect();
+        m.failover = None;
+        m.odown_since = 0;
+        m.leader = None;
+        for s in m.sentinels.values_mut() {
+            s.master_down = false;
+            s.leader = None;
+        }
+        event(pubsub, "+switch-master", format!("{} {} {} {} {}", name, old_ip, old_port, ip, port));
+    }
+}
+
+/// Sentinel work, ten times a second, for every monitored master.
+pub fn cron(redisdb: &db, state: &mut dbstate) {
+    let pubsub = &state.pubsub;
+    let Some(sentinel) = &mut state.sentinel else { return };
+    let now = now_ms();
+    let names: Vec<String> = sentinel.masters.keys().cloned().collect();
+    for name in &names {
+        for target in sentinel.masters[name].targets() {
+            sentinel.handle_instance(redisdb, pubsub, name, &target, now);
+        }
+        sentinel.check_odown(pubsub, name, now);
+        let m = &sentinel.masters[name];
+        if m.odown_since != 0 && m.failover.is_none() && now.saturating_sub(m.failover_start) >= m.failover_timeout * 2 {
+            sentinel.start_failover(pubsub, name, false, now);
+        }
+        sentinel.run_failover(pubsub, name, now);
+        sentinel.ask_other_sentinels(name, now);
+    }
+}
+
+/// Our side of a link: writes queued commands and hands each reply to
+/// `process_reply` with what it answers. On a subscribed link, messages
+/// come with nothing asked and are hellos.
+#[allow(clippy::too_many_arguments)]
+async fn run_link(redisdb: db, name: String, target: Target, ip: String, port: u16, generation: u64, mut rx: mpsc::UnboundedReceiver<(Vec<String>, Request)>) {
+    // Errors just end the link: the instance may be down, which is for
+    // PINGs going unanswered to tell, and the cron makes a new one.
+    let _: Result<(), Error> = async {
+        let mut handler = RespHandler::new(TcpStream::connect((ip.as_str(), port)).await?);
+        let mut pending = VecDeque::new();
+        loop {
+            tokio::select! {
+                Some((args, request)) = rx.recv() => {
+                    handler.write_value(Value::Array(args.into_iter().map(Value::bulk).collect())).await?;
+                    pending.push_back(request);
+                }
+                reply = handler.read_value() => {
+                    let Some(reply) = reply? else { return Ok(()) };
+                    let mut state = redisdb.state.lock().await;
+                    match pending.pop_front() {
+                        Some(request) => process_reply(&mut state, &name, &target, request, reply),
+                        None => process_message(&mut state, reply)
+                    }
+                }
+            }
+        }
+    }.await;
+    if let Some(sentinel) = &mut redisdb.state.lock().await.sentinel
+        && let Some(i) = sentinel.masters.get_mut(&name).and_then(|m| m.instance_mut(&target)) {
+        if i.link.as_ref().is_some_and(|l| l.generation == generation) {
+            i.link = None;
+            i.ping_sent = 0;
+        }
+        if i.pubsub.as_ref().is_some_and(|l| l.generation == generation) {
+            i.pubsub = None;
+        }
+    }
+}
+
+fn process_reply(state: &mut dbstate, name: &str, target: &Target, request: Request, reply: Value) {
+    let pubsub = &state.pubsub;
+    let Some(sentinel) = &mut state.sentinel else { return };
+    let now = now_ms();
+    match (request, reply) {
+        (Request::Ping, reply) => {
+            let Some(i) = sentinel.masters.get_mut(name).and_then(|m| m.instance_mut(target)) else { return };
+            i.ping_sent = 0;
+            let valid = match &reply {
+                Value::SimpleString(s) => s == "PONG",
+                Value::SimpleError(e) => e.starts_with("LOADING") || e.starts_with("MASTERDOWN"),
+                _ => false
+            };
+            if valid {
+                i.last_avail = now;
+            }
+        }
+        (Request::Info, Value::BulkString(info)) => sentinel.process_info(pubsub, name, target, &bytes_to_string(&info), now),
+        (Request::IsMasterDown, Value::Array(reply)) => {
+            let Some(s) = sentinel.masters.get_mut(name).and_then(|m| m.instance_mut(target)) else { return };
+            if let [Value::Integer(down), Value::BulkString(leader), Value::Integer(epoch)] = &reply[..] {
+                let leader = bytes_to_string(leader);
+                s.last_down_reply = now;
+                s.master_down = *down == 1;
+                if leader != "*" {
+                    s.leader = Some(leader.clone());
+                    s.leader_epoch = *epoch as u64;
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// A message on a subscribed link: a hello published on an instance.
+fn process_message(state: &mut dbstate, msg: Value) {
+    if let Value::Array(items) = msg
+        && let [Value::BulkString(kind), Value::BulkString(channel), Value::BulkString(payload)] = &items[..]
+        && kind == b"message" && channel == HELLO_CHANNEL.as_bytes() {
+        let pubsub = &state.pubsub;
+        if let Some(sentinel) = &mut state.sentinel {
+            sentinel.process_hello(pubsub, &bytes_to_string(payload));
+        }
+    }
+}
+
+impl Sentinel {
+    /// `ip,port,runid,current_epoch,master_name,master_ip,master_port,
+    /// master_config_epoch`: a sentinel announcing itself and its view of a
+    /// master. Adopts the master address if its config epoch is newer.
+    fn process_hello(&mut self, pubsub: &PubSub, payload: &str) {
+        let parts: Vec<&str> = payload.split(',').collect();
+        let [ip, port, run_id, epoch, name, master_ip, master_port, config_epoch] = parts[..] else { return };
+        let (Ok(port), Ok(epoch), Ok(master_port), Ok(config_epoch)) = (port.parse::<u16>(), epoch.parse::<u64>(), master_port.parse::<u16>(), config_epoch.parse::<u64>()) else { return };
+        if run_id == self.myid {
+            return
+        }
+        let Some(m) = self.masters.get_mut(name) else { return };
+        if !m.sentinels.contains_key(run_id) {
+            // Same address, new ID: it restarted.
+            let stale: Vec<String> = m.sentinels.iter().filter(|(_, s)| s.ip == ip && s.port == port).map(|(id, _)| id.clone()).collect();
+            for id in stale {
+                event(pubsub, "-dup-sentinel", m.describe(&Target::Sentinel(id.clone())));
+                m.sentinels.remove(&id);
+            }
+            let mut s = Instance::new(ip.to_string(), port);
+            s.run_id = run_id.to_string();
+            m.sentinels.insert(run_id.to_string(), s);
+            event(pubsub, "+sentinel", m.describe(&Target::Sentinel(run_id.to_string())));
+        }
+        m.sentinels.get_mut(run_id).unwrap().last_hello_heard = now_ms();
+        let describe_sender = m.describe(&Target::Sentinel(run_id.to_string()));
+        let switch = if m.config_epoch < config_epoch {
+            m.config_epoch = config_epoch;
+            m.instance.ip != master_ip || m.instance.port != master_port
+        } else {
+            false
+        };
+        if epoch > self.current_epoch {
+            self.current_epoch = epoch;
+            event(pubsub, "+new-epoch", epoch.to_string());
+        }
+        if switch {
+            event(pubsub, "+config-update-from", describe_sender);
+            self.switch_master(pubsub, name, master_ip.to_string(), master_port);
+        }
+    }
+
+    /// Takes in an INFO reply: the role and replication state of the
+    /// instance and, from a master, its replicas. Replicas that turn into
+    /// masters or follow the wrong one are dealt with here, as is the
+    /// progress of a failover.
+    fn process_info(&mut self, pubsub: &PubSub, name: &str, target: &Target, info: &str, now: u64) {
+        let Some(m) = self.masters.get_mut(name) else { return };
+        let fields: HashMap<&str, &str> = info.lines().filter_map(|l| l.trim_end().split_once(':')).collect();
+        let Some(i) = m.instance_mut(target) else { return };
+        i.info_refresh = now;
+        if let Some(role) = fields.get("role") && i.role != *role {
+            i.role = role.to_string();
+            i.role_reported_time = now;
+        }
+        if i.role == "slave" {
+            i.master_host = fields.get("master_host").unwrap_or(&"").to_string();
+            i.master_port = fields.get("master_port").and_then(|p| p.parse().ok()).unwrap_or(0);
+            i.master_link_up = fields.get("master_link_status") == Some(&"up");
+            i.offset = fields.get("slave_repl_offset").and_then(|o| o.parse().ok()).unwrap_or(0);
+            i.priority = fields.get("slave_priority").and_then(|p| p.parse().ok()).unwrap_or(100);
+        }
+
+        if *target == Target::Master && i.role == "master" {
+            let mut found: Vec<(String, u16)> = Vec::new();
+            for (field, value) in &fields {
+                if !field.starts_with("slave") || !field[5..].chars().all(|c| c.is_ascii_digit()) || field.len() == 5 {
+                    continue
+                }
+                let kv: HashMap<&str, &str> = value.split(',').filter_map(|p| p.split_once('=')).collect();
+                if let (Some(ip), Some(Ok(port))) = (kv.get("ip"), kv.get("port").map(|p| p.parse::<u16>())) {
+                    found.push((ip.to_string(), port));
+                }
+            }
+            found.sort();
+            for (ip, port) in found {
+                let addr = format!("{}:{}", ip, port);
+                if !m.replicas.contains_key(&addr) {
+                    m.replicas.insert(addr.clone(), Instance::new(ip, port));
+                    event(pubsub, "+slave", m.describe(&Target::Replica(addr)));
+                }
+            }
+        }
+
+        let Target::Replica(addr) = target else { return };
+        let sane = m.looks_sane(now);
+        let (master_ip, master_port) = (m.instance.ip.clone(), m.instance.port);
+        let promoted = m.failover.as_ref().and_then(|f| f.promoted.clone());
+        let failover_state = m.failover.as_ref().map(|f| f.state);
+        let failover_epoch = m.failover.as_ref().map_or(0, |f| f.epoch);
+        let r = &m.replicas[addr];
+        // Give a new configuration time to arrive before forcing ours.
+        let settled = sane && failover_state.is_none() && r.sdown_since == 0
+            && now.saturating_sub(r.role_reported_time) > PUBLISH_PERIOD * 4;
+        let master_port_str = master_port.to_string();
+        if r.role == "master" {
+            if failover_state == Some(FailoverState::WaitPromotion) && promoted.as_ref() == Some(addr) {
+                m.config_epoch = failover_epoch;
+                let f = m.failover.as_mut().unwrap();
+                f.state = FailoverState::ReconfSlaves;
+                f.since = now;
+                event(pubsub, "+promoted-slave", m.describe(target));
+                event(pubsub, "+failover-state-reconf-slaves", m.describe(&Target::Master));
+                // Spread the new configuration right away.
+                m.instance.last_hello = 0;
+                for r in m.replicas.values_mut() {
+                    r.last_hello = 0;
+                }
+            } else if settled && r.send(&["REPLICAOF", &master_ip, &master_port_str], Request::Other) {
+                event(pubsub, "+convert-to-slave", m.describe(target));
+            }
+        } else if r.role == "slave" {
+            if (r.master_host != master_ip || r.master_port != master_port)
+                && failover_state.is_none()
+                && settled
+                && r.send(&["REPLICAOF", &master_ip, &master_port_str], Request::Other) {
+                event(pubsub, "+fix-slave-config", m.describe(target));
+            }
+            if failover_state == Some(FailoverState::ReconfSlaves)
+                && let Some(p) = promoted.as_ref().and_then(|p| m.replicas.get(p)) {
+                let follows_promoted = r.master_host == p.ip && r.master_port == p.port;
+                let (reconf, link_up) = (r.reconf, r.master_link_up);
+                if matches!(reconf, Reconf::Sent(_)) && follows_promoted {
+                    m.replicas.get_mut(addr).unwrap().reconf = Reconf::InProgress;
+                    event(pubsub, "+slave-reconf-inprog", m.describe(target));
+                }
+                if matches!(m.replicas[addr].reconf, Reconf::InProgress) && link_up {
+                    m.replicas.get_mut(addr).unwrap().reconf = Reconf::Done;
+                    event(pubsub, "+slave-reconf-done", m.describe(target));
+                }
+            }
+        }
+    }
+}
+
+/// Fields of SENTINEL MASTER(S), REPLICAS and SENTINELS, as a flat array
+/// of names and values.
+fn instance_value(m: &Master, target: &Target, now: u64) -> Value {
+    let Some(i) = m.instance(target) else { return Value::NullArray };
+    let ago = |t: u64| if t == 0 { 0 } else { now.saturating_sub(t) };
+    let name = match target {
+        Target::Master => m.name.clone(),
+        Target::Replica(addr) => addr.clone(),
+        Target::Sentinel(id) => id.clone(),
+    };
+    let mut fields = vec![
+        ("name", name),
+        ("ip", i.ip.clone()),
+        ("port", i.port.to_string()),
+        ("runid", i.run_id.clone()),
+        ("flags", m.flags(target)),
+        ("last-ping-sent", ago(i.ping_sent).to_string()),
+        ("last-ok-ping-reply", ago(i.last_avail).to_string()),
+        ("down-after-milliseconds", m.down_after.to_string()),
+    ];
+    if i.sdown_since != 0 {
+        fields.push(("s-down-time", ago(i.sdown_since).to_string()));
+    }
+    match target {
+        Target::Master => {
+            if m.odown_since != 0 {
+                fields.push(("o-down-time", ago(m.odown_since).to_string()));
+            }
+            fields.extend([
+                ("info-refresh", ago(i.info_refresh).to_string()),
+                ("role-reported", i.role.clone()),
+                ("role-reported-time", ago(i.role_reported_time).to_string()),
+                ("config-epoch", m.config_epoch.to_string()),
+                ("num-slaves", m.replicas.len().to_string()),
+                ("num-other-sentinels", m.sentinels.len().to_string()),
+                ("quorum", m.quorum.to_string()),
+                ("failover-timeout", m.failover_timeout.to_string()),
+                ("parallel-syncs", m.parallel_syncs.to_string()),
+            ]);
+        }
+        Target::Replica(_) => fields.extend([
+            ("info-refresh", ago(i.info_refresh).to_string()),
+            ("role-reported", i.role.clone()),
+            ("role-reported-time", ago(i.role_reported_time).to_string()),
+            ("master-link-status", if i.master_link_up { "ok" } else { "err" }.to_string()),
+            ("master-host", i.master_host.clone()),
+            ("master-port", i.master_port.to_string()),
+            ("slave-priority", i.priority.to_string()),
+            ("slave-repl-offset", i.offset.to_string()),
+        ]),
+        Target::Sentinel(_) => fields.extend([
+            ("last-hello-message", ago(i.last_hello_heard).to_string()),
+            ("voted-leader", i.leader.clone().unwrap_or_else(|| "?".to_string())),
+            ("voted-leader-epoch", i.leader_epoch.to_string()),
+        ]),
+    }
+    Value::Array(fields.into_iter().flat_map(|(k, v)| [Value::bulk(k), Value::bulk(v)]).collect())
+}
+
+/// SENTINEL subcommand [args]. Only available in sentinel mode.
+pub fn sentinel_handle(args: &[String], state: &mut dbstate) -> Result<Value, Error> {
+    let pubsub = &state.pubsub;
+    let Some(sentinel) = &mut state.sentinel else {
+        return Err(anyhow!("ERR unknown command 'sentinel', with args beginning with: {}", args.iter().take(20).map(|a| format!("'{}' ", a)).collect::<String>()))
+    };
+    let subcommand = args[0].to_uppercase();
+    let now = now_ms();
+    let ok = || Value::SimpleString("OK".to_string());
+    match (subcommand.as_str(), &args[1..]) {
+        ("MYID", []) => Ok(Value::bulk(sentinel.myid.clone())),
+        ("MASTERS", []) => Ok(Value::Array(sentinel.masters.values().map(|m| instance_value(m, &Target::Master, now)).collect())),
+        ("MASTER", [name]) => Ok(instance_value(sentinel.master(name)?, &Target::Master, now)),
+        ("REPLICAS" | "SLAVES", [name]) => {
+            let m = sentinel.master(name)?;
+            Ok(Value::Array(m.replicas.keys().map(|a| instance_value(m, &Target::Replica(a.clone()), now)).collect()))
+        }
+        ("SENTINELS", [name]) => {
+            let m = sentinel.master(name)?;
+            Ok(Value::Array(m.sentinels.keys().map(|id| instance_value(m, &Target::Sentinel(id.clone()), now)).collect()))
+        }
+        ("GET-MASTER-ADDR-BY-NAME", [name]) => Ok(match sentinel.masters.get(name) {
+            Some(m) => {
+                let (ip, port) = m.current_addr();
+                Value::Array(vec![Value::bulk(ip), Value::bulk(port.to_string())])
+            }
+            None => Value::NullArray
+        }),
+        ("IS-MASTER-DOWN-BY-ADDR", [ip, port, epoch, run_id]) => {
+            let port = port.parse::<u16>().map_err(|_| anyhow!("ERR value is not an integer or out of range"))?;
+            let epoch = epoch.parse::<u64>().map_err(|_| anyhow!("ERR value is not an integer or out of range"))?;
+            let master = sentinel.masters.values().find(|m| m.instance.ip == *ip && m.instance.port == port).map(|m| m.name.clone());
+            let down = master.as_ref().is_some_and(|name| sentinel.masters[name].instance.sdown_since != 0);
+            let (leader, leader_epoch) = match &master {
+                Some(name) if run_id != "*" => sentinel.vote_leader(pubsub, name, epoch, run_id),
+                _ => (None, 0)
+            };
+            Ok(Value::Array(vec![
+                Value::Integer(down as i64),
+                Value::bulk(leader.unwrap_or_else(|| "*".to_string())),
+                Value::Integer(leader_epoch as i64),
+            ]))
+        }
+        ("MONITOR", [name, ip, port, quorum]) => {
+            sentinel.monitor(name, ip, port, quorum)?;
+            event(pubsub, "+monitor", sentinel.masters[name].describe(&Target::Master) + &format!(" quorum {}", quorum));
+            Ok(ok())
+        }
+        ("REMOVE", [name]) => {
+            let m = sentinel.masters.remove(name).ok_or_else(|| anyhow!("ERR No such master with that name"))?;
+            event(pubsub, "-monitor", m.describe(&Target::Master));
+            Ok(ok())
+        }
+        ("SET", [name, options @ ..]) if !options.is_empty() && options.len().is_multiple_of(2) => {
+            for pair in options.chunks(2) {
+                sentinel.set(name, &pair[0], &pair[1])?;
+            }
+            Ok(ok())
+        }
+        ("FAILOVER", [name]) => {
+            let m = sentinel.master(name)?;
+            if m.failover.is_some() {
+                return Err(anyhow!("INPROG Failover already in progress"))
+            }
+            if Sentinel::select_replica(m, now).is_none() {
+                return Err(anyhow!("NOGOODSLAVE No suitable replica to promote"))
+            }
+            sentinel.start_failover(pubsub, name, true, now);
+            Ok(ok())
+        }
+        ("MYID" | "MASTERS" | "MASTER" | "REPLICAS" | "SLAVES" | "SENTINELS" | "GET-MASTER-ADDR-BY-NAME" | "IS-MASTER-DOWN-BY-ADDR" | "MONITOR" | "REMOVE" | "SET" | "FAILOVER", _) => {
+            Err(anyhow!("ERR wrong number of arguments for 'sentinel|{}' command", subcommand.to_lowercase()))
+        }
+        _ => Err(anyhow!("ERR unknown subcommand '{}'. Try SENTINEL HELP.", args[0]))
+    }
+}
+
+/// The Sentinel section of INFO.
+pub fn info(state: &dbstate) -> Vec<(String, String)> {
+    let Some(sentinel) = &state.sentinel else { return Vec::new() };
+    let mut fields = vec![("sentinel_masters".to_string(), sentinel.masters.len().to_string())];
+    for (n, m) in sentinel.masters.values().enumerate() {
+        let status = if m.odown_since != 0 { "odown" } else if m.instance.sdown_since != 0 { "sdown" } else { "ok" };
+        let (ip, port) = m.current_addr();
+        fields.push((format!("master{}", n), format!("name={},status={},address={}:{},slaves={},sentinels={}",
+            m.name, status, ip, port, m.replicas.len(), m.sentinels.len() + 1)));
+    }
+    fields
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc;
+
+    use super::*;
+
+    fn bulks(a: &[&str]) -> Value {
+        Value::Array(a.iter().map(Value::bulk).collect())
+    }
+
+    fn monitoring(quorum: &str) -> Sentinel {
+        let mut sentinel = Sentinel::new(SENTINEL_PORT);
+        sentinel.directive(&["monitor", "mymaster", "127.0.0.1", "6379", quorum]).unwrap();
+        sentinel
+    }
+
+    fn hello(run_id: &str, master_port: u16, config_epoch: u64) -> String {
+        format!("127.0.0.1,26380,{},1,mymaster,127.0.0.1,{},{}", run_id, master_port, config_epoch)
+    }
+
+    #[test]
+    fn monitor_and_set_are_validated() {
+        let mut sentinel = monitoring("2");
+        for (args, err) in [
+            (["monitor", "other", "127.0.0.1", "6379", "0"], "ERR Quorum must be 1 or greater."),
+            (["monitor", "other", "127.0.0.1", "0", "1"], "ERR Invalid port"),
+            (["monitor", "other", "localhost:1", "6379", "1"], "ERR Invalid IP address or hostname specified"),
+            (["monitor", "mymaster", "127.0.0.1", "6379", "1"], "ERR Duplicated master name"),
+        ] {
+            assert_eq!(sentinel.directive(&args).unwrap_err().to_string(), err);
+        }
+        sentinel.directive(&["down-after-milliseconds", "mymaster", "5000"]).unwrap();
+        assert_eq!(sentinel.masters["mymaster"].down_after, 5000);
+        assert!(sentinel.directive(&["quorum", "mymaster", "0"]).is_err());
+        assert!(sentinel.directive(&["quorum", "nobody", "1"]).is_err());
+    }
+
+    #[test]
+    fn hellos_add_sentinels_and_newer_configurations() {
+        let mut sentinel = monitoring("2");
+        let mut pubsub = PubSub::default();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        pubsub.subscribe(1, &tx, "+switch-master");
+        let other = "b".repeat(40);
+
+        sentinel.process_hello(&pubsub, &hello(&other, 6379, 0));
+        assert!(sentinel.masters["mymaster"].sentinels.contains_key(&other));
+        assert_eq!(sentinel.current_epoch, 1);
+
+        sentinel.process_hello(&pubsub, &hello(&other, 6380, 1));
+        let m = &sentinel.masters["mymaster"];
+        assert_eq!((m.config_epoch, m.current_addr()), (1, ("127.0.0.1".to_string(), 6380)));
+        assert!(m.replicas.contains_key("127.0.0.1:6379"));
+        assert_eq!(rx.try_recv().unwrap(), bulks(&["message", "+switch-master", "mymaster 127.0.0.1 6379 127.0.0.1 6380"]));
+
+        // An older configuration doesn't switch back.
+        sentinel.process_hello(&pubsub, &hello(&other, 6379, 1));
+        assert_eq!(sentinel.masters["mymaster"].instance.port, 6380);
+    }
+
+    #[test]
+    fn master_info_lists_replicas() {
+        let mut sentinel = monitoring("2");
+        let pubsub = PubSub::default();
+        let info = "# Replication\r\nrole:master\r\nconnected_slaves:2\r\n\
+            slave0:ip=127.0.0.1,port=6380,state=online,offset=10,lag=0\r\n\
+            slave1:ip=127.0.0.1,port=6381,state=online,offset=10,lag=0\r\n";
+        sentinel.process_info(&pubsub, "mymaster", &Target::Master, info, now_ms());
+        let m = &sentinel.masters["mymaster"];
+        assert_eq!(m.instance.role, "master");
+        assert_eq!(m.replicas.keys().collect::<Vec<_>>(), ["127.0.0.1:6380", "127.0.0.1:6381"]);
+
+        let replica = Target::Replica("127.0.0.1:6380".to_string());
+        let info = "role:slave\r\nmaster_host:127.0.0.1\r\nmaster_port:6379\r\nmaster_link_status:up\r\nslave_repl_offset:42\r\nslave_priority:10\r\n";
+        sentinel.process_info(&pubsub, "mymaster", &replica, info, now_ms());
+        let r = sentinel.masters["mymaster"].instance(&replica).unwrap();
+        assert!(r.master_link_up);
+        assert_eq!((r.offset, r.priority), (42, 10));
+    }
+
+    #[test]
+    fn odown_needs_the_quorum() {
+        let mut sentinel = monitoring("2");
+        let pubsub = PubSub::default();
+        sentinel.process_hello(&pubsub, &hello(&"b".repeat(40), 6379, 0));
+        let now = now_ms();
+        sentinel.masters.get_mut("mymaster").unwrap().instance.sdown_since = now;
+        sentinel.check_odown(&pubsub, "mymaster", now);
+        assert_eq!(sentinel.masters["mymaster"].odown_since, 0);
+
+        sentinel.masters.get_mut("mymaster").unwrap().sentinels.get_mut(&"b".repeat(40)).unwrap().master_down = true;
+        sentinel.check_odown(&pubsub, "mymaster", now);
+        assert_eq!(sentinel.masters["mymaster"].odown_since, now);
+        assert!(sentinel.masters["mymaster"].flags(&Target::Master).contains("o_down"));
+    }
+
+    #[test]
+    fn one_vote_per_epoch() {
+        let mut sentinel = monitoring("2");
+        let pubsub = PubSub::default();
+        let (first, second) = ("b".repeat(40), "c".repeat(40));
+        assert_eq!(sentinel.vote_leader(&pubsub, "mymaster", 1, &first), (Some(first.clone()), 1));
+        assert_eq!(sentinel.vote_leader(&pubsub, "mymaster", 1, &second), (Some(first.clone()), 1));
+        assert_eq!(sentinel.vote_leader(&pubsub, "mymaster", 2, &second), (Some(second), 2));
+        assert_eq!(sentinel.current_epoch, 2);
+    }
+
+    #[tokio::test]
+    async fn sentinel_commands() {
+        let db = crate::database::db::new();
+        let mut state = db.state.lock().await;
+        let args = |a: &[&str]| a.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert!(sentinel_handle(&args(&["MASTERS"]), &mut state).is_err());
+
+        state.sentinel = Some(Sentinel::new(SENTINEL_PORT));
+        assert_eq!(sentinel_handle(&args(&["MONITOR", "mymaster", "127.0.0.1", "6379", "2"]), &mut state).unwrap(), Value::SimpleString("OK".to_string()));
+        assert_eq!(sentinel_handle(&args(&["GET-MASTER-ADDR-BY-NAME", "mymaster"]), &mut state).unwrap(), bulks(&["127.0.0.1", "6379"]));
+        assert_eq!(sentinel_handle(&args(&["GET-MASTER-ADDR-BY-NAME", "nobody"]), &mut state).unwrap(), Value::NullArray);
+        assert_eq!(sentinel_handle(&args(&["IS-MASTER-DOWN-BY-ADDR", "127.0.0.1", "6379", "1", "*"]), &mut state).unwrap(),
+            Value::Array(vec![Value::Integer(0), Value::bulk("*"), Value::Integer(0)]));
+        assert_eq!(sentinel_handle(&args(&["FAILOVER", "mymaster"]), &mut state).unwrap_err().to_string(),
+            "NOGOODSLAVE No suitable replica to promote");
+        assert_eq!(sentinel_handle(&args(&["REMOVE", "mymaster"]), &mut state).unwrap(), Value::SimpleString("OK".to_string()));
+        assert!(sentinel_handle(&args(&["MASTER", "mymaster"]), &mut state).is_err());
+    }
+}
@@ -0,0 +1,1218 @@
+use std::{collections::{BTreeMap, BTreeSet}, fmt, ops::Bound, time::SystemTime};
+
+use anyhow::{anyhow, Error};
+
+/// Entries per block before a new one is started, like Redis's
+/// `stream-node-max-entries`.
+const BLOCK_MAX_ENTRIES: usize = 100;
+
+/// Entries `~` trimming may remove when no LIMIT is given.
+const DEFAULT_TRIM_LIMIT: u64 = 100 * BLOCK_MAX_ENTRIES as u64;
+
+/// Entry flag: the entry uses the block's master field list, so only its
+/// values are stored.
+const FLAG_SAME_FIELDS: u8 = 1;
+/// Entry flag: the entry was removed by XDEL or exact trimming but is still
+/// taking space in the block.
+const FLAG_DELETED: u8 = 2;
+
+pub const ERR_INVALID_ID: &str = "ERR Invalid stream ID specified as stream command argument";
+pub const ERR_ID_TOO_SMALL: &str = "ERR The ID specified in XADD is equal or smaller than the target stream top item";
+pub const ERR_ID_ZERO: &str = "ERR The ID specified in XADD must be greater than 0-0";
+pub const ERR_SETID_SMALL: &str = "ERR The ID specified in XSETID is smaller than the target stream top item";
+pub const ERR_ID_EXHAUSTED: &str = "ERR The stream has exhausted the last possible ID, unable to add more items";
+
+pub type StreamEntry = (StreamId, Vec<(String, String)>);
+
+/// An entry delivered to a consumer group. History reads and claims may hit
+/// IDs that were deleted from the stream, which carry no fields.
+pub type DeliveredEntry = (StreamId, Option<Vec<(String, String)>>);
+
+pub fn now_ms() -> u64 {
+    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis() as u64
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct StreamId {
+    pub ms: u64,
+    pub seq: u64,
+}
+
+impl StreamId {
+    pub const MIN: StreamId = StreamId { ms: 0, seq: 0 };
+    pub const MAX: StreamId = StreamId { ms: u64::MAX, seq: u64::MAX };
+
+    pub fn new(ms: u64, seq: u64) -> Self {
+        Self { ms, seq }
+    }
+
+    /// Parses `ms-seq` or `ms`, filling a missing sequence with `missing_seq`.
+    /// `-` and `+` stand for the smallest and largest IDs unless `strict`.
+    pub fn parse(s: &str, missing_seq: u64, strict: bool) -> Result<StreamId, Error> {
+        match s {
+            "-" if !strict => return Ok(StreamId::MIN),
+            "+" if !strict => return Ok(StreamId::MAX),
+            _ => {}
+        }
+        let (ms, seq) = match s.split_once('-') {
+            Some((ms, seq)) => (ms, Some(seq)),
+            None => (s, None)
+        };
+        let ms = ms.parse::<u64>().map_err(|_| anyhow!(ERR_INVALID_ID))?;
+        let seq = match seq {
+            Some(seq) => seq.parse::<u64>().map_err(|_| anyhow!(ERR_INVALID_ID))?,
+            None => missing_seq
+        };
+        Ok(StreamId { ms, seq })
+    }
+
+    /// Parses one end of an XRANGE-style interval, where a leading `(` makes
+    /// it exclusive.
+    pub fn parse_bound(s: &str, is_start: bool) -> Result<Bound<StreamId>, Error> {
+        let missing_seq = if is_start { 0 } else { u64::MAX };
+        match s.strip_prefix('(') {
+            Some(rest) => {
+                if rest == "-" || rest == "+" {
+                    return Err(anyhow!("ERR invalid {} ID for the interval", if is_start { "start" } else { "end" }))
+                }
+                Ok(Bound::Excluded(StreamId::parse(rest, missing_seq, true)?))
+            }
+            None => Ok(Bound::Included(StreamId::parse(s, missing_seq, false)?))
+        }
+    }
+
+    pub fn incr(self) -> Option<StreamId> {
+        match self.seq.checked_add(1) {
+            Some(seq) => Some(StreamId { ms: self.ms, seq }),
+            None => Some(StreamId { ms: self.ms.checked_add(1)?, seq: 0 })
+        }
+    }
+}
+
+impl fmt::Display for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.ms, self.seq)
+    }
+}
+
+/// The ID argument of XADD.
+#[derive(Clone, Copy, Debug)]
+pub enum XAddId {
+    /// `*`: milliseconds from the clock, sequence picked automatically.
+    Auto,
+    /// `ms-*`: explicit milliseconds, sequence picked automatically.
+    AutoSeq(u64),
+    Explicit(StreamId),
+}
+
+impl XAddId {
+    pub fn parse(s: &str) -> Result<XAddId, Error> {
+        if s == "*" {
+            return Ok(XAddId::Auto)
+        }
+        if let Some(ms) = s.strip_suffix("-*") {
+            return Ok(XAddId::AutoSeq(ms.parse::<u64>().map_err(|_| anyhow!(ERR_INVALID_ID))?))
+        }
+        Ok(XAddId::Explicit(StreamId::parse(s, 0, true)?))
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum TrimStrategy {
+    MaxLen(u64),
+    MinId(StreamId),
+}
+
+/// A MAXLEN/MINID clause of XADD or XTRIM.
+#[derive(Clone, Copy, Debug)]
+pub struct Trim {
+    pub strategy: TrimStrategy,
+    /// `~`: only whole blocks are removed, so the stream may stay a little
+    /// above the threshold.
+    pub approx: bool,
+    /// Most entries one call may remove; `None` is unlimited.
+    pub limit: Option<u64>,
+}
+
+impl Trim {
+    /// Parses `MAXLEN|MINID [=|~] threshold [LIMIT count]` starting at
+    /// `args[*i]`, leaving `i` on the first unconsumed argument.
+    pub fn parse(args: &[String], i: &mut usize) -> Result<Trim, Error> {
+        let maxlen = args[*i].eq_ignore_ascii_case("MAXLEN");
+        *i += 1;
+        let mut approx = false;
+        match args.get(*i).map(|a| a.as_str()) {
+            Some("~") => { approx = true; *i += 1 }
+            Some("=") => *i += 1,
+            _ => {}
+        }
+        let threshold = args.get(*i).ok_or_else(|| anyhow!("ERR syntax error"))?;
+        *i += 1;
+        let strategy = if maxlen {
+            let n = threshold.parse::<i64>().map_err(|_| anyhow!("ERR value is not an integer or out of range"))?;
+            if n < 0 {
+                return Err(anyhow!("ERR The MAXLEN argument must be >= 0."))
+            }
+            TrimStrategy::MaxLen(n as u64)
+        } else {
+            TrimStrategy::MinId(StreamId::parse(threshold, 0, true)?)
+        };
+        let mut limit = None;
+        if args.get(*i).is_some_and(|a| a.eq_ignore_ascii_case("LIMIT")) {
+            let n = args.get(*i + 1)
+                .ok_or_else(|| anyhow!("ERR syntax error"))?
+                .parse::<i64>()
+                .map_err(|_| anyhow!("ERR value is not an integer or out of range"))?;
+            if n < 0 {
+                return Err(anyhow!("ERR The LIMIT argument must be >= 0."))
+            }
+            if !approx {
+                return Err(anyhow!("ERR syntax error, LIMIT cannot be used without the special ~ option"))
+            }
+            limit = Some(n as u64);
+            *i += 2;
+        }
+        let limit = match limit {
+            Some(0) => None,
+            Some(n) => Some(n),
+            None if approx => Some(DEFAULT_TRIM_LIMIT),
+            None => None
+        };
+        Ok(Trim { strategy, approx, limit })
+    }
+}
+
+/// A stream stored as a map of compact blocks keyed by their master ID, the
+/// ID of the first entry written to the block. Inside a block, entries are
+/// packed into a byte buffer with IDs delta-encoded against the master ID,
+/// and entries whose fields match the block's master field list store only
+/// their values.
+#[derive(Clone, Default)]
+pub struct Stream {
+    blocks: BTreeMap<StreamId, Block>,
+    length: usize,
+    last_id: StreamId,
+    entries_added: u64,
+    max_deleted_id: StreamId,
+    pub groups: BTreeMap<String, ConsumerGroup>,
+}
+
+#[derive(Clone, Default)]
+pub struct ConsumerGroup {
+    pub last_delivered: StreamId,
+    /// Entries the group has read, used to compute its lag; `None` once it
+    /// can no longer be known.
+    pub entries_read: Option<u64>,
+    /// Entries delivered but not yet acknowledged, across all consumers.
+    pub pel: BTreeMap<StreamId, PendingEntry>,
+    pub consumers: BTreeMap<String, Consumer>,
+}
+
+#[derive(Clone)]
+pub struct PendingEntry {
+    pub consumer: String,
+    /// Unix milliseconds of the last delivery.
+    pub delivery_time: u64,
+    pub delivery_count: u64,
+}
+
+#[derive(Clone, Default)]
+pub struct Consumer {
+    /// Last time the consumer tried to interact with the group.
+    pub seen_time: u64,
+    /// Last time the consumer was actually handed entries.
+    pub active_time: Option<u64>,
+    pub pending: BTreeSet<StreamId>,
+}
+
+/// Options of XCLAIM that change how claimed entries are recorded.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ClaimOptions {
+    /// Delivery time to set, in unix milliseconds; defaults to now.
+    pub time: Option<u64>,
+    pub retry_count: Option<u64>,
+    /// Create PEL entries for IDs that are in the stream but not pending.
+    pub force: bool,
+    /// Only return IDs and don't count a delivery.
+    pub just_id: bool,
+}
+
+impl ConsumerGroup {
+    pub fn new(last_delivered: StreamId, entries_read: Option<u64>) -> Self {
+        Self { last_delivered, entries_read, ..Default::default() }
+    }
+
+    /// Returns the consumer, creating it on first use.
+    pub fn consumer(&mut self, name: &str, now: u64) -> &mut Consumer {
+        let consumer = self.consumers.entry(name.to_string()).or_default();
+        consumer.seen_time = now;
+        consumer
+    }
+
+    pub fn create_consumer(&mut self, name: &str, now: u64) -> bool {
+        if self.consumers.contains_key(name) {
+            return false
+        }
+        self.consumer(name, now);
+        true
+    }
+
+    /// Removes a consumer along with its pending entries, returning how many
+    /// were pending.
+    pub fn delete_consumer(&mut self, name: &str) -> usize {
+        let Some(consumer) = self.consumers.remove(name) else {
+            return 0
+        };
+        for id in &consumer.pending {
+            self.pel.remove(id);
+        }
+        consumer.pending.len()
+    }
+
+    pub fn ack(&mut self, id: StreamId) -> bool {
+        let Some(entry) = self.pel.remove(&id) else {
+            return false
+        };
+        if let Some(consumer) = self.consumers.get_mut(&entry.consumer) {
+            consumer.pending.remove(&id);
+        }
+        true
+    }
+
+    /// Records a delivery of `id` to `consumer`, moving the PEL entry from its
+    /// previous owner if there was one.
+    fn deliver(&mut self, id: StreamId, consumer: &str, time: u64, count_delivery: bool) {
+        let entry = self.pel.entry(id).or_insert_with(|| PendingEntry {
+            consumer: consumer.to_string(),
+            delivery_time: time,
+            delivery_count: 0,
+        });
+        if entry.consumer != consumer {
+            if let Some(old) = self.consumers.get_mut(&entry.consumer) {
+                old.pending.remove(&id);
+            }
+            entry.consumer = consumer.to_string();
+        }
+        entry.delivery_time = time;
+        if count_delivery {
+            entry.delivery_count += 1;
+        }
+        self.consumers.entry(consumer.to_string()).or_default().pending.insert(id);
+    }
+}
+
+#[derive(Clone)]
+struct Block {
+    master_fields: Vec<String>,
+    /// Live entries.
+    count: usize,
+    /// Tombstoned entries still stored in `data`.
+    deleted: usize,
+    last_id: StreamId,
+    data: Vec<u8>,
+}
+
+impl Stream {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.length
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    /// The ID of the last entry ever added.
+    pub fn last_id(&self) -> StreamId {
+        self.last_id
+    }
+
+    /// Entries added over the stream's lifetime, deleted ones included.
+    pub fn entries_added(&self) -> u64 {
+        self.entries_added
+    }
+
+    /// The largest ID removed by XDEL or trimming.
+    pub fn max_deleted_id(&self) -> StreamId {
+        self.max_deleted_id
+    }
+
+    /// Deleted entries still taking space in their blocks.
+    pub fn tombstones(&self) -> usize {
+        self.blocks.values().map(|b| b.deleted).sum()
+    }
+
+    /// XSETID: moves the stream's top ID and, optionally, its counters. The
+    /// ID may go back as far as the last live entry.
+    pub fn set_id(&mut self, id: StreamId, entries_added: Option<u64>, max_deleted_id: Option<StreamId>) -> Result<(), Error> {
+        if let Some((last, _)) = self.last_entry() && id < last {
+            return Err(anyhow!(ERR_SETID_SMALL))
+        }
+        if let Some(n) = entries_added && (self.length as u64) > n {
+            return Err(anyhow!("ERR The entries_added specified in XSETID is smaller than the target stream length"))
+        }
+        if let Some(max) = max_deleted_id && id < max {
+            return Err(anyhow!("ERR The ID specified in XSETID is smaller than the provided max_deleted_entry_id"))
+        }
+        self.last_id = id;
+        if let Some(n) = entries_added {
+            self.entries_added = n;
+        }
+        if let Some(max) = max_deleted_id {
+            self.max_deleted_id = max;
+        }
+        Ok(())
+    }
+
+    /// Resolves an XADD ID against the top of the stream, with Redis's rules
+    /// and error replies.
+    pub fn next_id(&self, spec: XAddId) -> Result<StreamId, Error> {
+        let last = self.last_id;
+        match spec {
+            XAddId::Auto => {
+                let now = now_ms();
+                if now > last.ms {
+                    Ok(StreamId::new(now, 0))
+                } else {
+                    // The clock went backwards or hasn't moved: keep counting
+                    // from the last ID.
+                    last.incr().ok_or_else(|| anyhow!(ERR_ID_EXHAUSTED))
+                }
+            }
+            XAddId::AutoSeq(ms) => {
+                if ms > last.ms {
+                    Ok(StreamId::new(ms, 0))
+                } else if ms == last.ms {
+                    match last.seq.checked_add(1) {
+                        Some(seq) => Ok(StreamId::new(ms, seq)),
+                        None => Err(anyhow!(ERR_ID_TOO_SMALL))
+                    }
+                } else {
+                    Err(anyhow!(ERR_ID_TOO_SMALL))
+                }
+            }
+            XAddId::Explicit(id) => {
+                if id == StreamId::MIN {
+                    Err(anyhow!(ERR_ID_ZERO))
+                } else if id <= last {
+                    Err(anyhow!(ERR_ID_TOO_SMALL))
+                } else {
+                    Ok(id)
+                }
+            }
+        }
+    }
+
+    /// Appends an entry. The caller has already checked that `id` is greater
+    /// than `last_id`.
+    pub fn insert(&mut self, id: StreamId, fields: Vec<(String, String)>) {
+        let needs_block = match self.blocks.last_key_value() {
+            Some((_, block)) => block.count + block.deleted >= BLOCK_MAX_ENTRIES,
+            None => true
+        };
+        if needs_block {
+            self.blocks.insert(id, Block {
+                master_fields: fields.iter().map(|(f, _)| f.clone()).collect(),
+                count: 0,
+                deleted: 0,
+                last_id: id,
+                data: Vec::new(),
+            });
+        }
+        let mut last = self.blocks.last_entry().unwrap();
+        let master = *last.key();
+        last.get_mut().push(master, id, &fields);
+        self.length += 1;
+        self.last_id = id;
+        self.entries_added += 1;
+    }
+
+    /// XDEL: tombstones the entry with `id`. Blocks left with no live entries
+    /// are dropped.
+    pub fn delete(&mut self, id: StreamId) -> bool {
+        let Some((&master, block)) = self.blocks.range_mut(..=id).next_back() else {
+            return false
+        };
+        if !block.delete(master, id) {
+            return false
+        }
+        if block.count == 0 {
+            self.blocks.remove(&master);
+        }
+        self.length -= 1;
+        if id > self.max_deleted_id {
+            self.max_deleted_id = id;
+        }
+        true
+    }
+
+    /// Removes entries from the head of the stream according to `trim` and
+    /// returns how many were removed. Whole blocks go first; exact trimming
+    /// then tombstones single entries in the block straddling the threshold,
+    /// dropping it too if that leaves it empty.
+    pub fn trim(&mut self, trim: &Trim) -> u64 {
+        let mut removed = 0u64;
+        while let Some(mut first) = self.blocks.first_entry() {
+            let master = *first.key();
+            let block = first.get_mut();
+            let live = block.count as u64;
+            let whole_block = match trim.strategy {
+                TrimStrategy::MaxLen(n) => self.length as u64 - live >= n,
+                TrimStrategy::MinId(min) => block.last_id < min
+            };
+            if whole_block {
+                if let Some(limit) = trim.limit && removed + live > limit {
+                    break;
+                }
+                self.max_deleted_id = self.max_deleted_id.max(block.last_id);
+                first.remove();
+                self.length -= live as usize;
+                removed += live;
+                continue;
+            }
+            if trim.approx {
+                break;
+            }
+            for (id, _) in block.entries(master) {
+                let done = match trim.strategy {
+                    TrimStrategy::MaxLen(n) => self.length as u64 <= n,
+                    TrimStrategy::MinId(min) => id >= min
+                };
+                if done {
+                    break;
+                }
+                block.delete(master, id);
+                self.length -= 1;
+                removed += 1;
+                self.max_deleted_id = self.max_deleted_id.max(id);
+            }
+            if block.count > 0 {
+                break;
+            }
+            first.remove();
+        }
+        removed
+    }
+
+    /// The fields of the entry with `id`, if it is still in the stream.
+    pub fn get(&self, id: StreamId) -> Option<Vec<(String, String)>> {
+        self.range(Bound::Included(id), Bound::Included(id)).pop().map(|(_, fields)| fields)
+    }
+
+    /// XREADGROUP for one stream. With `after == None` (the `>` ID) it hands
+    /// out entries past the group's last delivered ID; otherwise it replays
+    /// the consumer's own pending entries after `after`.
+    pub fn read_group(&mut self, group: &str, consumer: &str, after: Option<StreamId>, count: Option<usize>, noack: bool) -> Option<Vec<DeliveredEntry>> {
+        let now = now_ms();
+        let last_delivered = self.groups.get(group)?.last_delivered;
+        let mut delivered = Vec::new();
+        match after {
+            None => {
+                let entries = self.range_count(Bound::Excluded(last_delivered), Bound::Unbounded, count);
+                let first = self.first_id();
+                let counters: Vec<(bool, Option<u64>)> = entries.iter()
+                    .map(|(id, _)| (self.has_tombstones_from(*id, first), self.estimate_entries_read(*id, first)))
+                    .collect();
+                let group = self.groups.get_mut(group)?;
+                group.consumer(consumer, now);
+                for ((id, fields), (tombstones, estimate)) in entries.into_iter().zip(counters) {
+                    group.last_delivered = id;
+                    group.entries_read = match group.entries_read {
+                        Some(read) if !tombstones => Some(read + 1),
+                        _ => estimate
+                    };
+                    if !noack {
+                        group.deliver(id, consumer, now, true);
+                    }
+                    delivered.push((id, Some(fields)));
+                }
+            }
+            Some(after) => {
+                let pending: Vec<StreamId> = {
+                    let group = self.groups.get_mut(group)?;
+                    let c = group.consumer(consumer, now);
+                    let ids = c.pending.range((Bound::Excluded(after), Bound::Unbounded)).copied();
+                    match count {
+                        Some(count) => ids.take(count).collect(),
+                        None => ids.collect()
+                    }
+                };
+                for id in pending {
+                    delivered.push((id, self.get(id)));
+                }
+                // Entries deleted from the stream are reported but don't
+                // count as a new delivery.
+                let group = self.groups.get_mut(group)?;
+                for (id, fields) in &delivered {
+                    if fields.is_some() {
+                        group.deliver(*id, consumer, now, true);
+                    }
+                }
+            }
+        }
+        if !delivered.is_empty() {
+            self.groups.get_mut(group)?.consumer(consumer, now).active_time = Some(now);
+        }
+        Some(delivered)
+    }
+
+    /// XCLAIM: transfers the pending entries in `ids` idle for at least
+    /// `min_idle` ms to `consumer`. Entries deleted from the stream are
+    /// dropped from the PEL instead of being claimed.
+    pub fn claim(&mut self, group: &str, consumer: &str, min_idle: u64, ids: &[StreamId], opts: ClaimOptions) -> Option<Vec<DeliveredEntry>> {
+        let now = now_ms();
+        let mut claimed = Vec::new();
+        for &id in ids {
+            let fields = self.get(id);
+            let group = self.groups.get_mut(group)?;
+            group.consumer(consumer, now);
+            match group.pel.get(&id) {
+                None if opts.force && fields.is_some() => {}
+                None => continue,
+                Some(_) if fields.is_none() => {
+                    group.ack(id);
+                    continue;
+                }
+                Some(entry) if now.saturating_sub(entry.delivery_time) < min_idle => continue,
+                Some(_) => {}
+            }
+            group.deliver(id, consumer, opts.time.unwrap_or(now), !opts.just_id);
+            if let Some(n) = opts.retry_count {
+                group.pel.get_mut(&id).unwrap().delivery_count = n;
+            }
+            group.consumer(consumer, now).active_time = Some(now);
+            claimed.push((id, if opts.just_id { None } else { fields }));
+        }
+        Some(claimed)
+    }
+
+    /// XAUTOCLAIM: scans the PEL from `start` and claims up to `count` entries
+    /// idle for at least `min_idle` ms. Returns the cursor for the next call,
+    /// the claimed entries and the IDs that no longer exist in the stream.
+    pub fn autoclaim(&mut self, group: &str, consumer: &str, min_idle: u64, start: StreamId, count: usize, just_id: bool) -> Option<(StreamId, Vec<DeliveredEntry>, Vec<StreamId>)> {
+        let now = now_ms();
+        // Like Redis, look at no more than ten times COUNT PEL entries per call.
+        let mut attempts = count * 10;
+        let candidates: Vec<(StreamId, u64)> = self.groups.get(group)?
+            .pel
+            .range(start..)
+            .map(|(id, e)| (*id, e.delivery_time))
+            .collect();
+        let mut claimed = Vec::new();
+        let mut deleted = Vec::new();
+        let mut next = StreamId::MIN;
+        for (id, delivery_time) in candidates {
+            if attempts == 0 || claimed.len() >= count {
+                next = id;
+                break;
+            }
+            attempts -= 1;
+            if now.saturating_sub(delivery_time) < min_idle {
+                continue;
+            }
+            let fields = self.get(id);
+            let group = self.groups.get_mut(group)?;
+            if fields.is_none() {
+                group.ack(id);
+                deleted.push(id);
+                continue;
+            }
+            group.deliver(id, consumer, now, !just_id);
+            claimed.push((id, if just_id { None } else { fields }));
+        }
+        let group = self.groups.get_mut(group)?;
+        let c = group.consumer(consumer, now);
+        if !claimed.is_empty() {
+            c.active_time = Some(now);
+        }
+        Some((next, claimed, deleted))
+    }
+
+    /// The first live entry.
+    pub fn first_entry(&self) -> Option<StreamEntry> {
+        self.blocks.iter().find_map(|(master, block)| block.entries(*master).into_iter().next())
+    }
+
+    /// Entries with IDs between `start` and `end`, in ID order.
+    pub fn range(&self, start: Bound<StreamId>, end: Bound<StreamId>) -> Vec<StreamEntry> {
+        self.range_count(start, end, None)
+    }
+
+    /// Like `range`, stopping after `count` entries.
+    pub fn range_count(&self, start: Bound<StreamId>, end: Bound<StreamId>, count: Option<usize>) -> Vec<StreamEntry> {
+        // The first block that can hold `start` is the last one whose master
+        // ID is not above it.
+        let first_block = match start {
+            Bound::Included(s) | Bound::Excluded(s) => self.blocks.range(..=s).next_back().map(|(k, _)| *k),
+            Bound::Unbounded => None
+        };
+        let blocks = match first_block {
+            Some(k) => self.blocks.range(k..),
+            None => self.blocks.range(..)
+        };
+        let mut entries = Vec::new();
+        for (master, block) in blocks {
+            if past_end(*master, end) {
+                break;
+            }
+            for (id, fields) in block.entries(*master) {
+                if past_end(id, end) || count.is_some_and(|c| entries.len() >= c) {
+                    return entries;
+                }
+                if !before_start(id, start) {
+                    entries.push((id, fields));
+                }
+            }
+        }
+        entries
+    }
+
+    /// Entries with IDs between `start` and `end`, newest first, stopping
+    /// after `count` entries.
+    pub fn rev_range(&self, start: Bound<StreamId>, end: Bound<StreamId>, count: Option<usize>) -> Vec<StreamEntry> {
+        let blocks = match end {
+            Bound::Included(e) | Bound::Excluded(e) => self.blocks.range(..=e),
+            Bound::Unbounded => self.blocks.range(..)
+        };
+        let mut entries = Vec::new();
+        for (master, block) in blocks.rev() {
+            for (id, fields) in block.entries(*master).into_iter().rev() {
+                if before_start(id, start) || count.is_some_and(|c| entries.len() >= c) {
+                    return entries;
+                }
+                if !past_end(id, end) {
+                    entries.push((id, fields));
+                }
+            }
+        }
+        entries
+    }
+
+    pub fn last_entry(&self) -> Option<StreamEntry> {
+        self.rev_range(Bound::Unbounded, Bound::Unbounded, Some(1)).pop()
+    }
+
+    /// Number of blocks holding the stream's entries.
+    pub fn block_count(&self) -> usize {
+        self.blocks.len()
+    }
+
+    /// The ID of the first live entry, or 0-0 for an empty stream.
+    pub fn first_id(&self) -> StreamId {
+        self.first_entry().map(|(id, _)| id).unwrap_or_default()
+    }
+
+    /// Whether an XDEL may have left a gap at or after `id`, which makes
+    /// logical read counters past it unreliable.
+    fn has_tombstones_from(&self, id: StreamId, first: StreamId) -> bool {
+        let max = self.max_deleted_id;
+        self.length > 0 && max != StreamId::MIN && max >= first && max >= id
+    }
+
+    /// How many entries were ever added up to and including `id`, when that
+    /// can be known without walking the stream.
+    fn estimate_entries_read(&self, id: StreamId, first: StreamId) -> Option<u64> {
+        if self.entries_added == 0 {
+            return Some(0)
+        }
+        if self.length == 0 && id <= self.last_id {
+            return Some(self.entries_added)
+        }
+        if id == self.last_id {
+            return Some(self.entries_added)
+        }
+        if id > self.last_id {
+            return None
+        }
+        if self.max_deleted_id == StreamId::MIN || self.max_deleted_id < first {
+            // No fragmentation ahead.
+            if id < first {
+                return Some(self.entries_added - self.length as u64)
+            }
+            if id == first {
+                return Some(self.entries_added - self.length as u64 + 1)
+            }
+        }
+        None
+    }
+
+    /// Entries the group has yet to read, or `None` if it can't be known.
+    pub fn lag(&self, group: &ConsumerGroup) -> Option<u64> {
+        if self.entries_added == 0 {
+            return Some(0)
+        }
+        let first = self.first_id();
+        if let Some(read) = group.entries_read
+            && !self.has_tombstones_from(group.last_delivered, first) {
+            return Some(self.entries_added.saturating_sub(read))
+        }
+        self.estimate_entries_read(group.last_delivered, first)
+            .map(|read| self.entries_added.saturating_sub(read))
+    }
+}
+
+fn before_start(id: StreamId, start: Bound<StreamId>) -> bool {
+    match start {
+        Bound::Included(s) => id < s,
+        Bound::Excluded(s) => id <= s,
+        Bound::Unbounded => false
+    }
+}
+
+fn past_end(id: StreamId, end: Bound<StreamId>) -> bool {
+    match end {
+        Bound::Included(e) => id > e,
+        Bound::Excluded(e) => id >= e,
+        Bound::Unbounded => false
+    }
+}
+
+impl Block {
+    fn push(&mut self, master: StreamId, id: StreamId, fields: &[(String, String)]) {
+        let same_fields = fields.len() == self.master_fields.len()
+            && fields.iter().zip(&self.master_fields).all(|((f, _), m)| f == m);
+        self.data.push(if same_fields { FLAG_SAME_FIELDS } else { 0 });
+        self.last_id = id;
+        let ms_delta = id.ms - master.ms;
+        write_varint(&mut self.data, ms_delta);
+        // Sequences only share a base with the master inside the same millisecond.
+        write_varint(&mut self.data, if ms_delta == 0 { id.seq - master.seq } else { id.seq });
+        if same_fields {
+            for (_, v) in fields {
+                write_str(&mut self.data, v);
+            }
+        } else {
+            write_varint(&mut self.data, fields.len() as u64);
+            for (f, v) in fields {
+                write_str(&mut self.data, f);
+                write_str(&mut self.data, v);
+            }
+        }
+        self.count += 1;
+    }
+
+    fn entries(&self, master: StreamId) -> Vec<StreamEntry> {
+        let mut entries = Vec::with_capacity(self.count);
+        let mut pos = 0;
+        while pos < self.data.len() {
+            let (flags, id, fields) = self.decode(master, &mut pos);
+            if flags & FLAG_DELETED == 0 {
+                entries.push((id, fields));
+            }
+        }
+        entries
+    }
+
+    fn delete(&mut self, master: StreamId, id: StreamId) -> bool {
+        let mut pos = 0;
+        while pos < self.data.len() {
+            let start = pos;
+            let (flags, entry_id, _) = self.decode(master, &mut pos);
+            if entry_id > id {
+                break;
+            }
+            if entry_id == id {
+                if flags & FLAG_DELETED != 0 {
+                    return false
+                }
+                self.data[start] |= FLAG_DELETED;
+                self.count -= 1;
+                self.deleted += 1;
+                return true
+            }
+        }
+        false
+    }
+
+    /// Decodes the entry starting at `pos` and moves `pos` past it.
+    fn decode(&self, master: StreamId, pos: &mut usize) -> (u8, StreamId, Vec<(String, String)>) {
+        let flags = self.data[*pos];
+        *pos += 1;
+        let ms_delta = read_varint(&self.data, pos);
+        let seq = read_varint(&self.data, pos);
+        let id = if ms_delta == 0 {
+            StreamId::new(master.ms, master.seq + seq)
+        } else {
+            StreamId::new(master.ms + ms_delta, seq)
+        };
+        let fields = if flags & FLAG_SAME_FIELDS != 0 {
+            self.master_fields.iter().map(|f| (f.clone(), read_str(&self.data, pos))).collect()
+        } else {
+            let n = read_varint(&self.data, pos);
+            (0..n).map(|_| (read_str(&self.data, pos), read_str(&self.data, pos))).collect()
+        };
+        (flags, id, fields)
+    }
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        buf.push((v as u8) | 0x80);
+        v >>= 7;
+    }
+    buf.push(v as u8);
+}
+
+fn read_varint(buf: &[u8], pos: &mut usize) -> u64 {
+    let mut v = 0u64;
+    let mut shift = 0;
+    loop {
+        let b = buf[*pos];
+        *pos += 1;
+        v |= ((b & 0x7f) as u64) << shift;
+        if b & 0x80 == 0 {
+            return v
+        }
+        shift += 7;
+    }
+}
+
+fn write_str(buf: &mut Vec<u8>, s: &str) {
+    write_varint(buf, s.len() as u64);
+    buf.extend_from_slice(s.as_bytes());
+}
+
+fn read_str(buf: &[u8], pos: &mut usize) -> String {
+    let len = read_varint(buf, pos) as usize;
+    let s = String::from_utf8_lossy(&buf[*pos..*pos + len]).into_owned();
+    *pos += len;
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(ms: u64, seq: u64) -> StreamId {
+        StreamId::new(ms, seq)
+    }
+
+    fn fields(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|(f, v)| (f.to_string(), v.to_string())).collect()
+    }
+
+    fn block(master_fields: &[&str]) -> Block {
+        Block { master_fields: master_fields.iter().map(|f| f.to_string()).collect(), count: 0, deleted: 0, last_id: StreamId::MIN, data: Vec::new() }
+    }
+
+    #[test]
+    fn block_round_trip() {
+        let master = id(1000, 5);
+        let mut block = block(&["a", "b"]);
+        let entries = vec![
+            (master, fields(&[("a", "1"), ("b", "2")])),
+            (id(1000, 300), fields(&[("a", ""), ("b", "é\r\n")])),
+            (id(1001, 0), fields(&[("b", "x"), ("a", "y")])),
+            (StreamId::MAX, fields(&[("c", &"z".repeat(200))])),
+        ];
+        for (id, f) in &entries {
+            block.push(master, *id, f);
+        }
+        assert_eq!(block.count, 4);
+        assert_eq!(block.entries(master), entries);
+    }
+
+    #[test]
+    fn block_stores_only_values_for_master_fields() {
+        let master = id(1, 0);
+        let mut same = block(&["field"]);
+        same.push(master, master, &fields(&[("field", "v")]));
+        let mut other = block(&["field"]);
+        other.push(master, master, &fields(&[("other", "v")]));
+        assert_eq!(same.data[0], FLAG_SAME_FIELDS);
+        assert_eq!(other.data[0], 0);
+        assert!(same.data.len() < other.data.len());
+    }
+
+    #[test]
+    fn range_across_blocks() {
+        let mut stream = Stream::new();
+        for ms in 1..=250 {
+            stream.insert(id(ms, 0), fields(&[("n", &ms.to_string())]));
+        }
+        assert_eq!((stream.len(), stream.blocks.len(), stream.last_id()), (250, 3, id(250, 0)));
+        let ids = |entries: Vec<StreamEntry>| entries.into_iter().map(|(id, _)| id.ms).collect::<Vec<_>>();
+        assert_eq!(ids(stream.range(Bound::Included(id(99, 0)), Bound::Included(id(102, 0)))), [99, 100, 101, 102]);
+        assert_eq!(ids(stream.range(Bound::Excluded(id(200, 0)), Bound::Excluded(id(203, 0)))), [201, 202]);
+        assert_eq!(stream.range(Bound::Unbounded, Bound::Unbounded).len(), 250);
+        assert!(stream.range(Bound::Included(id(251, 0)), Bound::Unbounded).is_empty());
+    }
+
+    #[test]
+    fn stream_id_parse() {
+        assert_eq!(StreamId::parse("5-3", 0, true).unwrap(), id(5, 3));
+        assert_eq!(StreamId::parse("5", 7, true).unwrap(), id(5, 7));
+        assert_eq!(StreamId::parse("18446744073709551615-18446744073709551615", 0, true).unwrap(), StreamId::MAX);
+        assert_eq!(StreamId::parse("-", 0, false).unwrap(), StreamId::MIN);
+        assert_eq!(StreamId::parse("+", 0, false).unwrap(), StreamId::MAX);
+        for bad in ["-", "+", "*", "", "5-", "-5", "a-1", "1-b", "1-2-3", "18446744073709551616", "1-18446744073709551616"] {
+            assert!(StreamId::parse(bad, 0, true).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn stream_id_parse_bound() {
+        assert_eq!(StreamId::parse_bound("5", true).unwrap(), Bound::Included(id(5, 0)));
+        assert_eq!(StreamId::parse_bound("5", false).unwrap(), Bound::Included(id(5, u64::MAX)));
+        assert_eq!(StreamId::parse_bound("(5-1", true).unwrap(), Bound::Excluded(id(5, 1)));
+        assert!(StreamId::parse_bound("(-", true).is_err());
+        assert!(StreamId::parse_bound("(+", false).is_err());
+    }
+
+    #[test]
+    fn xadd_id_parse() {
+        assert!(matches!(XAddId::parse("*").unwrap(), XAddId::Auto));
+        assert!(matches!(XAddId::parse("12-*").unwrap(), XAddId::AutoSeq(12)));
+        assert!(matches!(XAddId::parse("12-3").unwrap(), XAddId::Explicit(id) if id == StreamId::new(12, 3)));
+        for bad in ["-*", "x-*", "18446744073709551616-*", "*-1", "12-*-*"] {
+            assert!(XAddId::parse(bad).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn stream_id_incr() {
+        assert_eq!(id(1, 2).incr(), Some(id(1, 3)));
+        assert_eq!(id(1, u64::MAX).incr(), Some(id(2, 0)));
+        assert_eq!(StreamId::MAX.incr(), None);
+    }
+
+    #[test]
+    fn next_id() {
+        let mut stream = Stream::new();
+        assert_eq!(stream.next_id(XAddId::Explicit(StreamId::MIN)).unwrap_err().to_string(), ERR_ID_ZERO);
+        assert_eq!(stream.next_id(XAddId::AutoSeq(0)).unwrap(), id(0, 1));
+        stream.insert(id(5, 5), fields(&[("f", "v")]));
+        assert_eq!(stream.next_id(XAddId::AutoSeq(5)).unwrap(), id(5, 6));
+        assert_eq!(stream.next_id(XAddId::AutoSeq(6)).unwrap(), id(6, 0));
+        assert_eq!(stream.next_id(XAddId::AutoSeq(4)).unwrap_err().to_string(), ERR_ID_TOO_SMALL);
+        assert_eq!(stream.next_id(XAddId::Explicit(id(5, 5))).unwrap_err().to_string(), ERR_ID_TOO_SMALL);
+        stream.insert(StreamId::MAX, fields(&[("f", "v")]));
+        assert_eq!(stream.next_id(XAddId::Auto).unwrap_err().to_string(), ERR_ID_EXHAUSTED);
+    }
+
+    fn numbered(n: u64) -> Stream {
+        let mut stream = Stream::new();
+        for ms in 1..=n {
+            stream.insert(id(ms, 0), fields(&[("n", &ms.to_string())]));
+        }
+        stream
+    }
+
+    fn trim(strategy: TrimStrategy, approx: bool) -> Trim {
+        Trim { strategy, approx, limit: None }
+    }
+
+    #[test]
+    fn block_delete_sets_flag() {
+        let master = id(1, 0);
+        let mut block = block(&["f"]);
+        for seq in 0..3 {
+            block.push(master, id(1, seq), &fields(&[("f", "v")]));
+        }
+        assert!(block.delete(master, id(1, 1)));
+        assert!(!block.delete(master, id(1, 1)));
+        assert!(!block.delete(master, id(1, 7)));
+        assert_eq!((block.count, block.deleted), (2, 1));
+        let mut pos = 0;
+        let flags: Vec<u8> = (0..3).map(|_| block.decode(master, &mut pos).0 & FLAG_DELETED).collect();
+        assert_eq!(flags, [0, FLAG_DELETED, 0]);
+        assert_eq!(block.entries(master).into_iter().map(|(id, _)| id).collect::<Vec<_>>(), [id(1, 0), id(1, 2)]);
+    }
+
+    #[test]
+    fn delete_tracks_length_and_max_deleted_id() {
+        let mut stream = numbered(3);
+        assert!(stream.delete(id(2, 0)));
+        assert!(!stream.delete(id(2, 0)));
+        assert!(stream.delete(id(1, 0)));
+        assert_eq!((stream.len(), stream.max_deleted_id(), stream.last_id()), (1, id(2, 0), id(3, 0)));
+        assert!(stream.delete(id(3, 0)));
+        assert!(stream.is_empty() && stream.blocks.is_empty());
+        assert_eq!((stream.last_id(), stream.entries_added()), (id(3, 0), 3));
+    }
+
+    #[test]
+    fn trim_maxlen() {
+        let mut stream = numbered(250);
+        assert_eq!(stream.trim(&trim(TrimStrategy::MaxLen(120), true)), 100);
+        assert_eq!(stream.len(), 150);
+        assert_eq!(stream.trim(&trim(TrimStrategy::MaxLen(120), false)), 30);
+        assert_eq!((stream.len(), stream.first_entry().unwrap().0), (120, id(131, 0)));
+        assert_eq!(stream.trim(&trim(TrimStrategy::MaxLen(200), false)), 0);
+    }
+
+    #[test]
+    fn trim_minid() {
+        let mut stream = numbered(250);
+        assert_eq!(stream.trim(&trim(TrimStrategy::MinId(id(150, 0)), true)), 100);
+        assert_eq!(stream.trim(&trim(TrimStrategy::MinId(id(150, 0)), false)), 49);
+        assert_eq!(stream.first_entry().unwrap().0, id(150, 0));
+    }
+
+    #[test]
+    fn trim_respects_limit() {
+        let mut stream = numbered(250);
+        let limited = Trim { strategy: TrimStrategy::MaxLen(0), approx: true, limit: Some(150) };
+        assert_eq!(stream.trim(&limited), 100);
+        assert_eq!(stream.len(), 150);
+    }
+
+    #[test]
+    fn trim_parse() {
+        let args = |a: &[&str]| a.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let mut i = 0;
+        let parsed = Trim::parse(&args(&["MAXLEN", "~", "10", "LIMIT", "5", "rest"]), &mut i).unwrap();
+        assert!(matches!(parsed.strategy, TrimStrategy::MaxLen(10)) && parsed.approx && parsed.limit == Some(5));
+        assert_eq!(i, 5);
+        let mut i = 0;
+        let parsed = Trim::parse(&args(&["MINID", "=", "3-1"]), &mut i).unwrap();
+        assert!(matches!(parsed.strategy, TrimStrategy::MinId(id) if id == StreamId::new(3, 1)) && !parsed.approx);
+        assert_eq!(parsed.limit, None);
+        for bad in [&["MAXLEN", "-1"][..], &["MAXLEN", "10", "LIMIT", "5"], &["MAXLEN"], &["MINID", "x"]] {
+            assert!(Trim::parse(&args(bad), &mut 0).is_err(), "{bad:?}");
+        }
+    }
+
+    #[test]
+    fn set_id() {
+        let mut stream = numbered(3);
+        assert_eq!(stream.set_id(id(2, 0), None, None).unwrap_err().to_string(), ERR_SETID_SMALL);
+        assert!(stream.set_id(id(9, 0), Some(2), None).is_err());
+        assert!(stream.set_id(id(9, 0), None, Some(id(10, 0))).is_err());
+        stream.set_id(id(9, 0), Some(7), Some(id(1, 0))).unwrap();
+        assert_eq!((stream.last_id(), stream.entries_added(), stream.max_deleted_id()), (id(9, 0), 7, id(1, 0)));
+    }
+
+    fn with_group(n: u64) -> Stream {
+        let mut stream = numbered(n);
+        stream.groups.insert("g".to_string(), ConsumerGroup::new(StreamId::MIN, Some(0)));
+        stream
+    }
+
+    fn delivered_ids(entries: Vec<DeliveredEntry>) -> Vec<u64> {
+        entries.into_iter().map(|(id, _)| id.ms).collect()
+    }
+
+    #[test]
+    fn read_group_delivers_new_entries_once() {
+        let mut stream = with_group(3);
+        assert_eq!(delivered_ids(stream.read_group("g", "alice", None, Some(2), false).unwrap()), [1, 2]);
+        assert_eq!(delivered_ids(stream.read_group("g", "bob", None, None, false).unwrap()), [3]);
+        assert!(stream.read_group("g", "bob", None, None, false).unwrap().is_empty());
+        assert!(stream.read_group("missing", "bob", None, None, false).is_none());
+        let group = &stream.groups["g"];
+        assert_eq!((group.last_delivered, group.entries_read, group.pel.len()), (id(3, 0), Some(3), 3));
+        assert_eq!(group.pel[&id(3, 0)].consumer, "bob");
+    }
+
+    #[test]
+    fn read_group_replays_own_pending_entries() {
+        let mut stream = with_group(3);
+        stream.read_group("g", "alice", None, Some(2), false).unwrap();
+        stream.read_group("g", "bob", None, None, false).unwrap();
+        stream.delete(id(2, 0));
+        let replay = stream.read_group("g", "alice", Some(StreamId::MIN), None, false).unwrap();
+        assert_eq!(replay.iter().map(|(id, f)| (id.ms, f.is_some())).collect::<Vec<_>>(), [(1, true), (2, false)]);
+        assert_eq!(stream.groups["g"].pel[&id(1, 0)].delivery_count, 2);
+        assert_eq!(stream.groups["g"].pel[&id(2, 0)].delivery_count, 1);
+    }
+
+    #[test]
+    fn noack_skips_the_pel() {
+        let mut stream = with_group(2);
+        assert_eq!(stream.read_group("g", "c", None, None, true).unwrap().len(), 2);
+        assert!(stream.groups["g"].pel.is_empty());
+    }
+
+    #[test]
+    fn ack_and_delete_consumer() {
+        let mut stream = with_group(3);
+        stream.read_group("g", "c", None, None, false).unwrap();
+        let group = stream.groups.get_mut("g").unwrap();
+        assert!(group.ack(id(1, 0)));
+        assert!(!group.ack(id(1, 0)));
+        assert!(!group.create_consumer("c", 0));
+        assert_eq!(group.delete_consumer("c"), 2);
+        assert!(group.pel.is_empty() && group.consumers.is_empty());
+    }
+
+    #[test]
+    fn claim_moves_idle_entries() {
+        let mut stream = with_group(3);
+        stream.read_group("g", "alice", None, None, false).unwrap();
+        assert!(stream.claim("g", "bob", 60_000, &[id(1, 0)], ClaimOptions::default()).unwrap().is_empty());
+        let claimed = stream.claim("g", "bob", 0, &[id(1, 0), id(9, 0)], ClaimOptions::default()).unwrap();
+        assert_eq!(delivered_ids(claimed), [1]);
+        let group = &stream.groups["g"];
+        assert_eq!((group.pel[&id(1, 0)].consumer.as_str(), group.pel[&id(1, 0)].delivery_count), ("bob", 2));
+        assert!(!group.consumers["alice"].pending.contains(&id(1, 0)));
+        let forced = ClaimOptions { force: true, just_id: true, ..Default::default() };
+        stream.groups.get_mut("g").unwrap().ack(id(2, 0));
+        let claimed = stream.claim("g", "bob", 0, &[id(2, 0)], forced).unwrap();
+        assert_eq!(claimed.iter().map(|(id, f)| (id.ms, f.is_some())).collect::<Vec<_>>(), [(2, false)]);
+        assert_eq!(stream.groups["g"].pel[&id(2, 0)].delivery_count, 0);
+    }
+
+    #[test]
+    fn autoclaim_pages_and_drops_deleted_entries() {
+        let mut stream = with_group(4);
+        stream.read_group("g", "alice", None, None, false).unwrap();
+        stream.delete(id(2, 0));
+        let (next, claimed, deleted) = stream.autoclaim("g", "bob", 0, StreamId::MIN, 2, false).unwrap();
+        assert_eq!((next, delivered_ids(claimed), deleted), (id(4, 0), vec![1, 3], vec![id(2, 0)]));
+        let (next, claimed, _) = stream.autoclaim("g", "bob", 0, next, 2, true).unwrap();
+        assert_eq!((next, delivered_ids(claimed)), (StreamId::MIN, vec![4]));
+        assert!(!stream.groups["g"].pel.contains_key(&id(2, 0)));
+    }
+
+    #[test]
+    fn range_count_and_rev_range() {
+        let mut stream = numbered(250);
+        stream.delete(id(100, 0));
+        let ids = |entries: Vec<StreamEntry>| entries.into_iter().map(|(id, _)| id.ms).collect::<Vec<_>>();
+        assert_eq!(ids(stream.range_count(Bound::Included(id(98, 0)), Bound::Unbounded, Some(3))), [98, 99, 101]);
+        assert_eq!(ids(stream.rev_range(Bound::Unbounded, Bound::Included(id(102, 0)), Some(3))), [102, 101, 99]);
+        assert_eq!(ids(stream.rev_range(Bound::Excluded(id(247, 0)), Bound::Unbounded, None)), [250, 249, 248]);
+        assert!(stream.rev_range(Bound::Included(id(5, 0)), Bound::Included(id(4, 0)), None).is_empty());
+        assert_eq!((stream.first_id(), stream.last_entry().unwrap().0), (id(1, 0), id(250, 0)));
+    }
+
+    #[test]
+    fn lag() {
+        let mut stream = with_group(5);
+        assert_eq!(stream.lag(&stream.groups["g"]), Some(5));
+        stream.read_group("g", "c", None, Some(2), false).unwrap();
+        assert_eq!(stream.lag(&stream.groups["g"]), Some(3));
+        // A deletion past the group's position makes its counter unreliable.
+        stream.delete(id(4, 0));
+        assert_eq!(stream.lag(&stream.groups["g"]), None);
+        stream.read_group("g", "c", None, None, false).unwrap();
+        assert_eq!(stream.lag(&stream.groups["g"]), Some(0));
+    }
+
+    #[test]
+    fn exact_trim_drops_emptied_block() {
+        let mut stream = Stream::new();
+        for ms in 1..=3 {
+            stream.insert(StreamId::new(ms, 0), fields(&[("f", "v")]));
+        }
+        stream.delete(StreamId::new(3, 0));
+        let trim = Trim { strategy: TrimStrategy::MinId(StreamId::new(3, 0)), approx: false, limit: None };
+        assert_eq!(stream.trim(&trim), 2);
+        assert_eq!((stream.len(), stream.block_count()), (0, 0));
+        assert_eq!(stream.max_deleted_id(), StreamId::new(3, 0));
+    }
+
+    #[test]
+    fn set_id_below_a_deleted_top_entry() {
+        let mut stream = Stream::new();
+        stream.insert(StreamId::new(1, 0), fields(&[("f", "v")]));
+        stream.insert(StreamId::new(5, 0), fields(&[("f", "v")]));
+        stream.delete(StreamId::new(5, 0));
+        assert!(stream.set_id(StreamId::new(0, 5), None, None).is_err());
+        stream.set_id(StreamId::new(2, 0), None, None).unwrap();
+    }
+}
@@ -0,0 +1,158 @@
+use std::collections::{HashMap, HashSet};
+
+use anyhow::Error;
+
+/// CLIENT TRACKING settings of one client.
+#[derive(Default)]
+pub struct TrackingClient {
+    /// Client that receives the invalidations instead of this one.
+    pub redirect: Option<u64>,
+    pub bcast: bool,
+    pub optin: bool,
+    pub optout: bool,
+    pub noloop: bool,
+    /// BCAST prefixes; the empty prefix matches every key.
+    pub prefixes: Vec<String>,
+    /// CLIENT CACHING YES/NO, good for the next command only.
+    pub caching: Option<bool>,
+}
+
+#[derive(Default)]
+pub struct Tracking {
+    pub clients: HashMap<u64, TrackingClient>,
+    /// Default mode: clients that may hold a cached copy of each key.
+    /// Clients are dropped from a key once invalidated, and lazily after
+    /// they turn tracking off.
+    keys: HashMap<String, HashSet<u64>>,
+}
+
+impl Tracking {
+    /// CLIENT TRACKING ON. Prefixes accumulate over calls; the mode can only
+    /// change after tracking is turned off.
+    pub fn enable(&mut self, id: u64, mut options: TrackingClient) -> Result<(), Error> {
+        if options.bcast && options.prefixes.is_empty() {
+            options.prefixes.push(String::new());
+        }
+        let existing = self.clients.get(&id);
+        if let Some(c) = existing {
+            if c.bcast != options.bcast {
+                return Err(anyhow::anyhow!("ERR You can't switch BCAST mode on/off before disabling tracking for this client, and then re-enabling it with a different mode."))
+            }
+            if c.optin != options.optin || c.optout != options.optout {
+                return Err(anyhow::anyhow!("ERR You can't switch OPTIN/OPTOUT mode before disabling tracking for this client, and then re-enabling it with a different mode."))
+            }
+        }
+        let mut prefixes = existing.map(|c| c.prefixes.clone()).unwrap_or_default();
+        for prefix in options.prefixes {
+            if prefixes.contains(&prefix) {
+                continue;
+            }
+            if let Some(other) = prefixes.iter().find(|p| p.starts_with(&prefix) || prefix.starts_with(p.as_str())) {
+                return Err(anyhow::anyhow!("ERR Prefix '{}' overlaps with an existing prefix '{}'. Prefixes for a single client must not overlap.", prefix, other))
+            }
+            prefixes.push(prefix);
+        }
+        options.prefixes = prefixes;
+        self.clients.insert(id, options);
+        Ok(())
+    }
+
+    pub fn disable(&mut self, id: u64) {
+        self.clients.remove(&id);
+    }
+
+    pub fn reset_caching(&mut self, id: u64) {
+        if let Some(c) = self.clients.get_mut(&id) {
+            c.caching = None;
+        }
+    }
+
+    /// Records that client `id` read `keys`, when its mode asks for it.
+    pub fn remember(&mut self, id: u64, keys: &[&String]) {
+        let Some(c) = self.clients.get(&id) else { return };
+        if c.bcast || (c.optin && c.caching != Some(true)) || (c.optout && c.caching == Some(false)) {
+            return
+        }
+        for key in keys {
+            self.keys.entry(key.to_string()).or_default().insert(id);
+        }
+    }
+
+    /// Tracking clients to invalidate now that `key` changed. `writer` is
+    /// the client that changed it, skipped by NOLOOP clients.
+    pub fn invalidated(&mut self, key: &str, writer: Option<u64>) -> Vec<u64> {
+        let mut ids: Vec<u64> = self.keys.remove(key).unwrap_or_default().into_iter()
+            .filter(|id| self.clients.get(id).is_some_and(|c| !c.bcast))
+            .collect();
+        for (id, c) in &self.clients {
+            if c.bcast && c.prefixes.iter().any(|p| key.starts_with(p.as_str())) {
+                ids.push(*id);
+            }
+        }
+        ids.retain(|id| !(Some(*id) == writer && self.clients[id].noloop));
+        ids
+    }
+
+    /// Every tracking client, all to be invalidated at once now that a
+    /// database was flushed. Nobody holds a key anymore.
+    pub fn flushed(&mut self) -> Vec<u64> {
+        self.keys.clear();
+        self.clients.keys().copied().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sorted(mut ids: Vec<u64>) -> Vec<u64> {
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn default_mode_invalidates_once() {
+        let mut tracking = Tracking::default();
+        tracking.enable(1, TrackingClient::default()).unwrap();
+        tracking.enable(2, TrackingClient { noloop: true, ..Default::default() }).unwrap();
+        let key = "k".to_string();
+        tracking.remember(1, &[&key]);
+        tracking.remember(2, &[&key]);
+        tracking.remember(3, &[&key]);
+        assert_eq!(sorted(tracking.invalidated("k", Some(2))), [1]);
+        assert!(tracking.invalidated("k", None).is_empty());
+        tracking.remember(1, &[&key]);
+        tracking.disable(1);
+        assert!(tracking.invalidated("k", None).is_empty());
+    }
+
+    #[test]
+    fn optin_and_optout_follow_caching() {
+        let mut tracking = Tracking::default();
+        tracking.enable(1, TrackingClient { optin: true, ..Default::default() }).unwrap();
+        tracking.enable(2, TrackingClient { optout: true, caching: Some(false), ..Default::default() }).unwrap();
+        let key = "k".to_string();
+        tracking.remember(1, &[&key]);
+        tracking.remember(2, &[&key]);
+        assert!(tracking.invalidated("k", None).is_empty());
+        tracking.clients.get_mut(&1).unwrap().caching = Some(true);
+        tracking.reset_caching(2);
+        tracking.remember(1, &[&key]);
+        tracking.remember(2, &[&key]);
+        assert_eq!(sorted(tracking.invalidated("k", None)), [1, 2]);
+    }
+
+    #[test]
+    fn bcast_prefixes() {
+        let mut tracking = Tracking::default();
+        tracking.enable(1, TrackingClient { bcast: true, prefixes: vec!["user:".to_string()], ..Default::default() }).unwrap();
+        tracking.enable(2, TrackingClient { bcast: true, ..Default::default() }).unwrap();
+        assert_eq!(sorted(tracking.invalidated("user:1", None)), [1, 2]);
+        assert_eq!(tracking.invalidated("other", None), [2]);
+        let overlapping = TrackingClient { bcast: true, prefixes: vec!["user".to_string()], ..Default::default() };
+        assert!(tracking.enable(1, overlapping).is_err());
+        assert!(tracking.enable(1, TrackingClient::default()).is_err());
+        tracking.enable(1, TrackingClient { bcast: true, prefixes: vec!["user:".to_string(), "obj:".to_string()], ..Default::default() }).unwrap();
+        assert_eq!(tracking.clients[&1].prefixes, ["user:", "obj:"]);
+    }
+}